@@ -18,14 +18,12 @@ use criterion::Criterion;
 criterion_group!(benches, benchmark);
 criterion_main!(benches);
 
-extern crate keccak_hasher;
 extern crate reference_trie;
 extern crate trie_bench;
 
 fn benchmark(c: &mut Criterion) {
 	trie_bench::standard_benchmark::<
-		keccak_hasher::KeccakHasher,
-		reference_trie::ReferenceNodeCodec,
+		reference_trie::ExtensionLayout,
 		reference_trie::ReferenceTrieStream,
 	>(c, "ref");
 }
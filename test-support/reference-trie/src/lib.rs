@@ -17,21 +17,64 @@
 use std::fmt;
 use std::error::Error as StdError;
 use std::iter::once;
+use std::marker::PhantomData;
 use parity_codec::{Decode, Input, Output, Encode, Compact};
 use trie_root::Hasher;
-use trie_db::{node::Node, triedbmut::ChildReference, DBValue};
+use trie_db::{node::{Node, Value}, triedbmut::ChildReference};
 use keccak_hasher::KeccakHasher;
 
-pub use trie_db::{Trie, TrieMut, NibbleSlice, NodeCodec, Recorder, Record};
+pub use trie_db::{Trie, TrieMut, TrieDB, TrieDBMut, TrieError, TrieIterator, TrieLayout, NibbleSlice, NibbleVec, NodeCodec, Recorder, Record, Diff, proof, node};
+pub use trie_db::{TrieVisitor, visit_trie};
+pub use trie_db::{NodeCache, CachedNode, CachedHashDB, CachedHashDBRef};
 pub use trie_root::TrieStream;
 
-pub type RefTrieDB<'a> = trie_db::TrieDB<'a, keccak_hasher::KeccakHasher, ReferenceNodeCodec>;
-pub type RefTrieDBMut<'a> = trie_db::TrieDBMut<'a, KeccakHasher, ReferenceNodeCodec>;
-pub type RefFatDB<'a> = trie_db::FatDB<'a, KeccakHasher, ReferenceNodeCodec>;
-pub type RefFatDBMut<'a> = trie_db::FatDBMut<'a, KeccakHasher, ReferenceNodeCodec>;
-pub type RefSecTrieDB<'a> = trie_db::SecTrieDB<'a, KeccakHasher, ReferenceNodeCodec>;
-pub type RefSecTrieDBMut<'a> = trie_db::SecTrieDBMut<'a, KeccakHasher, ReferenceNodeCodec>;
-pub type RefLookup<'a, Q> = trie_db::Lookup<'a, KeccakHasher, ReferenceNodeCodec, Q>;
+/// Trie layout using extension nodes.
+#[derive(Default, Clone)]
+pub struct ExtensionLayout;
+
+impl TrieLayout for ExtensionLayout {
+	type Hash = KeccakHasher;
+	type Codec = ReferenceNodeCodec;
+}
+
+/// Trie layout without extension nodes.
+#[derive(Default, Clone)]
+pub struct NoExtensionLayout;
+
+impl TrieLayout for NoExtensionLayout {
+	const USE_EXTENSION: bool = false;
+	type Hash = KeccakHasher;
+	type Codec = ReferenceNodeCodecNoExt;
+}
+
+/// Trie layout without extension nodes, keeping values of 33 bytes or more in value nodes.
+#[derive(Default, Clone)]
+pub struct HashedValueLayout;
+
+impl TrieLayout for HashedValueLayout {
+	const USE_EXTENSION: bool = false;
+	const VALUE_THRESHOLD: Option<usize> = Some(33);
+	type Hash = KeccakHasher;
+	type Codec = ReferenceNodeCodecNoExt;
+}
+
+pub type RefTrieDB<'a> = trie_db::TrieDB<'a, ExtensionLayout>;
+pub type RefTrieDBMut<'a> = trie_db::TrieDBMut<'a, ExtensionLayout>;
+pub type RefTrieDBOwned<D> = trie_db::TrieDBOwned<ExtensionLayout, D>;
+pub type RefTrieDBMutOwned<D> = trie_db::TrieDBMutOwned<ExtensionLayout, D>;
+pub type RefFatDB<'a> = trie_db::FatDB<'a, ExtensionLayout>;
+pub type RefFatDBMut<'a> = trie_db::FatDBMut<'a, ExtensionLayout>;
+pub type RefSecTrieDB<'a> = trie_db::SecTrieDB<'a, ExtensionLayout>;
+pub type RefSecTrieDBMut<'a> = trie_db::SecTrieDBMut<'a, ExtensionLayout>;
+pub type RefTrieDBReverseIterator<'a> = trie_db::TrieDBReverseIterator<'a, ExtensionLayout>;
+pub type RefTrieDBNodeIterator<'a> = trie_db::TrieDBNodeIterator<'a, ExtensionLayout>;
+pub type RefTrieDBDiffIterator<'a> = trie_db::TrieDBDiffIterator<'a, ExtensionLayout>;
+pub type RefLookup<'a, Q> = trie_db::Lookup<'a, ExtensionLayout, Q>;
+
+pub type RefTrieDBNoExt<'a> = trie_db::TrieDB<'a, NoExtensionLayout>;
+pub type RefTrieDBMutNoExt<'a> = trie_db::TrieDBMut<'a, NoExtensionLayout>;
+pub type RefTrieDBReverseIteratorNoExt<'a> = trie_db::TrieDBReverseIterator<'a, NoExtensionLayout>;
+pub type RefTrieDBNodeIteratorNoExt<'a> = trie_db::TrieDBNodeIterator<'a, NoExtensionLayout>;
 
 pub fn ref_trie_root<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out where
 	I: IntoIterator<Item = (A, B)>,
@@ -41,6 +84,14 @@ pub fn ref_trie_root<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out where
 	trie_root::trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(input)
 }
 
+pub fn ref_trie_root_no_ext<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]> + Ord + fmt::Debug,
+	B: AsRef<[u8]> + fmt::Debug,
+{
+	trie_root::trie_root::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input)
+}
+
 const EMPTY_TRIE: u8 = 0;
 const LEAF_NODE_OFFSET: u8 = 1;
 const EXTENSION_NODE_OFFSET: u8 = 128;
@@ -51,6 +102,26 @@ const EXTENSION_NODE_OVER: u8 = BRANCH_NODE_NO_VALUE - EXTENSION_NODE_OFFSET;
 const LEAF_NODE_LAST: u8 = EXTENSION_NODE_OFFSET - 1;
 const EXTENSION_NODE_LAST: u8 = BRANCH_NODE_NO_VALUE - 1;
 
+// Without extension nodes, every node but the empty one is a header byte followed by
+// the number of nibbles of its partial key and the nibbles themselves.
+const NO_EXT_LEAF_NODE: u8 = 1;
+const NO_EXT_BRANCH_NODE_NO_VALUE: u8 = 2;
+const NO_EXT_BRANCH_NODE_WITH_VALUE: u8 = 3;
+// the value is replaced by the hash of its value node.
+const NO_EXT_HASHED_VALUE_LEAF_NODE: u8 = 4;
+const NO_EXT_BRANCH_NODE_WITH_HASHED_VALUE: u8 = 5;
+
+/// Create the start of a node without extension nodes, with its partial key.
+fn nibbled_node_start(header: u8, nibbles: &[u8]) -> Vec<u8> {
+	let mut output = vec![header];
+	Compact(nibbles.len() as u32).encode_to(&mut output);
+	if nibbles.len() % 2 == 1 {
+		output.push(nibbles[0]);
+	}
+	output.extend(nibbles[nibbles.len() % 2..].chunks(2).map(|ch| ch[0] << 4 | ch[1]));
+	output
+}
+
 /// Create a leaf/extension node, encoding a number of nibbles. Note that this
 /// cannot handle a number of nibbles that is zero or greater than 125 and if
 /// you attempt to do so *IT WILL PANIC*.
@@ -82,16 +153,25 @@ pub fn branch_node(has_value: bool, has_children: impl Iterator<Item = bool>) ->
 	[first, (bitmap % 256 ) as u8, (bitmap / 256 ) as u8]
 }
 
-/// Reference implementation of a `TrieStream`.
+/// Reference implementation of a `TrieStream`, encoding nodes as layout `L` does: with
+/// or without extension nodes, and inlining the same nodes.
 #[derive(Default, Clone)]
-pub struct ReferenceTrieStream {
-	buffer: Vec<u8>
+pub struct ReferenceTrieStream<L = ExtensionLayout> {
+	buffer: Vec<u8>,
+	marker: PhantomData<L>,
 }
 
-impl TrieStream for ReferenceTrieStream {
+/// Reference implementation of a `TrieStream` without extension nodes.
+pub type ReferenceTrieStreamNoExt = ReferenceTrieStream<NoExtensionLayout>;
+
+impl<L: TrieLayout> TrieStream for ReferenceTrieStream<L> {
+	const USE_EXTENSION: bool = L::USE_EXTENSION;
+	const INLINE_THRESHOLD: Option<usize> = L::INLINE_THRESHOLD;
+
 	fn new() -> Self {
 		ReferenceTrieStream {
-			buffer: Vec::new()
+			buffer: Vec::new(),
+			marker: PhantomData,
 		}
 	}
 
@@ -100,29 +180,52 @@ impl TrieStream for ReferenceTrieStream {
 	}
 
 	fn append_leaf(&mut self, key: &[u8], value: &[u8]) {
-		self.buffer.extend(fuse_nibbles_node(key, true));
+		if L::USE_EXTENSION {
+			self.buffer.extend(fuse_nibbles_node(key, true));
+		} else {
+			self.buffer.extend(nibbled_node_start(NO_EXT_LEAF_NODE, key));
+		}
 		value.encode_to(&mut self.buffer);
 	}
 
 	fn begin_branch(&mut self, maybe_value: Option<&[u8]>, has_children: impl Iterator<Item = bool>) {
+		if !L::USE_EXTENSION {
+			return self.begin_nibbled_branch(&[], maybe_value, has_children);
+		}
 		self.buffer.extend(&branch_node(maybe_value.is_some(), has_children));
 		if let Some(value) = maybe_value {
 			value.encode_to(&mut self.buffer);
 		}
 	}
 
+	fn begin_nibbled_branch(&mut self, partial: &[u8], maybe_value: Option<&[u8]>, has_children: impl Iterator<Item = bool>) {
+		assert!(!L::USE_EXTENSION, "layouts with extension nodes never encode nibbled branches");
+		let header = match maybe_value {
+			Some(_) => NO_EXT_BRANCH_NODE_WITH_VALUE,
+			None => NO_EXT_BRANCH_NODE_NO_VALUE,
+		};
+		self.buffer.extend(nibbled_node_start(header, partial));
+		self.buffer.extend(&branch_node(maybe_value.is_some(), has_children)[1..]);
+		if let Some(value) = maybe_value {
+			value.encode_to(&mut self.buffer);
+		}
+	}
+
 	fn append_extension(&mut self, key: &[u8]) {
+		assert!(L::USE_EXTENSION, "layouts without extension nodes never encode extensions");
 		self.buffer.extend(fuse_nibbles_node(key, false));
 	}
 
 	fn append_substream<H: Hasher>(&mut self, other: Self) {
 		let data = other.out();
-		match data.len() {
-			0...31 => data.encode_to(&mut self.buffer),
-			_ => H::hash(&data).as_ref().encode_to(&mut self.buffer),
+		match Self::is_inline::<H>(data.len()) {
+			true => data.encode_to(&mut self.buffer),
+			false => H::hash(&data).as_ref().encode_to(&mut self.buffer),
 		}
 	}
 
+	fn as_raw(&self) -> &[u8] { &self.buffer }
+
 	fn out(self) -> Vec<u8> { self.buffer }
 }
 
@@ -191,6 +294,12 @@ fn take<'a>(input: &mut &'a[u8], count: usize) -> Option<&'a[u8]> {
 	Some(r)
 }
 
+// The nibbles of a partial key encoded from a `NibbleSlice`.
+fn partial_nibbles(partial: &[u8]) -> Vec<u8> {
+	let slice = NibbleSlice::from_encoded(partial).0;
+	(0..slice.len()).map(|i| slice.at(i)).collect()
+}
+
 fn partial_to_key(partial: &[u8], offset: u8, over: u8) -> Vec<u8> {
 	let nibble_count = (partial.len() - 1) * 2 + if partial[0] & 16 == 16 { 1 } else { 0 };
 	assert!(nibble_count < over as usize);
@@ -221,7 +330,7 @@ impl NodeCodec<KeccakHasher> for ReferenceNodeCodec {
 				let bitmap = u16::decode(input).ok_or(ReferenceError::BadFormat)?;
 				let value = if has_value {
 					let count = <Compact<u32>>::decode(input).ok_or(ReferenceError::BadFormat)?.0 as usize;
-					Some(take(input, count).ok_or(ReferenceError::BadFormat)?)
+					Some(Value::Inline(take(input, count).ok_or(ReferenceError::BadFormat)?))
 				} else {
 					None
 				};
@@ -246,7 +355,7 @@ impl NodeCodec<KeccakHasher> for ReferenceNodeCodec {
 				let nibble_data = take(input, (nibble_count + 1) / 2).ok_or(ReferenceError::BadFormat)?;
 				let nibble_slice = NibbleSlice::new_offset(nibble_data, nibble_count % 2);
 				let count = <Compact<u32>>::decode(input).ok_or(ReferenceError::BadFormat)?.0 as usize;
-				Ok(Node::Leaf(nibble_slice, take(input, count).ok_or(ReferenceError::BadFormat)?))
+				Ok(Node::Leaf(nibble_slice, Value::Inline(take(input, count).ok_or(ReferenceError::BadFormat)?)))
 			}
 		}
 	}
@@ -269,9 +378,9 @@ impl NodeCodec<KeccakHasher> for ReferenceNodeCodec {
 		vec![EMPTY_TRIE]
 	}
 
-	fn leaf_node(partial: &[u8], value: &[u8]) -> Vec<u8> {
+	fn leaf_node(partial: &[u8], value: Value) -> Vec<u8> {
 		let mut output = partial_to_key(partial, LEAF_NODE_OFFSET, LEAF_NODE_OVER);
-		value.encode_to(&mut output);
+		inline_value(value).encode_to(&mut output);
 		output
 	}
 
@@ -279,17 +388,17 @@ impl NodeCodec<KeccakHasher> for ReferenceNodeCodec {
 		let mut output = partial_to_key(partial, EXTENSION_NODE_OFFSET, EXTENSION_NODE_OVER);
 		match child {
 			ChildReference::Hash(h) => h.as_ref().encode_to(&mut output),
-			ChildReference::Inline(inline_data, len) => (&AsRef::<[u8]>::as_ref(&inline_data)[..len]).encode_to(&mut output),
+			ChildReference::Inline(inline_data) => (&inline_data[..]).encode_to(&mut output),
 		};
 		output
 	}
 
-	fn branch_node<I>(children: I, maybe_value: Option<DBValue>) -> Vec<u8> where
+	fn branch_node<I>(children: I, maybe_value: Option<Value>) -> Vec<u8> where
 		I: IntoIterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>> + Iterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>>
 	{
 		let mut output = vec![0, 0, 0];
 		let have_value = if let Some(value) = maybe_value {
-			(&*value).encode_to(&mut output);
+			inline_value(value).encode_to(&mut output);
 			true
 		} else {
 			false
@@ -299,8 +408,8 @@ impl NodeCodec<KeccakHasher> for ReferenceNodeCodec {
 				h.as_ref().encode_to(&mut output);
 				true
 			}
-			Some(ChildReference::Inline(inline_data, len)) => {
-				(&AsRef::<[u8]>::as_ref(&inline_data)[..len]).encode_to(&mut output);
+			Some(ChildReference::Inline(inline_data)) => {
+				(&inline_data[..]).encode_to(&mut output);
 				true
 			}
 			None => false,
@@ -308,4 +417,141 @@ impl NodeCodec<KeccakHasher> for ReferenceNodeCodec {
 		output[0..3].copy_from_slice(&prefix[..]);
 		output
 	}
+
+	fn branch_node_nibbled<I>(_partial: &[u8], _children: I, _maybe_value: Option<Value>) -> Vec<u8> where
+		I: IntoIterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>> + Iterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>>
+	{
+		unreachable!("no nibbled branches")
+	}
+}
+
+// The value of a node of `ReferenceNodeCodec`, which keeps every value in its node.
+fn inline_value(value: Value) -> &[u8] {
+	match value {
+		Value::Inline(value) => value,
+		Value::Node(_) => unreachable!("layouts using this codec keep every value in its node"),
+	}
+}
+
+// Decode a value of `ReferenceNodeCodecNoExt`, or the hash of its value node if `hashed`.
+fn decode_value<'a>(input: &mut &'a [u8], hashed: bool) -> Result<Value<'a>, ReferenceError> {
+	let count = <Compact<u32>>::decode(input).ok_or(ReferenceError::BadFormat)?.0 as usize;
+	let value = take(input, count).ok_or(ReferenceError::BadFormat)?;
+	Ok(match hashed {
+		true => Value::Node(value),
+		false => Value::Inline(value),
+	})
+}
+
+/// Simple reference implementation of a `NodeCodec` without extension nodes.
+///
+/// Values may be kept in value nodes, in which case the node holds their hash.
+#[derive(Default, Clone)]
+pub struct ReferenceNodeCodecNoExt;
+
+impl NodeCodec<KeccakHasher> for ReferenceNodeCodecNoExt {
+	type Error = ReferenceError;
+	const VALUE_NODES: bool = true;
+
+	fn hashed_null_node() -> <KeccakHasher as Hasher>::Out {
+		<ReferenceNodeCodec as NodeCodec<KeccakHasher>>::hashed_null_node()
+	}
+
+	fn decode(data: &[u8]) -> ::std::result::Result<Node, Self::Error> {
+		let input = &mut &*data;
+		let header = input.read_byte().ok_or(ReferenceError::BadFormat)?;
+		if header == EMPTY_TRIE {
+			return Ok(Node::Empty)
+		}
+		let nibble_count = <Compact<u32>>::decode(input).ok_or(ReferenceError::BadFormat)?.0 as usize;
+		let nibble_data = take(input, (nibble_count + 1) / 2).ok_or(ReferenceError::BadFormat)?;
+		let nibble_slice = NibbleSlice::new_offset(nibble_data, nibble_count % 2);
+		let value_kind = match header {
+			NO_EXT_LEAF_NODE => return Ok(Node::Leaf(nibble_slice, decode_value(input, false)?)),
+			NO_EXT_HASHED_VALUE_LEAF_NODE => return Ok(Node::Leaf(nibble_slice, decode_value(input, true)?)),
+			NO_EXT_BRANCH_NODE_NO_VALUE => None,
+			NO_EXT_BRANCH_NODE_WITH_VALUE => Some(false),
+			NO_EXT_BRANCH_NODE_WITH_HASHED_VALUE => Some(true),
+			_ => return Err(ReferenceError::BadFormat),
+		};
+		let bitmap = u16::decode(input).ok_or(ReferenceError::BadFormat)?;
+		let value = match value_kind {
+			Some(hashed) => Some(decode_value(input, hashed)?),
+			None => None,
+		};
+		let mut children = [None; 16];
+		let mut pot_cursor = 1;
+		for i in 0..16 {
+			if bitmap & pot_cursor != 0 {
+				let count = <Compact<u32>>::decode(input).ok_or(ReferenceError::BadFormat)?.0 as usize;
+				children[i] = Some(take(input, count).ok_or(ReferenceError::BadFormat)?);
+			}
+			pot_cursor <<= 1;
+		}
+		Ok(Node::NibbledBranch(nibble_slice, children, value))
+	}
+
+	fn try_decode_hash(data: &[u8]) -> Option<<KeccakHasher as Hasher>::Out> {
+		<ReferenceNodeCodec as NodeCodec<KeccakHasher>>::try_decode_hash(data)
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		data == &[EMPTY_TRIE][..]
+	}
+
+	fn empty_node() -> Vec<u8> {
+		vec![EMPTY_TRIE]
+	}
+
+	fn leaf_node(partial: &[u8], value: Value) -> Vec<u8> {
+		let (header, value) = match value {
+			Value::Inline(value) => (NO_EXT_LEAF_NODE, value),
+			Value::Node(hash) => (NO_EXT_HASHED_VALUE_LEAF_NODE, hash),
+		};
+		let mut output = nibbled_node_start(header, &partial_nibbles(partial));
+		value.encode_to(&mut output);
+		output
+	}
+
+	fn ext_node(_partial: &[u8], _child: ChildReference<<KeccakHasher as Hasher>::Out>) -> Vec<u8> {
+		unreachable!("no extension nodes")
+	}
+
+	fn branch_node<I>(children: I, maybe_value: Option<Value>) -> Vec<u8> where
+		I: IntoIterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>> + Iterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>>
+	{
+		Self::branch_node_nibbled(&NibbleSlice::new(&[]).encoded(false), children, maybe_value)
+	}
+
+	fn branch_node_nibbled<I>(partial: &[u8], children: I, maybe_value: Option<Value>) -> Vec<u8> where
+		I: IntoIterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>> + Iterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>>
+	{
+		let (header, maybe_value) = match maybe_value {
+			Some(Value::Inline(value)) => (NO_EXT_BRANCH_NODE_WITH_VALUE, Some(value)),
+			Some(Value::Node(hash)) => (NO_EXT_BRANCH_NODE_WITH_HASHED_VALUE, Some(hash)),
+			None => (NO_EXT_BRANCH_NODE_NO_VALUE, None),
+		};
+		let mut output = nibbled_node_start(header, &partial_nibbles(partial));
+		let bitmap_index = output.len();
+		output.extend_from_slice(&[0, 0]);
+		let have_value = if let Some(value) = maybe_value {
+			value.encode_to(&mut output);
+			true
+		} else {
+			false
+		};
+		let prefix = branch_node(have_value, children.map(|maybe_child| match maybe_child {
+			Some(ChildReference::Hash(h)) => {
+				h.as_ref().encode_to(&mut output);
+				true
+			}
+			Some(ChildReference::Inline(inline_data)) => {
+				(&inline_data[..]).encode_to(&mut output);
+				true
+			}
+			None => false,
+		}));
+		output[bitmap_index..bitmap_index + 2].copy_from_slice(&prefix[1..]);
+		output
+	}
 }
@@ -0,0 +1,286 @@
+// Copyright 2017, 2018 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runner for JSON trie test fixtures, in the format of the Ethereum `trietest.json`,
+//! `trieanyorder.json` and `hex_encoded_securetrie_test.json` files.
+//!
+//! A fixture maps case names to an `in` field, holding either a list of `[key, value]`
+//! pairs applied in order or an object of key/value pairs, and the expected `root`.
+//! Strings starting with `0x` are hex encoded, any other string stands for its bytes,
+//! and a `null` value removes the key.
+//!
+//! The `fixtures` directory of this crate vendors the upstream `trietest.json` and
+//! `trieanyorder.json` files and the `test1` case of `hex_encoded_securetrie_test.json`;
+//! see `fixture_path`.
+
+use std::collections::BTreeMap;
+use std::{fmt, fs, io};
+use std::path::{Path, PathBuf};
+use memory_db::{MemoryDB, PrefixedKey};
+use rustc_hex::{FromHex, ToHex};
+use serde_json::Value;
+use trie_db::{DBValue, NodeCodec, SecTrieDBMut, TrieDBMut, TrieHash, TrieLayout, TrieMut};
+use trie_root::TrieStream;
+
+/// A single case of a fixture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+	/// Name of the case.
+	pub name: String,
+	/// Changes to apply in order; `None` removes the key.
+	pub input: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+	/// Expected root.
+	pub root: Vec<u8>,
+}
+
+impl TestCase {
+	/// The contents of the trie once all changes are applied.
+	pub fn state(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+		let mut state = BTreeMap::new();
+		for (key, value) in &self.input {
+			match value {
+				Some(value) if !value.is_empty() => { state.insert(key.clone(), value.clone()); }
+				_ => { state.remove(key); }
+			}
+		}
+		state
+	}
+}
+
+/// Error loading a fixture.
+#[derive(Debug)]
+pub enum Error {
+	/// The fixture file could not be read.
+	Io(io::Error),
+	/// The fixture is not valid JSON.
+	Json(serde_json::Error),
+	/// The fixture is valid JSON, but not in the expected format.
+	Format(String),
+}
+
+impl std::error::Error for Error {
+	fn description(&self) -> &str {
+		"fixture loading error"
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "Cannot read fixture: {}", e),
+			Error::Json(e) => write!(f, "Invalid JSON: {}", e),
+			Error::Format(e) => write!(f, "Invalid fixture: {}", e),
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl From<serde_json::Error> for Error {
+	fn from(e: serde_json::Error) -> Self {
+		Error::Json(e)
+	}
+}
+
+/// Path of a fixture file vendored with this crate, e.g. `fixture_path("trietest.json")`.
+pub fn fixture_path(name: &str) -> PathBuf {
+	Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(name)
+}
+
+/// Load the cases of a fixture file.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<TestCase>, Error> {
+	parse(&fs::read_to_string(path)?)
+}
+
+/// Parse the cases of a fixture.
+pub fn parse(json: &str) -> Result<Vec<TestCase>, Error> {
+	let cases = match serde_json::from_str(json)? {
+		Value::Object(cases) => cases,
+		_ => return Err(Error::Format("expected an object of cases".into())),
+	};
+
+	cases.into_iter().map(|(name, case)| {
+		let input = match &case["in"] {
+			Value::Array(pairs) => pairs.iter().map(|pair| match pair.as_array().map(|p| &p[..]) {
+				Some([key, value]) => Ok((bytes(key)?, optional_bytes(value)?)),
+				_ => Err(Error::Format(format!("{}: expected a [key, value] pair", name))),
+			}).collect::<Result<_, Error>>()?,
+			Value::Object(pairs) => pairs.iter().map(|(key, value)| {
+				Ok((string_bytes(key)?, optional_bytes(value)?))
+			}).collect::<Result<_, Error>>()?,
+			_ => return Err(Error::Format(format!("{}: expected `in` to be a list or an object", name))),
+		};
+		let root = bytes(&case["root"])?;
+		Ok(TestCase { name, input, root })
+	}).collect()
+}
+
+fn string_bytes(s: &str) -> Result<Vec<u8>, Error> {
+	if s.starts_with("0x") {
+		s[2..].from_hex().map_err(|e| Error::Format(format!("{}: {}", s, e)))
+	} else {
+		Ok(s.as_bytes().to_vec())
+	}
+}
+
+fn bytes(value: &Value) -> Result<Vec<u8>, Error> {
+	match value {
+		Value::String(s) => string_bytes(s),
+		_ => Err(Error::Format(format!("expected a string, got {}", value))),
+	}
+}
+
+fn optional_bytes(value: &Value) -> Result<Option<Vec<u8>>, Error> {
+	match value {
+		Value::Null => Ok(None),
+		value => bytes(value).map(Some),
+	}
+}
+
+/// The way a root was computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runner {
+	/// Changes applied in order through a `TrieDBMut`.
+	TrieDBMut,
+	/// Changes applied in order through a `SecTrieDBMut`.
+	SecTrieDBMut,
+	/// `trie_root` over the final contents.
+	TrieRoot,
+	/// `sec_trie_root` over the final contents.
+	SecTrieRoot,
+}
+
+/// A case whose computed root differs from the expected one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+	/// Name of the case.
+	pub case: String,
+	/// How the root was computed.
+	pub runner: Runner,
+	/// Expected root.
+	pub expected: Vec<u8>,
+	/// Computed root, or the error which prevented computing it.
+	pub got: Result<Vec<u8>, String>,
+}
+
+impl fmt::Display for Mismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} ({:?}): expected 0x{}, ", self.case, self.runner, self.expected.to_hex::<String>())?;
+		match &self.got {
+			Ok(root) => write!(f, "got 0x{}", root.to_hex::<String>()),
+			Err(e) => write!(f, "failed: {}", e),
+		}
+	}
+}
+
+/// Check the cases of a plain trie fixture (`trietest.json`, `trieanyorder.json`)
+/// through both `TrieDBMut` and `trie_root`, returning every mismatch.
+pub fn check<L, S>(cases: &[TestCase]) -> Vec<Mismatch> where
+	L: TrieLayout,
+	TrieHash<L>: Ord,
+	S: TrieStream,
+{
+	check_with::<L, S>(cases, false)
+}
+
+/// Check the cases of a secure trie fixture (`hex_encoded_securetrie_test.json`)
+/// through both `SecTrieDBMut` and `sec_trie_root`, returning every mismatch.
+pub fn check_secure<L, S>(cases: &[TestCase]) -> Vec<Mismatch> where
+	L: TrieLayout,
+	TrieHash<L>: Ord,
+	S: TrieStream,
+{
+	check_with::<L, S>(cases, true)
+}
+
+fn check_with<L, S>(cases: &[TestCase], secure: bool) -> Vec<Mismatch> where
+	L: TrieLayout,
+	TrieHash<L>: Ord,
+	S: TrieStream,
+{
+	let mut mismatches = Vec::new();
+	for case in cases {
+		let mut report = |runner, got: Result<Vec<u8>, String>| {
+			if got.as_ref().map_or(true, |root| *root != case.root) {
+				mismatches.push(Mismatch { case: case.name.clone(), runner, expected: case.root.clone(), got });
+			}
+		};
+
+		let null_node = L::Codec::empty_node();
+		let mut memdb = MemoryDB::<L::Hash, PrefixedKey<L::Hash>, DBValue>::from_null_node(&null_node, null_node[..].into());
+		let mut root = TrieHash::<L>::default();
+		if secure {
+			report(Runner::SecTrieDBMut, apply(&mut SecTrieDBMut::<L>::new(&mut memdb, &mut root), case));
+			report(Runner::SecTrieRoot, Ok(trie_root::sec_trie_root::<L::Hash, S, _, _, _>(case.state()).as_ref().to_vec()));
+		} else {
+			report(Runner::TrieDBMut, apply(&mut TrieDBMut::<L>::new(&mut memdb, &mut root), case));
+			report(Runner::TrieRoot, Ok(trie_root::trie_root::<L::Hash, S, _, _, _>(case.state()).as_ref().to_vec()));
+		}
+	}
+	mismatches
+}
+
+fn apply<L: TrieLayout, T: TrieMut<L>>(t: &mut T, case: &TestCase) -> Result<Vec<u8>, String> {
+	for (key, value) in &case.input {
+		match value {
+			Some(value) => t.insert(key, value),
+			None => t.remove(key),
+		}.map_err(|e| e.to_string())?;
+	}
+	Ok(t.root().as_ref().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+	use trie_ethereum::{EthereumLayout, RlpTrieStream};
+	use super::{check, check_secure, fixture_path, load, parse, Runner};
+
+	#[test]
+	fn ethereum_fixtures() {
+		for name in &["trietest.json", "trieanyorder.json"] {
+			let cases = load(fixture_path(name)).unwrap();
+			assert!(!cases.is_empty());
+			let mismatches = check::<EthereumLayout, RlpTrieStream>(&cases);
+			assert!(mismatches.is_empty(), "{}", mismatches.iter().map(|m| m.to_string()).collect::<Vec<_>>().join("\n"));
+		}
+
+		let cases = load(fixture_path("hex_encoded_securetrie_test.json")).unwrap();
+		let mismatches = check_secure::<EthereumLayout, RlpTrieStream>(&cases);
+		assert!(mismatches.is_empty(), "{}", mismatches.iter().map(|m| m.to_string()).collect::<Vec<_>>().join("\n"));
+	}
+
+	#[test]
+	fn reports_mismatches() {
+		let cases = parse(r#"{
+			"wrong": {
+				"in": [["dog", "puppy"], ["doge", null], ["0x0102", "0x03"]],
+				"root": "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+			}
+		}"#).unwrap();
+		assert_eq!(cases[0].input, vec![
+			(b"dog".to_vec(), Some(b"puppy".to_vec())),
+			(b"doge".to_vec(), None),
+			(vec![1, 2], Some(vec![3])),
+		]);
+
+		let mismatches = check::<EthereumLayout, RlpTrieStream>(&cases);
+		assert_eq!(mismatches.iter().map(|m| m.runner).collect::<Vec<_>>(), vec![Runner::TrieDBMut, Runner::TrieRoot]);
+		assert_eq!(mismatches[0].got, mismatches[1].got);
+		assert!(mismatches[0].to_string().starts_with("wrong (TrieDBMut): expected 0x56e81f"));
+	}
+}
@@ -0,0 +1,340 @@
+// Copyright 2017, 2019 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iteration over the differences between two tries.
+
+use nibbleslice::NibbleSlice;
+use node::{Node, Value};
+use node_codec::NodeCodec;
+use triedb::TrieDB;
+use super::{DBValue, Result, Trie, TrieError, TrieLayout, TrieHash, CError, load_value};
+use elastic_array::ElasticArray36;
+use ::core_::iter;
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// A difference between two tries.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Diff {
+	/// The key only has a value in the new trie.
+	Added(Vec<u8>, DBValue),
+	/// The key only has a value in the old trie.
+	Removed(Vec<u8>, DBValue),
+	/// The key has different values in the two tries; the old value comes first.
+	Changed(Vec<u8>, DBValue, DBValue),
+}
+
+// An element of the traversal frontier of a trie, positioned by its nibble path.
+enum Entry<HO> {
+	// a value stored under the path.
+	Value(Vec<u8>, DBValue),
+	// a node stored by hash which has not been fetched yet.
+	Node(Vec<u8>, HO),
+}
+
+// Traversal state of one of the two tries.
+struct Side<'a, L: TrieLayout> {
+	trie: &'a TrieDB<'a, L>,
+	// entries still to visit, the first in key order last.
+	stack: Vec<Entry<TrieHash<L>>>,
+}
+
+impl<'a, L: TrieLayout> Side<'a, L> {
+	fn new(trie: &'a TrieDB<'a, L>) -> Self {
+		Side { trie, stack: iter::once(Entry::Node(Vec::new(), *trie.root())).collect() }
+	}
+
+	// Replace the node on top of the stack with its contents.
+	fn expand(&mut self) -> Result<(), TrieHash<L>, CError<L>> {
+		let (path, hash) = match self.stack.pop() {
+			Some(Entry::Node(path, hash)) => (path, hash),
+			_ => unreachable!("only called with a node on top of the stack; qed"),
+		};
+		let data = self.trie.db()
+			.get(&hash, &encoded_prefix(&path))
+			.ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash)))?;
+		self.push_node(path, hash, &data)
+	}
+
+	// Push the contents of an encoded node, inline children included.
+	fn push_node(&mut self, path: Vec<u8>, hash: TrieHash<L>, data: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		match L::Codec::decode(data).map_err(|e| Box::new(TrieError::DecoderError(hash, e)))? {
+			Node::Empty => {}
+			Node::Leaf(slice, value) => {
+				let mut path = path;
+				path.extend(slice.iter());
+				let value = load_value::<L, _>(self.trie.db(), value, &encoded_prefix(&path))?;
+				self.stack.push(Entry::Value(path, value));
+			}
+			Node::Extension(slice, child) => {
+				let mut path = path;
+				path.extend(slice.iter());
+				self.push_child(path, hash, child)?;
+			}
+			Node::Branch(children, value) => self.push_branch(path, hash, children, value)?,
+			Node::NibbledBranch(slice, children, value) => {
+				let mut path = path;
+				path.extend(slice.iter());
+				self.push_branch(path, hash, children, value)?;
+			}
+		}
+		Ok(())
+	}
+
+	// Push the children and value of a branch at `path`.
+	fn push_branch(
+		&mut self,
+		path: Vec<u8>,
+		hash: TrieHash<L>,
+		children: [Option<&[u8]>; 16],
+		value: Option<Value>,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		for i in (0..16).rev() {
+			if let Some(child) = children[i] {
+				let mut child_path = path.clone();
+				child_path.push(i as u8);
+				self.push_child(child_path, hash, child)?;
+			}
+		}
+		if let Some(value) = value {
+			let value = load_value::<L, _>(self.trie.db(), value, &encoded_prefix(&path))?;
+			self.stack.push(Entry::Value(path, value));
+		}
+		Ok(())
+	}
+
+	fn push_child(&mut self, path: Vec<u8>, parent: TrieHash<L>, data: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		match L::Codec::try_decode_hash(data) {
+			Some(hash) => {
+				self.stack.push(Entry::Node(path, hash));
+				Ok(())
+			}
+			None => self.push_node(path, parent, data),
+		}
+	}
+
+	fn pop_value(&mut self) -> (Vec<u8>, DBValue) {
+		match self.stack.pop() {
+			Some(Entry::Value(path, value)) => (key(&path), value),
+			_ => unreachable!("only called with a value on top of the stack; qed"),
+		}
+	}
+}
+
+/// Iterator over the differences between two tries, in ascending key order.
+///
+/// Both tries are walked in lockstep, and subtrees which are stored under the same
+/// hash at the same position in both tries are skipped without being fetched.
+pub struct TrieDBDiffIterator<'a, L: TrieLayout> {
+	old: Side<'a, L>,
+	new: Side<'a, L>,
+}
+
+impl<'a, L: TrieLayout> TrieDBDiffIterator<'a, L> {
+	/// Create a new iterator over the changes needed to turn `old` into `new`.
+	pub fn new(old: &'a TrieDB<'a, L>, new: &'a TrieDB<'a, L>) -> Self {
+		TrieDBDiffIterator { old: Side::new(old), new: Side::new(new) }
+	}
+}
+
+// What to do with the first entries of both sides.
+enum Step {
+	ExpandOld,
+	ExpandNew,
+	Removed,
+	Added,
+	Compare,
+	Skip,
+}
+
+impl<'a, L: TrieLayout> Iterator for TrieDBDiffIterator<'a, L> {
+	type Item = Result<Diff, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let step = match (self.old.stack.last(), self.new.stack.last()) {
+				(None, None) => return None,
+				(Some(Entry::Value(..)), None) => Step::Removed,
+				(Some(Entry::Node(..)), None) => Step::ExpandOld,
+				(None, Some(Entry::Value(..))) => Step::Added,
+				(None, Some(Entry::Node(..))) => Step::ExpandNew,
+				(Some(Entry::Node(a, a_hash)), Some(Entry::Node(b, b_hash))) => {
+					if a == b && a_hash == b_hash {
+						Step::Skip
+					} else if a <= b {
+						Step::ExpandOld
+					} else {
+						Step::ExpandNew
+					}
+				}
+				(Some(Entry::Value(a, _)), Some(Entry::Value(b, _))) => {
+					if a < b {
+						Step::Removed
+					} else if a > b {
+						Step::Added
+					} else {
+						Step::Compare
+					}
+				}
+				(Some(Entry::Value(a, _)), Some(Entry::Node(b, _))) => {
+					if a < b { Step::Removed } else { Step::ExpandNew }
+				}
+				(Some(Entry::Node(a, _)), Some(Entry::Value(b, _))) => {
+					if b < a { Step::Added } else { Step::ExpandOld }
+				}
+			};
+
+			match step {
+				Step::ExpandOld => if let Err(e) = self.old.expand() {
+					return Some(Err(e));
+				},
+				Step::ExpandNew => if let Err(e) = self.new.expand() {
+					return Some(Err(e));
+				},
+				Step::Removed => {
+					let (key, value) = self.old.pop_value();
+					return Some(Ok(Diff::Removed(key, value)));
+				}
+				Step::Added => {
+					let (key, value) = self.new.pop_value();
+					return Some(Ok(Diff::Added(key, value)));
+				}
+				Step::Compare => {
+					let (key, old) = self.old.pop_value();
+					let (_, new) = self.new.pop_value();
+					if old != new {
+						return Some(Ok(Diff::Changed(key, old, new)));
+					}
+				}
+				Step::Skip => {
+					self.old.stack.pop();
+					self.new.stack.pop();
+				}
+			}
+		}
+	}
+}
+
+// Collapse a path of nibbles into bytes.
+fn key(path: &[u8]) -> Vec<u8> {
+	path.chunks(2).map(|pair| pair[0] << 4 | pair.get(1).cloned().unwrap_or(0)).collect()
+}
+
+// Encoded prefix under which the node at the given path is stored.
+fn encoded_prefix(path: &[u8]) -> ElasticArray36<u8> {
+	NibbleSlice::new(&key(path)).encoded_leftmost(path.len(), false)
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use standardmap::{Alphabet, StandardMap, ValueMode};
+	use DBValue;
+	use reference_trie::{RefTrieDB, RefTrieDBMut, RefTrieDBDiffIterator, Trie, TrieMut, Diff};
+	use std::collections::BTreeMap;
+
+	type MemDB = MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>;
+
+	fn naive_diff(old: &RefTrieDB, new: &RefTrieDB) -> Vec<Diff> {
+		let old: BTreeMap<_, _> = old.iter().unwrap().map(|x| x.unwrap()).collect();
+		let new: BTreeMap<_, _> = new.iter().unwrap().map(|x| x.unwrap()).collect();
+		let mut keys: Vec<_> = old.keys().chain(new.keys()).cloned().collect();
+		keys.sort();
+		keys.dedup();
+		keys.into_iter().filter_map(|key| match (old.get(&key), new.get(&key)) {
+			(Some(a), Some(b)) if a != b => Some(Diff::Changed(key, a.clone(), b.clone())),
+			(Some(a), None) => Some(Diff::Removed(key, a.clone())),
+			(None, Some(b)) => Some(Diff::Added(key, b.clone())),
+			_ => None,
+		}).collect()
+	}
+
+	fn populate(db: &mut MemDB, root: &mut <KeccakHasher as ::hash_db::Hasher>::Out, pairs: &[(Vec<u8>, Vec<u8>)]) {
+		let mut t = RefTrieDBMut::from_existing(db, root).unwrap();
+		for (key, value) in pairs {
+			t.insert(key, value).unwrap();
+		}
+	}
+
+	#[test]
+	fn diff_matches_naive_diff() {
+		let pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 4,
+			journal_key: 0,
+			value_mode: ValueMode::Index,
+			count: 400,
+		}.make();
+
+		let mut old_db = MemDB::default();
+		let mut old_root = Default::default();
+		RefTrieDBMut::new(&mut old_db, &mut old_root);
+		populate(&mut old_db, &mut old_root, &pairs[..300]);
+
+		let mut new_db = old_db.clone();
+		let mut new_root = old_root;
+		populate(&mut new_db, &mut new_root, &pairs[300..]);
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut new_db, &mut new_root).unwrap();
+			for (key, _) in pairs[..300].iter().step_by(7) {
+				t.remove(key).unwrap();
+			}
+			for (key, _) in pairs[..300].iter().skip(3).step_by(11) {
+				t.insert(key, b"changed").unwrap();
+			}
+		}
+
+		let old = RefTrieDB::new(&old_db, &old_root).unwrap();
+		let new = RefTrieDB::new(&new_db, &new_root).unwrap();
+		let diff: Vec<_> = RefTrieDBDiffIterator::new(&old, &new).map(|x| x.unwrap()).collect();
+		assert!(!diff.is_empty());
+		assert_eq!(diff, naive_diff(&old, &new));
+
+		let reverse: Vec<_> = RefTrieDBDiffIterator::new(&new, &old).map(|x| x.unwrap()).collect();
+		assert_eq!(reverse, naive_diff(&new, &old));
+	}
+
+	#[test]
+	fn identical_tries_have_no_diff() {
+		let mut db = MemDB::default();
+		let mut root = Default::default();
+		RefTrieDBMut::new(&mut db, &mut root);
+		populate(&mut db, &mut root, &[(b"A".to_vec(), b"A".to_vec()), (b"AB".to_vec(), b"AB".to_vec())]);
+
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		assert_eq!(RefTrieDBDiffIterator::new(&t, &t).count(), 0);
+	}
+
+	#[test]
+	fn diff_against_empty_trie() {
+		let mut db = MemDB::default();
+		let mut empty_root = Default::default();
+		RefTrieDBMut::new(&mut db, &mut empty_root);
+		let mut root = empty_root;
+		populate(&mut db, &mut root, &[(b"A".to_vec(), b"1".to_vec()), (b"B".to_vec(), b"2".to_vec())]);
+
+		let empty = RefTrieDB::new(&db, &empty_root).unwrap();
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		let diff: Vec<_> = RefTrieDBDiffIterator::new(&empty, &t).map(|x| x.unwrap()).collect();
+		assert_eq!(diff, vec![
+			Diff::Added(b"A".to_vec(), DBValue::from_slice(b"1")),
+			Diff::Added(b"B".to_vec(), DBValue::from_slice(b"2")),
+		]);
+	}
+}
@@ -13,30 +13,30 @@
 // limitations under the License.
 
 use hash_db::{HashDB, Hasher};
-use super::{Result, DBValue, TrieDBMut, TrieMut};
-use node_codec::NodeCodec;
+use super::{Result, DBValue, TrieDBMut, TrieMut, TrieLayout, TrieHash, CError};
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
 
 /// A mutable `Trie` implementation which hashes keys and uses a generic `HashDB` backing database.
 /// Additionaly it stores inserted hash-key mappings for later retrieval.
 ///
 /// Use it as a `Trie` or `TrieMut` trait object.
-pub struct FatDBMut<'db, H, C>
+pub struct FatDBMut<'db, L>
 where
-	H: Hasher + 'db,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
-	raw: TrieDBMut<'db, H, C>,
+	raw: TrieDBMut<'db, L>,
 }
 
-impl<'db, H, C> FatDBMut<'db, H, C>
+impl<'db, L> FatDBMut<'db, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
 	/// Create a new trie with the backing database `db` and empty `root`
 	/// Initialise to the state entailed by the genesis block.
 	/// This guarantees the trie is built correctly.
-	pub fn new(db: &'db mut dyn HashDB<H, DBValue>, root: &'db mut H::Out) -> Self {
+	pub fn new(db: &'db mut dyn HashDB<L::Hash, DBValue>, root: &'db mut TrieHash<L>) -> Self {
 		FatDBMut { raw: TrieDBMut::new(db, root) }
 	}
 
@@ -44,67 +44,77 @@ where
 	///
 	/// Returns an error if root does not exist.
 	pub fn from_existing(
-		db: &'db mut dyn HashDB<H, DBValue>,
-		root: &'db mut H::Out,
-	) -> Result<Self, H::Out, C::Error> {
+		db: &'db mut dyn HashDB<L::Hash, DBValue>,
+		root: &'db mut TrieHash<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
 		Ok(FatDBMut { raw: TrieDBMut::from_existing(db, root)? })
 	}
 
 	/// Get the backing database.
-	pub fn db(&self) -> &dyn HashDB<H, DBValue> {
+	pub fn db(&self) -> &dyn HashDB<L::Hash, DBValue> {
 		self.raw.db()
 	}
 
 	/// Get the backing database.
-	pub fn db_mut(&mut self) -> &mut dyn HashDB<H, DBValue> {
+	pub fn db_mut(&mut self) -> &mut dyn HashDB<L::Hash, DBValue> {
 		self.raw.db_mut()
 	}
 }
 
-impl<'db, H, C> TrieMut<H, C> for FatDBMut<'db, H, C>
+impl<'db, L> TrieMut<L> for FatDBMut<'db, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
-	fn root(&mut self) -> &H::Out { self.raw.root() }
+	fn root(&mut self) -> &TrieHash<L> { self.raw.root() }
 
 	fn is_empty(&self) -> bool { self.raw.is_empty() }
 
-	fn contains(&self, key: &[u8]) -> Result<bool, H::Out, C::Error> {
-		self.raw.contains(H::hash(key).as_ref())
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.raw.contains(L::Hash::hash(key).as_ref())
 	}
 
-	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, H::Out, C::Error>
+	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
 		where 'a: 'key
 	{
-		self.raw.get(H::hash(key).as_ref())
+		self.raw.get(L::Hash::hash(key).as_ref())
 	}
 
-	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error> {
-		let hash = H::hash(key);
+	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let hash = L::Hash::hash(key);
 		let out = self.raw.insert(hash.as_ref(), value)?;
 		let db = self.raw.db_mut();
 
 		// insert if it doesn't exist.
 		if out.is_none() {
-			let aux_hash = H::hash(hash.as_ref());
+			let aux_hash = L::Hash::hash(hash.as_ref());
 			db.emplace(aux_hash, &[], DBValue::from_slice(key));
 		}
 		Ok(out)
 	}
 
-	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error> {
-		let hash = H::hash(key);
+	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let hash = L::Hash::hash(key);
 		let out = self.raw.remove(hash.as_ref())?;
 
 		// remove if it already exists.
 		if out.is_some() {
-			let aux_hash = H::hash(hash.as_ref());
+			let aux_hash = L::Hash::hash(hash.as_ref());
 			self.raw.db_mut().remove(&aux_hash, &[]);
 		}
 
 		Ok(out)
 	}
+
+	fn remove_prefix(&mut self, prefix: &[u8], limit: Option<usize>) -> Result<usize, TrieHash<L>, CError<L>> {
+		let mut removed = Vec::new();
+		let count = self.raw.remove_prefix_with(prefix, limit, |hash| removed.push(L::Hash::hash(hash)))?;
+
+		for aux_hash in removed {
+			self.raw.db_mut().remove(&aux_hash, &[]);
+		}
+
+		Ok(count)
+	}
 }
 
 #[cfg(test)]
@@ -142,4 +152,28 @@ mod test {
 		t.remove(&key).unwrap();
 		assert_eq!(t.db().get(&aux_hash, &[]), None);
 	}
+
+	#[test]
+	fn fatdbmut_remove_prefix_key_mapping() {
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		let mut root = Default::default();
+		let mut t = RefFatDBMut::new(&mut memdb, &mut root);
+		let keys: Vec<Vec<u8>> = (0u8..16).map(|i| vec![i]).collect();
+		for key in &keys {
+			t.insert(key, key).unwrap();
+		}
+
+		let prefix = &KeccakHasher::hash(&keys[0])[..1];
+		let removed: Vec<_> = keys.iter()
+			.filter(|key| KeccakHasher::hash(key).starts_with(prefix))
+			.collect();
+		assert_eq!(t.remove_prefix(prefix, None).unwrap(), removed.len());
+
+		for key in &keys {
+			let aux_hash = KeccakHasher::hash(&KeccakHasher::hash(key));
+			let gone = removed.contains(&key);
+			assert_eq!(t.get(key).unwrap().is_none(), gone);
+			assert_eq!(t.db().get(&aux_hash, &[]).is_none(), gone);
+		}
+	}
 }
@@ -25,6 +25,8 @@ extern crate hash_db;
 extern crate rand;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 
 #[cfg(test)]
 extern crate env_logger;
@@ -81,17 +83,23 @@ pub mod triedbmut;
 pub mod sectriedb;
 pub mod sectriedbmut;
 pub mod recorder;
+pub mod proof;
 
+mod diff;
 mod fatdb;
 mod fatdbmut;
 mod lookup;
 mod nibblevec;
 mod nibbleslice;
 mod node_codec;
+mod visitor;
+#[cfg(feature = "std")]
+mod node_cache;
 
 pub use hash_db::{HashDB, HashDBRef, Hasher};
-pub use self::triedb::{TrieDB, TrieDBIterator};
-pub use self::triedbmut::{TrieDBMut, ChildReference};
+pub use self::triedb::{TrieDB, TrieDBOwned, TrieDBIterator, TrieDBReverseIterator, TrieDBNodeIterator, TrieNodeItem};
+pub use self::diff::{Diff, TrieDBDiffIterator};
+pub use self::triedbmut::{TrieDBMut, TrieDBMutOwned, ChildReference, ChangeSet, Savepoint};
 pub use self::sectriedbmut::SecTrieDBMut;
 pub use self::sectriedb::SecTrieDB;
 pub use self::fatdb::{FatDB, FatDBIterator};
@@ -99,10 +107,52 @@ pub use self::fatdbmut::FatDBMut;
 pub use self::recorder::{Recorder, Record};
 pub use self::lookup::Lookup;
 pub use self::nibbleslice::NibbleSlice;
+pub use self::nibblevec::NibbleVec;
 pub use node_codec::NodeCodec;
+pub use self::visitor::{TrieVisitor, visit_trie};
+use node::Value;
+#[cfg(feature = "std")]
+pub use self::node_cache::{NodeCache, CachedNode, CachedHashDB, CachedHashDBRef};
 
 pub type DBValue = elastic_array::ElasticArray128<u8>;
 
+/// Trie layout: the hasher and node codec of a trie, along with the options
+/// determining its structure.
+pub trait TrieLayout {
+	/// Whether the trie uses extension nodes. Without them, the partial key leading
+	/// to a branch is stored in the branch itself, as a `Node::NibbledBranch`.
+	const USE_EXTENSION: bool = true;
+	/// Encoded nodes shorter than this are inlined in their parent instead of being
+	/// stored under their hash; `None` stands for the length of the hash. `Some(0)`
+	/// stores every node by hash.
+	const INLINE_THRESHOLD: Option<usize> = None;
+	/// Values at least this long are stored in the database as value nodes of their own,
+	/// under the key of the value, their node only holding their hash; `None` keeps every
+	/// value in its node. Requires a codec with `NodeCodec::VALUE_NODES` set, which is
+	/// checked when creating a trie.
+	const VALUE_THRESHOLD: Option<usize> = None;
+	/// Hasher to use for the trie.
+	type Hash: Hasher;
+	/// Codec to use for encoding and decoding trie nodes.
+	type Codec: NodeCodec<Self::Hash>;
+
+	/// Whether a node encoded in `len` bytes is inlined in its parent. Nodes as long
+	/// as the hash are never inlined, so that they cannot be mistaken for one.
+	fn is_inline(len: usize) -> bool {
+		len != Self::Hash::LENGTH && len < Self::INLINE_THRESHOLD.unwrap_or(Self::Hash::LENGTH)
+	}
+
+	/// Whether a value of `len` bytes is kept in its node rather than in a value node.
+	fn is_inline_value(len: usize) -> bool {
+		Self::VALUE_THRESHOLD.map_or(true, |threshold| len < threshold)
+	}
+}
+
+/// Alias accessor to the hasher output type of a `TrieLayout`.
+pub type TrieHash<L> = <<L as TrieLayout>::Hash as Hasher>::Out;
+/// Alias accessor to the node codec error type of a `TrieLayout`.
+pub type CError<L> = <<L as TrieLayout>::Codec as NodeCodec<<L as TrieLayout>::Hash>>::Error;
+
 /// Trie Errors.
 ///
 /// These borrow the data within them to avoid excessive copying on every
@@ -115,6 +165,8 @@ pub enum TrieError<T, E> {
 	IncompleteDatabase(T),
 	/// Corrupt Trie item
 	DecoderError(T, E),
+	/// A value node reference which is not a hash.
+	InvalidValueHash(Vec<u8>),
 }
 
 #[cfg(feature = "std")]
@@ -126,6 +178,7 @@ impl<T, E> fmt::Display for TrieError<T, E> where T: MaybeDebug, E: MaybeDebug {
 			TrieError::DecoderError(ref hash, ref decoder_err) => {
 				write!(f, "Decoding failed for hash {:?}; err: {:?}", hash, decoder_err)
 			}
+			TrieError::InvalidValueHash(ref data) => write!(f, "Invalid value node hash: {:?}", data),
 		}
 	}
 }
@@ -137,6 +190,7 @@ impl<T, E> Error for TrieError<T, E> where T: fmt::Debug, E: Error {
 			TrieError::InvalidStateRoot(_) => "Invalid state root",
 			TrieError::IncompleteDatabase(_) => "Incomplete database",
 			TrieError::DecoderError(_, ref err) => err.description(),
+			TrieError::InvalidValueHash(_) => "Invalid value node hash",
 		}
 	}
 }
@@ -148,6 +202,47 @@ pub type Result<T, H, E> = ::core_::result::Result<T, Box<TrieError<H, E>>>;
 /// Trie-Item type used for iterators over trie data.
 pub type TrieItem<'a, U, E> = Result<(Vec<u8>, DBValue), U, E>;
 
+// Panic unless the codec of the layout can encode the value nodes its `VALUE_THRESHOLD`
+// calls for.
+fn check_value_nodes<L: TrieLayout>() {
+	assert!(
+		L::VALUE_THRESHOLD.is_none() || <L::Codec as NodeCodec<L::Hash>>::VALUE_NODES,
+		"VALUE_THRESHOLD requires a codec able to encode value nodes"
+	);
+}
+
+// Hash of the value node referred to by a `Value::Node`, `None` if `data` is not
+// the length of a hash.
+fn value_hash<L: TrieLayout>(data: &[u8]) -> Option<TrieHash<L>> {
+	let mut hash = TrieHash::<L>::default();
+	if data.len() != hash.as_ref().len() {
+		return None;
+	}
+	hash.as_mut().copy_from_slice(data);
+	Some(hash)
+}
+
+// Hash of the value node referred to by a `Value::Node`, failing on malformed hashes.
+fn checked_value_hash<L: TrieLayout>(data: &[u8]) -> Result<TrieHash<L>, TrieHash<L>, CError<L>> {
+	value_hash::<L>(data).ok_or_else(|| Box::new(TrieError::InvalidValueHash(data.to_vec())))
+}
+
+// Load a value, fetching it from its value node if its node only holds its hash.
+// `prefix` is the encoded key of the value.
+fn load_value<L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized>(
+	db: &D,
+	value: Value,
+	prefix: &[u8],
+) -> Result<DBValue, TrieHash<L>, CError<L>> {
+	match value {
+		Value::Inline(value) => Ok(DBValue::from_slice(value)),
+		Value::Node(hash) => {
+			let hash = checked_value_hash::<L>(hash)?;
+			db.get(&hash, prefix).ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash)))
+		}
+	}
+}
+
 /// Description of what kind of query will be made to the trie.
 ///
 /// This is implemented for any &mut recorder (where the query will return
@@ -162,6 +257,9 @@ pub trait Query<H: Hasher> {
 
 	/// Record that a node has been passed through.
 	fn record(&mut self, _hash: &H::Out, _data: &[u8], _depth: u32) {}
+
+	/// Record that a value has been fetched from its value node.
+	fn record_value(&mut self, _hash: &H::Out, _data: &[u8], _depth: u32) {}
 }
 
 impl<'a, H: Hasher> Query<H> for &'a mut Recorder<H::Out> {
@@ -170,6 +268,9 @@ impl<'a, H: Hasher> Query<H> for &'a mut Recorder<H::Out> {
 	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
 		(&mut **self).record(hash, data, depth);
 	}
+	fn record_value(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
+		(&mut **self).record_value(hash, data, depth);
+	}
 }
 
 impl<F, T, H: Hasher> Query<H> for F where F: for<'a> FnOnce(&'a [u8]) -> T {
@@ -183,71 +284,87 @@ impl<'a, F, T, H: Hasher> Query<H> for (&'a mut Recorder<H::Out>, F) where F: Fn
 	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
 		self.0.record(hash, data, depth)
 	}
+	fn record_value(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
+		self.0.record_value(hash, data, depth)
+	}
 }
 
 /// A key-value datastore implemented as a database-backed modified Merkle tree.
-pub trait Trie<H: Hasher, C: NodeCodec<H>> {
+pub trait Trie<L: TrieLayout> {
 	/// Return the root of the trie.
-	fn root(&self) -> &H::Out;
+	fn root(&self) -> &TrieHash<L>;
 
 	/// Is the trie empty?
-	fn is_empty(&self) -> bool { *self.root() == C::hashed_null_node() }
+	fn is_empty(&self) -> bool { *self.root() == L::Codec::hashed_null_node() }
 
 	/// Does the trie contain a given key?
-	fn contains(&self, key: &[u8]) -> Result<bool, H::Out, C::Error> {
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
 		self.get(key).map(|x|x.is_some() )
 	}
 
 	/// What is the value of the given key in this trie?
-	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, H::Out, C::Error> where 'a: 'key {
+	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> where 'a: 'key {
 		self.get_with(key, DBValue::from_slice)
 	}
 
 	/// Search for the key with the given query parameter. See the docs of the `Query`
 	/// trait for more details.
-	fn get_with<'a, 'key, Q: Query<H>>(
+	fn get_with<'a, 'key, Q: Query<L::Hash>>(
 		&'a self,
 		key: &'key [u8],
 		query: Q
-	) -> Result<Option<Q::Item>, H::Out, C::Error> where 'a: 'key;
+	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> where 'a: 'key;
 
 	/// Returns a depth-first iterator over the elements of trie.
 	fn iter<'a>(&'a self) -> Result<
-		Box<dyn TrieIterator<H, C, Item = TrieItem<H::Out, C::Error >> + 'a>,
-		H::Out,
-		C::Error,
+		Box<dyn TrieIterator<L, Item = TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
+	>;
+
+	/// Returns a depth-first iterator over the elements of trie whose keys start with `prefix`.
+	fn iter_prefix<'a>(&'a self, prefix: &[u8]) -> Result<
+		Box<dyn TrieIterator<L, Item = TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
 	>;
 }
 
 /// A key-value datastore implemented as a database-backed modified Merkle tree.
-pub trait TrieMut<H: Hasher, C: NodeCodec<H>> {
+pub trait TrieMut<L: TrieLayout> {
 	/// Return the root of the trie.
-	fn root(&mut self) -> &H::Out;
+	fn root(&mut self) -> &TrieHash<L>;
 
 	/// Is the trie empty?
 	fn is_empty(&self) -> bool;
 
 	/// Does the trie contain a given key?
-	fn contains(&self, key: &[u8]) -> Result<bool, H::Out, C::Error> {
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
 		self.get(key).map(|x| x.is_some())
 	}
 
 	/// What is the value of the given key in this trie?
-	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, H::Out, C::Error> where 'a: 'key;
+	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> where 'a: 'key;
 
 	/// Insert a `key`/`value` pair into the trie. An empty value is equivalent to removing
 	/// `key` from the trie. Returns the old value associated with this key, if it existed.
-	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error>;
+	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>;
 
 	/// Remove a `key` from the trie. Equivalent to making it equal to the empty
 	/// value. Returns the old value associated with this key, if it existed.
-	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error>;
+	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>;
+
+	/// Remove all the entries whose key starts with `prefix`, or at most `limit` of them.
+	/// Returns the number of entries removed; entries may remain under the prefix when
+	/// that reaches `limit`, so a large removal can be done in steps.
+	/// For secure tries, the prefix applies to the hashed keys.
+	fn remove_prefix(&mut self, prefix: &[u8], limit: Option<usize>) -> Result<usize, TrieHash<L>, CError<L>>;
 }
 
 /// A trie iterator that also supports random access (`seek()`).
-pub trait TrieIterator<H: Hasher, C: NodeCodec<H>>: Iterator {
+pub trait TrieIterator<L: TrieLayout>: Iterator {
 	/// Position the iterator on the first element with key >= `key`
-	fn seek(&mut self, key: &[u8]) -> Result<(), H::Out, <C as NodeCodec<H>>::Error>;
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>>;
 }
 
 /// Trie types
@@ -269,21 +386,20 @@ impl Default for TrieSpec {
 
 /// Trie factory.
 #[derive(Default, Clone)]
-pub struct TrieFactory<H: Hasher, C: NodeCodec<H>> {
+pub struct TrieFactory<L: TrieLayout> {
 	spec: TrieSpec,
-	mark_hash: PhantomData<H>,
-	mark_codec: PhantomData<C>,
+	mark_layout: PhantomData<L>,
 }
 
 /// All different kinds of tries.
 /// This is used to prevent a heap allocation for every created trie.
-pub enum TrieKinds<'db, H: Hasher + 'db, C: NodeCodec<H>> {
+pub enum TrieKinds<'db, L: TrieLayout> {
 	/// A generic trie db.
-	Generic(TrieDB<'db, H, C>),
+	Generic(TrieDB<'db, L>),
 	/// A secure trie db.
-	Secure(SecTrieDB<'db, H, C>),
+	Secure(SecTrieDB<'db, L>),
 	/// A fat trie db.
-	Fat(FatDB<'db, H, C>),
+	Fat(FatDB<'db, L>),
 }
 
 // wrapper macro for making the match easier to deal with.
@@ -297,8 +413,8 @@ macro_rules! wrapper {
 	}
 }
 
-impl<'db, H: Hasher, C: NodeCodec<H>> Trie<H, C> for TrieKinds<'db, H, C> {
-	fn root(&self) -> &H::Out {
+impl<'db, L: TrieLayout> Trie<L> for TrieKinds<'db, L> {
+	fn root(&self) -> &TrieHash<L> {
 		wrapper!(self, root,)
 	}
 
@@ -306,41 +422,48 @@ impl<'db, H: Hasher, C: NodeCodec<H>> Trie<H, C> for TrieKinds<'db, H, C> {
 		wrapper!(self, is_empty,)
 	}
 
-	fn contains(&self, key: &[u8]) -> Result<bool, H::Out, C::Error> {
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
 		wrapper!(self, contains, key)
 	}
 
-	fn get_with<'a, 'key, Q: Query<H>>(&'a self, key: &'key [u8], query: Q) -> Result<Option<Q::Item>, H::Out, C::Error>
+	fn get_with<'a, 'key, Q: Query<L::Hash>>(&'a self, key: &'key [u8], query: Q) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
 		where 'a: 'key
 	{
 		wrapper!(self, get_with, key, query)
 	}
 
 	fn iter<'a>(&'a self) -> Result<
-		Box<dyn TrieIterator<H, C, Item = TrieItem<H::Out, C::Error>> + 'a>,
-		H::Out,
-		C::Error,
+		Box<dyn TrieIterator<L, Item = TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
 	> {
 		wrapper!(self, iter,)
 	}
+
+	fn iter_prefix<'a>(&'a self, prefix: &[u8]) -> Result<
+		Box<dyn TrieIterator<L, Item = TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
+	> {
+		wrapper!(self, iter_prefix, prefix)
+	}
 }
 
-impl<'db, H, C> TrieFactory<H, C>
+impl<'db, L> TrieFactory<L>
 where
-	H: Hasher,
-	C: NodeCodec<H> + 'db
+	L: TrieLayout + 'db
 {
 	/// Creates new factory.
 	pub fn new(spec: TrieSpec) -> Self {
-		TrieFactory { spec, mark_hash: PhantomData, mark_codec: PhantomData }
+		TrieFactory { spec, mark_layout: PhantomData }
 	}
 
 	/// Create new immutable instance of Trie.
 	pub fn readonly(
 		&self,
-		db: &'db dyn HashDBRef<H, DBValue>,
-		root: &'db H::Out
-	) -> Result<TrieKinds<'db, H, C>, H::Out, <C as NodeCodec<H>>::Error> {
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>
+	) -> Result<TrieKinds<'db, L>, TrieHash<L>, CError<L>> {
 		match self.spec {
 			TrieSpec::Generic => Ok(TrieKinds::Generic(TrieDB::new(db, root)?)),
 			TrieSpec::Secure => Ok(TrieKinds::Secure(SecTrieDB::new(db, root)?)),
@@ -351,26 +474,26 @@ where
 	/// Create new mutable instance of Trie.
 	pub fn create(
 		&self,
-		db: &'db mut dyn HashDB<H, DBValue>,
-		root: &'db mut H::Out,
-	) -> Box<dyn TrieMut<H, C> + 'db> {
+		db: &'db mut dyn HashDB<L::Hash, DBValue>,
+		root: &'db mut TrieHash<L>,
+	) -> Box<dyn TrieMut<L> + 'db> {
 		match self.spec {
-			TrieSpec::Generic => Box::new(TrieDBMut::<_, C>::new(db, root)),
-			TrieSpec::Secure => Box::new(SecTrieDBMut::<_, C>::new(db, root)),
-			TrieSpec::Fat => Box::new(FatDBMut::<_, C>::new(db, root)),
+			TrieSpec::Generic => Box::new(TrieDBMut::<L>::new(db, root)),
+			TrieSpec::Secure => Box::new(SecTrieDBMut::<L>::new(db, root)),
+			TrieSpec::Fat => Box::new(FatDBMut::<L>::new(db, root)),
 		}
 	}
 
 	/// Create new mutable instance of trie and check for errors.
 	pub fn from_existing(
 		&self,
-		db: &'db mut dyn HashDB<H, DBValue>,
-		root: &'db mut H::Out
-	) -> Result<Box<dyn TrieMut<H,C> + 'db>, H::Out, <C as NodeCodec<H>>::Error> {
+		db: &'db mut dyn HashDB<L::Hash, DBValue>,
+		root: &'db mut TrieHash<L>
+	) -> Result<Box<dyn TrieMut<L> + 'db>, TrieHash<L>, CError<L>> {
 		match self.spec {
-			TrieSpec::Generic => Ok(Box::new(TrieDBMut::<_, C>::from_existing(db, root)?)),
-			TrieSpec::Secure => Ok(Box::new(SecTrieDBMut::<_, C>::from_existing(db, root)?)),
-			TrieSpec::Fat => Ok(Box::new(FatDBMut::<_, C>::from_existing(db, root)?)),
+			TrieSpec::Generic => Ok(Box::new(TrieDBMut::<L>::from_existing(db, root)?)),
+			TrieSpec::Secure => Ok(Box::new(SecTrieDBMut::<L>::from_existing(db, root)?)),
+			TrieSpec::Fat => Ok(Box::new(FatDBMut::<L>::from_existing(db, root)?)),
 		}
 	}
 
@@ -14,57 +14,95 @@
 
 //! Trie lookup via HashDB.
 
-use hash_db::{HashDBRef, Hasher};
+use hash_db::HashDBRef;
 use nibbleslice::NibbleSlice;
-use node::Node;
+use node::{Node, Value};
 use node_codec::NodeCodec;
-use super::{DBValue, Result, TrieError, Query};
+use super::{DBValue, Result, TrieError, Query, TrieLayout, TrieHash, CError, checked_value_hash};
 use ::core_::marker::PhantomData;
+#[cfg(feature = "std")]
+use node_cache::{NodeCache, CachedNode};
+#[cfg(feature = "std")]
+use std::sync::Arc;
 
 #[cfg(not(feature = "std"))]
 use alloc::boxed::Box;
 
 /// Trie lookup helper object.
-pub struct Lookup<'a, H: Hasher + 'a, C: NodeCodec<H>, Q: Query<H>> {
+pub struct Lookup<'a, L: TrieLayout, Q: Query<L::Hash>> {
 	/// database to query from.
-	pub db: &'a dyn HashDBRef<H, DBValue>,
+	pub db: &'a dyn HashDBRef<L::Hash, DBValue>,
 	/// Query object to record nodes and transform data.
 	pub query: Q,
 	/// Hash to start at
-	pub hash: H::Out,
-	pub marker: PhantomData<C>, // TODO: probably not needed when all is said and done? When Query is made generic?
+	pub hash: TrieHash<L>,
+	/// Cache of decoded nodes to look nodes up in before the database.
+	#[cfg(feature = "std")]
+	pub cache: Option<&'a NodeCache<TrieHash<L>>>,
+	pub marker: PhantomData<L>,
 }
 
-impl<'a, H, C, Q> Lookup<'a, H, C, Q>
+// A node or value node fetched for a lookup.
+enum Fetched {
+	Data(DBValue),
+	#[cfg(feature = "std")]
+	Cached(Arc<CachedNode>),
+}
+
+impl Fetched {
+	fn data(&self) -> &[u8] {
+		match *self {
+			Fetched::Data(ref data) => data,
+			#[cfg(feature = "std")]
+			Fetched::Cached(ref node) => node.data(),
+		}
+	}
+
+	// the node, if it is already decoded.
+	fn node(&self) -> Option<Node<'_>> {
+		match *self {
+			Fetched::Data(_) => None,
+			#[cfg(feature = "std")]
+			Fetched::Cached(ref node) => node.node(),
+		}
+	}
+}
+
+impl<'a, L, Q> Lookup<'a, L, Q>
 where
-	H: Hasher,
-	C: NodeCodec<H>,
-	Q: Query<H>,
+	L: TrieLayout,
+	Q: Query<L::Hash>,
 {
 	/// Look up the given key. If the value is found, it will be passed to the given
 	/// function to decode or copy.
-	pub fn look_up(mut self, key: NibbleSlice) -> Result<Option<Q::Item>, H::Out, C::Error> {
-		let mut partial = key;
+	pub fn look_up(self, key: NibbleSlice) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
+		self.look_up_from(key, 0)
+	}
+
+	/// Look up the given key, starting at the node found after its first `key_nibbles`
+	/// nibbles, as `look_up` does.
+	pub fn look_up_from(mut self, key: NibbleSlice, mut key_nibbles: usize) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
+		let mut partial = key.mid(key_nibbles);
 		let mut hash = self.hash;
-		let mut key_nibbles = 0;
 
 		// this loop iterates through non-inline nodes.
 		for depth in 0.. {
-			let node_data = match self.db.get(&hash, &key.encoded_leftmost(key_nibbles, false)) {
-				Some(value) => value,
+			let fetched = match self.fetch(&hash, &key.encoded_leftmost(key_nibbles, false)) {
+				Some(fetched) => fetched,
 				None => return Err(Box::new(match depth {
 					0 => TrieError::InvalidStateRoot(hash),
 					_ => TrieError::IncompleteDatabase(hash),
 				})),
 			};
 
-			self.query.record(&hash, &node_data, depth);
+			self.query.record(&hash, fetched.data(), depth);
 
 			// this loop iterates through all inline children (usually max 1)
 			// without incrementing the depth.
-			let mut node_data = &node_data[..];
+			let mut node_data = fetched.data();
+			let mut cached = fetched.node();
 			loop {
-				let decoded = match C::decode(node_data) {
+				let decoded = match cached.take().map_or_else(|| L::Codec::decode(node_data), Ok) {
 					Ok(node) => node,
 					Err(e) => {
 						return Err(Box::new(TrieError::DecoderError(hash, e)))
@@ -73,7 +111,7 @@ where
 				match decoded {
 					Node::Leaf(slice, value) => {
 						return Ok(match slice == partial {
-							true => Some(self.query.decode(value)),
+							true => Some(self.decode(value, key, depth)?),
 							false => None,
 						})
 					}
@@ -87,7 +125,7 @@ where
 						}
 					}
 					Node::Branch(children, value) => match partial.is_empty() {
-						true => return Ok(value.map(move |val| self.query.decode(val))),
+						true => return value.map(|value| self.decode(value, key, depth)).transpose(),
 						false => match children[partial.at(0) as usize] {
 							Some(x) => {
 								node_data = x;
@@ -97,11 +135,27 @@ where
 							None => return Ok(None)
 						}
 					},
+					Node::NibbledBranch(slice, children, value) => {
+						if !partial.starts_with(&slice) {
+							return Ok(None)
+						}
+						match partial.len() == slice.len() {
+							true => return value.map(|value| self.decode(value, key, depth)).transpose(),
+							false => match children[partial.at(slice.len()) as usize] {
+								Some(x) => {
+									node_data = x;
+									partial = partial.mid(slice.len() + 1);
+									key_nibbles += slice.len() + 1;
+								}
+								None => return Ok(None)
+							}
+						}
+					},
 					_ => return Ok(None),
 				}
 
 				// check if new node data is inline or hash.
-				if let Some(h) = C::try_decode_hash(&node_data) {
+				if let Some(h) = L::Codec::try_decode_hash(&node_data) {
 					hash = h;
 					break
 				}
@@ -109,4 +163,34 @@ where
 		}
 		Ok(None)
 	}
+
+	// Get a node or value node from the cache if there is one, or else the database.
+	fn fetch(&self, hash: &TrieHash<L>, prefix: &[u8]) -> Option<Fetched> {
+		#[cfg(feature = "std")]
+		{
+			if let Some(cache) = self.cache {
+				return cache.get_or_fetch::<L, _>(hash, prefix, || self.db.get(hash, prefix)).map(Fetched::Cached);
+			}
+		}
+		self.db.get(hash, prefix).map(Fetched::Data)
+	}
+
+	// Decode a value found at `key`, fetching and recording its value node if its node
+	// only holds its hash.
+	fn decode(self, value: Value, key: NibbleSlice, depth: u32) -> Result<Q::Item, TrieHash<L>, CError<L>> {
+		match value {
+			Value::Inline(value) => Ok(self.query.decode(value)),
+			Value::Node(hash) => {
+				let hash = checked_value_hash::<L>(hash)?;
+				match self.fetch(&hash, &key.encoded_leftmost(key.len(), false)) {
+					Some(value) => {
+						let mut query = self.query;
+						query.record_value(&hash, value.data(), depth + 1);
+						Ok(query.decode(value.data()))
+					}
+					None => Err(Box::new(TrieError::IncompleteDatabase(hash))),
+				}
+			}
+		}
+	}
 }
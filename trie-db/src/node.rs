@@ -23,17 +23,59 @@ use alloc::vec::Vec;
 /// Partial node key type.
 pub type NodeKey = ElasticArray36<u8>;
 
+/// A value as stored in a node.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Value<'a> {
+	/// The value itself.
+	Inline(&'a [u8]),
+	/// The hash of the value node holding the value, stored in the database under the
+	/// key of the value. See `TrieLayout::VALUE_THRESHOLD`.
+	Node(&'a [u8]),
+}
+
 /// Type of node in the trie and essential information thereof.
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum Node<'a> {
 	/// Null trie node; could be an empty root or an empty branch entry.
 	Empty,
 	/// Leaf node; has key slice and value. Value may not be empty.
-	Leaf(NibbleSlice<'a>, &'a [u8]),
+	Leaf(NibbleSlice<'a>, Value<'a>),
 	/// Extension node; has key slice and node data. Data may not be null.
 	Extension(NibbleSlice<'a>, &'a [u8]),
 	/// Branch node; has array of 16 child nodes (each possibly null) and an optional immediate node data.
-	Branch([Option<&'a [u8]>; 16], Option<&'a [u8]>),
+	Branch([Option<&'a [u8]>; 16], Option<Value<'a>>),
+	/// Branch node with the partial key leading to it, in place of an extension node;
+	/// has key slice, array of 16 child nodes (each possibly null) and an optional immediate node data.
+	/// Only used by codecs without extension nodes.
+	NibbledBranch(NibbleSlice<'a>, [Option<&'a [u8]>; 16], Option<Value<'a>>),
+}
+
+/// An owning value type, see `Value`.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum OwnedValue {
+	/// The value itself.
+	Inline(DBValue),
+	/// The hash of the value node holding the value.
+	Node(DBValue),
+}
+
+impl OwnedValue {
+	/// Borrow as a `Value`.
+	pub fn as_value(&self) -> Value {
+		match *self {
+			OwnedValue::Inline(ref value) => Value::Inline(value),
+			OwnedValue::Node(ref hash) => Value::Node(hash),
+		}
+	}
+}
+
+impl<'a> From<Value<'a>> for OwnedValue {
+	fn from(value: Value<'a>) -> Self {
+		match value {
+			Value::Inline(value) => OwnedValue::Inline(DBValue::from_slice(value)),
+			Value::Node(hash) => OwnedValue::Node(DBValue::from_slice(hash)),
+		}
+	}
 }
 
 /// A Sparse (non mutable) owned vector struct to hold branch keys and value
@@ -42,10 +84,11 @@ pub struct Branch {
 	data: Vec<u8>,
 	ubounds: [usize; 18],
 	has_value: bool,
+	value_node: bool,
 }
 
 impl Branch {
-	fn new(children: [Option<&[u8]>; 16], maybe_value: Option<&[u8]>) -> Self {
+	fn new(children: [Option<&[u8]>; 16], maybe_value: Option<Value>) -> Self {
 		let mut data = Vec::with_capacity(children.iter()
 			.filter_map(|n| n.clone())
 			.map(|child| child.len())
@@ -58,17 +101,31 @@ impl Branch {
 			}
 			*ub = data.len();
 		}
-		if let Some(value) = maybe_value {
-			data.extend_from_slice(value);
+		let value_node = match maybe_value {
+			Some(Value::Inline(value)) => {
+				data.extend_from_slice(value);
+				false
+			}
+			Some(Value::Node(hash)) => {
+				data.extend_from_slice(hash);
+				true
+			}
+			None => false,
+		};
+		if maybe_value.is_some() {
 			ubounds[17] = data.len();
 		}
-		Branch { data, ubounds, has_value: maybe_value.is_some() }
+		Branch { data, ubounds, has_value: maybe_value.is_some(), value_node }
 	}
 
 	/// Get the node value, if any
-	pub fn get_value(&self) -> Option<&[u8]> {
+	pub fn get_value(&self) -> Option<Value> {
 		if self.has_value {
-			Some(&self.data[self.ubounds[16]..self.ubounds[17]])
+			let value = &self.data[self.ubounds[16]..self.ubounds[17]];
+			Some(match self.value_node {
+				true => Value::Node(value),
+				false => Value::Inline(value),
+			})
 		} else {
 			None
 		}
@@ -90,25 +147,28 @@ impl Branch {
 }
 
 /// An owning node type. Useful for trie iterators.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum OwnedNode {
 	/// Empty trie node.
 	Empty,
 	/// Leaf node: partial key and value.
-	Leaf(NibbleVec, DBValue),
+	Leaf(NibbleVec, OwnedValue),
 	/// Extension node: partial key and child node.
 	Extension(NibbleVec, DBValue),
 	/// Branch node: 16 children and an optional value.
 	Branch(Branch),
+	/// Branch node with a partial key: partial key, 16 children and an optional value.
+	NibbledBranch(NibbleVec, Branch),
 }
 
 impl<'a> From<Node<'a>> for OwnedNode {
 	fn from(node: Node<'a>) -> Self {
 		match node {
 			Node::Empty => OwnedNode::Empty,
-			Node::Leaf(k, v) => OwnedNode::Leaf(k.into(), DBValue::from_slice(v)),
+			Node::Leaf(k, v) => OwnedNode::Leaf(k.into(), v.into()),
 			Node::Extension(k, child) => OwnedNode::Extension(k.into(), DBValue::from_slice(child)),
 			Node::Branch(c, val) => OwnedNode::Branch(Branch::new(c, val)),
+			Node::NibbledBranch(k, c, val) => OwnedNode::NibbledBranch(k.into(), Branch::new(c, val)),
 		}
 	}
 }
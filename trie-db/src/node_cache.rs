@@ -0,0 +1,515 @@
+// Copyright 2017, 2018 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shared cache of decoded trie nodes.
+
+use hash_db::{AsHashDB, HashDB, HashDBRef, Hasher};
+use nibbleslice::NibbleSlice;
+use node::{Node, NodeKey, Value};
+use node_codec::NodeCodec;
+use super::{DBValue, TrieLayout};
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A node held by a `NodeCache`: its encoding, along with where the parts of the
+/// node lie in it, so that the node can be read again without decoding it.
+pub struct CachedNode {
+	data: DBValue,
+	plan: Option<NodePlan>,
+}
+
+// The parts of a decoded node: its partial key, and the ranges of its encoding
+// holding its children and value.
+enum NodePlan {
+	Empty,
+	Leaf(NodeKey, ValuePlan),
+	Extension(NodeKey, (usize, usize)),
+	Branch([Option<(usize, usize)>; 16], Option<ValuePlan>),
+	NibbledBranch(NodeKey, [Option<(usize, usize)>; 16], Option<ValuePlan>),
+}
+
+enum ValuePlan {
+	Inline((usize, usize)),
+	Node((usize, usize)),
+}
+
+impl CachedNode {
+	/// Decode the data of a node fetched from the database. Data which does not decode
+	/// to a node, such as a value node, is kept as it is.
+	pub fn decode<L: TrieLayout>(data: DBValue) -> Self {
+		let plan = L::Codec::decode(&data).ok().and_then(|node| NodePlan::new(&data, node));
+		CachedNode { data, plan }
+	}
+
+	/// The encoded node.
+	pub fn data(&self) -> &DBValue {
+		&self.data
+	}
+
+	/// The decoded node, if the data is one.
+	pub fn node(&self) -> Option<Node<'_>> {
+		let data = &self.data[..];
+		let range = |range: (usize, usize)| &data[range.0..range.1];
+		let value = |value: &ValuePlan| match *value {
+			ValuePlan::Inline(value) => Value::Inline(range(value)),
+			ValuePlan::Node(hash) => Value::Node(range(hash)),
+		};
+		let children = |plan: &[Option<(usize, usize)>; 16]| {
+			let mut children = [None; 16];
+			for (child, plan) in children.iter_mut().zip(plan.iter()) {
+				*child = plan.map(range);
+			}
+			children
+		};
+		Some(match *self.plan.as_ref()? {
+			NodePlan::Empty => Node::Empty,
+			NodePlan::Leaf(ref partial, ref plan) => {
+				Node::Leaf(NibbleSlice::from_encoded(partial).0, value(plan))
+			}
+			NodePlan::Extension(ref partial, child) => {
+				Node::Extension(NibbleSlice::from_encoded(partial).0, range(child))
+			}
+			NodePlan::Branch(ref plan, ref plan_value) => {
+				Node::Branch(children(plan), plan_value.as_ref().map(value))
+			}
+			NodePlan::NibbledBranch(ref partial, ref plan, ref plan_value) => Node::NibbledBranch(
+				NibbleSlice::from_encoded(partial).0,
+				children(plan),
+				plan_value.as_ref().map(value),
+			),
+		})
+	}
+}
+
+impl NodePlan {
+	// locate the parts of a node decoded from `data`. Returns `None` if the codec
+	// did not decode them from `data` itself.
+	fn new(data: &[u8], node: Node) -> Option<Self> {
+		let range = |part: &[u8]| {
+			let start = (part.as_ptr() as usize).checked_sub(data.as_ptr() as usize)?;
+			match start + part.len() <= data.len() {
+				true => Some((start, start + part.len())),
+				false => None,
+			}
+		};
+		let value = |value: Value| Some(match value {
+			Value::Inline(value) => ValuePlan::Inline(range(value)?),
+			Value::Node(hash) => ValuePlan::Node(range(hash)?),
+		});
+		let children = |children: [Option<&[u8]>; 16]| {
+			let mut plan = [None; 16];
+			for (plan, child) in plan.iter_mut().zip(children.iter()) {
+				if let Some(child) = child {
+					*plan = Some(range(child)?);
+				}
+			}
+			Some(plan)
+		};
+		let maybe_value = |plan: Option<Value>| match plan {
+			Some(plan) => value(plan).map(Some),
+			None => Some(None),
+		};
+		Some(match node {
+			Node::Empty => NodePlan::Empty,
+			Node::Leaf(partial, plan) => NodePlan::Leaf(partial.encoded(false), value(plan)?),
+			Node::Extension(partial, child) => NodePlan::Extension(partial.encoded(false), range(child)?),
+			Node::Branch(plan, plan_value) => {
+				NodePlan::Branch(children(plan)?, maybe_value(plan_value)?)
+			}
+			Node::NibbledBranch(partial, plan, plan_value) => NodePlan::NibbledBranch(
+				partial.encoded(false),
+				children(plan)?,
+				maybe_value(plan_value)?,
+			),
+		})
+	}
+}
+
+/// A least recently used cache of decoded nodes keyed by their hash and prefix, bounded
+/// by the total size of the encoded nodes it holds.
+///
+/// The cache is safe to share between threads, so a single one can serve any number of
+/// tries through an `Arc`. Nodes are content addressed, so a cached node never goes stale;
+/// it is only dropped when the space is needed or the node is removed through a
+/// `CachedHashDB`.
+pub struct NodeCache<HO> {
+	lru: Mutex<Lru<HO>>,
+	hits: AtomicUsize,
+	misses: AtomicUsize,
+}
+
+struct Lru<HO> {
+	/// Nodes with the tick they were last used at.
+	entries: HashMap<(HO, NodeKey), (Arc<CachedNode>, u64)>,
+	/// Keys of the nodes by the tick they were last used at.
+	order: BTreeMap<u64, (HO, NodeKey)>,
+	tick: u64,
+	size: usize,
+	max_size: usize,
+}
+
+impl<HO: Hash + Eq + Copy> Lru<HO> {
+	fn touch(&mut self, key: &(HO, NodeKey)) -> Option<Arc<CachedNode>> {
+		let tick = self.tick;
+		let (node, last_used) = self.entries.get_mut(key)?;
+		self.order.remove(last_used);
+		self.order.insert(tick, key.clone());
+		*last_used = tick;
+		self.tick += 1;
+		Some(node.clone())
+	}
+
+	fn insert(&mut self, key: (HO, NodeKey), node: Arc<CachedNode>) {
+		self.remove(&key);
+		if node.data.len() > self.max_size {
+			return;
+		}
+
+		self.size += node.data.len();
+		while self.size > self.max_size {
+			let oldest = self.order.values().next().cloned()
+				.expect("size is only non-zero with entries in the cache; qed");
+			self.remove(&oldest);
+		}
+
+		self.order.insert(self.tick, key.clone());
+		self.entries.insert(key, (node, self.tick));
+		self.tick += 1;
+	}
+
+	fn remove(&mut self, key: &(HO, NodeKey)) {
+		if let Some((node, last_used)) = self.entries.remove(key) {
+			self.order.remove(&last_used);
+			self.size -= node.data.len();
+		}
+	}
+}
+
+impl<HO: Hash + Eq + Copy> NodeCache<HO> {
+	/// Create an empty cache holding at most `max_size` bytes of encoded nodes.
+	pub fn new(max_size: usize) -> Self {
+		NodeCache {
+			lru: Mutex::new(Lru {
+				entries: HashMap::new(),
+				order: BTreeMap::new(),
+				tick: 0,
+				size: 0,
+				max_size,
+			}),
+			hits: AtomicUsize::new(0),
+			misses: AtomicUsize::new(0),
+		}
+	}
+
+	/// Get the node stored under `hash` at `prefix`, marking it as the most recently used one.
+	pub fn get(&self, hash: &HO, prefix: &[u8]) -> Option<Arc<CachedNode>> {
+		let node = self.lru().touch(&(*hash, NodeKey::from_slice(prefix)));
+		match node {
+			Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+			None => self.misses.fetch_add(1, Ordering::Relaxed),
+		};
+		node
+	}
+
+	/// Get the node stored under `hash` at `prefix`, or fetch its data with `fetch` and
+	/// cache it, decoded as a node of layout `L`.
+	pub fn get_or_fetch<L, F>(&self, hash: &HO, prefix: &[u8], fetch: F) -> Option<Arc<CachedNode>>
+	where
+		L: TrieLayout,
+		L::Hash: Hasher<Out = HO>,
+		F: FnOnce() -> Option<DBValue>,
+	{
+		if let Some(node) = self.get(hash, prefix) {
+			return Some(node);
+		}
+		let node = Arc::new(CachedNode::decode::<L>(fetch()?));
+		self.insert(*hash, prefix, node.clone());
+		Some(node)
+	}
+
+	/// Add a node stored under `hash` at `prefix`, evicting the least recently used nodes
+	/// to make room for it. Nodes larger than the whole cache are not added.
+	pub fn insert(&self, hash: HO, prefix: &[u8], node: Arc<CachedNode>) {
+		self.lru().insert((hash, NodeKey::from_slice(prefix)), node)
+	}
+
+	/// Drop the node stored under `hash` at `prefix` from the cache.
+	pub fn remove(&self, hash: &HO, prefix: &[u8]) {
+		self.lru().remove(&(*hash, NodeKey::from_slice(prefix)))
+	}
+
+	/// Drop all nodes from the cache.
+	pub fn clear(&self) {
+		let mut lru = self.lru();
+		lru.entries.clear();
+		lru.order.clear();
+		lru.size = 0;
+	}
+
+	/// The number of nodes in the cache.
+	pub fn len(&self) -> usize {
+		self.lru().entries.len()
+	}
+
+	/// Whether the cache holds no nodes.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// The total size of the encoded nodes in the cache, in bytes.
+	pub fn size(&self) -> usize {
+		self.lru().size
+	}
+
+	/// The number of lookups which found their node in the cache.
+	pub fn hits(&self) -> usize {
+		self.hits.load(Ordering::Relaxed)
+	}
+
+	/// The number of lookups which did not find their node in the cache.
+	pub fn misses(&self) -> usize {
+		self.misses.load(Ordering::Relaxed)
+	}
+
+	fn lru(&self) -> ::std::sync::MutexGuard<'_, Lru<HO>> {
+		// the cache is left consistent by every operation, so a poisoned lock is still usable.
+		self.lru.lock().unwrap_or_else(|e| e.into_inner())
+	}
+}
+
+/// A read-only database which looks nodes up in a `NodeCache` before going to the backing
+/// database, caching what it finds there as nodes of layout `L`. Use it to create a `TrieDB`
+/// whose iterators share the cache; lookups only skip decoding nodes when the trie is
+/// created with `TrieDB::with_cache`.
+pub struct CachedHashDBRef<'a, L: TrieLayout> {
+	db: &'a dyn HashDBRef<L::Hash, DBValue>,
+	cache: Arc<NodeCache<<L::Hash as Hasher>::Out>>,
+	marker: PhantomData<fn() -> L>,
+}
+
+impl<'a, L: TrieLayout> CachedHashDBRef<'a, L> {
+	/// Wrap a database with a cache.
+	pub fn new(db: &'a dyn HashDBRef<L::Hash, DBValue>, cache: Arc<NodeCache<<L::Hash as Hasher>::Out>>) -> Self {
+		CachedHashDBRef { db, cache, marker: PhantomData }
+	}
+
+	/// Get the cache.
+	pub fn cache(&self) -> &Arc<NodeCache<<L::Hash as Hasher>::Out>> {
+		&self.cache
+	}
+}
+
+impl<'a, L: TrieLayout> HashDBRef<L::Hash, DBValue> for CachedHashDBRef<'a, L> {
+	fn get(&self, key: &<L::Hash as Hasher>::Out, prefix: &[u8]) -> Option<DBValue> {
+		self.cache.get_or_fetch::<L, _>(key, prefix, || self.db.get(key, prefix))
+			.map(|node| node.data().clone())
+	}
+
+	fn contains(&self, key: &<L::Hash as Hasher>::Out, prefix: &[u8]) -> bool {
+		HashDBRef::get(self, key, prefix).is_some()
+	}
+}
+
+/// A database which looks nodes up in a `NodeCache` before going to the backing database,
+/// caching what it finds there as nodes of layout `L`. Removed nodes are dropped from
+/// the cache. Use it to create a `TrieDBMut`.
+pub struct CachedHashDB<'a, L: TrieLayout> {
+	db: &'a mut dyn HashDB<L::Hash, DBValue>,
+	cache: Arc<NodeCache<<L::Hash as Hasher>::Out>>,
+	marker: PhantomData<fn() -> L>,
+}
+
+impl<'a, L: TrieLayout> CachedHashDB<'a, L> {
+	/// Wrap a database with a cache.
+	pub fn new(db: &'a mut dyn HashDB<L::Hash, DBValue>, cache: Arc<NodeCache<<L::Hash as Hasher>::Out>>) -> Self {
+		CachedHashDB { db, cache, marker: PhantomData }
+	}
+
+	/// Get the cache.
+	pub fn cache(&self) -> &Arc<NodeCache<<L::Hash as Hasher>::Out>> {
+		&self.cache
+	}
+}
+
+impl<'a, L: TrieLayout> HashDB<L::Hash, DBValue> for CachedHashDB<'a, L> {
+	fn get(&self, key: &<L::Hash as Hasher>::Out, prefix: &[u8]) -> Option<DBValue> {
+		self.cache.get_or_fetch::<L, _>(key, prefix, || self.db.get(key, prefix))
+			.map(|node| node.data().clone())
+	}
+
+	fn contains(&self, key: &<L::Hash as Hasher>::Out, prefix: &[u8]) -> bool {
+		HashDB::get(self, key, prefix).is_some()
+	}
+
+	fn insert(&mut self, prefix: &[u8], value: &[u8]) -> <L::Hash as Hasher>::Out {
+		self.db.insert(prefix, value)
+	}
+
+	fn emplace(&mut self, key: <L::Hash as Hasher>::Out, prefix: &[u8], value: DBValue) {
+		self.db.emplace(key, prefix, value)
+	}
+
+	fn remove(&mut self, key: &<L::Hash as Hasher>::Out, prefix: &[u8]) {
+		self.cache.remove(key, prefix);
+		self.db.remove(key, prefix)
+	}
+}
+
+impl<'a, L: TrieLayout> HashDBRef<L::Hash, DBValue> for CachedHashDB<'a, L> {
+	fn get(&self, key: &<L::Hash as Hasher>::Out, prefix: &[u8]) -> Option<DBValue> { HashDB::get(self, key, prefix) }
+	fn contains(&self, key: &<L::Hash as Hasher>::Out, prefix: &[u8]) -> bool { HashDB::contains(self, key, prefix) }
+}
+
+impl<'a, L: TrieLayout> AsHashDB<L::Hash, DBValue> for CachedHashDB<'a, L> {
+	fn as_hash_db(&self) -> &dyn HashDB<L::Hash, DBValue> { self }
+	fn as_hash_db_mut<'b>(&'b mut self) -> &'b mut (dyn HashDB<L::Hash, DBValue> + 'b) { self }
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use memory_db::{MemoryDB, HashKey, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use hash_db::{Hasher, HashDBRef};
+	use reference_trie::{RefTrieDB, RefTrieDBMut, RefTrieDBMutNoExt, Trie, TrieMut, NodeCodec,
+		ExtensionLayout, NoExtensionLayout, TrieLayout, NodeCache, CachedNode, CachedHashDB,
+		CachedHashDBRef};
+	use DBValue;
+
+	fn node(data: &[u8]) -> Arc<CachedNode> {
+		Arc::new(CachedNode::decode::<ExtensionLayout>(DBValue::from_slice(data)))
+	}
+
+	#[test]
+	fn evicts_least_recently_used() {
+		let cache = NodeCache::new(10);
+		let (a, b, c) = (KeccakHasher::hash(b"a"), KeccakHasher::hash(b"b"), KeccakHasher::hash(b"c"));
+		cache.insert(a, &[], node(&[1; 4]));
+		cache.insert(b, &[], node(&[2; 4]));
+		assert!(cache.get(&a, &[]).is_some());
+		cache.insert(c, &[], node(&[3; 4]));
+
+		assert_eq!(cache.len(), 2);
+		assert_eq!(cache.size(), 8);
+		assert!(cache.get(&b, &[]).is_none());
+		assert_eq!(*cache.get(&a, &[]).unwrap().data(), DBValue::from_slice(&[1; 4]));
+		assert_eq!(*cache.get(&c, &[]).unwrap().data(), DBValue::from_slice(&[3; 4]));
+		assert_eq!((cache.hits(), cache.misses()), (3, 1));
+
+		cache.insert(b, &[], node(&[2; 11]));
+		assert!(cache.get(&b, &[]).is_none());
+		assert_eq!(cache.len(), 2);
+	}
+
+	#[test]
+	fn keyed_by_hash_and_prefix() {
+		let cache = NodeCache::new(100);
+		let a = KeccakHasher::hash(b"a");
+		cache.insert(a, &[0x10], node(&[1; 4]));
+		assert!(cache.get(&a, &[]).is_none());
+		cache.insert(a, &[], node(&[1; 4]));
+		assert_eq!(cache.len(), 2);
+
+		cache.remove(&a, &[0x10]);
+		assert!(cache.get(&a, &[0x10]).is_none());
+		assert!(cache.get(&a, &[]).is_some());
+	}
+
+	fn decodes_nodes_of<L: TrieLayout<Hash = KeccakHasher>>(memdb: &MemoryDB<KeccakHasher, HashKey<KeccakHasher>, DBValue>) {
+		for (hash, _) in memdb.clone().drain() {
+			let data = memdb.get(&hash, &[]).unwrap();
+			let cached = CachedNode::decode::<L>(data.clone());
+			assert_eq!(cached.node().unwrap(), L::Codec::decode(&data).unwrap());
+		}
+	}
+
+	#[test]
+	fn cached_nodes_match_decoded_ones() {
+		let pairs: Vec<_> = (0u8..100).map(|i| (vec![i / 3, i], vec![i; 1 + i as usize % 40])).collect();
+
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (key, value) in &pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+		decodes_nodes_of::<ExtensionLayout>(&memdb);
+
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+			for (key, value) in &pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+		decodes_nodes_of::<NoExtensionLayout>(&memdb);
+	}
+
+	#[test]
+	fn shared_between_tries() {
+		let cache = Arc::new(NodeCache::new(1 << 20));
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"dog", b"cat").unwrap();
+			t.insert(b"doge", b"coin").unwrap();
+			t.insert(b"horse", b"stallion").unwrap();
+		}
+
+		{
+			let t = RefTrieDB::with_cache(&memdb, &root, &cache).unwrap();
+			assert_eq!(t.get(b"doge").unwrap().unwrap(), DBValue::from_slice(b"coin"));
+			assert_eq!(cache.hits(), 0);
+			assert_eq!(t.get(b"dog").unwrap().unwrap(), DBValue::from_slice(b"cat"));
+			assert!(cache.hits() > 0);
+		}
+
+		let misses = cache.misses();
+		{
+			let mut cached = CachedHashDB::<ExtensionLayout>::new(&mut memdb, cache.clone());
+			let mut t = RefTrieDBMut::from_existing(&mut cached, &mut root).unwrap();
+			assert_eq!(t.get(b"horse").unwrap().unwrap(), DBValue::from_slice(b"stallion"));
+			assert_eq!(cache.misses(), misses);
+			t.insert(b"horse", b"mare").unwrap();
+		}
+
+		let t = RefTrieDB::with_cache(&memdb, &root, &cache).unwrap();
+		assert_eq!(t.get(b"horse").unwrap().unwrap(), DBValue::from_slice(b"mare"));
+		assert_eq!(t.get(b"dog").unwrap().unwrap(), DBValue::from_slice(b"cat"));
+	}
+
+	#[test]
+	fn contains_goes_through_the_cache() {
+		let cache = Arc::new(NodeCache::new(1 << 20));
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		RefTrieDBMut::new(&mut memdb, &mut root).insert(b"dog", b"cat").unwrap();
+
+		let cached = CachedHashDBRef::<ExtensionLayout>::new(&memdb, cache.clone());
+		assert!(cached.contains(&root, &[0]));
+		assert_eq!((cache.hits(), cache.misses(), cache.len()), (0, 1, 1));
+		assert!(cached.contains(&root, &[0]));
+		assert_eq!(cache.hits(), 1);
+		assert!(!cached.contains(&root, &[0x10]));
+		assert_eq!(cache.len(), 1);
+	}
+}
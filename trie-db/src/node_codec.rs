@@ -16,7 +16,7 @@
 //! to parametrize the hashes used in the codec.
 
 use hash_db::Hasher;
-use node::Node;
+use node::{Node, Value};
 use ChildReference;
 
 #[cfg(feature = "std")]
@@ -26,8 +26,6 @@ use std::error::Error;
 #[cfg(not(feature = "std"))]
 use alloc::vec::Vec;
 
-use elastic_array::ElasticArray128;
-
 #[cfg(not(feature = "std"))]
 pub trait Error {}
 
@@ -39,6 +37,10 @@ pub trait NodeCodec<H: Hasher>: Sized {
 	/// Codec error type
 	type Error: Error;
 
+	/// Whether the codec can encode `Value::Node`, which layouts with a
+	/// `TrieLayout::VALUE_THRESHOLD` require.
+	const VALUE_NODES: bool = false;
+
 	/// Get the hashed null node.
 	fn hashed_null_node() -> H::Out;
 
@@ -54,13 +56,19 @@ pub trait NodeCodec<H: Hasher>: Sized {
 	/// Returns an empty node
 	fn empty_node() -> Vec<u8>;
 
-	/// Returns an encoded leaf node
-	fn leaf_node(partial: &[u8], value: &[u8]) -> Vec<u8>;
+	/// Returns an encoded leaf node. Codecs without `VALUE_NODES` never get a `Value::Node`.
+	fn leaf_node(partial: &[u8], value: Value) -> Vec<u8>;
 
 	/// Returns an encoded extension node
 	fn ext_node(partial: &[u8], child_ref: ChildReference<H::Out>) -> Vec<u8>;
 
 	/// Returns an encoded branch node. Takes an iterator yielding `ChildReference<H::Out>` and an optional value
-	fn branch_node<I>(children: I, value: Option<ElasticArray128<u8>>) -> Vec<u8>
+	fn branch_node<I>(children: I, value: Option<Value>) -> Vec<u8>
+	where I: IntoIterator<Item=Option<ChildReference<H::Out>>> + Iterator<Item=Option<ChildReference<H::Out>>>;
+
+	/// Returns an encoded branch node with a partial key, for layouts without extension nodes.
+	/// Takes the partial key, an iterator yielding `ChildReference<H::Out>` and an optional value.
+	/// Never called for layouts with extension nodes.
+	fn branch_node_nibbled<I>(partial: &[u8], children: I, value: Option<Value>) -> Vec<u8>
 	where I: IntoIterator<Item=Option<ChildReference<H::Out>>> + Iterator<Item=Option<ChildReference<H::Out>>>;
 }
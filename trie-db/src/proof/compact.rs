@@ -0,0 +1,712 @@
+// Copyright 2017, 2019 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compact proof encoding.
+//!
+//! In a plain proof every node is accompanied by the hashes of its children, even
+//! when those children are themselves part of the proof and their hashes can be
+//! recomputed. The compact encoding lists the nodes in depth-first order and leaves
+//! out the references to included children: an omitted branch child is left empty,
+//! while an omitted extension child or value node is replaced by a zero hash. Each
+//! compact node is preceded by a header marking the references it omits, for itself
+//! and for each of its inline children, so that the encoding works with any codec.
+//! Value nodes included in the proof follow the node referring to them. A node
+//! referred to more than once is only omitted at its first reference, and keeps its
+//! hash at the others. Decoding rebuilds the nodes bottom-up.
+
+use hash_db::Hasher;
+use nibbleslice::NibbleSlice;
+use node::{Node, Value};
+use node_codec::NodeCodec;
+use triedbmut::ChildReference;
+use {DBValue, TrieLayout, TrieHash, CError, value_hash};
+
+#[cfg(feature = "std")]
+use ::std::collections::HashMap;
+#[cfg(feature = "std")]
+use ::std::{error::Error, fmt};
+
+#[cfg(not(feature = "std"))]
+use ::hashmap_core::HashMap;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// Errors that may occur while encoding or decoding a compact proof.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CompactError<HO, CE> {
+	/// The proof does not contain the root node.
+	MissingRoot(HO),
+	/// The proof contains a node which is not reachable from the root.
+	ExtraneousNode(HO),
+	/// The compact proof ended while the children of a node were still expected.
+	IncompleteProof,
+	/// A node in the proof could not be decoded.
+	DecodeError(HO, CE),
+	/// The header of a compact node, given by the hash of its entry, does not match
+	/// the references of the node.
+	InvalidHeader(HO),
+	/// The nodes of the compact proof are nested deeper than any supported key.
+	TooDeep,
+}
+
+#[cfg(feature = "std")]
+impl<HO: fmt::Debug, CE: fmt::Debug> fmt::Display for CompactError<HO, CE> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CompactError::MissingRoot(ref root) => write!(f, "Proof is missing the root node: {:?}", root),
+			CompactError::ExtraneousNode(ref hash) => write!(f, "Unreachable node in proof: {:?}", hash),
+			CompactError::IncompleteProof => write!(f, "Compact proof ended unexpectedly"),
+			CompactError::DecodeError(ref hash, ref decoder_err) => {
+				write!(f, "Decoding failed for hash {:?}; err: {:?}", hash, decoder_err)
+			}
+			CompactError::InvalidHeader(ref hash) => write!(f, "Invalid header for compact node: {:?}", hash),
+			CompactError::TooDeep => write!(f, "Compact proof nodes are nested too deep"),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl<HO: fmt::Debug, CE: Error> Error for CompactError<HO, CE> {
+	fn description(&self) -> &str {
+		match *self {
+			CompactError::MissingRoot(_) => "Missing root node",
+			CompactError::ExtraneousNode(_) => "Unreachable node in proof",
+			CompactError::IncompleteProof => "Incomplete compact proof",
+			CompactError::DecodeError(_, ref err) => err.description(),
+			CompactError::InvalidHeader(_) => "Invalid compact node header",
+			CompactError::TooDeep => "Compact proof too deep",
+		}
+	}
+}
+
+// Flags starting the header entry of a node.
+// the value node is omitted and replaced by a zero hash.
+const OMITTED_VALUE: u8 = 1;
+// the flags are followed by the bitmap of the omitted children, the child of an
+// extension being the first one. An omitted branch child is left empty, while an
+// omitted extension child is replaced by a zero hash.
+const OMITTED_CHILDREN: u8 = 2;
+// the node is an extension whose child is omitted, given by its encoded partial key
+// alone. Only the node of a compact proof entry, not an inline one, may be given so.
+const EXTENSION_PARTIAL: u8 = 4;
+
+// Maximum nesting of the nodes of a compact proof, enough for keys of 127 bytes.
+const MAX_DEPTH: usize = 256;
+
+/// Encode the nodes of a proof for the trie rooted at `root` in compact form.
+///
+/// The result holds the same number of nodes as the proof, in depth-first order
+/// starting from the root.
+pub fn encode_compact<L>(
+	root: &TrieHash<L>,
+	proof: &[Vec<u8>],
+) -> Result<Vec<Vec<u8>>, CompactError<TrieHash<L>, CError<L>>>
+where
+	L: TrieLayout,
+{
+	if proof.is_empty() {
+		return Ok(Vec::new());
+	}
+
+	let mut used = Vec::new();
+	used.resize(proof.len(), false);
+	let mut encoder = Encoder::<L> {
+		proof,
+		nodes: proof.iter()
+			.enumerate()
+			.map(|(index, node)| (L::Hash::hash(node), index))
+			.collect(),
+		used,
+		output: Vec::with_capacity(proof.len()),
+	};
+	let root_index = encoder.claim(root).ok_or(CompactError::MissingRoot(*root))?;
+	encoder.encode(root_index)?;
+
+	match encoder.used.iter().position(|used| !used) {
+		Some(index) => Err(CompactError::ExtraneousNode(L::Hash::hash(&proof[index]))),
+		None => Ok(encoder.output),
+	}
+}
+
+/// Decode a compact proof back into the plain proof nodes, in depth-first order
+/// starting from the root.
+pub fn decode_compact<L>(
+	compact: &[Vec<u8>],
+) -> Result<Vec<Vec<u8>>, CompactError<TrieHash<L>, CError<L>>>
+where
+	L: TrieLayout,
+{
+	if compact.is_empty() {
+		return Ok(Vec::new());
+	}
+
+	let mut decoder = Decoder {
+		compact,
+		position: 0,
+		depth: 0,
+		output: Vec::with_capacity(compact.len()),
+	};
+	decoder.node::<L>()?;
+
+	match compact.get(decoder.position) {
+		Some(node) => Err(CompactError::ExtraneousNode(L::Hash::hash(node))),
+		None => Ok(decoder.output),
+	}
+}
+
+// Reference to a child which is inlined into its parent node.
+fn inline_child<HO>(data: &[u8]) -> ChildReference<HO> {
+	ChildReference::Inline(DBValue::from_slice(data))
+}
+
+// Append `len` to `output` as a LEB128 number.
+fn encode_len(mut len: usize, output: &mut Vec<u8>) {
+	while len >= 0x80 {
+		output.push(len as u8 | 0x80);
+		len >>= 7;
+	}
+	output.push(len as u8);
+}
+
+// Read a LEB128 number of at most 28 bits from the start of `data`, returning it along
+// with the rest of `data`.
+fn decode_len(data: &[u8]) -> Option<(usize, &[u8])> {
+	let mut len = 0;
+	for (index, byte) in data.iter().take(4).enumerate() {
+		len |= ((byte & 0x7f) as usize) << (index * 7);
+		if byte & 0x80 == 0 {
+			return Some((len, &data[index + 1..]));
+		}
+	}
+	None
+}
+
+// An entry of the proof following its parent node in the compact proof.
+enum Included {
+	Node(usize),
+	Value(usize),
+}
+
+// State of the encoding of a proof.
+struct Encoder<'a, L: TrieLayout> {
+	proof: &'a [Vec<u8>],
+	// index of each node of the proof by hash.
+	nodes: HashMap<TrieHash<L>, usize>,
+	// whether each node of the proof has been claimed by a reference.
+	used: Vec<bool>,
+	output: Vec<Vec<u8>>,
+}
+
+impl<'a, L: TrieLayout> Encoder<'a, L> {
+	// Claim the proof node `hash` for the reference being encoded, unless it is not in
+	// the proof or an earlier reference claimed it.
+	fn claim(&mut self, hash: &TrieHash<L>) -> Option<usize> {
+		match self.nodes.get(hash) {
+			Some(&index) if !self.used[index] => {
+				self.used[index] = true;
+				Some(index)
+			}
+			_ => None,
+		}
+	}
+
+	// Encode the proof node at `index` followed by the entries it omits.
+	fn encode(&mut self, index: usize) -> Result<(), CompactError<TrieHash<L>, CError<L>>> {
+		let proof = self.proof;
+		// entries omitted from the node, in the order they follow it.
+		let mut included = Vec::new();
+		let mut header = Vec::new();
+		let node = self.compact_node(&proof[index], false, &mut header, &mut included)?;
+
+		let mut entry = Vec::with_capacity(1 + header.len() + node.len());
+		encode_len(header.len(), &mut entry);
+		entry.extend_from_slice(&header);
+		entry.extend_from_slice(&node);
+		self.output.push(entry);
+
+		for included in included {
+			match included {
+				Included::Node(index) => self.encode(index)?,
+				Included::Value(index) => self.output.push(proof[index].clone()),
+			}
+		}
+		Ok(())
+	}
+
+	// Encoding of the node `data` with the children and value node it claims omitted,
+	// inline children included, pushing the header entries of the node and of its
+	// inline children to `header` and the omitted entries to `included`.
+	fn compact_node(
+		&mut self,
+		data: &[u8],
+		inline: bool,
+		header: &mut Vec<u8>,
+		included: &mut Vec<Included>,
+	) -> Result<Vec<u8>, CompactError<TrieHash<L>, CError<L>>> {
+		let node = L::Codec::decode(data).map_err(|e| CompactError::DecodeError(L::Hash::hash(data), e))?;
+		// room for the flags and the bitmap of omitted children, which are only known
+		// once the header entries of the inline children are pushed.
+		let entry = header.len();
+		header.extend_from_slice(&[0; 3]);
+		let zero = TrieHash::<L>::default();
+		let mut flags = 0;
+		let mut omitted_children = 0u16;
+
+		let encoded = match node {
+			Node::Empty => data.to_vec(),
+			Node::Leaf(partial, value) => match self.omit_value(value, included) {
+				true => {
+					flags |= OMITTED_VALUE;
+					L::Codec::leaf_node(&partial.encoded(true), Value::Node(zero.as_ref()))
+				}
+				false => data.to_vec(),
+			},
+			Node::Extension(partial, child) => match self.child_reference(child, header, included)? {
+				Some(child) => L::Codec::ext_node(&partial.encoded(false), child),
+				None if inline => {
+					omitted_children = 1;
+					L::Codec::ext_node(&partial.encoded(false), ChildReference::Hash(zero))
+				}
+				None => {
+					flags |= EXTENSION_PARTIAL;
+					partial.encoded(false).to_vec()
+				}
+			},
+			Node::Branch(children, value) => {
+				// the value node comes before the children.
+				let value = self.compact_value(value, &zero, &mut flags, included);
+				let children = self.children(&children, &mut omitted_children, header, included)?;
+				L::Codec::branch_node(children.into_iter(), value)
+			}
+			Node::NibbledBranch(partial, children, value) => {
+				let value = self.compact_value(value, &zero, &mut flags, included);
+				let children = self.children(&children, &mut omitted_children, header, included)?;
+				L::Codec::branch_node_nibbled(&partial.encoded(false), children.into_iter(), value)
+			}
+		};
+
+		if omitted_children == 0 {
+			header.drain(entry + 1..entry + 3);
+		} else {
+			flags |= OMITTED_CHILDREN;
+			header[entry + 1..entry + 3].copy_from_slice(&omitted_children.to_le_bytes());
+		}
+		header[entry] = flags;
+		Ok(encoded)
+	}
+
+	// Value of a compact branch, with a zero hash in place of a value node it claims.
+	fn compact_value<'b>(
+		&mut self,
+		value: Option<Value<'b>>,
+		zero: &'b TrieHash<L>,
+		flags: &mut u8,
+		included: &mut Vec<Included>,
+	) -> Option<Value<'b>> {
+		match value {
+			Some(value) if self.omit_value(value, included) => {
+				*flags |= OMITTED_VALUE;
+				Some(Value::Node(zero.as_ref()))
+			}
+			value => value,
+		}
+	}
+
+	// References to the children of a branch, leaving out the omitted ones, which are
+	// marked in `omitted`.
+	fn children(
+		&mut self,
+		children: &[Option<&[u8]>; 16],
+		omitted: &mut u16,
+		header: &mut Vec<u8>,
+		included: &mut Vec<Included>,
+	) -> Result<Vec<Option<ChildReference<TrieHash<L>>>>, CompactError<TrieHash<L>, CError<L>>> {
+		let mut references = Vec::with_capacity(16);
+		for (index, child) in children.iter().enumerate() {
+			references.push(match *child {
+				Some(child) => {
+					let reference = self.child_reference(child, header, included)?;
+					if reference.is_none() {
+						*omitted |= 1 << index;
+					}
+					reference
+				}
+				None => None,
+			});
+		}
+		Ok(references)
+	}
+
+	// Reference to the child `child` in the compact node, or `None` if the child is
+	// omitted.
+	fn child_reference(
+		&mut self,
+		child: &[u8],
+		header: &mut Vec<u8>,
+		included: &mut Vec<Included>,
+	) -> Result<Option<ChildReference<TrieHash<L>>>, CompactError<TrieHash<L>, CError<L>>> {
+		match L::Codec::try_decode_hash(child) {
+			Some(hash) => Ok(match self.claim(&hash) {
+				Some(index) => {
+					included.push(Included::Node(index));
+					None
+				}
+				None => Some(ChildReference::Hash(hash)),
+			}),
+			// an inline node is only small compared to the inline threshold, which may
+			// leave room for the hashes of its own children.
+			None => self.compact_node(child, true, header, included).map(|encoded| Some(inline_child(&encoded))),
+		}
+	}
+
+	// Whether `value` is held by a value node claimed by the compact node, which is
+	// then pushed to `included`.
+	fn omit_value(&mut self, value: Value, included: &mut Vec<Included>) -> bool {
+		match value {
+			Value::Node(hash) => match value_hash::<L>(hash).and_then(|hash| self.claim(&hash)) {
+				Some(index) => {
+					included.push(Included::Value(index));
+					true
+				}
+				None => false,
+			},
+			Value::Inline(_) => false,
+		}
+	}
+}
+
+// Header entries of a compact proof entry which are left to match with its node and
+// the inline children of the node.
+struct Header<'a> {
+	entry: &'a [u8],
+	remaining: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+	// Take the next header entry: the flags and the bitmap of omitted children.
+	fn next<L: TrieLayout>(&mut self) -> Result<(u8, u16), CompactError<TrieHash<L>, CError<L>>> {
+		let remaining = self.remaining;
+		let (flags, rest) = match remaining.split_first() {
+			Some((&flags, rest)) if flags & !(OMITTED_VALUE | OMITTED_CHILDREN | EXTENSION_PARTIAL) == 0 => (flags, rest),
+			_ => return Err(self.invalid::<L>()),
+		};
+		if flags & OMITTED_CHILDREN == 0 {
+			self.remaining = rest;
+			return Ok((flags, 0));
+		}
+		if rest.len() < 2 || rest[..2] == [0, 0] {
+			return Err(self.invalid::<L>());
+		}
+		self.remaining = &rest[2..];
+		Ok((flags, u16::from_le_bytes([rest[0], rest[1]])))
+	}
+
+	fn invalid<L: TrieLayout>(&self) -> CompactError<TrieHash<L>, CError<L>> {
+		CompactError::InvalidHeader(L::Hash::hash(self.entry))
+	}
+}
+
+// State of the decoding of a compact proof.
+struct Decoder<'a> {
+	compact: &'a [Vec<u8>],
+	// position of the next entry of the compact proof.
+	position: usize,
+	// nesting of the node being decoded.
+	depth: usize,
+	output: Vec<Vec<u8>>,
+}
+
+impl<'a> Decoder<'a> {
+	fn next<L: TrieLayout>(&mut self) -> Result<&'a Vec<u8>, CompactError<TrieHash<L>, CError<L>>> {
+		let entry = self.compact.get(self.position).ok_or(CompactError::IncompleteProof)?;
+		self.position += 1;
+		Ok(entry)
+	}
+
+	// Decode the next node along with the nodes of its omitted children, returning its
+	// hash.
+	fn node<L: TrieLayout>(&mut self) -> Result<TrieHash<L>, CompactError<TrieHash<L>, CError<L>>> {
+		let entry = self.next::<L>()?;
+		let mut header = Header { entry, remaining: &[] };
+		let data = match decode_len(entry) {
+			Some((len, rest)) if len <= rest.len() => {
+				let (remaining, data) = rest.split_at(len);
+				header.remaining = remaining;
+				data
+			}
+			_ => return Err(header.invalid::<L>()),
+		};
+
+		// reserve the slot so that the output stays in depth-first order.
+		let slot = self.output.len();
+		self.output.push(Vec::new());
+
+		let encoded = self.expand_node::<L>(data, false, &mut header)?;
+		if !header.remaining.is_empty() {
+			return Err(header.invalid::<L>());
+		}
+		let hash = L::Hash::hash(&encoded);
+		self.output[slot] = encoded;
+		Ok(hash)
+	}
+
+	// Plain encoding of the compact node `data`, decoding the nodes it omits, inline
+	// children included.
+	fn expand_node<L: TrieLayout>(
+		&mut self,
+		data: &[u8],
+		inline: bool,
+		header: &mut Header<'a>,
+	) -> Result<Vec<u8>, CompactError<TrieHash<L>, CError<L>>> {
+		if self.depth == MAX_DEPTH {
+			return Err(CompactError::TooDeep);
+		}
+		self.depth += 1;
+
+		let (flags, omitted_children) = header.next::<L>()?;
+		let encoded = if flags & EXTENSION_PARTIAL != 0 {
+			if inline || flags != EXTENSION_PARTIAL || !canonical_extension_partial(data) {
+				return Err(header.invalid::<L>());
+			}
+			L::Codec::ext_node(data, ChildReference::Hash(self.node::<L>()?))
+		} else {
+			self.expand_decoded::<L>(data, flags & OMITTED_VALUE != 0, omitted_children, header)?
+		};
+
+		self.depth -= 1;
+		Ok(encoded)
+	}
+
+	// Plain encoding of the compact node `data` given by its codec encoding.
+	fn expand_decoded<L: TrieLayout>(
+		&mut self,
+		data: &[u8],
+		omitted_value: bool,
+		omitted_children: u16,
+		header: &mut Header<'a>,
+	) -> Result<Vec<u8>, CompactError<TrieHash<L>, CError<L>>> {
+		let node = L::Codec::decode(data).map_err(|e| CompactError::DecodeError(L::Hash::hash(data), e))?;
+		Ok(match node {
+			Node::Empty if omitted_children == 0 && !omitted_value => data.to_vec(),
+			Node::Leaf(partial, value) if omitted_children == 0 => {
+				match self.value_node::<L>(Some(value), omitted_value, header)? {
+					Some(hash) => L::Codec::leaf_node(&partial.encoded(true), Value::Node(hash.as_ref())),
+					None => data.to_vec(),
+				}
+			}
+			Node::Extension(partial, child) if !omitted_value => {
+				let child = match omitted_children {
+					0 => self.reference::<L>(child, header)?,
+					1 => ChildReference::Hash(self.node::<L>()?),
+					_ => return Err(header.invalid::<L>()),
+				};
+				L::Codec::ext_node(&partial.encoded(false), child)
+			}
+			Node::Branch(children, value) => {
+				let hash = self.value_node::<L>(value, omitted_value, header)?;
+				let references = self.children::<L>(&children, omitted_children, header)?;
+				let value = value.map(|value| hash.as_ref().map_or(value, |hash| Value::Node(hash.as_ref())));
+				L::Codec::branch_node(references.into_iter(), value)
+			}
+			Node::NibbledBranch(partial, children, value) => {
+				let hash = self.value_node::<L>(value, omitted_value, header)?;
+				let references = self.children::<L>(&children, omitted_children, header)?;
+				let value = value.map(|value| hash.as_ref().map_or(value, |hash| Value::Node(hash.as_ref())));
+				L::Codec::branch_node_nibbled(&partial.encoded(false), references.into_iter(), value)
+			}
+			_ => return Err(header.invalid::<L>()),
+		})
+	}
+
+	// Hash of the value node following in the compact proof, if the value node of the
+	// compact node is `omitted`.
+	fn value_node<L: TrieLayout>(
+		&mut self,
+		value: Option<Value>,
+		omitted: bool,
+		header: &Header,
+	) -> Result<Option<TrieHash<L>>, CompactError<TrieHash<L>, CError<L>>> {
+		match (value, omitted) {
+			(_, false) => Ok(None),
+			(Some(Value::Node(_)), true) => {
+				let data = self.next::<L>()?;
+				self.output.push(data.clone());
+				Ok(Some(L::Hash::hash(data)))
+			}
+			(_, true) => Err(header.invalid::<L>()),
+		}
+	}
+
+	// References to the children of a branch, decoding the omitted ones.
+	fn children<L: TrieLayout>(
+		&mut self,
+		children: &[Option<&[u8]>; 16],
+		omitted: u16,
+		header: &mut Header<'a>,
+	) -> Result<Vec<Option<ChildReference<TrieHash<L>>>>, CompactError<TrieHash<L>, CError<L>>> {
+		let mut references = Vec::with_capacity(16);
+		for (index, child) in children.iter().enumerate() {
+			references.push(match (*child, omitted & (1 << index) != 0) {
+				(Some(child), false) => Some(self.reference::<L>(child, header)?),
+				(None, true) => Some(ChildReference::Hash(self.node::<L>()?)),
+				(None, false) => None,
+				(Some(_), true) => return Err(header.invalid::<L>()),
+			});
+		}
+		Ok(references)
+	}
+
+	// Reference to a child given the child data of its compact parent node, expanding
+	// the child if it is inline.
+	fn reference<L: TrieLayout>(
+		&mut self,
+		child: &[u8],
+		header: &mut Header<'a>,
+	) -> Result<ChildReference<TrieHash<L>>, CompactError<TrieHash<L>, CError<L>>> {
+		Ok(match L::Codec::try_decode_hash(child) {
+			Some(hash) => ChildReference::Hash(hash),
+			None => inline_child(&self.expand_node::<L>(child, true, header)?),
+		})
+	}
+}
+
+// Whether `data` is the canonical encoding of the partial key of an extension.
+fn canonical_extension_partial(data: &[u8]) -> bool {
+	if data.is_empty() {
+		return false;
+	}
+	let (partial, is_leaf) = NibbleSlice::from_encoded(data);
+	!is_leaf && !partial.is_empty() && partial.encoded(false)[..] == data[..]
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use hash_db::Hasher;
+	use keccak_hasher::KeccakHasher;
+	use standardmap::{Alphabet, StandardMap, ValueMode};
+	use DBValue;
+	use reference_trie::{RefTrieDBMut, TrieMut, ExtensionLayout, proof};
+	use reference_trie::proof::CompactError;
+	use super::MAX_DEPTH;
+
+	type Out = <KeccakHasher as Hasher>::Out;
+
+	fn test_db() -> (MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, Out, Vec<Vec<u8>>) {
+		let pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 5,
+			journal_key: 0,
+			value_mode: ValueMode::Index,
+			count: 500,
+		}.make();
+
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in &pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+		(db, root, pairs.into_iter().map(|(k, _)| k).collect())
+	}
+
+	#[test]
+	fn compact_round_trip() {
+		let (db, root, keys) = test_db();
+		let mut proven: Vec<_> = keys.iter().step_by(37).cloned().collect();
+		proven.push(b"not in the trie".to_vec());
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &proven).unwrap();
+
+		let compact = proof::encode_compact::<ExtensionLayout>(&root, &nodes).unwrap();
+		assert_eq!(compact.len(), nodes.len());
+		let compact_size: usize = compact.iter().map(Vec::len).sum();
+		let plain_size: usize = nodes.iter().map(Vec::len).sum();
+		assert!(compact_size < plain_size);
+
+		let decoded = proof::decode_compact::<ExtensionLayout>(&compact).unwrap();
+		assert_eq!(decoded, nodes);
+	}
+
+	#[test]
+	fn unreachable_node_is_rejected() {
+		let (db, root, keys) = test_db();
+		let mut nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &keys[..1]).unwrap();
+		nodes.push(vec![0]);
+		assert_eq!(
+			proof::encode_compact::<ExtensionLayout>(&root, &nodes),
+			Err(CompactError::ExtraneousNode(KeccakHasher::hash(&[0u8][..])))
+		);
+	}
+
+	#[test]
+	fn truncated_compact_proof_is_rejected() {
+		let (db, root, keys) = test_db();
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &keys[..1]).unwrap();
+		let mut compact = proof::encode_compact::<ExtensionLayout>(&root, &nodes).unwrap();
+		compact.pop();
+		assert_eq!(
+			proof::decode_compact::<ExtensionLayout>(&compact),
+			Err(CompactError::IncompleteProof)
+		);
+	}
+
+	#[test]
+	fn shared_subtree_is_included_once() {
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for key in &[[0x10u8], [0x11], [0x20], [0x21]] {
+				t.insert(key, &[7; 40]).unwrap();
+			}
+		}
+		let keys = [[0x10u8], [0x20]];
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &keys).unwrap();
+		assert_eq!(nodes.len(), 3);
+
+		let compact = proof::encode_compact::<ExtensionLayout>(&root, &nodes).unwrap();
+		assert_eq!(compact.len(), nodes.len());
+		let decoded = proof::decode_compact::<ExtensionLayout>(&compact).unwrap();
+		assert_eq!(decoded, nodes);
+
+		let items = [(&keys[0][..], Some(&[7; 40][..])), (&keys[1][..], Some(&[7; 40][..]))];
+		assert_eq!(proof::verify_proof::<ExtensionLayout, _, _>(&root, &decoded, &items), Ok(()));
+	}
+
+	#[test]
+	fn invalid_header_is_rejected() {
+		let (db, root, keys) = test_db();
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &keys[..1]).unwrap();
+		let mut compact = proof::encode_compact::<ExtensionLayout>(&root, &nodes).unwrap();
+		// claim that the value of the root is omitted.
+		compact[0][1] |= 1;
+		assert_eq!(
+			proof::decode_compact::<ExtensionLayout>(&compact),
+			Err(CompactError::InvalidHeader(KeccakHasher::hash(&compact[0])))
+		);
+	}
+
+	#[test]
+	fn deep_compact_proof_is_rejected() {
+		let (db, root, keys) = test_db();
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &keys[..1]).unwrap();
+		let compact = proof::encode_compact::<ExtensionLayout>(&root, &nodes).unwrap();
+		// the root omits a child, which is the root again.
+		let compact = vec![compact[0].clone(); MAX_DEPTH + 1];
+		assert_eq!(proof::decode_compact::<ExtensionLayout>(&compact), Err(CompactError::TooDeep));
+	}
+}
@@ -0,0 +1,147 @@
+// Copyright 2017, 2019 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proof generation.
+
+use hash_db::HashDBRef;
+use recorder::Recorder;
+use triedb::TrieDB;
+use super::super::{DBValue, Result, Trie, TrieLayout, TrieHash, CError};
+
+#[cfg(feature = "std")]
+use ::std::collections::HashSet;
+
+#[cfg(not(feature = "std"))]
+use ::hashmap_core::HashSet;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// Generate a proof for the given set of keys against the trie rooted at `root`.
+///
+/// Both present and absent keys can be proven: the proof for an absent key is the
+/// path which ends where the key diverges from the trie. The returned nodes are
+/// deduplicated and ordered by first use when walking the keys in ascending order,
+/// so the root node always comes first.
+///
+/// The value nodes of proven values too large to be kept in their node follow the
+/// node referring to them.
+pub fn generate_proof<L, K>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	keys: &[K],
+) -> Result<Vec<Vec<u8>>, TrieHash<L>, CError<L>>
+where
+	L: TrieLayout,
+	K: AsRef<[u8]>,
+{
+	record_proof::<L, K>(db, root, keys, Recorder::new())
+}
+
+// Generate a proof as `generate_proof` does, recording the nodes with `recorder`.
+pub(crate) fn record_proof<L, K>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	keys: &[K],
+	mut recorder: Recorder<TrieHash<L>>,
+) -> Result<Vec<Vec<u8>>, TrieHash<L>, CError<L>>
+where
+	L: TrieLayout,
+	K: AsRef<[u8]>,
+{
+	let trie = TrieDB::<L>::new(db, root)?;
+
+	let mut keys: Vec<&[u8]> = keys.iter().map(AsRef::as_ref).collect();
+	keys.sort();
+	keys.dedup();
+
+	for key in keys {
+		trie.get_with(key, &mut recorder)?;
+	}
+
+	let mut seen = HashSet::new();
+	Ok(recorder.drain()
+		.into_iter()
+		.filter(|record| seen.insert(record.hash))
+		.map(|record| record.data)
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, HashKey, PrefixedKey};
+	use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
+	use keccak_hasher::KeccakHasher;
+	use DBValue;
+	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, ExtensionLayout, proof};
+
+	fn test_db() -> (MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, <KeccakHasher as Hasher>::Out) {
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			t.insert(b"dog", b"cat").unwrap();
+			t.insert(b"lunch", b"time").unwrap();
+			t.insert(b"notdog", b"notcat").unwrap();
+			t.insert(b"hotdog", b"hotcat").unwrap();
+			t.insert(b"letter", b"confusion").unwrap();
+			t.insert(b"insert", b"remove").unwrap();
+			t.insert(b"pirate", b"aargh!").unwrap();
+			t.insert(b"yo ho ho", b"and a bottle of rum").unwrap();
+		}
+		(db, root)
+	}
+
+	#[test]
+	fn proof_starts_at_root_without_duplicates() {
+		let (db, root) = test_db();
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(
+			&db,
+			&root,
+			&[&b"pirate"[..], b"letter", b"pirate", b"nothing"],
+		).unwrap();
+
+		assert_eq!(KeccakHasher::hash(&nodes[0]), root);
+		let mut hashes: Vec<_> = nodes.iter().map(|n| KeccakHasher::hash(n)).collect();
+		hashes.sort();
+		hashes.dedup();
+		assert_eq!(hashes.len(), nodes.len());
+	}
+
+	#[test]
+	fn proof_is_sufficient_for_lookups() {
+		let (db, root) = test_db();
+		let keys: [&[u8]; 4] = [b"dog", b"insert", b"doge", b"zzz"];
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &keys).unwrap();
+
+		let mut proof_db = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		for node in &nodes {
+			proof_db.insert(EMPTY_PREFIX, node);
+		}
+
+		let full = RefTrieDB::new(&db, &root).unwrap();
+		let partial = RefTrieDB::new(&proof_db, &root).unwrap();
+		for key in keys.iter() {
+			assert_eq!(partial.get(key).unwrap(), full.get(key).unwrap());
+		}
+	}
+
+	#[test]
+	fn empty_key_set_gives_empty_proof() {
+		let (db, root) = test_db();
+		let keys: [&[u8]; 0] = [];
+		let nodes = proof::generate_proof::<ExtensionLayout, _>(&db, &root, &keys).unwrap();
+		assert!(nodes.is_empty());
+	}
+}
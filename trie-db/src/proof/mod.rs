@@ -0,0 +1,31 @@
+// Copyright 2017, 2019 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generation and verification of Merkle proofs for sets of keys.
+//!
+//! A proof is the set of encoded trie nodes which a verifier needs, alongside the
+//! trie root, in order to walk the path of every proven key. Proofs can be
+//! transferred in a compact form which omits the hashes of included nodes.
+//! Range proofs additionally prove that a set of items is the complete content
+//! of a range of keys.
+
+mod compact;
+mod generate;
+mod range;
+mod verify;
+
+pub use self::compact::{encode_compact, decode_compact, CompactError};
+pub use self::generate::generate_proof;
+pub use self::range::{generate_range_proof, verify_range_proof};
+pub use self::verify::{verify_proof, VerifyError};
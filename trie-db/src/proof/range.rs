@@ -0,0 +1,564 @@
+// Copyright 2017, 2019 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proofs for the complete contents of a key range.
+//!
+//! A range proof consists of the items stored under keys in `[start, end]` and the
+//! nodes on the lookup paths of `start` and `end`. The verifier walks those
+//! boundary paths, rebuilds every subtree lying strictly between them from the
+//! items, keeps the references of subtrees lying outside of the range, and checks
+//! that the reconstructed trie hashes to the expected root. Any item omitted from
+//! or added to the range therefore changes the root.
+
+use hash_db::{HashDBRef, Hasher};
+use nibbleslice::NibbleSlice;
+use node::{Node, OwnedValue, Value};
+use node_codec::NodeCodec;
+use recorder::Recorder;
+use triedb::{TrieDB, TrieDBIterator};
+use triedbmut::ChildReference;
+use super::generate::record_proof;
+use super::VerifyError;
+use super::super::{DBValue, Result, TrieIterator, TrieLayout, TrieHash, CError, check_value_nodes};
+use ::core_::marker::PhantomData;
+
+#[cfg(feature = "std")]
+use ::std::collections::HashMap;
+
+#[cfg(not(feature = "std"))]
+use ::hashmap_core::HashMap;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// Generate a proof for all the items stored under keys in `[start, end]`.
+///
+/// Returns the items, in ascending key order, along with the proof nodes. As the items
+/// hold the values, value nodes are left out of the proof.
+pub fn generate_range_proof<L>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	start: &[u8],
+	end: &[u8],
+) -> Result<(Vec<(Vec<u8>, DBValue)>, Vec<Vec<u8>>), TrieHash<L>, CError<L>>
+where
+	L: TrieLayout,
+{
+	let trie = TrieDB::<L>::new(db, root)?;
+	let mut iter = TrieDBIterator::new(&trie)?;
+	iter.seek(start)?;
+
+	let mut items = Vec::new();
+	for item in iter {
+		let (key, value) = item?;
+		if &key[..] > end {
+			break;
+		}
+		items.push((key, value));
+	}
+
+	let proof = record_proof::<L, _>(db, root, &[start, end], Recorder::new().without_values())?;
+	Ok((items, proof))
+}
+
+/// Verify that `items` is the complete content of the range `[start, end]` of the
+/// trie rooted at `root`.
+///
+/// The items must be in strictly ascending key order.
+pub fn verify_range_proof<L, K, V>(
+	root: &TrieHash<L>,
+	start: &[u8],
+	end: &[u8],
+	items: &[(K, V)],
+	proof: &[Vec<u8>],
+) -> ::core_::result::Result<(), VerifyError<TrieHash<L>, CError<L>>>
+where
+	L: TrieLayout,
+	K: AsRef<[u8]>,
+	V: AsRef<[u8]>,
+{
+	check_value_nodes::<L>();
+	let mut previous: Option<&[u8]> = None;
+	for (key, _) in items {
+		let key = key.as_ref();
+		if key < start || key > end || previous.map_or(false, |previous| previous >= key) {
+			return Err(VerifyError::ItemOutOfRange(key.to_vec()));
+		}
+		previous = Some(key);
+	}
+
+	let mut nodes = HashMap::with_capacity(proof.len());
+	for (index, node) in proof.iter().enumerate() {
+		let hash = L::Hash::hash(node);
+		if nodes.insert(hash, index).is_some() {
+			return Err(VerifyError::ExtraneousNode(hash));
+		}
+	}
+
+	let mut used = Vec::new();
+	used.resize(proof.len(), false);
+	let mut verifier = RangeVerifier::<L> {
+		proof,
+		nodes,
+		used,
+		start: NibbleSlice::new(start).iter().collect(),
+		end: NibbleSlice::new(end).iter().collect(),
+		items: items.iter()
+			.map(|(key, value)| RangeItem {
+				nibbles: NibbleSlice::new(key.as_ref()).iter().collect(),
+				key: key.as_ref(),
+				value: value.as_ref(),
+			})
+			.collect(),
+		cursor: 0,
+		marker: PhantomData,
+	};
+
+	let computed = match verifier.nodes.get(root) {
+		Some(&index) => {
+			verifier.used[index] = true;
+			match verifier.node(&mut Vec::new(), *root, &proof[index])? {
+				Some(encoded) => L::Hash::hash(&encoded),
+				None => L::Codec::hashed_null_node(),
+			}
+		}
+		None if *root == L::Codec::hashed_null_node() => L::Codec::hashed_null_node(),
+		None => return Err(VerifyError::MissingNode(*root)),
+	};
+
+	if let Some(item) = verifier.items.get(verifier.cursor) {
+		return Err(VerifyError::ValueMismatch(item.key.to_vec()));
+	}
+	if computed != *root {
+		return Err(VerifyError::RootMismatch(computed));
+	}
+	match verifier.used.iter().position(|used| !used) {
+		Some(index) => Err(VerifyError::ExtraneousNode(L::Hash::hash(&proof[index]))),
+		None => Ok(()),
+	}
+}
+
+struct RangeItem<'a> {
+	nibbles: Vec<u8>,
+	key: &'a [u8],
+	value: &'a [u8],
+}
+
+// Where a subtree lies relative to the proven range.
+enum Position {
+	// on the lookup path of one of the range bounds.
+	Boundary,
+	// all its keys are within the range.
+	Inside,
+	// none of its keys are within the range.
+	Outside,
+}
+
+struct RangeVerifier<'a, L: TrieLayout> {
+	proof: &'a [Vec<u8>],
+	nodes: HashMap<TrieHash<L>, usize>,
+	used: Vec<bool>,
+	start: Vec<u8>,
+	end: Vec<u8>,
+	items: Vec<RangeItem<'a>>,
+	// index of the first item not yet placed in the trie.
+	cursor: usize,
+	marker: PhantomData<L>,
+}
+
+impl<'a, L: TrieLayout> RangeVerifier<'a, L> {
+	fn position(&self, path: &[u8]) -> Position {
+		if self.start.starts_with(path) || self.end.starts_with(path) {
+			Position::Boundary
+		} else if path < &self.start[..] || path > &self.end[..] {
+			Position::Outside
+		} else {
+			Position::Inside
+		}
+	}
+
+	// Take the items with keys starting with the nibbles of `prefix`. Every item
+	// before them must have been placed already.
+	fn take_items(&mut self, prefix: &[u8]) -> ::core_::result::Result<(usize, usize), VerifyError<TrieHash<L>, CError<L>>> {
+		let begin = self.cursor;
+		if let Some(item) = self.items.get(begin) {
+			if !item.nibbles.starts_with(prefix) && &item.nibbles[..] < prefix {
+				return Err(VerifyError::ValueMismatch(item.key.to_vec()));
+			}
+		}
+		while self.items.get(self.cursor).map_or(false, |item| item.nibbles.starts_with(prefix)) {
+			self.cursor += 1;
+		}
+		Ok((begin, self.cursor))
+	}
+
+	// Rebuild the node found at `path` on one of the boundary paths.
+	fn node(
+		&mut self,
+		path: &mut Vec<u8>,
+		hash: TrieHash<L>,
+		data: &'a [u8],
+	) -> ::core_::result::Result<Option<Vec<u8>>, VerifyError<TrieHash<L>, CError<L>>> {
+		match L::Codec::decode(data).map_err(|e| VerifyError::DecodeError(hash, e))? {
+			Node::Empty => Ok(None),
+			Node::Leaf(slice, _) => {
+				let depth = path.len();
+				path.extend(slice.iter());
+				let in_range = path[..] >= self.start[..] && path[..] <= self.end[..];
+				path.truncate(depth);
+				match in_range {
+					true => {
+						let (begin, end) = self.take_items(path)?;
+						Ok(self.build(begin, end, depth))
+					}
+					false => Ok(Some(data.to_vec())),
+				}
+			}
+			Node::Extension(slice, child) => {
+				let depth = path.len();
+				path.extend(slice.iter());
+				let child = self.child(path, hash, Some(child))?;
+				path.truncate(depth);
+				Ok(child.map(|child| L::Codec::ext_node(&slice.encoded(false), child)))
+			}
+			Node::Branch(children, value) => {
+				let (value, references) = self.branch(path, hash, &children, value)?;
+				let value = value.as_ref().map(OwnedValue::as_value);
+				Ok(Some(L::Codec::branch_node(references.into_iter(), value)))
+			}
+			Node::NibbledBranch(slice, children, value) => {
+				let depth = path.len();
+				path.extend(slice.iter());
+				let encoded = match self.position(path) {
+					Position::Outside => Some(data.to_vec()),
+					Position::Inside => {
+						let (begin, end) = self.take_items(path)?;
+						self.build(begin, end, depth)
+					}
+					Position::Boundary => {
+						let (value, references) = self.branch(path, hash, &children, value)?;
+						let value = value.as_ref().map(OwnedValue::as_value);
+						Some(L::Codec::branch_node_nibbled(&slice.encoded(false), references.into_iter(), value))
+					}
+				};
+				path.truncate(depth);
+				Ok(encoded)
+			}
+		}
+	}
+
+	// Rebuild the value and child references of the branch found at `path` on one
+	// of the boundary paths.
+	fn branch(
+		&mut self,
+		path: &mut Vec<u8>,
+		hash: TrieHash<L>,
+		children: &[Option<&'a [u8]>; 16],
+		value: Option<Value<'a>>,
+	) -> ::core_::result::Result<(Option<OwnedValue>, Vec<Option<ChildReference<TrieHash<L>>>>), VerifyError<TrieHash<L>, CError<L>>> {
+		let value = match self.position(path) {
+			Position::Boundary if path[..] >= self.start[..] && path[..] <= self.end[..] => {
+				let (begin, end) = self.take_items(path)?;
+				match end - begin {
+					0 => None,
+					_ => {
+						// the items below this branch are placed by its children.
+						self.cursor = begin;
+						match self.items[begin].nibbles.len() == path.len() {
+							true => {
+								self.cursor += 1;
+								Some(item_value::<L>(self.items[begin].value))
+							}
+							false => None,
+						}
+					}
+				}
+			}
+			_ => value.map(OwnedValue::from),
+		};
+
+		let mut references = Vec::with_capacity(16);
+		for (i, child) in children.iter().enumerate() {
+			path.push(i as u8);
+			references.push(self.child(path, hash, *child)?);
+			path.pop();
+		}
+		Ok((value, references))
+	}
+
+	// Reference to the child at `path` of the node with hash `parent`.
+	fn child(
+		&mut self,
+		path: &mut Vec<u8>,
+		parent: TrieHash<L>,
+		data: Option<&'a [u8]>,
+	) -> ::core_::result::Result<Option<ChildReference<TrieHash<L>>>, VerifyError<TrieHash<L>, CError<L>>> {
+		match self.position(path) {
+			Position::Outside => Ok(data.map(reference::<L>)),
+			Position::Inside => {
+				let (begin, end) = self.take_items(path)?;
+				let depth = path.len();
+				Ok(self.build(begin, end, depth).map(|encoded| encoded_reference::<L>(&encoded)))
+			}
+			Position::Boundary => match data {
+				None => Ok(None),
+				Some(data) => {
+					let encoded = match L::Codec::try_decode_hash(data) {
+						Some(hash) => {
+							let index = *self.nodes.get(&hash).ok_or(VerifyError::MissingNode(hash))?;
+							self.used[index] = true;
+							self.node(path, hash, &self.proof[index])?
+						}
+						None => self.node(path, parent, data)?,
+					};
+					Ok(encoded.map(|encoded| encoded_reference::<L>(&encoded)))
+				}
+			},
+		}
+	}
+
+	// Build the encoded node holding `items[begin..end]`, all of which share the
+	// first `depth` nibbles of their keys.
+	fn build(&self, begin: usize, end: usize, depth: usize) -> Option<Vec<u8>> {
+		let items = &self.items[begin..end];
+		match items.len() {
+			0 => return None,
+			1 => {
+				let key = NibbleSlice::new(items[0].key).mid(depth);
+				return Some(L::Codec::leaf_node(&key.encoded(true), item_value::<L>(items[0].value).as_value()));
+			}
+			_ => {}
+		}
+
+		let first = &items[0].nibbles;
+		let last = &items[items.len() - 1].nibbles;
+		let shared = first[depth..].iter().zip(last[depth..].iter()).take_while(|&(a, b)| a == b).count();
+		let key = NibbleSlice::new(items[0].key).mid(depth);
+		if shared > 0 && L::USE_EXTENSION {
+			let child = self.build(begin, end, depth + shared).map(|encoded| encoded_reference::<L>(&encoded));
+			return child.map(|child| L::Codec::ext_node(&key.encoded_leftmost(shared, false), child));
+		}
+		// without extension nodes, the shared nibbles are the partial key of the branch.
+		let partial = key.encoded_leftmost(shared, false);
+		let depth = depth + shared;
+
+		let mut begin = begin;
+		let value = match first.len() == depth {
+			true => {
+				begin += 1;
+				Some(item_value::<L>(items[0].value))
+			}
+			false => None,
+		};
+		let value = value.as_ref().map(OwnedValue::as_value);
+		let mut children = Vec::with_capacity(16);
+		for i in 0..16 {
+			let mut child_end = begin;
+			while child_end < end && self.items[child_end].nibbles[depth] == i {
+				child_end += 1;
+			}
+			children.push(self.build(begin, child_end, depth + 1).map(|encoded| encoded_reference::<L>(&encoded)));
+			begin = child_end;
+		}
+		match L::USE_EXTENSION {
+			true => Some(L::Codec::branch_node(children.into_iter(), value)),
+			false => Some(L::Codec::branch_node_nibbled(&partial, children.into_iter(), value)),
+		}
+	}
+}
+
+// A value as stored in its node.
+fn item_value<L: TrieLayout>(value: &[u8]) -> OwnedValue {
+	match L::is_inline_value(value.len()) {
+		true => OwnedValue::Inline(DBValue::from_slice(value)),
+		false => OwnedValue::Node(DBValue::from_slice(L::Hash::hash(value).as_ref())),
+	}
+}
+
+// Reference to a child given its encoding.
+fn encoded_reference<L: TrieLayout>(encoded: &[u8]) -> ChildReference<TrieHash<L>> {
+	match L::is_inline(encoded.len()) {
+		true => ChildReference::Inline(DBValue::from_slice(encoded)),
+		false => ChildReference::Hash(L::Hash::hash(encoded)),
+	}
+}
+
+// Reference to a child given the child data of its parent node.
+fn reference<L: TrieLayout>(data: &[u8]) -> ChildReference<TrieHash<L>> {
+	match L::Codec::try_decode_hash(data) {
+		Some(hash) => ChildReference::Hash(hash),
+		None => ChildReference::Inline(DBValue::from_slice(data)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use hash_db::Hasher;
+	use keccak_hasher::KeccakHasher;
+	use standardmap::{Alphabet, StandardMap, ValueMode};
+	use DBValue;
+	use reference_trie::{RefTrieDBMut, RefTrieDBMutNoExt, TrieMut, ReferenceNodeCodec, ExtensionLayout, NoExtensionLayout, proof};
+	use reference_trie::proof::VerifyError;
+
+	type Out = <KeccakHasher as Hasher>::Out;
+
+	fn test_db(count: u32) -> (MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, Out, Vec<Vec<u8>>) {
+		let mut pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 4,
+			journal_key: 0,
+			value_mode: ValueMode::Mirror,
+			count,
+		}.make();
+		pairs.sort();
+		pairs.dedup_by(|a, b| a.0 == b.0);
+
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in &pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+		(db, root, pairs.into_iter().map(|(k, _)| k).collect())
+	}
+
+	fn verify(
+		root: &Out,
+		start: &[u8],
+		end: &[u8],
+		items: &[(Vec<u8>, DBValue)],
+		nodes: &[Vec<u8>],
+	) -> Result<(), VerifyError<Out, <ReferenceNodeCodec as ::reference_trie::NodeCodec<KeccakHasher>>::Error>> {
+		proof::verify_range_proof::<ExtensionLayout, _, _>(root, start, end, items, nodes)
+	}
+
+	#[test]
+	fn range_proofs_verify() {
+		let (db, root, keys) = test_db(300);
+		let bounds: Vec<(Vec<u8>, Vec<u8>)> = vec![
+			(vec![], vec![0xff; 8]),
+			(keys[0].clone(), keys[0].clone()),
+			(keys[10].clone(), keys[20].clone()),
+			(keys[10][..2].to_vec(), keys[200].clone()),
+			(b"a".to_vec(), b"ab".to_vec()),
+			(b"zz".to_vec(), b"zzzz".to_vec()),
+			(keys[50].clone(), keys[40].clone()),
+			(keys[keys.len() - 5].clone(), vec![0xff]),
+		];
+		for (start, end) in bounds {
+			let (items, nodes) = proof::generate_range_proof::<ExtensionLayout>(
+				&db, &root, &start, &end,
+			).unwrap();
+			let expected: Vec<_> = keys.iter().filter(|k| **k >= start && **k <= end).collect();
+			assert_eq!(items.iter().map(|i| &i.0).collect::<Vec<_>>(), expected);
+			assert_eq!(verify(&root, &start, &end, &items, &nodes), Ok(()));
+		}
+	}
+
+	#[test]
+	fn incomplete_range_is_rejected() {
+		let (db, root, keys) = test_db(300);
+		let (start, end) = (keys[100].clone(), keys[150].clone());
+		let (mut items, nodes) = proof::generate_range_proof::<ExtensionLayout>(
+			&db, &root, &start, &end,
+		).unwrap();
+
+		let mut missing_first = items.clone();
+		missing_first.remove(0);
+		assert!(verify(&root, &start, &end, &missing_first, &nodes).is_err());
+
+		let mut missing_last = items.clone();
+		missing_last.pop();
+		assert!(verify(&root, &start, &end, &missing_last, &nodes).is_err());
+
+		items.remove(25);
+		assert!(verify(&root, &start, &end, &items, &nodes).is_err());
+	}
+
+	#[test]
+	fn altered_range_is_rejected() {
+		let (db, root, keys) = test_db(300);
+		let (start, end) = (keys[100].clone(), keys[150].clone());
+		let (items, nodes) = proof::generate_range_proof::<ExtensionLayout>(
+			&db, &root, &start, &end,
+		).unwrap();
+
+		let mut changed = items.clone();
+		changed[10].1 = DBValue::from_slice(b"changed");
+		assert!(verify(&root, &start, &end, &changed, &nodes).is_err());
+
+		let mut added = items.clone();
+		let mut key = items[10].0.clone();
+		key.push(0);
+		added.insert(11, (key, DBValue::from_slice(b"added")));
+		assert!(verify(&root, &start, &end, &added, &nodes).is_err());
+
+		let mut unordered = items.clone();
+		unordered.swap(3, 4);
+		assert_eq!(
+			verify(&root, &start, &end, &unordered, &nodes),
+			Err(VerifyError::ItemOutOfRange(items[3].0.clone()))
+		);
+
+		assert_eq!(
+			verify(&root, &start, &items[20].0, &items, &nodes),
+			Err(VerifyError::ItemOutOfRange(items[21].0.clone()))
+		);
+	}
+
+	#[test]
+	fn missing_boundary_node_is_rejected() {
+		let (db, root, keys) = test_db(300);
+		let (start, end) = (keys[100].clone(), keys[150].clone());
+		let (items, mut nodes) = proof::generate_range_proof::<ExtensionLayout>(
+			&db, &root, &start, &end,
+		).unwrap();
+		let removed = nodes.pop().unwrap();
+		assert_eq!(
+			verify(&root, &start, &end, &items, &nodes),
+			Err(VerifyError::MissingNode(KeccakHasher::hash(&removed)))
+		);
+	}
+
+	#[test]
+	fn range_proofs_without_extension_nodes() {
+		let (_, _, keys) = test_db(300);
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut db, &mut root);
+			for key in &keys {
+				t.insert(key, key).unwrap();
+			}
+		}
+
+		for (start, end) in vec![(vec![], vec![0xff; 8]), (keys[10].clone(), keys[20].clone()), (keys[10][..2].to_vec(), keys[200].clone())] {
+			let (items, nodes) = proof::generate_range_proof::<NoExtensionLayout>(
+				&db, &root, &start, &end,
+			).unwrap();
+			assert_eq!(items.len(), keys.iter().filter(|k| **k >= start && **k <= end).count());
+			assert_eq!(
+				proof::verify_range_proof::<NoExtensionLayout, _, _>(&root, &start, &end, &items, &nodes),
+				Ok(())
+			);
+
+			let mut missing = items.clone();
+			missing.remove(missing.len() / 2);
+			assert!(proof::verify_range_proof::<NoExtensionLayout, _, _>(&root, &start, &end, &missing, &nodes).is_err());
+		}
+	}
+}
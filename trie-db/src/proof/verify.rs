@@ -0,0 +1,319 @@
+// Copyright 2017, 2019 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proof verification.
+
+use hash_db::Hasher;
+use nibbleslice::NibbleSlice;
+use node::{Node, Value};
+use node_codec::NodeCodec;
+use {TrieLayout, TrieHash, CError, value_hash};
+
+#[cfg(feature = "std")]
+use ::std::collections::HashMap;
+#[cfg(feature = "std")]
+use ::std::{error::Error, fmt};
+
+#[cfg(not(feature = "std"))]
+use ::hashmap_core::HashMap;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// Errors that may occur while verifying a proof.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum VerifyError<HO, CE> {
+	/// The proof contains a node which is not needed to verify any of the items.
+	ExtraneousNode(HO),
+	/// A node needed to walk the path of one of the keys is missing from the proof.
+	MissingNode(HO),
+	/// The value in the trie differs from the expected value for the given key.
+	ValueMismatch(Vec<u8>),
+	/// The given key was claimed to be absent, but it has a value in the trie.
+	NonExistenceClaimViolated(Vec<u8>),
+	/// A node in the proof could not be decoded.
+	DecodeError(HO, CE),
+	/// An item of a range proof is outside of the range, or the items are not in
+	/// strictly ascending key order.
+	ItemOutOfRange(Vec<u8>),
+	/// The trie rebuilt from a range proof has a different root, given here.
+	RootMismatch(HO),
+}
+
+#[cfg(feature = "std")]
+impl<HO: fmt::Debug, CE: fmt::Debug> fmt::Display for VerifyError<HO, CE> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			VerifyError::ExtraneousNode(ref hash) => write!(f, "Extraneous node in proof: {:?}", hash),
+			VerifyError::MissingNode(ref hash) => write!(f, "Proof is missing expected node: {:?}", hash),
+			VerifyError::ValueMismatch(ref key) => write!(f, "Value mismatch for key: {:?}", key),
+			VerifyError::NonExistenceClaimViolated(ref key) => {
+				write!(f, "Key claimed to be absent has a value: {:?}", key)
+			}
+			VerifyError::DecodeError(ref hash, ref decoder_err) => {
+				write!(f, "Decoding failed for hash {:?}; err: {:?}", hash, decoder_err)
+			}
+			VerifyError::ItemOutOfRange(ref key) => write!(f, "Item out of range or order: {:?}", key),
+			VerifyError::RootMismatch(ref root) => write!(f, "Proof rebuilds a different root: {:?}", root),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl<HO: fmt::Debug, CE: Error> Error for VerifyError<HO, CE> {
+	fn description(&self) -> &str {
+		match *self {
+			VerifyError::ExtraneousNode(_) => "Extraneous node in proof",
+			VerifyError::MissingNode(_) => "Missing node in proof",
+			VerifyError::ValueMismatch(_) => "Value mismatch",
+			VerifyError::NonExistenceClaimViolated(_) => "Non-existence claim violated",
+			VerifyError::DecodeError(_, ref err) => err.description(),
+			VerifyError::ItemOutOfRange(_) => "Item out of range",
+			VerifyError::RootMismatch(_) => "Root mismatch",
+		}
+	}
+}
+
+/// Verify a proof of a set of items against the trie root `root`.
+///
+/// Each item is a key together with its expected value, `None` claiming that the
+/// key is absent from the trie. Every node of the proof must be used to walk the
+/// path of at least one of the keys. Values kept in value nodes are checked against
+/// their hash, so their value nodes may be left out of the proof.
+pub fn verify_proof<L, K, V>(
+	root: &TrieHash<L>,
+	proof: &[Vec<u8>],
+	items: &[(K, Option<V>)],
+) -> Result<(), VerifyError<TrieHash<L>, CError<L>>>
+where
+	L: TrieLayout,
+	K: AsRef<[u8]>,
+	V: AsRef<[u8]>,
+{
+	let mut nodes = HashMap::with_capacity(proof.len());
+	for (index, node) in proof.iter().enumerate() {
+		let hash = L::Hash::hash(node);
+		if nodes.insert(hash, index).is_some() {
+			return Err(VerifyError::ExtraneousNode(hash));
+		}
+	}
+
+	let mut used = Vec::new();
+	used.resize(proof.len(), false);
+	for (key, expected) in items {
+		let key = key.as_ref();
+		let value = lookup::<L>(root, proof, &nodes, &mut used, key)?;
+		match (expected, value) {
+			(Some(expected), Some(Value::Inline(value))) if expected.as_ref() == value => {}
+			(Some(expected), Some(Value::Node(hash))) => {
+				let expected_hash = L::Hash::hash(expected.as_ref());
+				if value_hash::<L>(hash) != Some(expected_hash) {
+					return Err(VerifyError::ValueMismatch(key.to_vec()));
+				}
+				if let Some(&index) = nodes.get(&expected_hash) {
+					used[index] = true;
+				}
+			}
+			(None, None) => {}
+			(None, Some(_)) => return Err(VerifyError::NonExistenceClaimViolated(key.to_vec())),
+			_ => return Err(VerifyError::ValueMismatch(key.to_vec())),
+		}
+	}
+
+	match used.iter().position(|used| !used) {
+		Some(index) => Err(VerifyError::ExtraneousNode(L::Hash::hash(&proof[index]))),
+		None => Ok(()),
+	}
+}
+
+/// Walk the path of `key` through the proof nodes, marking the nodes passed through
+/// as used, and return the value found.
+fn lookup<'a, L>(
+	root: &TrieHash<L>,
+	proof: &'a [Vec<u8>],
+	nodes: &HashMap<TrieHash<L>, usize>,
+	used: &mut [bool],
+	key: &[u8],
+) -> Result<Option<Value<'a>>, VerifyError<TrieHash<L>, CError<L>>>
+where
+	L: TrieLayout,
+{
+	let mut partial = NibbleSlice::new(key);
+	let mut hash = *root;
+
+	// this loop iterates through non-inline nodes.
+	loop {
+		let index = match nodes.get(&hash) {
+			Some(index) => *index,
+			None if hash == *root && hash == L::Codec::hashed_null_node() => return Ok(None),
+			None => return Err(VerifyError::MissingNode(hash)),
+		};
+		used[index] = true;
+
+		// this loop iterates through all inline children.
+		let mut node_data = &proof[index][..];
+		loop {
+			match L::Codec::decode(node_data).map_err(|e| VerifyError::DecodeError(hash, e))? {
+				Node::Leaf(slice, value) => {
+					return Ok(match slice == partial {
+						true => Some(value),
+						false => None,
+					})
+				}
+				Node::Extension(slice, item) => {
+					if partial.starts_with(&slice) {
+						node_data = item;
+						partial = partial.mid(slice.len());
+					} else {
+						return Ok(None)
+					}
+				}
+				Node::Branch(children, value) => match partial.is_empty() {
+					true => return Ok(value),
+					false => match children[partial.at(0) as usize] {
+						Some(x) => {
+							node_data = x;
+							partial = partial.mid(1);
+						}
+						None => return Ok(None),
+					}
+				},
+				Node::NibbledBranch(slice, children, value) => {
+					if !partial.starts_with(&slice) {
+						return Ok(None)
+					}
+					match partial.len() == slice.len() {
+						true => return Ok(value),
+						false => match children[partial.at(slice.len()) as usize] {
+							Some(x) => {
+								node_data = x;
+								partial = partial.mid(slice.len() + 1);
+							}
+							None => return Ok(None),
+						}
+					}
+				},
+				Node::Empty => return Ok(None),
+			}
+
+			// check if new node data is inline or hash.
+			if let Some(h) = L::Codec::try_decode_hash(node_data) {
+				hash = h;
+				break
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use hash_db::Hasher;
+	use keccak_hasher::KeccakHasher;
+	use DBValue;
+	use reference_trie::{RefTrieDBMut, TrieMut, ReferenceNodeCodec, ExtensionLayout, proof};
+	use reference_trie::proof::VerifyError;
+
+	type Out = <KeccakHasher as Hasher>::Out;
+
+	fn test_db() -> (MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, Out) {
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			t.insert(b"do", b"verb").unwrap();
+			t.insert(b"dog", b"puppy").unwrap();
+			t.insert(b"doge", b"coin").unwrap();
+			t.insert(b"horse", b"stallion").unwrap();
+			t.insert(b"house", b"building").unwrap();
+			t.insert(b"houseful", b"container").unwrap();
+		}
+		(db, root)
+	}
+
+	fn make_proof(keys: &[&[u8]]) -> (Out, Vec<Vec<u8>>) {
+		let (db, root) = test_db();
+		let proof = proof::generate_proof::<ExtensionLayout, _>(&db, &root, keys).unwrap();
+		(root, proof)
+	}
+
+	fn verify(
+		root: &Out,
+		proof: &[Vec<u8>],
+		items: &[(&[u8], Option<&[u8]>)],
+	) -> Result<(), VerifyError<Out, <ReferenceNodeCodec as ::reference_trie::NodeCodec<KeccakHasher>>::Error>> {
+		proof::verify_proof::<ExtensionLayout, _, _>(root, proof, items)
+	}
+
+	#[test]
+	fn valid_proof_verifies() {
+		let (root, proof) = make_proof(&[b"do", b"dog", b"doge", b"bravo", b"hors"]);
+		let items: [(&[u8], Option<&[u8]>); 5] = [
+			(b"do", Some(b"verb")),
+			(b"dog", Some(b"puppy")),
+			(b"doge", Some(b"coin")),
+			(b"bravo", None),
+			(b"hors", None),
+		];
+		assert_eq!(verify(&root, &proof, &items), Ok(()));
+	}
+
+	#[test]
+	fn wrong_value_is_rejected() {
+		let (root, proof) = make_proof(&[b"dog"]);
+		assert_eq!(
+			verify(&root, &proof, &[(b"dog", Some(b"kitten"))]),
+			Err(VerifyError::ValueMismatch(b"dog".to_vec()))
+		);
+	}
+
+	#[test]
+	fn absent_key_claimed_present_is_rejected() {
+		let (root, proof) = make_proof(&[b"dogs"]);
+		assert_eq!(
+			verify(&root, &proof, &[(b"dogs", Some(b"puppies"))]),
+			Err(VerifyError::ValueMismatch(b"dogs".to_vec()))
+		);
+	}
+
+	#[test]
+	fn present_key_claimed_absent_is_rejected() {
+		let (root, proof) = make_proof(&[b"horse"]);
+		assert_eq!(
+			verify(&root, &proof, &[(b"horse", None)]),
+			Err(VerifyError::NonExistenceClaimViolated(b"horse".to_vec()))
+		);
+	}
+
+	#[test]
+	fn missing_node_is_rejected() {
+		let (root, mut proof) = make_proof(&[b"houseful"]);
+		let last = proof.pop().unwrap();
+		assert_eq!(
+			verify(&root, &proof, &[(b"houseful", Some(b"container"))]),
+			Err(VerifyError::MissingNode(KeccakHasher::hash(&last)))
+		);
+	}
+
+	#[test]
+	fn extraneous_node_is_rejected() {
+		let (root, proof) = make_proof(&[b"do", b"horse"]);
+		let (_, horse_proof) = make_proof(&[b"horse"]);
+		let extra = proof.iter().find(|node| !horse_proof.contains(node)).unwrap().clone();
+		assert_eq!(
+			verify(&root, &proof, &[(b"horse", Some(b"stallion"))]),
+			Err(VerifyError::ExtraneousNode(KeccakHasher::hash(&extra)))
+		);
+	}
+}
@@ -35,6 +35,7 @@ pub struct Record<HO> {
 pub struct Recorder<HO> {
 	nodes: Vec<Record<HO>>,
 	min_depth: u32,
+	record_values: bool,
 }
 
 impl<HO: Copy> Default for Recorder<HO> {
@@ -55,9 +56,17 @@ impl<HO: Copy> Recorder<HO> {
 		Recorder {
 			nodes: Vec::new(),
 			min_depth: depth,
+			record_values: true,
 		}
 	}
 
+	/// Make the `Recorder` skip the value nodes of values too large to be kept in their
+	/// node, leaving only their hash in the records.
+	pub fn without_values(mut self) -> Self {
+		self.record_values = false;
+		self
+	}
+
 	/// Record a visited node, given its hash, data, and depth.
 	pub fn record(&mut self, hash: &HO, data: &[u8], depth: u32) {
 		if depth >= self.min_depth {
@@ -69,6 +78,13 @@ impl<HO: Copy> Recorder<HO> {
 		}
 	}
 
+	/// Record a fetched value node, given its hash, data, and depth.
+	pub fn record_value(&mut self, hash: &HO, data: &[u8], depth: u32) {
+		if self.record_values {
+			self.record(hash, data, depth);
+		}
+	}
+
 	/// Drain all visited records.
 	pub fn drain(&mut self) -> Vec<Record<HO>> {
 		::core_::mem::replace(&mut self.nodes, Vec::new())
@@ -80,7 +96,8 @@ mod tests {
 	use memory_db::{MemoryDB, HashKey};
 	use hash_db::Hasher;
 	use keccak_hasher::KeccakHasher;
-	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, Recorder, Record};
+	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, Recorder, Record,
+		TrieDB, TrieDBMut, HashedValueLayout};
 
 	#[test]
 	fn basic_recorder() {
@@ -188,4 +205,29 @@ mod tests {
 			]
 		]);
 	}
+
+	#[test]
+	fn trie_record_values() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		let mut root = Default::default();
+		let large = [7u8; 64];
+		{
+			let mut x = TrieDBMut::<HashedValueLayout>::new(&mut db, &mut root);
+			x.insert(b"dog", &large).unwrap();
+			x.insert(b"doge", b"coin").unwrap();
+		}
+
+		let trie = TrieDB::<HashedValueLayout>::new(&db, &root).unwrap();
+		let mut recorder = Recorder::new();
+		trie.get_with(b"dog", &mut recorder).unwrap().unwrap();
+		let records = recorder.drain();
+		assert_eq!(records.last().unwrap().data, large.to_vec());
+		assert_eq!(records.last().unwrap().hash, KeccakHasher::hash(&large));
+
+		let mut recorder = Recorder::new().without_values();
+		trie.get_with(b"dog", &mut recorder).unwrap().unwrap();
+		let nodes: Vec<_> = recorder.drain().into_iter().map(|r| r.data).collect();
+		assert_eq!(nodes.len(), records.len() - 1);
+		assert!(!nodes.contains(&large.to_vec()));
+	}
 }
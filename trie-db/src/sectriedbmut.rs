@@ -13,29 +13,26 @@
 // limitations under the License.
 
 use hash_db::{HashDB, Hasher};
-use super::{Result, DBValue, TrieMut, TrieDBMut};
-use node_codec::NodeCodec;
+use super::{Result, DBValue, TrieMut, TrieDBMut, TrieLayout, TrieHash, CError};
 
 /// A mutable `Trie` implementation which hashes keys and uses a generic `HashDB` backing database.
 ///
 /// Use it as a `Trie` or `TrieMut` trait object. You can use `raw()` to get the backing `TrieDBMut` object.
-pub struct SecTrieDBMut<'db, H, C>
+pub struct SecTrieDBMut<'db, L>
 where
-	H: Hasher + 'db,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
-	raw: TrieDBMut<'db, H, C>
+	raw: TrieDBMut<'db, L>
 }
 
-impl<'db, H, C> SecTrieDBMut<'db, H, C>
+impl<'db, L> SecTrieDBMut<'db, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
 	/// Create a new trie with the backing database `db` and empty `root`
 	/// Initialise to the state entailed by the genesis block.
 	/// This guarantees the trie is built correctly.
-	pub fn new(db: &'db mut dyn HashDB<H, DBValue>, root: &'db mut H::Out) -> Self {
+	pub fn new(db: &'db mut dyn HashDB<L::Hash, DBValue>, root: &'db mut TrieHash<L>) -> Self {
 		SecTrieDBMut { raw: TrieDBMut::new(db, root) }
 	}
 
@@ -43,25 +40,24 @@ where
 	///
 	/// Returns an error if root does not exist.
 	pub fn from_existing(
-		db: &'db mut dyn HashDB<H, DBValue>,
-		root: &'db mut H::Out,
-	) -> Result<Self, H::Out, C::Error> {
+		db: &'db mut dyn HashDB<L::Hash, DBValue>,
+		root: &'db mut TrieHash<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
 		Ok(SecTrieDBMut { raw: TrieDBMut::from_existing(db, root)? })
 	}
 
 	/// Get the backing database.
-	pub fn db(&self) -> &dyn HashDB<H, DBValue> { self.raw.db() }
+	pub fn db(&self) -> &dyn HashDB<L::Hash, DBValue> { self.raw.db() }
 
 	/// Get the backing database.
-	pub fn db_mut(&mut self) -> &mut dyn HashDB<H, DBValue> { self.raw.db_mut() }
+	pub fn db_mut(&mut self) -> &mut dyn HashDB<L::Hash, DBValue> { self.raw.db_mut() }
 }
 
-impl<'db, H, C> TrieMut<H, C> for SecTrieDBMut<'db, H, C>
+impl<'db, L> TrieMut<L> for SecTrieDBMut<'db, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
-	fn root(&mut self) -> &H::Out {
+	fn root(&mut self) -> &TrieHash<L> {
 		self.raw.root()
 	}
 
@@ -69,22 +65,26 @@ where
 		self.raw.is_empty()
 	}
 
-	fn contains(&self, key: &[u8]) -> Result<bool, H::Out, C::Error> {
-		self.raw.contains(&H::hash(key).as_ref())
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.raw.contains(&L::Hash::hash(key).as_ref())
 	}
 
-	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, H::Out, C::Error>
+	fn get<'a, 'key>(&'a self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
 		where 'a: 'key
 	{
-		self.raw.get(&H::hash(key).as_ref())
+		self.raw.get(&L::Hash::hash(key).as_ref())
 	}
 
-	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error> {
-		self.raw.insert(&H::hash(key).as_ref(), value)
+	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.raw.insert(&L::Hash::hash(key).as_ref(), value)
 	}
 
-	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error> {
-		self.raw.remove(&H::hash(key).as_ref())
+	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.raw.remove(&L::Hash::hash(key).as_ref())
+	}
+
+	fn remove_prefix(&mut self, prefix: &[u8], limit: Option<usize>) -> Result<usize, TrieHash<L>, CError<L>> {
+		self.raw.remove_prefix(prefix, limit)
 	}
 }
 
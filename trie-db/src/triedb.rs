@@ -12,15 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use hash_db::{Hasher, HashDBRef};
+use hash_db::HashDBRef;
 use nibbleslice::{self, NibbleSlice};
 #[cfg(feature = "std")]
 use nibbleslice::combine_encoded;
-use super::node::{Node, OwnedNode};
+use super::node::{Node, OwnedNode, Value};
+use nibblevec::NibbleVec;
 use node_codec::NodeCodec;
 use super::lookup::Lookup;
-use super::{Result, DBValue, Trie, TrieItem, TrieError, TrieIterator, Query};
+#[cfg(feature = "std")]
+use super::node_cache::NodeCache;
+use super::{Result, DBValue, Trie, TrieItem, TrieError, TrieIterator, Query, TrieLayout, TrieHash, CError, load_value, check_value_nodes};
 use ::core_::marker::PhantomData;
+use ::core_::ops::Deref;
 
 #[cfg(feature = "std")]
 use ::std::fmt;
@@ -28,6 +32,10 @@ use ::std::fmt;
 use ::std::borrow::Cow;
 #[cfg(not(feature = "std"))]
 use ::alloc::borrow::Cow;
+#[cfg(feature = "std")]
+use ::std::sync::Arc;
+#[cfg(not(feature = "std"))]
+use ::alloc::sync::Arc;
 
 #[cfg(not(feature = "std"))]
 use alloc::boxed::Box;
@@ -36,9 +44,9 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 use elastic_array::ElasticArray36;
 
-/// A `Trie` implementation using a generic `HashDB` backing database, a `Hasher`
-/// implementation to generate keys and a `NodeCodec` implementation to encode/decode
-/// the nodes.
+/// A `Trie` implementation using a generic `HashDB` backing database and a `TrieLayout`
+/// providing the `Hasher` implementation to generate keys and the `NodeCodec`
+/// implementation to encode/decode the nodes.
 ///
 /// Use it as a `Trie` trait object. You can use `db()` to get the backing database object.
 /// Use `get` and `contains` to query values associated with keys in the trie.
@@ -66,121 +74,318 @@ use elastic_array::ElasticArray36;
 ///   assert_eq!(t.get(b"foo").unwrap().unwrap(), DBValue::from_slice(b"bar"));
 /// }
 /// ```
-pub struct TrieDB<'db, H, C>
+pub struct TrieDB<'db, L>
 where
-	H: Hasher + 'db,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
-	db: &'db dyn HashDBRef<H, DBValue>,
-	root: &'db H::Out,
+	db: &'db dyn HashDBRef<L::Hash, DBValue>,
+	root: &'db TrieHash<L>,
+	/// Cache of decoded nodes used by lookups.
+	#[cfg(feature = "std")]
+	cache: Option<&'db NodeCache<TrieHash<L>>>,
 	/// The number of hashes performed so far in operations on this trie.
 	hash_count: usize,
-	codec_marker: PhantomData<C>,
+	layout_marker: PhantomData<L>,
 }
 
-impl<'db, H, C> TrieDB<'db, H, C>
+impl<'db, L> TrieDB<'db, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
 	/// Create a new trie with the backing database `db` and `root`
 	/// Returns an error if `root` does not exist
 	pub fn new(
-		db: &'db dyn HashDBRef<H, DBValue>,
-		root: &'db H::Out,
-	) -> Result<Self, H::Out, C::Error> {
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		check_value_nodes::<L>();
 		if !db.contains(root, nibbleslice::EMPTY_ENCODED) {
 			Err(Box::new(TrieError::InvalidStateRoot(*root)))
 		} else {
-			Ok(TrieDB {db, root, hash_count: 0, codec_marker: PhantomData})
+			Ok(TrieDB {
+				db,
+				root,
+				#[cfg(feature = "std")]
+				cache: None,
+				hash_count: 0,
+				layout_marker: PhantomData,
+			})
 		}
 	}
 
+	/// Create a new trie with the backing database `db` and `root`, whose lookups
+	/// look nodes up in `cache` before `db`, caching the nodes they fetch.
+	/// Returns an error if `root` does not exist
+	#[cfg(feature = "std")]
+	pub fn with_cache(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>,
+		cache: &'db NodeCache<TrieHash<L>>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		let mut trie = Self::new(db, root)?;
+		trie.cache = Some(cache);
+		Ok(trie)
+	}
+
 	/// Get the backing database.
-	pub fn db(&'db self) -> &'db dyn HashDBRef<H, DBValue> { self.db }
+	pub fn db(&'db self) -> &'db dyn HashDBRef<L::Hash, DBValue> { self.db }
 
 	/// Get the data of the root node.
-	pub fn root_data(&self) -> Result<DBValue, H::Out, C::Error> {
-		self.db
-			.get(self.root, nibbleslice::EMPTY_ENCODED)
-			.ok_or_else(|| Box::new(TrieError::InvalidStateRoot(*self.root)))
+	pub fn root_data(&self) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		root_data::<L, _>(self.db, self.root)
 	}
 
 	/// Given some node-describing data `node`, and node key return the actual node RLP.
-	/// This could be a simple identity operation in the case that the node is sufficiently small, but
-	/// may require a database lookup. If `is_root_data` then this is root-data and
-	/// is known to be literal.
-	/// `partial_key` is encoded nibble slice that addresses the node.
-	fn get_raw_or_lookup(&'db self, node: &[u8], partial_key: &[u8]) -> Result<Cow<'db, DBValue>, H::Out, C::Error> {
-		match (partial_key == nibbleslice::EMPTY_ENCODED, C::try_decode_hash(node)) {
-			(false, Some(key)) => {
-				self.db
-					.get(&key, partial_key)
-					.map(|v| Cow::Owned(v))
-					.ok_or_else(|| Box::new(TrieError::IncompleteDatabase(key)))
-			}
-			_ => Ok(Cow::Owned(DBValue::from_slice(node)))
+	/// See `get_raw_or_lookup`.
+	fn get_raw_or_lookup(&'db self, node: &[u8], partial_key: &[u8]) -> Result<Cow<'db, DBValue>, TrieHash<L>, CError<L>> {
+		get_raw_or_lookup::<L, _>(self.db, node, partial_key)
+	}
+}
+
+/// Get the data of the root node `root`.
+fn root_data<L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized>(
+	db: &D,
+	root: &TrieHash<L>,
+) -> Result<DBValue, TrieHash<L>, CError<L>> {
+	db.get(root, nibbleslice::EMPTY_ENCODED)
+		.ok_or_else(|| Box::new(TrieError::InvalidStateRoot(*root)))
+}
+
+/// Given some node-describing data `node`, and node key return the actual node RLP.
+/// This could be a simple identity operation in the case that the node is sufficiently small, but
+/// may require a database lookup. If `is_root_data` then this is root-data and
+/// is known to be literal.
+/// `partial_key` is encoded nibble slice that addresses the node.
+fn get_raw_or_lookup<'a, L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized>(
+	db: &D,
+	node: &[u8],
+	partial_key: &[u8],
+) -> Result<Cow<'a, DBValue>, TrieHash<L>, CError<L>> {
+	match (partial_key == nibbleslice::EMPTY_ENCODED, L::Codec::try_decode_hash(node)) {
+		(false, Some(key)) => {
+			db
+				.get(&key, partial_key)
+				.map(Cow::Owned)
+				.ok_or_else(|| Box::new(TrieError::IncompleteDatabase(key)))
 		}
+		_ => Ok(Cow::Owned(DBValue::from_slice(node)))
 	}
 }
 
-impl<'db, H, C> Trie<H, C> for TrieDB<'db, H, C>
+impl<'db, L> Trie<L> for TrieDB<'db, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
-	fn root(&self) -> &H::Out { self.root }
+	fn root(&self) -> &TrieHash<L> { self.root }
 
-	fn get_with<'a, 'key, Q: Query<H>>(&'a self, key: &'key [u8], query: Q) -> Result<Option<Q::Item>, H::Out, C::Error>
+	fn get_with<'a, 'key, Q: Query<L::Hash>>(&'a self, key: &'key [u8], query: Q) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
 		where 'a: 'key
 	{
 		Lookup {
 			db: self.db,
 			query: query,
 			hash: self.root.clone(),
-			marker: PhantomData::<C>,
+			#[cfg(feature = "std")]
+			cache: self.cache,
+			marker: PhantomData::<L>,
 		}.look_up(NibbleSlice::new(key))
 	}
 
 	fn iter<'a>(&'a self) -> Result<
-		Box<dyn TrieIterator<H, C, Item=TrieItem<H::Out, C::Error>> + 'a>,
-		H::Out,
-		C::Error,
+		Box<dyn TrieIterator<L, Item=TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
 	> {
 		TrieDBIterator::new(self).map(|iter| Box::new(iter) as Box<_>)
 	}
+
+	fn iter_prefix<'a>(&'a self, prefix: &[u8]) -> Result<
+		Box<dyn TrieIterator<L, Item=TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
+	> {
+		TrieDBIterator::new_prefixed(self, prefix).map(|iter| Box::new(iter) as Box<_>)
+	}
+}
+
+/// A `TrieDB` owning its root and sharing its backing database through an `Arc`,
+/// so that it can be kept in long-lived structures or sent to other threads.
+///
+/// Its iterators hold their own reference to the database and are `'static`, and
+/// can be sent to other threads if the database is `Send` and `Sync`.
+///
+/// # Example
+/// ```
+/// extern crate trie_db;
+/// extern crate reference_trie;
+/// extern crate keccak_hasher;
+/// extern crate memory_db;
+///
+/// use std::sync::Arc;
+/// use reference_trie::{RefTrieDBMut, RefTrieDBOwned, TrieMut};
+/// use trie_db::DBValue;
+/// use keccak_hasher::KeccakHasher;
+/// use memory_db::*;
+///
+/// fn main() {
+///   let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+///   let mut root = Default::default();
+///   RefTrieDBMut::new(&mut memdb, &mut root).insert(b"foo", b"bar").unwrap();
+///   let t = RefTrieDBOwned::new(Arc::new(memdb), root).unwrap();
+///   let items = t.iter().unwrap();
+///   drop(t);
+///   assert_eq!(items.map(|item| item.unwrap()).collect::<Vec<_>>(), vec![(b"foo".to_vec(), DBValue::from_slice(b"bar"))]);
+/// }
+/// ```
+pub struct TrieDBOwned<L, D>
+where
+	L: TrieLayout,
+	D: HashDBRef<L::Hash, DBValue>,
+{
+	db: Arc<D>,
+	root: TrieHash<L>,
+}
+
+impl<L, D> TrieDBOwned<L, D>
+where
+	L: TrieLayout,
+	D: HashDBRef<L::Hash, DBValue>,
+{
+	/// Create a new trie with the backing database `db` and `root`
+	/// Returns an error if `root` does not exist
+	pub fn new(db: Arc<D>, root: TrieHash<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		check_value_nodes::<L>();
+		if !db.contains(&root, nibbleslice::EMPTY_ENCODED) {
+			Err(Box::new(TrieError::InvalidStateRoot(root)))
+		} else {
+			Ok(TrieDBOwned { db, root })
+		}
+	}
+
+	/// Get the backing database.
+	pub fn db(&self) -> &Arc<D> { &self.db }
+
+	/// Get the data of the root node.
+	pub fn root_data(&self) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		root_data::<L, _>(&*self.db, &self.root)
+	}
+}
+
+impl<L, D> TrieDBOwned<L, D>
+where
+	L: TrieLayout + 'static,
+	D: HashDBRef<L::Hash, DBValue> + 'static,
+{
+	/// Returns a depth-first iterator over the elements of trie, which does not borrow
+	/// the trie.
+	pub fn iter(&self) -> Result<TrieDBIterator<'static, L, D>, TrieHash<L>, CError<L>> {
+		TrieDBIterator::with_db(IteratorDb::Shared(self.db.clone()), self.root, None)
+	}
+
+	/// Returns a depth-first iterator over the elements of trie whose keys start with
+	/// `prefix`, which does not borrow the trie.
+	pub fn iter_prefix(&self, prefix: &[u8]) -> Result<TrieDBIterator<'static, L, D>, TrieHash<L>, CError<L>> {
+		TrieDBIterator::with_db(IteratorDb::Shared(self.db.clone()), self.root, Some(prefix))
+	}
+
+	/// Returns an iterator over the elements of trie in descending key order, which
+	/// does not borrow the trie.
+	pub fn reverse_iter(&self) -> Result<TrieDBReverseIterator<'static, L, D>, TrieHash<L>, CError<L>> {
+		Ok(TrieDBReverseIterator { inner: self.iter()? })
+	}
+}
+
+impl<L, D> Clone for TrieDBOwned<L, D>
+where
+	L: TrieLayout,
+	D: HashDBRef<L::Hash, DBValue>,
+{
+	fn clone(&self) -> Self {
+		TrieDBOwned { db: self.db.clone(), root: self.root }
+	}
+}
+
+impl<L, D> Trie<L> for TrieDBOwned<L, D>
+where
+	L: TrieLayout,
+	D: HashDBRef<L::Hash, DBValue>,
+{
+	fn root(&self) -> &TrieHash<L> { &self.root }
+
+	fn get_with<'a, 'key, Q: Query<L::Hash>>(&'a self, key: &'key [u8], query: Q) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
+		where 'a: 'key
+	{
+		Lookup {
+			db: &*self.db,
+			query,
+			hash: self.root,
+			#[cfg(feature = "std")]
+			cache: None,
+			marker: PhantomData::<L>,
+		}.look_up(NibbleSlice::new(key))
+	}
+
+	fn iter<'a>(&'a self) -> Result<
+		Box<dyn TrieIterator<L, Item=TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
+	> {
+		TrieDBIterator::with_db(IteratorDb::Shared(self.db.clone()), self.root, None)
+			.map(|iter| Box::new(iter) as Box<_>)
+	}
+
+	fn iter_prefix<'a>(&'a self, prefix: &[u8]) -> Result<
+		Box<dyn TrieIterator<L, Item=TrieItem<TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
+	> {
+		TrieDBIterator::with_db(IteratorDb::Shared(self.db.clone()), self.root, Some(prefix))
+			.map(|iter| Box::new(iter) as Box<_>)
+	}
 }
 
 
 #[cfg(feature="std")]
 // This is for pretty debug output only
-struct TrieAwareDebugNode<'db, 'a, H, C>
+struct TrieAwareDebugNode<'db, 'a, L>
 where
-	H: Hasher + 'db,
-	C: NodeCodec<H> + 'db
+	L: TrieLayout
 {
-	trie: &'db TrieDB<'db, H, C>,
+	trie: &'db TrieDB<'db, L>,
 	node_key: &'a[u8],
 	partial_key: ElasticArray36<u8>,
 	index: Option<u8>,
 }
 
 #[cfg(feature="std")]
-impl<'db, 'a, H, C> fmt::Debug for TrieAwareDebugNode<'db, 'a, H, C>
+// Shows an inline value as its bytes and a value node by its hash.
+struct DebugValue<'a>(Value<'a>);
+
+#[cfg(feature="std")]
+impl<'a> fmt::Debug for DebugValue<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.0 {
+			Value::Inline(value) => value.fmt(f),
+			Value::Node(hash) => f.debug_tuple("ValueNode").field(&hash).finish(),
+		}
+	}
+}
+
+#[cfg(feature="std")]
+impl<'db, 'a, L> fmt::Debug for TrieAwareDebugNode<'db, 'a, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		if let Ok(node) = self.trie.get_raw_or_lookup(self.node_key, &self.partial_key) {
-			match C::decode(&node) {
+			match L::Codec::decode(&node) {
 				Ok(Node::Leaf(slice, value)) =>
 					match (f.debug_struct("Node::Leaf"), self.index) {
 						(ref mut d, Some(ref i)) => d.field("index", i),
 						(ref mut d, _) => d,
 					}
 						.field("slice", &slice)
-						.field("value", &value)
+						.field("value", &DebugValue(value))
 						.finish(),
 				Ok(Node::Extension(ref slice, ref item)) =>
 					match (f.debug_struct("Node::Extension"), self.index) {
@@ -195,8 +400,8 @@ where
 							index: None,
 						})
 						.finish(),
-				Ok(Node::Branch(ref nodes, ref value)) => {
-					let nodes: Vec<TrieAwareDebugNode<H, C>> = nodes.into_iter()
+				Ok(Node::Branch(ref nodes, value)) => {
+					let nodes: Vec<TrieAwareDebugNode<L>> = nodes.into_iter()
 						.enumerate()
 						.filter_map(|(i, n)| n.map(|n| (i, n)))
 						.map(|(i, n)| TrieAwareDebugNode {
@@ -211,7 +416,27 @@ where
 						(ref mut d, _) => d,
 					}
 						.field("nodes", &nodes)
-						.field("value", &value)
+						.field("value", &value.map(DebugValue))
+						.finish()
+				},
+				Ok(Node::NibbledBranch(slice, ref nodes, value)) => {
+					let nodes: Vec<TrieAwareDebugNode<L>> = nodes.iter()
+						.enumerate()
+						.filter_map(|(i, n)| n.map(|n| (i, n)))
+						.map(|(i, n)| TrieAwareDebugNode {
+							trie: self.trie,
+							index: Some(i as u8),
+							node_key: n,
+							partial_key: combine_encoded(&self.partial_key, n),
+						})
+						.collect();
+					match (f.debug_struct("Node::NibbledBranch"), self.index) {
+						(ref mut d, Some(ref i)) => d.field("index", i),
+						(ref mut d, _) => d,
+					}
+						.field("slice", &slice)
+						.field("nodes", &nodes)
+						.field("value", &value.map(DebugValue))
 						.finish()
 				},
 				Ok(Node::Empty) => f.debug_struct("Node::Empty").finish(),
@@ -233,10 +458,9 @@ where
 }
 
 #[cfg(feature="std")]
-impl<'db, H, C> fmt::Debug for TrieDB<'db, H, C>
+impl<'db, L> fmt::Debug for TrieDB<'db, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		let root_rlp = self.root_data().unwrap();
@@ -272,36 +496,188 @@ impl Crumb {
 		self.status = match (&self.status, &self.node) {
 			(_, &OwnedNode::Empty) => Status::Exiting,
 			(&Status::Entering, _) => Status::At,
-			(&Status::At, &OwnedNode::Branch(_)) => Status::AtChild(0),
-			(&Status::AtChild(x), &OwnedNode::Branch(_)) if x < 15 => Status::AtChild(x + 1),
+			(&Status::At, &OwnedNode::Branch(_))
+				| (&Status::At, &OwnedNode::NibbledBranch(..)) => Status::AtChild(0),
+			(&Status::AtChild(x), &OwnedNode::Branch(_))
+				| (&Status::AtChild(x), &OwnedNode::NibbledBranch(..)) if x < 15 => Status::AtChild(x + 1),
+			_ => Status::Exiting,
+		}
+	}
+
+	/// Move on to next status in the node's reversed sequence, where the children
+	/// of a branch come last to first and before its value.
+	fn decrement(&mut self) {
+		self.status = match (&self.status, &self.node) {
+			(_, &OwnedNode::Empty) => Status::Exiting,
+			(&Status::Entering, &OwnedNode::Branch(_))
+				| (&Status::Entering, &OwnedNode::NibbledBranch(..)) => Status::AtChild(15),
+			(&Status::Entering, _) => Status::At,
+			(&Status::AtChild(x), &OwnedNode::Branch(_))
+				| (&Status::AtChild(x), &OwnedNode::NibbledBranch(..)) if x > 0 => Status::AtChild(x - 1),
+			(&Status::AtChild(_), &OwnedNode::Branch(_))
+				| (&Status::AtChild(_), &OwnedNode::NibbledBranch(..)) => Status::At,
 			_ => Status::Exiting,
 		}
 	}
 }
 
-/// Iterator for going through all values in the trie.
-pub struct TrieDBIterator<'a, H: Hasher + 'a, C: NodeCodec<H> + 'a> {
-	db: &'a TrieDB<'a, H, C>,
+// Database read by an iterator, borrowed from a `TrieDB` or shared with a `TrieDBOwned`.
+enum IteratorDb<'a, D: ?Sized + 'a> {
+	Borrowed(&'a D),
+	Shared(Arc<D>),
+}
+
+impl<'a, D: ?Sized + 'a> Deref for IteratorDb<'a, D> {
+	type Target = D;
+
+	fn deref(&self) -> &D {
+		match *self {
+			IteratorDb::Borrowed(db) => db,
+			IteratorDb::Shared(ref db) => &**db,
+		}
+	}
+}
+
+/// Iterator for going through all values in the trie, reading the database `D`.
+pub struct TrieDBIterator<'a, L: TrieLayout, D: ?Sized + 'a = dyn HashDBRef<<L as TrieLayout>::Hash, DBValue> + 'a> {
+	db: IteratorDb<'a, D>,
+	root: TrieHash<L>,
 	trail: Vec<Crumb>,
 	key_nibbles: Vec<u8>,
+	prefix: Option<Vec<u8>>,
 }
 
-impl<'a, H: Hasher, C: NodeCodec<H>> TrieDBIterator<'a, H, C> {
+impl<'a, L: TrieLayout> TrieDBIterator<'a, L> {
 	/// Create a new iterator.
-	pub fn new(db: &'a TrieDB<H, C>) -> Result<TrieDBIterator<'a, H, C>, H::Out, C::Error> {
-		let mut r = TrieDBIterator { db, trail: Vec::with_capacity(8), key_nibbles: Vec::with_capacity(64) };
-		db.root_data().and_then(|root_data| r.descend(&root_data))?;
+	pub fn new(db: &'a TrieDB<L>) -> Result<TrieDBIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Self::with_db(IteratorDb::Borrowed(db.db), *db.root, None)
+	}
+
+	/// Create a new iterator over the keys starting with `prefix`.
+	pub fn new_prefixed(db: &'a TrieDB<L>, prefix: &[u8]) -> Result<TrieDBIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Self::with_db(IteratorDb::Borrowed(db.db), *db.root, Some(prefix))
+	}
+}
+
+impl<'a, L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized + 'a> TrieDBIterator<'a, L, D> {
+	fn with_db(db: IteratorDb<'a, D>, root: TrieHash<L>, prefix: Option<&[u8]>) -> Result<Self, TrieHash<L>, CError<L>> {
+		let mut r = TrieDBIterator {
+			db,
+			root,
+			trail: Vec::with_capacity(8),
+			key_nibbles: Vec::with_capacity(64),
+			prefix: prefix.map(|prefix| prefix.to_vec()),
+		};
+		match prefix {
+			Some(prefix) => { r.descend_to_prefix(prefix)?; },
+			None => r.root_data().and_then(|root_data| r.descend(&root_data))?,
+		}
 		Ok(r)
 	}
 
-	fn seek<'key>(&mut self, node_data: &DBValue, key: NibbleSlice<'key>) -> Result<(), H::Out, C::Error> {
+	/// Get the data of the root node.
+	fn root_data(&self) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		root_data::<L, _>(&*self.db, &self.root)
+	}
+
+	/// Get the data of a node referenced from the node at `partial_key`.
+	fn get_raw_or_lookup(&self, node: &[u8], partial_key: &[u8]) -> Result<Cow<'static, DBValue>, TrieHash<L>, CError<L>> {
+		get_raw_or_lookup::<L, _>(&*self.db, node, partial_key)
+	}
+
+	/// Reset the trail to the single node holding every key starting with `prefix`.
+	/// Returns the depth of that node, or `None` if no key starts with `prefix`.
+	fn descend_to_prefix(&mut self, prefix: &[u8]) -> Result<Option<usize>, TrieHash<L>, CError<L>> {
+		self.trail.clear();
+		self.key_nibbles.clear();
+		let key = NibbleSlice::new(prefix);
+		let mut node_data: Cow<DBValue> = Cow::Owned(self.root_data()?);
+		let mut partial = key;
+		let mut depth = 0;
+		loop {
+			let data = {
+				let node = L::Codec::decode(&node_data)
+					.map_err(|e|Box::new(TrieError::DecoderError(TrieHash::<L>::default(), e)))?;
+				match node {
+					Node::Leaf(slice, _) => {
+						if slice.starts_with(&partial) {
+							self.descend_into_node(node.into());
+							return Ok(Some(depth))
+						}
+						return Ok(None)
+					},
+					Node::Extension(slice, item) => {
+						if partial.starts_with(&slice) {
+							self.key_nibbles.extend(slice.iter());
+							partial = partial.mid(slice.len());
+							self.get_raw_or_lookup(item, &key.encoded_leftmost(self.key_nibbles.len(), false))?
+						} else if slice.starts_with(&partial) {
+							self.descend_into_node(node.into());
+							return Ok(Some(depth))
+						} else {
+							return Ok(None)
+						}
+					},
+					Node::NibbledBranch(slice, _, _) if slice.starts_with(&partial) => {
+						self.descend_into_node(node.into());
+						return Ok(Some(depth))
+					},
+					Node::NibbledBranch(slice, _, _) if !partial.starts_with(&slice) => return Ok(None),
+					Node::Branch(ref nodes, _) | Node::NibbledBranch(_, ref nodes, _) => {
+						if let Node::NibbledBranch(slice, _, _) = node {
+							// the key goes on past the partial key of the branch.
+							self.key_nibbles.extend(slice.iter());
+							partial = partial.mid(slice.len());
+						}
+						if partial.is_empty() {
+							self.descend_into_node(node.clone().into());
+							return Ok(Some(depth))
+						}
+						let i = partial.at(0);
+						match nodes[i as usize] {
+							Some(child) => {
+								self.key_nibbles.push(i);
+								partial = partial.mid(1);
+								self.get_raw_or_lookup(child, &key.encoded_leftmost(self.key_nibbles.len(), false))?
+							},
+							None => return Ok(None),
+						}
+					},
+					Node::Empty => return Ok(None),
+				}
+			};
+			depth += 1;
+			node_data = data;
+		}
+	}
+
+	fn seek<'key>(&mut self, node_data: &DBValue, key: NibbleSlice<'key>) -> Result<(), TrieHash<L>, CError<L>> {
 		let mut node_data = Cow::Borrowed(node_data);
 		let mut partial = key;
 		let mut full_key_nibbles = 0;
 		loop {
 			let data = {
-				let node = C::decode(&node_data)
-					.map_err(|e|Box::new(TrieError::DecoderError(H::Out::default(), e)))?;
+				let node = L::Codec::decode(&node_data)
+					.map_err(|e|Box::new(TrieError::DecoderError(TrieHash::<L>::default(), e)))?;
+				if let Node::NibbledBranch(ref slice, _, _) = node {
+					if partial.starts_with(slice) {
+						self.key_nibbles.extend(slice.iter());
+						full_key_nibbles += slice.len();
+						partial = partial.mid(slice.len());
+					} else if *slice < partial {
+						// every key below this branch comes before `key`.
+						self.trail.push(Crumb {
+							status: Status::AtChild(15),
+							node: node.clone().into(),
+						});
+						self.key_nibbles.extend(slice.iter());
+						self.key_nibbles.push(15);
+						return Ok(())
+					} else {
+						self.descend_into_node(node.clone().into());
+						return Ok(())
+					}
+				}
 				match node {
 					Node::Leaf(slice, _) => {
 						if slice >= partial {
@@ -328,14 +704,22 @@ impl<'a, H: Hasher, C: NodeCodec<H>> TrieDBIterator<'a, H, C> {
 							self.key_nibbles.extend(slice.iter());
 							full_key_nibbles += slice.len();
 							partial = partial.mid(slice.len());
-							let data = self.db.get_raw_or_lookup(&*item, &key.encoded_leftmost(full_key_nibbles, false))?;
+							let data = self.get_raw_or_lookup(&*item, &key.encoded_leftmost(full_key_nibbles, false))?;
 							data
+						} else if *slice < partial {
+							// every key below this extension comes before `key`.
+							self.trail.push(Crumb {
+								status: Status::Exiting,
+								node: node.clone().into(),
+							});
+							self.key_nibbles.extend(slice.iter());
+							return Ok(())
 						} else {
-							self.descend(&node_data)?;
+							self.descend_into_node(node.clone().into());
 							return Ok(())
 						}
 					},
-					Node::Branch(ref nodes, _) => match partial.is_empty() {
+					Node::Branch(ref nodes, _) | Node::NibbledBranch(_, ref nodes, _) => match partial.is_empty() {
 						true => {
 							self.trail.push(Crumb {
 								status: Status::Entering,
@@ -353,7 +737,7 @@ impl<'a, H: Hasher, C: NodeCodec<H>> TrieDBIterator<'a, H, C> {
 							full_key_nibbles += 1;
 							partial = partial.mid(1);
 							if let Some(ref child) = nodes[i as usize] {
-								let child = self.db.get_raw_or_lookup(&*child, &key.encoded_leftmost(full_key_nibbles, false))?;
+								let child = self.get_raw_or_lookup(&*child, &key.encoded_leftmost(full_key_nibbles, false))?;
 								child
 							} else {
 								return Ok(())
@@ -369,10 +753,10 @@ impl<'a, H: Hasher, C: NodeCodec<H>> TrieDBIterator<'a, H, C> {
 	}
 
 	/// Descend into a payload.
-	fn descend(&mut self, d: &[u8]) -> Result<(), H::Out, C::Error> {
-		let node_data = &self.db.get_raw_or_lookup(d, &self.encoded_key())?;
-		let node = C::decode(&node_data)
-			.map_err(|e|Box::new(TrieError::DecoderError(H::Out::default(), e)))?;
+	fn descend(&mut self, d: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let node_data = &self.get_raw_or_lookup(d, &self.encoded_key())?;
+		let node = L::Codec::decode(&node_data)
+			.map_err(|e|Box::new(TrieError::DecoderError(TrieHash::<L>::default(), e)))?;
 		Ok(self.descend_into_node(node.into()))
 	}
 
@@ -380,7 +764,9 @@ impl<'a, H: Hasher, C: NodeCodec<H>> TrieDBIterator<'a, H, C> {
 	fn descend_into_node(&mut self, node: OwnedNode) {
 		self.trail.push(Crumb { status: Status::Entering, node });
 		match &self.trail.last().expect("just pushed item; qed").node {
-			&OwnedNode::Leaf(ref n, _) | &OwnedNode::Extension(ref n, _) => {
+			&OwnedNode::Leaf(ref n, _)
+				| &OwnedNode::Extension(ref n, _)
+				| &OwnedNode::NibbledBranch(ref n, _) => {
 				self.key_nibbles.extend((0..n.len()).map(|i| n.at(i)));
 			},
 			_ => {}
@@ -389,42 +775,69 @@ impl<'a, H: Hasher, C: NodeCodec<H>> TrieDBIterator<'a, H, C> {
 
 	/// The present key.
 	fn key(&self) -> Vec<u8> {
-		// collapse the key_nibbles down to bytes.
-		let nibbles = &self.key_nibbles;
-		let mut i = 1;
-		let mut result = <Vec<u8>>::with_capacity(nibbles.len() / 2);
-		let len = nibbles.len();
-		while i < len {
-			result.push(nibbles[i - 1] * 16 + nibbles[i]);
-			i += 2;
-		}
-		result
+		nibbles_to_key(&self.key_nibbles)
 	}
 
 	/// Encoded key for storage lookup
 	fn encoded_key(&self) -> ElasticArray36<u8> {
-		let key = self.key();
-		let slice = NibbleSlice::new(&key);
-		if self.key_nibbles.len() % 2 == 1 {
-			NibbleSlice::new_composed(&slice, &NibbleSlice::new_offset(&self.key_nibbles[(self.key_nibbles.len() - 1)..], 1)).encoded(false)
-		} else {
-			slice.encoded(false)
-		}
+		encoded_nibbles(&self.key_nibbles)
+	}
+}
+
+/// Collapse a sequence of nibbles down to bytes, dropping a trailing odd nibble.
+fn nibbles_to_key(nibbles: &[u8]) -> Vec<u8> {
+	let mut i = 1;
+	let mut result = <Vec<u8>>::with_capacity(nibbles.len() / 2);
+	let len = nibbles.len();
+	while i < len {
+		result.push(nibbles[i - 1] * 16 + nibbles[i]);
+		i += 2;
 	}
+	result
 }
 
-impl<'a, H: Hasher, C: NodeCodec<H>> TrieIterator<H, C> for TrieDBIterator<'a, H, C> {
+/// Encoded key of a sequence of nibbles, for storage lookup.
+fn encoded_nibbles(nibbles: &[u8]) -> ElasticArray36<u8> {
+	let key = nibbles_to_key(nibbles);
+	let slice = NibbleSlice::new(&key);
+	if nibbles.len() % 2 == 1 {
+		NibbleSlice::new_composed(&slice, &NibbleSlice::new_offset(&nibbles[(nibbles.len() - 1)..], 1)).encoded(false)
+	} else {
+		slice.encoded(false)
+	}
+}
+
+impl<'a, L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized + 'a> TrieIterator<L> for TrieDBIterator<'a, L, D> {
 	/// Position the iterator on the first element with key >= `key`
-	fn seek(&mut self, key: &[u8]) -> Result<(), H::Out, C::Error> {
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		if let Some(prefix) = self.prefix.clone() {
+			if !key.starts_with(&prefix) {
+				// keys before the prefix start the whole range, keys after it leave nothing.
+				self.descend_to_prefix(&prefix)?;
+				if key > &prefix[..] {
+					self.trail.clear();
+				}
+				return Ok(())
+			}
+			if let Some(depth) = self.descend_to_prefix(&prefix)? {
+				self.trail.clear();
+				self.key_nibbles.clear();
+				let root_node = self.root_data()?;
+				self.seek(&root_node, NibbleSlice::new(key))?;
+				// forget the nodes above the prefix so iteration stops at the end of its subtree.
+				self.trail.drain(..depth);
+			}
+			return Ok(())
+		}
 		self.trail.clear();
 		self.key_nibbles.clear();
-		let root_node = self.db.root_data()?;
+		let root_node = self.root_data()?;
 		self.seek(&root_node, NibbleSlice::new(key.as_ref()))
 	}
 }
 
-impl<'a, H: Hasher, C: NodeCodec<H>> Iterator for TrieDBIterator<'a, H, C> {
-	type Item = TrieItem<'a, H::Out, C::Error>;
+impl<'a, L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized + 'a> Iterator for TrieDBIterator<'a, L, D> {
+	type Item = TrieItem<'a, TrieHash<L>, CError<L>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		enum IterStep<'b, O, E> {
@@ -445,32 +858,42 @@ impl<'a, H: Hasher, C: NodeCodec<H>> Iterator for TrieDBIterator<'a, H, C> {
 								self.key_nibbles.truncate(l - n.len());
 							},
 							OwnedNode::Branch(_) => { self.key_nibbles.pop(); },
+							OwnedNode::NibbledBranch(ref n, _) => {
+								let l = self.key_nibbles.len();
+								self.key_nibbles.truncate(l - n.len() - 1);
+							},
 							_ => {}
 						}
 						IterStep::PopTrail
 					},
-					(Status::At, &OwnedNode::Branch(ref branch)) if branch.has_value() => {
+					(Status::At, &OwnedNode::Branch(ref branch))
+						| (Status::At, &OwnedNode::NibbledBranch(_, ref branch)) if branch.has_value() => {
 						let value = branch.get_value().expect("already checked `has_value`");
-						return Some(Ok((self.key(), DBValue::from_slice(value))));
+						return Some(load_value::<L, _>(&*self.db, value, &self.encoded_key())
+							.map(|value| (self.key(), value)));
 					},
 					(Status::At, &OwnedNode::Leaf(_, ref v)) => {
-						return Some(Ok((self.key(), v.clone())));
+						return Some(load_value::<L, _>(&*self.db, v.as_value(), &self.encoded_key())
+							.map(|value| (self.key(), value)));
 					},
 					(Status::At, &OwnedNode::Extension(_, ref d)) => {
-						IterStep::Descend::<H::Out, C::Error>(self.db.get_raw_or_lookup(&*d, &self.encoded_key()))
+						IterStep::Descend::<TrieHash<L>, CError<L>>(self.get_raw_or_lookup(&*d, &self.encoded_key()))
 					},
-					(Status::At, &OwnedNode::Branch(_)) => IterStep::Continue,
-					(Status::AtChild(i), &OwnedNode::Branch(ref branch)) if branch.index(i).is_some() => {
+					(Status::At, &OwnedNode::Branch(_))
+						| (Status::At, &OwnedNode::NibbledBranch(..)) => IterStep::Continue,
+					(Status::AtChild(i), &OwnedNode::Branch(ref branch))
+						| (Status::AtChild(i), &OwnedNode::NibbledBranch(_, ref branch)) if branch.index(i).is_some() => {
 						match i {
 							0 => self.key_nibbles.push(0),
 							i => *self.key_nibbles.last_mut()
 								.expect("pushed as 0; moves sequentially; removed afterwards; qed") = i as u8,
 						}
-						IterStep::Descend::<H::Out, C::Error>(self.db.get_raw_or_lookup(
+						IterStep::Descend::<TrieHash<L>, CError<L>>(self.get_raw_or_lookup(
 							&branch.index(i).expect("this arm guarded by branch[i].is_some(); qed"),
 							&self.encoded_key()))
 					},
-					(Status::AtChild(i), &OwnedNode::Branch(_)) => {
+					(Status::AtChild(i), &OwnedNode::Branch(_))
+						| (Status::AtChild(i), &OwnedNode::NibbledBranch(..)) => {
 						if i == 0 {
 							self.key_nibbles.push(0);
 						}
@@ -484,11 +907,11 @@ impl<'a, H: Hasher, C: NodeCodec<H>> Iterator for TrieDBIterator<'a, H, C> {
 				IterStep::PopTrail => {
 					self.trail.pop();
 				},
-				IterStep::Descend::<H::Out, C::Error>(Ok(d)) => {
-					let node = C::decode(&d).ok()?;
+				IterStep::Descend::<TrieHash<L>, CError<L>>(Ok(d)) => {
+					let node = L::Codec::decode(&d).ok()?;
 					self.descend_into_node(node.into())
 				},
-				IterStep::Descend::<H::Out, C::Error>(Err(e)) => {
+				IterStep::Descend::<TrieHash<L>, CError<L>>(Err(e)) => {
 					return Some(Err(e))
 				}
 				IterStep::Continue => {},
@@ -497,53 +920,446 @@ impl<'a, H: Hasher, C: NodeCodec<H>> Iterator for TrieDBIterator<'a, H, C> {
 	}
 }
 
-#[cfg(test)]
-mod tests {
-	use memory_db::{MemoryDB, PrefixedKey};
-	use keccak_hasher::KeccakHasher;
-	use DBValue;
-	use reference_trie::{RefTrieDB, RefTrieDBMut, RefLookup, Trie, TrieMut, NibbleSlice};
+/// Iterator for going through all values in the trie in descending key order, reading
+/// the database `D`.
+pub struct TrieDBReverseIterator<'a, L: TrieLayout, D: ?Sized + 'a = dyn HashDBRef<<L as TrieLayout>::Hash, DBValue> + 'a> {
+	inner: TrieDBIterator<'a, L, D>,
+}
 
-	#[test]
-	fn iterator_works() {
-		let pairs = vec![
-			(hex!("0103000000000000000464").to_vec(), hex!("fffffffffe").to_vec()),
-			(hex!("0103000000000000000469").to_vec(), hex!("ffffffffff").to_vec()),
-		];
+impl<'a, L: TrieLayout> TrieDBReverseIterator<'a, L> {
+	/// Create a new iterator, starting from the last element.
+	pub fn new(db: &'a TrieDB<L>) -> Result<TrieDBReverseIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Ok(TrieDBReverseIterator { inner: TrieDBIterator::new(db)? })
+	}
+}
 
-		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
-		let mut root = Default::default();
-		{
-			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
-			for (x, y) in &pairs {
-				t.insert(x, y).unwrap();
-			}
-		}
+impl<'a, L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized + 'a> TrieDBReverseIterator<'a, L, D> {
 
-		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+	/// Position the iterator on the last element with key <= `key`.
+	pub fn seek_back(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let it = &mut self.inner;
+		it.trail.clear();
+		it.key_nibbles.clear();
+		let key = NibbleSlice::new(key);
+		let mut node_data: Cow<DBValue> = Cow::Owned(it.root_data()?);
+		let mut partial = key;
+		loop {
+			let data = {
+				let node = L::Codec::decode(&node_data)
+					.map_err(|e|Box::new(TrieError::DecoderError(TrieHash::<L>::default(), e)))?;
+				if let Node::NibbledBranch(slice, _, _) = node {
+					it.key_nibbles.extend(slice.iter());
+					if partial.starts_with(&slice) {
+						partial = partial.mid(slice.len());
+					} else {
+						// the whole subtree is either before or after `key`.
+						let status = if slice < partial { Status::Entering } else { Status::Exiting };
+						it.trail.push(Crumb { status, node: node.clone().into() });
+						return Ok(())
+					}
+				}
+				match node {
+					Node::Leaf(slice, _) => {
+						let status = if slice <= partial { Status::Entering } else { Status::Exiting };
+						it.trail.push(Crumb { status, node: node.clone().into() });
+						it.key_nibbles.extend(slice.iter());
+						return Ok(())
+					},
+					Node::Extension(slice, item) => {
+						if partial.starts_with(&slice) {
+							it.trail.push(Crumb {
+								status: Status::At,
+								node: node.clone().into(),
+							});
+							it.key_nibbles.extend(slice.iter());
+							partial = partial.mid(slice.len());
+							it.get_raw_or_lookup(item, &key.encoded_leftmost(it.key_nibbles.len(), false))?
+						} else {
+							// the whole subtree is either before or after `key`.
+							let status = if slice < partial { Status::Entering } else { Status::Exiting };
+							it.trail.push(Crumb { status, node: node.clone().into() });
+							it.key_nibbles.extend(slice.iter());
+							return Ok(())
+						}
+					},
+					Node::Branch(nodes, _) | Node::NibbledBranch(_, nodes, _) => {
+						// only the value of the branch itself can come before an exhausted key.
+						let i = if partial.is_empty() { 0 } else { partial.at(0) };
+						it.trail.push(Crumb {
+							status: Status::AtChild(i as usize),
+							node: node.clone().into(),
+						});
+						it.key_nibbles.push(i);
+						match nodes[i as usize] {
+							Some(child) if !partial.is_empty() => {
+								partial = partial.mid(1);
+								it.get_raw_or_lookup(child, &key.encoded_leftmost(it.key_nibbles.len(), false))?
+							},
+							_ => return Ok(()),
+						}
+					},
+					_ => return Ok(()),
+				}
+			};
 
-		let iter = trie.iter().unwrap();
-		let mut iter_pairs = Vec::new();
-		for pair in iter {
-			let (key, value) = pair.unwrap();
-			iter_pairs.push((key, value.to_vec()));
+			node_data = data;
 		}
-
-		assert_eq!(pairs, iter_pairs);
 	}
+}
 
-	#[test]
-	fn iterator_seek_works() {
-		let pairs = vec![
-			(hex!("0103000000000000000464").to_vec(), hex!("fffffffffe").to_vec()),
-			(hex!("0103000000000000000469").to_vec(), hex!("ffffffffff").to_vec()),
-		];
+impl<'a, L: TrieLayout, D: HashDBRef<L::Hash, DBValue> + ?Sized + 'a> Iterator for TrieDBReverseIterator<'a, L, D> {
+	type Item = TrieItem<'a, TrieHash<L>, CError<L>>;
 
-		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
-		let mut root = Default::default();
-		{
-			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
-			for (x, y) in &pairs {
+	fn next(&mut self) -> Option<Self::Item> {
+		enum IterStep<'b, O, E> {
+			Continue,
+			PopTrail,
+			Descend(Result<Cow<'b, DBValue>, O, E>),
+		}
+		let it = &mut self.inner;
+		loop {
+			let iter_step = {
+				it.trail.last_mut()?.decrement();
+				let b = it.trail.last().expect("trail.last_mut().is_some(); qed");
+
+				match (b.status.clone(), &b.node) {
+					(Status::Exiting, n) => {
+						match *n {
+							OwnedNode::Leaf(ref n, _)
+								| OwnedNode::Extension(ref n, _)
+								| OwnedNode::NibbledBranch(ref n, _) => {
+								let l = it.key_nibbles.len();
+								it.key_nibbles.truncate(l - n.len());
+							},
+							// the branch nibble is already gone once its value is reached.
+							_ => {}
+						}
+						IterStep::PopTrail
+					},
+					(Status::At, OwnedNode::Branch(branch))
+						| (Status::At, OwnedNode::NibbledBranch(_, branch)) => {
+						it.key_nibbles.pop();
+						match branch.get_value() {
+							Some(value) => return Some(load_value::<L, _>(&*it.db, value, &it.encoded_key())
+								.map(|value| (it.key(), value))),
+							None => IterStep::Continue,
+						}
+					},
+					(Status::At, OwnedNode::Leaf(_, v)) => {
+						return Some(load_value::<L, _>(&*it.db, v.as_value(), &it.encoded_key())
+							.map(|value| (it.key(), value)));
+					},
+					(Status::At, OwnedNode::Extension(_, d)) => {
+						IterStep::Descend::<TrieHash<L>, CError<L>>(it.get_raw_or_lookup(d, &it.encoded_key()))
+					},
+					(Status::AtChild(i), OwnedNode::Branch(branch))
+						| (Status::AtChild(i), OwnedNode::NibbledBranch(_, branch)) => {
+						match i {
+							15 => it.key_nibbles.push(15),
+							i => *it.key_nibbles.last_mut()
+								.expect("pushed as 15; moves sequentially; removed afterwards; qed") = i as u8,
+						}
+						match branch.index(i) {
+							Some(child) => IterStep::Descend::<TrieHash<L>, CError<L>>(
+								it.get_raw_or_lookup(child, &it.encoded_key())
+							),
+							None => IterStep::Continue,
+						}
+					},
+					_ => panic!() // Should never see Entering or AtChild without a Branch here.
+				}
+			};
+
+			match iter_step {
+				IterStep::PopTrail => {
+					it.trail.pop();
+				},
+				IterStep::Descend::<TrieHash<L>, CError<L>>(Ok(d)) => {
+					let node = L::Codec::decode(&d).ok()?;
+					it.descend_into_node(node.into())
+				},
+				IterStep::Descend::<TrieHash<L>, CError<L>>(Err(e)) => {
+					return Some(Err(e))
+				}
+				IterStep::Continue => {},
+			}
+		}
+	}
+}
+
+// The hash of a node, if it is not inlined, and its encoded data.
+type NodeData<HO> = (Option<HO>, DBValue);
+
+struct NodeCrumb<HO> {
+	crumb: Crumb,
+	hash: Option<HO>,
+	data: DBValue,
+}
+
+/// A trie node as yielded by `TrieDBNodeIterator`: the nibble path leading to the node,
+/// its hash if it is not inlined in its parent, its encoded data and its decoded form.
+pub type TrieNodeItem<HO, CE> = Result<(NibbleVec, Option<HO>, DBValue, OwnedNode), HO, CE>;
+
+/// Iterator for going through all nodes in the trie, in depth-first order.
+pub struct TrieDBNodeIterator<'a, L: TrieLayout> {
+	db: &'a TrieDB<'a, L>,
+	trail: Vec<NodeCrumb<TrieHash<L>>>,
+	key_nibbles: Vec<u8>,
+}
+
+impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
+	/// Create a new iterator.
+	pub fn new(db: &'a TrieDB<L>) -> Result<TrieDBNodeIterator<'a, L>, TrieHash<L>, CError<L>> {
+		let mut r = TrieDBNodeIterator { db, trail: Vec::with_capacity(8), key_nibbles: Vec::with_capacity(64) };
+		let root_data = db.root_data()?;
+		r.push(Some(*db.root), root_data, Status::Entering)?;
+		Ok(r)
+	}
+
+	/// Decode a node and push it on the trail.
+	fn push(&mut self, hash: Option<TrieHash<L>>, data: DBValue, status: Status) -> Result<(), TrieHash<L>, CError<L>> {
+		let node = L::Codec::decode(&data)
+			.map_err(|e|Box::new(TrieError::DecoderError(hash.unwrap_or_default(), e)))?
+			.into();
+		self.trail.push(NodeCrumb { crumb: Crumb { node, status }, hash, data });
+		Ok(())
+	}
+
+	/// Fetch the node referenced from the present position.
+	fn child(&self, child: &[u8], partial_key: &[u8]) -> Result<NodeData<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let data = self.db.get_raw_or_lookup(child, partial_key)?.into_owned();
+		Ok((L::Codec::try_decode_hash(child), data))
+	}
+
+	/// Move the last node of the trail on to its next status.
+	fn increment(&mut self) {
+		self.trail.last_mut().expect("only called with a non empty trail; qed").crumb.increment();
+	}
+
+	/// Encoded key for storage lookup
+	fn encoded_key(&self) -> ElasticArray36<u8> {
+		encoded_nibbles(&self.key_nibbles)
+	}
+}
+
+impl<'a, L: TrieLayout> TrieIterator<L> for TrieDBNodeIterator<'a, L> {
+	/// Position the iterator on the first node which may hold a key >= `key`.
+	/// The nodes above it are not yielded.
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.trail.clear();
+		self.key_nibbles.clear();
+		let key = NibbleSlice::new(key);
+		let mut partial = key;
+		let mut hash = Some(*self.db.root);
+		let mut data = self.db.root_data()?;
+		loop {
+			let (status, child) = {
+				let node = L::Codec::decode(&data)
+					.map_err(|e|Box::new(TrieError::DecoderError(hash.unwrap_or_default(), e)))?;
+				if let Node::NibbledBranch(slice, _, _) = node {
+					if partial.starts_with(&slice) && partial.len() > slice.len() {
+						self.key_nibbles.extend(slice.iter());
+						partial = partial.mid(slice.len());
+					} else if slice < partial {
+						// every key below this branch comes before `key`.
+						self.key_nibbles.extend(slice.iter());
+						self.key_nibbles.push(15);
+						return self.push(hash, data, Status::Exiting)
+					} else {
+						return self.push(hash, data, Status::Entering)
+					}
+				}
+				let (status, child) = match node {
+					Node::Leaf(slice, _) => {
+						if slice >= partial {
+							(Status::Entering, None)
+						} else {
+							self.key_nibbles.extend(slice.iter());
+							(Status::Exiting, None)
+						}
+					},
+					Node::Extension(slice, item) => {
+						if partial.starts_with(&slice) {
+							self.key_nibbles.extend(slice.iter());
+							partial = partial.mid(slice.len());
+							(Status::Exiting, Some(item))
+						} else if slice < partial {
+							self.key_nibbles.extend(slice.iter());
+							(Status::Exiting, None)
+						} else {
+							(Status::Entering, None)
+						}
+					},
+					Node::Branch(nodes, _) | Node::NibbledBranch(_, nodes, _) => {
+						if partial.is_empty() {
+							(Status::Entering, None)
+						} else {
+							let i = partial.at(0);
+							self.key_nibbles.push(i);
+							partial = partial.mid(1);
+							let status = if i < 15 { Status::AtChild(i as usize + 1) } else { Status::Exiting };
+							(status, nodes[i as usize])
+						}
+					},
+					Node::Empty => {
+						let status = if partial.is_empty() { Status::Entering } else { Status::Exiting };
+						(status, None)
+					},
+				};
+				let child = match child {
+					Some(child) => Some(self.child(child, &key.encoded_leftmost(self.key_nibbles.len(), false))?),
+					None => None,
+				};
+				(status, child)
+			};
+
+			self.push(hash, data, status)?;
+			match child {
+				Some((child_hash, child_data)) => {
+					hash = child_hash;
+					data = child_data;
+				},
+				None => return Ok(()),
+			}
+		}
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
+	type Item = TrieNodeItem<TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		enum IterStep<O, E> {
+			Continue,
+			PopTrail,
+			Descend(Result<NodeData<O>, O, E>),
+		}
+		loop {
+			// unlike `TrieDBIterator`, act on the present status before moving on.
+			let b = self.trail.last()?;
+			if b.crumb.status == Status::Entering {
+				let mut prefix = NibbleVec::new();
+				for nibble in &self.key_nibbles {
+					prefix.push(*nibble);
+				}
+				if let OwnedNode::Leaf(ref n, _)
+					| OwnedNode::Extension(ref n, _)
+					| OwnedNode::NibbledBranch(ref n, _) = b.crumb.node {
+					self.key_nibbles.extend((0..n.len()).map(|i| n.at(i)));
+				}
+				let item = (prefix, b.hash, b.data.clone(), b.crumb.node.clone());
+				self.increment();
+				return Some(Ok(item))
+			}
+
+			let iter_step = match (b.crumb.status.clone(), &b.crumb.node) {
+				(Status::Exiting, node) => {
+					match *node {
+						OwnedNode::Leaf(ref n, _) | OwnedNode::Extension(ref n, _) => {
+							let l = self.key_nibbles.len();
+							self.key_nibbles.truncate(l - n.len());
+						},
+						OwnedNode::Branch(_) => { self.key_nibbles.pop(); },
+						OwnedNode::NibbledBranch(ref n, _) => {
+							let l = self.key_nibbles.len();
+							self.key_nibbles.truncate(l - n.len() - 1);
+						},
+						_ => {}
+					}
+					IterStep::PopTrail
+				},
+				(Status::At, OwnedNode::Extension(_, d)) => {
+					IterStep::Descend::<TrieHash<L>, CError<L>>(self.child(d, &self.encoded_key()))
+				},
+				(Status::AtChild(i), OwnedNode::Branch(branch))
+					| (Status::AtChild(i), OwnedNode::NibbledBranch(_, branch)) => {
+					match i {
+						0 => self.key_nibbles.push(0),
+						i => *self.key_nibbles.last_mut()
+							.expect("pushed as 0; moves sequentially; removed afterwards; qed") = i as u8,
+					}
+					match branch.index(i) {
+						Some(child) => IterStep::Descend::<TrieHash<L>, CError<L>>(self.child(child, &self.encoded_key())),
+						None => IterStep::Continue,
+					}
+				},
+				// values are not nodes of their own.
+				_ => IterStep::Continue,
+			};
+
+			match iter_step {
+				IterStep::PopTrail => {
+					self.trail.pop();
+				},
+				IterStep::Continue => self.increment(),
+				IterStep::Descend::<TrieHash<L>, CError<L>>(Ok((hash, data))) => {
+					self.increment();
+					if let Err(e) = self.push(hash, data, Status::Entering) {
+						return Some(Err(e))
+					}
+				},
+				IterStep::Descend::<TrieHash<L>, CError<L>>(Err(e)) => {
+					self.increment();
+					return Some(Err(e))
+				},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use DBValue;
+	use reference_trie::{RefTrieDB, RefTrieDBMut, RefTrieDBOwned, RefTrieDBReverseIterator, RefTrieDBNodeIterator, RefLookup, Trie, TrieMut, TrieIterator, NibbleSlice, NodeCodec, ReferenceNodeCodec};
+	use reference_trie::{RefTrieDBNoExt, RefTrieDBMutNoExt, RefTrieDBReverseIteratorNoExt,
+		RefTrieDBNodeIteratorNoExt, ReferenceNodeCodecNoExt};
+	use hash_db::Hasher;
+	use standardmap::{Alphabet, StandardMap, ValueMode};
+	use std::sync::Arc;
+	use std::thread;
+
+	#[test]
+	fn iterator_works() {
+		let pairs = vec![
+			(hex!("0103000000000000000464").to_vec(), hex!("fffffffffe").to_vec()),
+			(hex!("0103000000000000000469").to_vec(), hex!("ffffffffff").to_vec()),
+		];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+
+		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+
+		let iter = trie.iter().unwrap();
+		let mut iter_pairs = Vec::new();
+		for pair in iter {
+			let (key, value) = pair.unwrap();
+			iter_pairs.push((key, value.to_vec()));
+		}
+
+		assert_eq!(pairs, iter_pairs);
+	}
+
+	#[test]
+	fn iterator_seek_works() {
+		let pairs = vec![
+			(hex!("0103000000000000000464").to_vec(), hex!("fffffffffe").to_vec()),
+			(hex!("0103000000000000000469").to_vec(), hex!("ffffffffff").to_vec()),
+		];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
 				t.insert(x, y).unwrap();
 			}
 		}
@@ -618,6 +1434,300 @@ mod tests {
 		assert_eq!(&d[4..], &iter.map(|x| x.unwrap().1).collect::<Vec<_>>()[..]);
 	}
 
+	#[test]
+	fn iterator_seek_past_extension() {
+		let d = vec![ DBValue::from_slice(b"AA"), DBValue::from_slice(b"AB") ];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for x in &d {
+				t.insert(x, x).unwrap();
+			}
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut iter = t.iter().unwrap();
+		iter.seek(b"0").unwrap();
+		assert_eq!(d, iter.map(|x| x.unwrap().1).collect::<Vec<_>>());
+		let mut iter = t.iter().unwrap();
+		iter.seek(b"B").unwrap();
+		assert!(iter.next().is_none());
+	}
+
+	#[test]
+	fn iterator_seek_without_extension_nodes() {
+		let keys: Vec<&[u8]> = vec![
+			b"a", b"aaa", b"aab", b"aabb", b"aabbaa", b"aabbbb", b"aba", b"abaabb", b"abab", b"abbaa",
+			b"abbaba", b"b", b"ba", b"baaabb", b"bab", b"bb", b"bbaa", b"bbaabb", b"bbab", b"bbaba",
+		];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+			// with one byte values, some branches encode to exactly the length of a hash.
+			for x in &keys {
+				t.insert(x, &x[..1]).unwrap();
+			}
+		}
+
+		let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+		for key in &[&b""[..], b"a", b"aab", b"ab", b"abc", b"b", b"baa", b"bba", b"bbab", b"c"] {
+			let mut iter = t.iter().unwrap();
+			iter.seek(key).unwrap();
+			let found: Vec<_> = iter.map(|x| x.unwrap().0).collect();
+			let expected: Vec<_> = keys.iter().filter(|k| **k >= *key).map(|k| k.to_vec()).collect();
+			assert_eq!(found, expected);
+		}
+	}
+
+	#[test]
+	fn iter_prefix() {
+		let pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 1,
+			journal_key: 3,
+			value_mode: ValueMode::Mirror,
+			count: 200,
+		}.make();
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let all: Vec<_> = t.iter().unwrap().map(|x| x.unwrap().0).collect();
+		for prefix in &[&b""[..], b"a", b"ab", b"abc", b"fff", b"fa", b"cdef", b"ceb", b"g"] {
+			let expected: Vec<_> = all.iter().filter(|k| k.starts_with(prefix)).cloned().collect();
+			let keys: Vec<_> = t.iter_prefix(prefix).unwrap().map(|x| x.unwrap().0).collect();
+			assert_eq!(keys, expected);
+		}
+	}
+
+	#[test]
+	fn iter_prefix_seek() {
+		let d = vec![
+			DBValue::from_slice(b"A"),
+			DBValue::from_slice(b"AA"),
+			DBValue::from_slice(b"AB"),
+			DBValue::from_slice(b"AC"),
+			DBValue::from_slice(b"B"),
+		];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for x in &d {
+				t.insert(x, x).unwrap();
+			}
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut iter = t.iter_prefix(b"A").unwrap();
+		iter.seek(b"AB").unwrap();
+		assert_eq!(&d[2..4], &iter.map(|x| x.unwrap().1).collect::<Vec<_>>()[..]);
+		let mut iter = t.iter_prefix(b"A").unwrap();
+		iter.seek(b"0").unwrap();
+		assert_eq!(&d[..4], &iter.map(|x| x.unwrap().1).collect::<Vec<_>>()[..]);
+		let mut iter = t.iter_prefix(b"A").unwrap();
+		iter.seek(b"AD").unwrap();
+		assert!(iter.next().is_none());
+		let mut iter = t.iter_prefix(b"A").unwrap();
+		iter.seek(b"B").unwrap();
+		assert!(iter.next().is_none());
+		assert!(t.iter_prefix(b"C").unwrap().next().is_none());
+	}
+
+	#[test]
+	fn reverse_iterator() {
+		let pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 1,
+			journal_key: 3,
+			value_mode: ValueMode::Mirror,
+			count: 200,
+		}.make();
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut all: Vec<_> = t.iter().unwrap().map(|x| x.unwrap().0).collect();
+		all.reverse();
+		let keys: Vec<_> = RefTrieDBReverseIterator::new(&t).unwrap().map(|x| x.unwrap().0).collect();
+		assert_eq!(keys, all);
+
+		for key in &[&b""[..], b"a", b"ab", b"abcd", b"abcdz", b"c", b"ceb", b"fff", b"g"] {
+			let expected: Vec<_> = all.iter().filter(|k| &k[..] <= *key).cloned().collect();
+			let mut iter = RefTrieDBReverseIterator::new(&t).unwrap();
+			iter.seek_back(key).unwrap();
+			let keys: Vec<_> = iter.map(|x| x.unwrap().0).collect();
+			assert_eq!(keys, expected);
+		}
+	}
+
+	#[test]
+	fn owned_trie() {
+		let pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 1,
+			journal_key: 3,
+			value_mode: ValueMode::Mirror,
+			count: 200,
+		}.make();
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+
+		let t = RefTrieDBOwned::new(Arc::new(memdb), root).unwrap();
+		let all: Vec<_> = RefTrieDB::new(&**t.db(), &root).unwrap().iter().unwrap().map(|x| x.unwrap()).collect();
+		let iter = t.iter().unwrap();
+		let reverse = t.reverse_iter().unwrap();
+		let prefixed = t.iter_prefix(b"a").unwrap();
+
+		let worker = t.clone();
+		let found = thread::spawn(move || pairs.iter().all(|(x, y)| worker.get(x).unwrap().unwrap() == DBValue::from_slice(y)));
+		assert!(found.join().unwrap());
+
+		// the iterators outlive the trie, and can be sent to other threads.
+		drop(t);
+		fn assert_send<T: Send>(value: T) -> T { value }
+		let iter = assert_send(iter);
+		let items = thread::spawn(move || iter.map(|x| x.unwrap()).collect::<Vec<_>>());
+		assert_eq!(items.join().unwrap(), all);
+		let reverse = assert_send(reverse);
+		let items = thread::spawn(move || reverse.map(|x| x.unwrap()).collect::<Vec<_>>());
+		assert_eq!(items.join().unwrap(), all.iter().rev().cloned().collect::<Vec<_>>());
+		assert_eq!(
+			assert_send(prefixed).map(|x| x.unwrap()).collect::<Vec<_>>(),
+			all.iter().filter(|(k, _)| k.starts_with(b"a")).cloned().collect::<Vec<_>>()
+		);
+	}
+
+	#[test]
+	fn reverse_iterator_seek_back() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for number in 0u32..100 {
+				t.insert(&number.to_be_bytes(), &[number as u8]).unwrap();
+			}
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut iter = RefTrieDBReverseIterator::new(&t).unwrap();
+		iter.seek_back(&50u32.to_be_bytes()).unwrap();
+		let latest: Vec<_> = iter.take(3).map(|x| x.unwrap().1).collect();
+		assert_eq!(latest, vec![
+			DBValue::from_slice(&[50]),
+			DBValue::from_slice(&[49]),
+			DBValue::from_slice(&[48]),
+		]);
+
+		let mut iter = RefTrieDBReverseIterator::new(&t).unwrap();
+		iter.seek_back(&[0, 0, 0]).unwrap();
+		assert!(iter.next().is_none());
+
+		let mut iter = RefTrieDBReverseIterator::new(&t).unwrap();
+		iter.seek_back(&[0xff]).unwrap();
+		assert_eq!(iter.next().unwrap().unwrap().0, 99u32.to_be_bytes().to_vec());
+	}
+
+	#[test]
+	fn node_iterator() {
+		let pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 1,
+			journal_key: 5,
+			value_mode: ValueMode::Random,
+			count: 200,
+		}.make();
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let nodes: Vec<_> = RefTrieDBNodeIterator::new(&t).unwrap().map(|x| x.unwrap()).collect();
+		let prefixes: Vec<Vec<u8>> = nodes.iter()
+			.map(|(prefix, _, _, _)| (0..prefix.len()).map(|i| prefix.at(i)).collect())
+			.collect();
+		let mut sorted = prefixes.clone();
+		sorted.sort();
+		assert_eq!(prefixes, sorted);
+		assert!(prefixes[0].is_empty());
+		assert_eq!(nodes[0].1, Some(root));
+
+		let mut hashed = 0;
+		for (_, hash, data, node) in &nodes {
+			assert_eq!(*node, ReferenceNodeCodec::decode(data).unwrap().into());
+			if let Some(hash) = hash {
+				assert_eq!(*hash, KeccakHasher::hash(data));
+				hashed += 1;
+			}
+		}
+		assert_eq!(hashed, memdb.keys().len());
+
+		for key in &[&b""[..], b"a", b"abc", b"bbbbb", b"dead", b"f", b"g"] {
+			let mut iter = RefTrieDBNodeIterator::new(&t).unwrap();
+			iter.seek(key).unwrap();
+			let tail: Vec<_> = iter.map(|x| x.unwrap()).collect();
+			assert_eq!(&tail[..], &nodes[nodes.len() - tail.len()..]);
+		}
+	}
+
+	#[test]
+	fn node_iterator_seek() {
+		let d = vec![
+			DBValue::from_slice(b"A"),
+			DBValue::from_slice(b"AA"),
+			DBValue::from_slice(b"AB"),
+		];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for x in &d {
+				t.insert(x, x).unwrap();
+			}
+		}
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut iter = RefTrieDBNodeIterator::new(&t).unwrap();
+		iter.seek(b"AB").unwrap();
+		let (prefix, hash, _, _) = iter.next().unwrap().unwrap();
+		assert_eq!((0..prefix.len()).map(|i| prefix.at(i)).collect::<Vec<_>>(), vec![4, 1, 4, 2]);
+		assert_eq!(hash, None);
+		assert!(iter.next().is_none());
+	}
+
 	#[test]
 	fn get_len() {
 		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
@@ -718,8 +1828,67 @@ mod tests {
 
 		// query for an invalid data type to trigger an error
 		let q = |x: &[u8]| x.len() < 64;
-		let lookup = RefLookup { db: t.db(), query: q, hash: root, marker: PhantomData };
+		let lookup = RefLookup { db: t.db(), query: q, hash: root, cache: None, marker: PhantomData };
 		let query_result = lookup.look_up(NibbleSlice::new(b"A"));
 		assert_eq!(query_result.unwrap().unwrap(), true);
 	}
+
+	#[test]
+	fn iterators_without_extension_nodes() {
+		let pairs = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 1,
+			journal_key: 3,
+			value_mode: ValueMode::Mirror,
+			count: 200,
+		}.make();
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+			for (x, y) in &pairs {
+				t.insert(x, y).unwrap();
+			}
+		}
+		let mut all: Vec<_> = pairs.into_iter().map(|(k, _)| k).collect();
+		all.sort();
+		all.dedup();
+
+		let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+		let keys: Vec<_> = t.iter().unwrap().map(|x| x.unwrap().0).collect();
+		assert_eq!(keys, all);
+
+		let nodes: Vec<_> = RefTrieDBNodeIteratorNoExt::new(&t).unwrap().map(|x| x.unwrap()).collect();
+		let prefixes: Vec<Vec<u8>> = nodes.iter()
+			.map(|(prefix, _, _, _)| (0..prefix.len()).map(|i| prefix.at(i)).collect())
+			.collect();
+		let mut sorted = prefixes.clone();
+		sorted.sort();
+		assert_eq!(prefixes, sorted);
+		for (_, _, data, node) in &nodes {
+			assert_eq!(*node, ReferenceNodeCodecNoExt::decode(data).unwrap().into());
+		}
+		assert_eq!(nodes.iter().filter(|node| node.1.is_some()).count(), memdb.keys().len());
+
+		for key in &[&b""[..], b"a", b"ab", b"abcd", b"abcdz", b"c", b"ceb", b"fff", b"g"] {
+			let mut iter = t.iter().unwrap();
+			iter.seek(key).unwrap();
+			let keys: Vec<_> = iter.map(|x| x.unwrap().0).collect();
+			assert_eq!(keys, all.iter().filter(|k| &k[..] >= *key).cloned().collect::<Vec<_>>());
+
+			let mut iter = RefTrieDBReverseIteratorNoExt::new(&t).unwrap();
+			iter.seek_back(key).unwrap();
+			let keys: Vec<_> = iter.map(|x| x.unwrap().0).collect();
+			assert_eq!(keys, all.iter().rev().filter(|k| &k[..] <= *key).cloned().collect::<Vec<_>>());
+
+			let keys: Vec<_> = t.iter_prefix(key).unwrap().map(|x| x.unwrap().0).collect();
+			assert_eq!(keys, all.iter().filter(|k| k.starts_with(key)).cloned().collect::<Vec<_>>());
+
+			let mut iter = RefTrieDBNodeIteratorNoExt::new(&t).unwrap();
+			iter.seek(key).unwrap();
+			let tail: Vec<_> = iter.map(|x| x.unwrap()).collect();
+			assert_eq!(&tail[..], &nodes[nodes.len() - tail.len()..]);
+		}
+	}
 }
@@ -14,14 +14,15 @@
 
 //! In-memory trie representation.
 
-use super::{Result, TrieError, TrieMut};
+use super::{Result, TrieError, TrieMut, TrieLayout, TrieHash, CError, checked_value_hash, check_value_nodes};
 use super::lookup::Lookup;
-use super::node::Node as EncodedNode;
+use super::node::{Node as EncodedNode, Value};
 use node_codec::NodeCodec;
 use super::{DBValue, node::NodeKey};
 
-use hash_db::{HashDB, Hasher};
+use hash_db::{HashDB, HashDBRef, Hasher};
 use nibbleslice::{self, NibbleSlice, combine_encoded};
+use nibblevec::NibbleVec;
 
 use ::core_::marker::PhantomData;
 use ::core_::mem;
@@ -70,6 +71,94 @@ fn empty_children<H>() -> Box<[Option<NodeHandle<H>>; 16]> {
 	])
 }
 
+// What `Node::into_encoded` hands to its callback to be stored.
+enum NodeToEncode<'a, H> {
+	// A child node.
+	Node(NodeHandle<H>),
+	// A value too large to be kept in its node.
+	Value(&'a [u8]),
+}
+
+// A value held by a node. Values too large to be kept in their node are stored in
+// value nodes of their own, which are only fetched when the value is read.
+#[derive(Debug, Clone)]
+enum NodeValue<H> {
+	// A value kept in its node, or a new value not stored yet.
+	Inline(DBValue),
+	// A value stored in a value node, known by its hash only.
+	Node(H),
+	// A value stored in a value node, along with its hash.
+	Cached(DBValue, H),
+}
+
+impl<H: AsRef<[u8]> + Copy + PartialEq> NodeValue<H> {
+	// decode a value found in an encoded node, without fetching its value node.
+	fn from_encoded<L>(value: Value) -> Result<Self, H, CError<L>>
+	where L: TrieLayout, L::Hash: Hasher<Out = H>,
+	{
+		Ok(match value {
+			Value::Inline(value) => NodeValue::Inline(DBValue::from_slice(value)),
+			Value::Node(hash) => NodeValue::Node(checked_value_hash::<L>(hash)?),
+		})
+	}
+
+	// the value as encoded in its node. New values too large to be kept in their node
+	// must be stored first.
+	fn as_encoded(&self) -> Value<'_> {
+		match *self {
+			NodeValue::Inline(ref value) => Value::Inline(value),
+			NodeValue::Node(ref hash) | NodeValue::Cached(_, ref hash) => Value::Node(hash.as_ref()),
+		}
+	}
+
+	// the hash of the value node holding the value, if it is stored in one.
+	fn hash(&self) -> Option<H> {
+		match *self {
+			NodeValue::Inline(_) => None,
+			NodeValue::Node(hash) | NodeValue::Cached(_, hash) => Some(hash),
+		}
+	}
+
+	// the value, if known without fetching its value node.
+	fn data(&self) -> Option<&[u8]> {
+		match *self {
+			NodeValue::Inline(ref value) | NodeValue::Cached(ref value, _) => Some(value),
+			NodeValue::Node(_) => None,
+		}
+	}
+
+	// whether both values are the same, without fetching their value nodes.
+	fn same_as<L>(&self, other: &Self) -> bool
+	where L: TrieLayout, L::Hash: Hasher<Out = H>,
+	{
+		let node_hash = |value: &Self| value.hash().unwrap_or_else(|| {
+			L::Hash::hash(value.data().expect("values without data have a hash; qed"))
+		});
+		match (self.data(), other.data()) {
+			(Some(data), Some(other_data)) => data == other_data,
+			_ => node_hash(self) == node_hash(other),
+		}
+	}
+
+	// the value, fetched from its value node found at `key` if needed.
+	fn load<L>(&self, db: &dyn HashDBRef<L::Hash, DBValue>, key: &[u8]) -> Result<DBValue, H, CError<L>>
+	where L: TrieLayout, L::Hash: Hasher<Out = H>,
+	{
+		match *self {
+			NodeValue::Inline(ref value) | NodeValue::Cached(ref value, _) => Ok(value.clone()),
+			NodeValue::Node(hash) => db.get(&hash, key).ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash))),
+		}
+	}
+}
+
+// A batched change: a key along with the value to insert, or `None` to remove it.
+type Change<H> = (Vec<u8>, Option<NodeValue<H>>);
+
+// The nibbles of a batched change's key after the first `depth`.
+fn rest<H>(change: &Change<H>, depth: usize) -> NibbleSlice<'_> {
+	NibbleSlice::new(&change.0).mid(depth)
+}
+
 struct Partial<'key> {
 	key: NibbleSlice<'key>,
 	split: usize,
@@ -94,6 +183,10 @@ impl<'key> Partial<'key> {
 	fn encoded_prefix(&self) -> NodeKey {
 		self.key.encoded_leftmost(self.split, false)
 	}
+
+	fn encoded_key(&self) -> NodeKey {
+		self.key.encoded(false)
+	}
 }
 
 /// Node types in the Trie.
@@ -104,14 +197,14 @@ enum Node<H> {
 	/// A leaf node contains the end of a key and a value.
 	/// This key is encoded from a `NibbleSlice`, meaning it contains
 	/// a flag indicating it is a leaf.
-	Leaf(NodeKey, DBValue),
+	Leaf(NodeKey, NodeValue<H>),
 	/// An extension contains a shared portion of a key and a child node.
 	/// The shared portion is encoded from a `NibbleSlice` meaning it contains
 	/// a flag indicating it is an extension.
 	/// The child node is always a branch.
 	Extension(NodeKey, NodeHandle<H>),
 	/// A branch has up to 16 children and an optional value.
-	Branch(Box<[Option<NodeHandle<H>>; 16]>, Option<DBValue>)
+	Branch(Box<[Option<NodeHandle<H>>; 16]>, Option<NodeValue<H>>)
 }
 
 impl<O> Node<O>
@@ -119,81 +212,177 @@ where
 	O: AsRef<[u8]> + AsMut<[u8]> + Default + crate::MaybeDebug + PartialEq + Eq + Hash + Send + Sync + Clone + Copy
 {
 	// load an inline node into memory or get the hash to do the lookup later.
-	fn inline_or_hash<C, H>(
-		node: &[u8],
-		db: &dyn HashDB<H, DBValue>,
-		storage: &mut NodeStorage<H::Out>
-	) -> NodeHandle<H::Out>
+	fn inline_or_hash<L>(node: &[u8], storage: &mut NodeStorage<O>) -> Result<NodeHandle<O>, O, CError<L>>
 	where
-		C: NodeCodec<H>,
-		H: Hasher<Out = O>,
+		L: TrieLayout,
+		L::Hash: Hasher<Out = O>,
 	{
-		C::try_decode_hash(&node)
-			.map(NodeHandle::Hash)
-			.unwrap_or_else(|| {
-				let child = Node::from_encoded::<C, H>(node, db, storage);
+		Ok(match L::Codec::try_decode_hash(&node) {
+			Some(hash) => NodeHandle::Hash(hash),
+			None => {
+				let child = Node::from_encoded::<L>(node, storage)?;
 				NodeHandle::InMemory(storage.alloc(Stored::New(child)))
-			})
+			}
+		})
 	}
 
-	// decode a node from encoded bytes without getting its children.
-	fn from_encoded<C, H>(
-		data: &[u8],
-		db: &dyn HashDB<H, DBValue>,
-		storage: &mut NodeStorage<H::Out>,
-	) -> Self
-	where C: NodeCodec<H>, H: Hasher<Out = O>,
+	// decode a node from encoded bytes without getting its children or value nodes.
+	fn from_encoded<L>(data: &[u8], storage: &mut NodeStorage<O>) -> Result<Self, O, CError<L>>
+	where L: TrieLayout, L::Hash: Hasher<Out = O>,
 	{
-		match C::decode(data).unwrap_or(EncodedNode::Empty) {
+		Ok(match L::Codec::decode(data).unwrap_or(EncodedNode::Empty) {
 			EncodedNode::Empty => Node::Empty,
-			EncodedNode::Leaf(k, v) => Node::Leaf(k.encoded(true), DBValue::from_slice(&v)),
+			EncodedNode::Leaf(k, v) => Node::Leaf(k.encoded(true), NodeValue::from_encoded::<L>(v)?),
 			EncodedNode::Extension(key, cb) => {
-				Node::Extension(
-					key.encoded(false),
-					Self::inline_or_hash::<C, H>(cb, db, storage))
+				Node::Extension(key.encoded(false), Self::inline_or_hash::<L>(cb, storage)?)
 			}
 			EncodedNode::Branch(ref encoded_children, val) => {
-				let mut child = |i:usize| {
-					encoded_children[i].map(|data|
-						Self::inline_or_hash::<C, H>(data, db, storage)
-					)
-				};
+				Self::branch_from_encoded::<L>(encoded_children, val, storage)?
+			}
+			EncodedNode::NibbledBranch(partial, ref encoded_children, val) => {
+				// kept in memory as an extension followed by a branch.
+				let branch = Self::branch_from_encoded::<L>(encoded_children, val, storage)?;
+				match partial.is_empty() {
+					true => branch,
+					false => Node::Extension(partial.encoded(false), storage.alloc(Stored::New(branch)).into()),
+				}
+			}
+		})
+	}
+
+	// decode the children and value of a branch.
+	fn branch_from_encoded<L>(
+		encoded_children: &[Option<&[u8]>; 16],
+		val: Option<Value>,
+		storage: &mut NodeStorage<O>,
+	) -> Result<Self, O, CError<L>>
+	where L: TrieLayout, L::Hash: Hasher<Out = O>,
+	{
+		let mut child = |i: usize| {
+			encoded_children[i].map(|data| Self::inline_or_hash::<L>(data, storage)).transpose()
+		};
 
-				let children = Box::new([
-					child(0), child(1), child(2), child(3),
-					child(4), child(5), child(6), child(7),
-					child(8), child(9), child(10), child(11),
-					child(12), child(13), child(14), child(15),
-				]);
+		let children = Box::new([
+			child(0)?, child(1)?, child(2)?, child(3)?,
+			child(4)?, child(5)?, child(6)?, child(7)?,
+			child(8)?, child(9)?, child(10)?, child(11)?,
+			child(12)?, child(13)?, child(14)?, child(15)?,
+		]);
+		let value = val.map(NodeValue::from_encoded::<L>).transpose()?;
 
-				Node::Branch(children, val.map(DBValue::from_slice))
+		Ok(Node::Branch(children, value))
+	}
+
+	// hand a new value too large to be kept in its node to `child_cb`, returning it
+	// along with the hash of its value node.
+	fn store_value<F, L>(
+		value: NodeValue<O>,
+		partial: &NodeKey,
+		storage: &mut NodeStorage<O>,
+		child_cb: &mut F,
+	) -> NodeValue<O>
+	where
+		L: TrieLayout,
+		L::Hash: Hasher<Out = O>,
+		F: FnMut(&mut NodeStorage<O>, NodeToEncode<O>, &NodeKey) -> ChildReference<O>,
+	{
+		match value {
+			NodeValue::Inline(value) if !L::is_inline_value(value.len()) => {
+				match child_cb(storage, NodeToEncode::Value(&value), partial) {
+					ChildReference::Hash(hash) => NodeValue::Cached(value, hash),
+					ChildReference::Inline(_) => unreachable!("value nodes are always stored by hash; qed"),
+				}
 			}
+			value => value,
+		}
+	}
+
+	// store the new values of the node found at `prefix` which are too large to be kept
+	// in it through `insert`, keeping them along with the hash of their value node.
+	fn store_values<L, F>(&mut self, prefix: &NodeKey, insert: &mut F)
+	where
+		L: TrieLayout,
+		L::Hash: Hasher<Out = O>,
+		F: FnMut(&[u8], &[u8]) -> O,
+	{
+		let (key, value) = match *self {
+			Node::Leaf(ref partial, ref mut value) => (combine_encoded(prefix, partial), value),
+			Node::Branch(_, Some(ref mut value)) => (prefix.clone(), value),
+			_ => return,
+		};
+		let hash = match *value {
+			NodeValue::Inline(ref data) if !L::is_inline_value(data.len()) => insert(&key, data),
+			_ => return,
+		};
+		if let NodeValue::Inline(data) = mem::replace(value, NodeValue::Node(hash)) {
+			*value = NodeValue::Cached(data, hash);
 		}
 	}
 
 	// TODO: parallelize
-	fn into_encoded<F, C, H>(self, mut child_cb: F) -> Vec<u8>
+	fn into_encoded<F, L>(self, storage: &mut NodeStorage<O>, mut child_cb: F) -> Vec<u8>
 	where
-		C: NodeCodec<H>,
-		F: FnMut(NodeHandle<H::Out>, &NodeKey) -> ChildReference<H::Out>,
-		H: Hasher<Out = O>,
+		L: TrieLayout,
+		L::Hash: Hasher<Out = O>,
+		F: FnMut(&mut NodeStorage<O>, NodeToEncode<O>, &NodeKey) -> ChildReference<O>,
 	{
 		match self {
-			Node::Empty => C::empty_node(),
-			Node::Leaf(partial, value) => C::leaf_node(&partial, &value),
-			Node::Extension(partial, child) => C::ext_node(&partial, child_cb(child, &partial)),
-			Node::Branch(mut children, value) => {
-				C::branch_node(
-					// map the `NodeHandle`s from the Branch to `ChildReferences`
-					children.iter_mut()
-						.map(Option::take)
-						.enumerate()
-						.map(|(i, maybe_child)|
-							maybe_child.map(|child| child_cb(child, &NibbleSlice::new_offset(&[i as u8], 1).encoded(false)))
-						),
-					value
-				)
+			Node::Empty => L::Codec::empty_node(),
+			Node::Leaf(partial, value) => {
+				let value = Self::store_value::<F, L>(value, &partial, storage, &mut child_cb);
+				L::Codec::leaf_node(&partial, value.as_encoded())
 			}
+			Node::Extension(partial, child) => if L::USE_EXTENSION {
+				let child = child_cb(storage, NodeToEncode::Node(child), &partial);
+				L::Codec::ext_node(&partial, child)
+			} else {
+				// without extension nodes, the partial key goes into the branch below.
+				let branch = match child {
+					NodeHandle::InMemory(h) => match storage.destroy(h) {
+						Stored::New(node) | Stored::Cached(node, _) => node,
+					},
+					NodeHandle::Hash(_) => unreachable!("extensions are only followed by in-memory branches without extension nodes; qed"),
+				};
+				match branch {
+					Node::Branch(mut children, value) => Self::encode_branch::<F, L>(Some(&partial), &mut children, value, storage, child_cb),
+					_ => unreachable!("an extension is always followed by a branch; qed"),
+				}
+			},
+			Node::Branch(mut children, value) => Self::encode_branch::<F, L>(None, &mut children, value, storage, child_cb),
+		}
+	}
+
+	// encode a branch, preceded by the partial key of an extension if the codec has none.
+	fn encode_branch<F, L>(
+		partial: Option<&NodeKey>,
+		children: &mut [Option<NodeHandle<O>>; 16],
+		value: Option<NodeValue<O>>,
+		storage: &mut NodeStorage<O>,
+		mut child_cb: F,
+	) -> Vec<u8>
+	where
+		L: TrieLayout,
+		L::Hash: Hasher<Out = O>,
+		F: FnMut(&mut NodeStorage<O>, NodeToEncode<O>, &NodeKey) -> ChildReference<O>,
+	{
+		let partial = partial.cloned().unwrap_or_else(|| NibbleSlice::new(&[]).encoded(false));
+		// the value node is stored before the children.
+		let value = value.map(|value| Self::store_value::<F, L>(value, &partial, storage, &mut child_cb));
+		let value = value.as_ref().map(NodeValue::as_encoded);
+		// map the `NodeHandle`s from the Branch to `ChildReferences`
+		let children = children.iter_mut()
+			.map(Option::take)
+			.enumerate()
+			.map(|(i, maybe_child)| maybe_child.map(|child| {
+				let index = NibbleSlice::new_offset(&[i as u8], 1).encoded(false);
+				match L::USE_EXTENSION {
+					true => child_cb(storage, NodeToEncode::Node(child), &index),
+					false => child_cb(storage, NodeToEncode::Node(child), &combine_encoded(&partial, &index)),
+				}
+			}));
+		match L::USE_EXTENSION {
+			true => L::Codec::branch_node(children, value),
+			false => L::Codec::branch_node_nibbled(&partial, children, value),
 		}
 	}
 }
@@ -240,36 +429,93 @@ enum Stored<H> {
 	Cached(Node<H>, H),
 }
 
+impl<H: Copy> NodeHandle<H> {
+	// copy of the handle, sharing the storage handle of an in-memory node.
+	fn shallow_copy(&self) -> Self {
+		match *self {
+			NodeHandle::InMemory(ref storage) => NodeHandle::InMemory(StorageHandle(storage.0)),
+			NodeHandle::Hash(hash) => NodeHandle::Hash(hash),
+		}
+	}
+}
+
+impl<H: Copy> Node<H> {
+	// copy of the node, sharing the storage handles of its children.
+	fn shallow_copy(&self) -> Self {
+		match *self {
+			Node::Empty => Node::Empty,
+			Node::Leaf(ref partial, ref value) => Node::Leaf(partial.clone(), value.clone()),
+			Node::Extension(ref partial, ref child) => Node::Extension(partial.clone(), child.shallow_copy()),
+			Node::Branch(ref children, ref value) => {
+				let mut copy = empty_children();
+				for (copy, child) in copy.iter_mut().zip(children.iter()) {
+					*copy = child.as_ref().map(NodeHandle::shallow_copy);
+				}
+				Node::Branch(copy, value.clone())
+			}
+		}
+	}
+}
+
+impl<H: Copy> Stored<H> {
+	// Copy of a node being destroyed, for the journal to put back on revert. The copy
+	// shares the storage handles of the node, which is fine as it is only restored
+	// along with the nodes they refer to, once the handles taken from the original
+	// are gone.
+	fn journal_copy(&self) -> Self {
+		match *self {
+			Stored::New(ref node) => Stored::New(node.shallow_copy()),
+			Stored::Cached(ref node, hash) => Stored::Cached(node.shallow_copy(), hash),
+		}
+	}
+}
+
 /// Used to build a collection of child nodes from a collection of `NodeHandle`s
 pub enum ChildReference<HO> { // `HO` is e.g. `H256`, i.e. the output of a `Hasher`
 	Hash(HO),
-	Inline(HO, usize), // usize is the length of the node data we store in the `H::Out`
+	Inline(DBValue), // the encoded node, shorter than the layout's inline threshold
 }
 
 /// Compact and cache-friendly storage for Trie nodes.
 struct NodeStorage<H> {
 	nodes: Vec<Stored<H>>,
 	free_indices: VecDeque<usize>,
+	/// Changes to undo when reverting, recorded while a savepoint is held.
+	journal: Option<Vec<StorageChange<H>>>,
 }
 
-impl<H> NodeStorage<H> {
+/// A change made to the node storage.
+enum StorageChange<H> {
+	/// A node was allocated at an index, growing the storage if the flag is set.
+	Alloc(usize, bool),
+	/// A node was removed from an index.
+	Destroy(usize, Stored<H>),
+}
+
+impl<H: Copy> NodeStorage<H> {
 	/// Create a new storage.
 	fn empty() -> Self {
 		NodeStorage {
 			nodes: Vec::new(),
 			free_indices: VecDeque::new(),
+			journal: None,
 		}
 	}
 
 	/// Allocate a new node in the storage.
 	fn alloc(&mut self, stored: Stored<H>) -> StorageHandle {
-		if let Some(idx) = self.free_indices.pop_front() {
+		let (idx, grown) = if let Some(idx) = self.free_indices.pop_front() {
 			self.nodes[idx] = stored;
-			StorageHandle(idx)
+			(idx, false)
 		} else {
 			self.nodes.push(stored);
-			StorageHandle(self.nodes.len() - 1)
+			(self.nodes.len() - 1, true)
+		};
+
+		if let Some(ref mut journal) = self.journal {
+			journal.push(StorageChange::Alloc(idx, grown));
 		}
+		StorageHandle(idx)
 	}
 
 	/// Remove a node from the storage, consuming the handle and returning the node.
@@ -277,7 +523,73 @@ impl<H> NodeStorage<H> {
 		let idx = handle.0;
 
 		self.free_indices.push_back(idx);
-		mem::replace(&mut self.nodes[idx], Stored::New(Node::Empty))
+		let stored = mem::replace(&mut self.nodes[idx], Stored::New(Node::Empty));
+		if let Some(ref mut journal) = self.journal {
+			journal.push(StorageChange::Destroy(idx, stored.journal_copy()));
+		}
+		stored
+	}
+
+	/// Start recording changes if not doing so already.
+	/// Returns the position to revert to for undoing all changes from now on.
+	fn checkpoint(&mut self) -> usize {
+		self.journal.get_or_insert_with(Vec::new).len()
+	}
+
+	/// Undo all changes recorded since the given checkpoint.
+	fn revert(&mut self, checkpoint: usize) {
+		let mut journal = match self.journal.take() {
+			Some(journal) => journal,
+			None => return,
+		};
+
+		for change in journal.drain(checkpoint..).rev() {
+			match change {
+				StorageChange::Alloc(idx, true) => {
+					self.nodes.pop();
+					debug_assert_eq!(self.nodes.len(), idx);
+				}
+				StorageChange::Alloc(idx, false) => {
+					self.nodes[idx] = Stored::New(Node::Empty);
+					self.free_indices.push_front(idx);
+				}
+				StorageChange::Destroy(idx, stored) => {
+					self.nodes[idx] = stored;
+					self.free_indices.pop_back();
+				}
+			}
+		}
+		self.journal = Some(journal);
+	}
+
+	/// Stop recording changes, forgetting those recorded.
+	fn clear_journal(&mut self) {
+		self.journal = None;
+	}
+
+	/// Move the new nodes below a handle to another storage, returning the handle to
+	/// use there. Cached nodes are unchanged, so they are only referred to by hash.
+	#[cfg(feature = "parallel")]
+	fn detach(&mut self, handle: NodeHandle<H>, into: &mut NodeStorage<H>) -> NodeHandle<H> {
+		match handle {
+			NodeHandle::Hash(hash) => NodeHandle::Hash(hash),
+			NodeHandle::InMemory(h) => match self.destroy(h) {
+				Stored::Cached(_, hash) => NodeHandle::Hash(hash),
+				Stored::New(node) => {
+					let node = match node {
+						Node::Extension(partial, child) => Node::Extension(partial, self.detach(child, into)),
+						Node::Branch(mut children, value) => {
+							for child in children.iter_mut() {
+								*child = child.take().map(|c| self.detach(c, into));
+							}
+							Node::Branch(children, value)
+						}
+						other => other,
+					};
+					NodeHandle::InMemory(into.alloc(Stored::New(node)))
+				}
+			},
+		}
 	}
 }
 
@@ -292,11 +604,99 @@ impl<'a, H> Index<&'a StorageHandle> for NodeStorage<H> {
 	}
 }
 
+/// The changes to the backing database made by committing a `TrieDBMut`.
+/// Created by `TrieDBMut::commit_changeset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeSet<HO> {
+	/// Nodes to insert, with their hash and prefix.
+	pub inserted: Vec<(HO, NodeKey, DBValue)>,
+	/// Hashes and prefixes of nodes to remove.
+	pub removed: Vec<(HO, NodeKey)>,
+	/// The root of the trie once the changes are applied.
+	pub root: HO,
+}
+
+impl<HO> ChangeSet<HO> {
+	/// Write the changes into a database, removing nodes before inserting new ones
+	/// just as `TrieDBMut::commit` does.
+	pub fn apply_to<H: Hasher<Out = HO>>(self, db: &mut dyn HashDB<H, DBValue>) {
+		for (hash, prefix) in self.removed {
+			db.remove(&hash, &prefix);
+		}
+		for (hash, prefix, value) in self.inserted {
+			db.emplace(hash, &prefix, value);
+		}
+	}
+}
+
+/// A point in the uncommitted changes of a `TrieDBMut` which can be returned to.
+/// Created by `TrieDBMut::savepoint`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Savepoint {
+	index: usize,
+	id: usize,
+}
+
+// The in-memory state of the trie when a savepoint was made.
+struct SavepointState<H> {
+	// identifies the savepoint among all those made by the trie.
+	id: usize,
+	root: H,
+	root_handle: NodeHandle<H>,
+	storage: usize,
+	death_row: usize,
+}
+
+// The in-memory state of a `TrieDBMut`, kept by a `TrieDBMutOwned` between changes.
+struct TrieState<L: TrieLayout> {
+	storage: NodeStorage<TrieHash<L>>,
+	root_handle: NodeHandle<TrieHash<L>>,
+	death_row: HashSet<(TrieHash<L>, NodeKey)>,
+	/// Entries added to the death row while a savepoint is held.
+	death_row_journal: Vec<(TrieHash<L>, NodeKey)>,
+	savepoints: Vec<SavepointState<TrieHash<L>>>,
+	/// The number of savepoints made so far, which identifies the next one.
+	savepoint_count: usize,
+	/// The changes committed since the last `commit_changeset`, in change set mode.
+	changeset: Option<ChangeSet<TrieHash<L>>>,
+	/// The number of hash operations this trie has performed.
+	/// Note that none are performed until changes are committed.
+	hash_count: usize,
+}
+
+impl<L: TrieLayout> TrieState<L> {
+	// the state of a trie without changes, at `root`.
+	fn new(root: TrieHash<L>) -> Self {
+		TrieState {
+			storage: NodeStorage::empty(),
+			root_handle: NodeHandle::Hash(root),
+			death_row: HashSet::new(),
+			death_row_journal: Vec::new(),
+			savepoints: Vec::new(),
+			savepoint_count: 0,
+			changeset: None,
+			hash_count: 0,
+		}
+	}
+
+	fn is_empty(&self) -> bool {
+		match self.root_handle {
+			NodeHandle::Hash(h) => h == L::Codec::hashed_null_node(),
+			NodeHandle::InMemory(ref h) => matches!(self.storage[h], Node::Empty),
+		}
+	}
+
+	fn get(&self, db: &dyn HashDBRef<L::Hash, DBValue>, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		lookup::<L>(db, &self.storage, NibbleSlice::new(key), &self.root_handle)
+	}
+}
+
 /// A `Trie` implementation using a generic `HashDB` backing database.
 ///
 /// Use it as a `TrieMut` trait object. You can use `db()` to get the backing database object.
 /// Note that changes are not committed to the database until `commit` is called.
-/// Querying the root or dropping the trie will commit automatically.
+/// Querying the root or dropping the trie will commit automatically, unless the trie
+/// is in change set mode (see `set_changeset_mode`).
 ///
 /// # Example
 /// ```
@@ -325,174 +725,279 @@ impl<'a, H> Index<&'a StorageHandle> for NodeStorage<H> {
 ///   assert!(!t.contains(b"foo").unwrap());
 /// }
 /// ```
-pub struct TrieDBMut<'a, H, C>
+pub struct TrieDBMut<'a, L>
 where
-	H: Hasher + 'a,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
-	storage: NodeStorage<H::Out>,
-	db: &'a mut dyn HashDB<H, DBValue>,
-	root: &'a mut H::Out,
-	root_handle: NodeHandle<H::Out>,
-	death_row: HashSet<(H::Out, NodeKey)>,
-	/// The number of hash operations this trie has performed.
-	/// Note that none are performed until changes are committed.
-	hash_count: usize,
-	marker: PhantomData<C>, // TODO: rpheimer: "we could have the NodeCodec trait take &self to its methods and then we don't need PhantomData. we can just store an instance of C: NodeCodec in the trie struct. If it's a ZST it won't have any additional overhead anyway"
+	db: &'a mut dyn HashDB<L::Hash, DBValue>,
+	root: &'a mut TrieHash<L>,
+	state: TrieState<L>,
+	/// Whether dropping the trie commits it when not in change set mode.
+	commit_on_drop: bool,
+	marker: PhantomData<L>, // TODO: rpheimer: "we could have the NodeCodec trait take &self to its methods and then we don't need PhantomData. we can just store an instance of C: NodeCodec in the trie struct. If it's a ZST it won't have any additional overhead anyway"
 }
 
-impl<'a, H, C> TrieDBMut<'a, H, C>
+impl<'a, L> TrieDBMut<'a, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
 	/// Create a new trie with backing database `db` and empty `root`.
-	pub fn new(db: &'a mut dyn HashDB<H, DBValue>, root: &'a mut H::Out) -> Self {
-		*root = C::hashed_null_node();
-		let root_handle = NodeHandle::Hash(C::hashed_null_node());
-
-		TrieDBMut {
-			storage: NodeStorage::empty(),
-			db: db,
-			root: root,
-			root_handle: root_handle,
-			death_row: HashSet::new(),
-			hash_count: 0,
-			marker: PhantomData,
-		}
+	pub fn new(db: &'a mut dyn HashDB<L::Hash, DBValue>, root: &'a mut TrieHash<L>) -> Self {
+		check_value_nodes::<L>();
+		*root = L::Codec::hashed_null_node();
+		Self::with_state(db, root, TrieState::new(L::Codec::hashed_null_node()))
 	}
 
 	/// Create a new trie with the backing database `db` and `root.
 	/// Returns an error if `root` does not exist.
 	pub fn from_existing(
-		db: &'a mut dyn HashDB<H, DBValue>,
-		root: &'a mut H::Out,
-	) -> Result<Self, H::Out, C::Error> {
+		db: &'a mut dyn HashDB<L::Hash, DBValue>,
+		root: &'a mut TrieHash<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		check_value_nodes::<L>();
 		if !db.contains(root, nibbleslice::EMPTY_ENCODED) {
 			return Err(Box::new(TrieError::InvalidStateRoot(*root)));
 		}
 
-		let root_handle = NodeHandle::Hash(*root);
-		Ok(TrieDBMut {
-			storage: NodeStorage::empty(),
-			db: db,
-			root: root,
-			root_handle: root_handle,
-			death_row: HashSet::new(),
-			hash_count: 0,
+		let state = TrieState::new(*root);
+		Ok(Self::with_state(db, root, state))
+	}
+
+	// a trie working on `db` and `root` from the given in-memory state.
+	fn with_state(db: &'a mut dyn HashDB<L::Hash, DBValue>, root: &'a mut TrieHash<L>, state: TrieState<L>) -> Self {
+		TrieDBMut {
+			db,
+			root,
+			state,
+			commit_on_drop: true,
 			marker: PhantomData,
-		})
+		}
 	}
+
 	/// Get the backing database.
-	pub fn db(&self) -> &dyn HashDB<H, DBValue> {
+	pub fn db(&self) -> &dyn HashDB<L::Hash, DBValue> {
 		self.db
 	}
 
 	/// Get the backing database mutably.
-	pub fn db_mut(&mut self) -> &mut dyn HashDB<H, DBValue> {
+	pub fn db_mut(&mut self) -> &mut dyn HashDB<L::Hash, DBValue> {
 		self.db
 	}
 
+	/// Record the current uncommitted state of the trie, so that later changes
+	/// can be undone with `rollback_to`. Savepoints nest: rolling back to or releasing
+	/// a savepoint does the same to those made after it.
+	///
+	/// Committing the trie, which also happens when querying the root,
+	/// releases all savepoints.
+	pub fn savepoint(&mut self) -> Savepoint {
+		let id = self.state.savepoint_count;
+		self.state.savepoint_count += 1;
+		let state = SavepointState {
+			id,
+			root: *self.root,
+			root_handle: self.root_handle(),
+			storage: self.state.storage.checkpoint(),
+			death_row: self.state.death_row_journal.len(),
+		};
+		self.state.savepoints.push(state);
+		Savepoint { index: self.state.savepoints.len() - 1, id }
+	}
+
+	/// Undo all changes made since the savepoint was made, and release it.
+	///
+	/// Panics if the savepoint has been released.
+	pub fn rollback_to(&mut self, savepoint: Savepoint) {
+		self.check_savepoint(&savepoint);
+		let state = self.state.savepoints.drain(savepoint.index..).next().expect("index checked above; qed");
+
+		self.state.storage.revert(state.storage);
+		for entry in self.state.death_row_journal.drain(state.death_row..) {
+			self.state.death_row.remove(&entry);
+		}
+		*self.root = state.root;
+		self.state.root_handle = state.root_handle;
+		self.clear_journals();
+	}
+
+	/// Release a savepoint, keeping the changes made since.
+	///
+	/// Panics if the savepoint has been released.
+	pub fn release(&mut self, savepoint: Savepoint) {
+		self.check_savepoint(&savepoint);
+		self.state.savepoints.truncate(savepoint.index);
+		self.clear_journals();
+	}
+
+	// panic unless the savepoint is still held, its index possibly having been reused
+	// by a later one.
+	fn check_savepoint(&self, savepoint: &Savepoint) {
+		let held = self.state.savepoints.get(savepoint.index).map(|state| state.id) == Some(savepoint.id);
+		assert!(held, "savepoint has already been released");
+	}
+
+	// stop journaling changes once no savepoint needs them.
+	fn clear_journals(&mut self) {
+		if self.state.savepoints.is_empty() {
+			self.state.storage.clear_journal();
+			self.state.death_row_journal.clear();
+		}
+	}
+
+	// mark a node of the database for removal on commit.
+	fn kill(&mut self, hash: TrieHash<L>, prefix: NodeKey) {
+		if self.state.death_row.insert((hash, prefix.clone())) && !self.state.savepoints.is_empty() {
+			self.state.death_row_journal.push((hash, prefix));
+		}
+	}
+
+	// mark the value node of a value removed from the trie, if it has one, for removal
+	// on commit. `key` is the encoded key of the value.
+	fn kill_value(&mut self, value: &NodeValue<TrieHash<L>>, key: NodeKey) {
+		if let Some(hash) = value.hash() {
+			self.kill(hash, key);
+		}
+	}
+
+	/// Insert a sequence of key-value pairs. See `apply_batch`.
+	pub fn extend_sorted<I, K, V>(&mut self, items: I) -> Result<(), TrieHash<L>, CError<L>>
+	where
+		I: IntoIterator<Item = (K, V)>,
+		K: AsRef<[u8]>,
+		V: AsRef<[u8]>,
+	{
+		self.apply_batch(items.into_iter().map(|(k, v)| (k, Some(v))))
+	}
+
+	/// Apply a batch of changes, where `Some(value)` inserts a value and `None` removes it.
+	/// Every node touched by the batch is restructured once, rather than once per change.
+	///
+	/// The changes are expected in ascending key order; other input is sorted first.
+	/// When a key is changed several times, the last change wins.
+	pub fn apply_batch<I, K, V>(&mut self, changes: I) -> Result<(), TrieHash<L>, CError<L>>
+	where
+		I: IntoIterator<Item = (K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: AsRef<[u8]>,
+	{
+		let mut changes: Vec<Change<TrieHash<L>>> = changes.into_iter()
+			.map(|(k, v)| {
+				// inserting an empty value is a removal.
+				let v = v.and_then(|v| match v.as_ref().is_empty() {
+					true => None,
+					false => Some(NodeValue::Inline(DBValue::from_slice(v.as_ref()))),
+				});
+				(k.as_ref().to_vec(), v)
+			})
+			.collect();
+		// reversing first makes the stable sort keep the last change to a key first.
+		changes.reverse();
+		changes.sort_by(|a, b| a.0.cmp(&b.0));
+		changes.dedup_by(|a, b| a.0 == b.0);
+		if changes.is_empty() {
+			return Ok(());
+		}
+
+		trace!(target: "trie", "apply_batch: {} changes", changes.len());
+
+		let root_handle = self.root_handle();
+		match self.batch_at(root_handle, &changes, 0)? {
+			Some((handle, changed)) => {
+				trace!(target: "trie", "apply_batch: altered trie={}", changed);
+				self.state.root_handle = NodeHandle::InMemory(handle);
+			}
+			None => {
+				trace!(target: "trie", "apply_batch: obliterated trie");
+				self.state.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
+				*self.root = L::Codec::hashed_null_node();
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Remove the entries whose key starts with `prefix`, at most `limit` of them if given,
+	/// passing each removed key to `removed`. Whole subtrees are detached from the trie at once.
+	/// Returns the number of entries removed.
+	pub fn remove_prefix_with<F>(&mut self, prefix: &[u8], limit: Option<usize>, mut removed: F) -> Result<usize, TrieHash<L>, CError<L>>
+	where F: FnMut(&[u8])
+	{
+		trace!(target: "trie", "remove_prefix: prefix={:#x?}, limit={:?}", prefix, limit);
+
+		let limit = limit.unwrap_or(usize::max_value());
+		let mut count = 0;
+		if limit == 0 {
+			return Ok(0);
+		}
+
+		let root_handle = self.root_handle();
+		let path = NodeKey::from_slice(nibbleslice::EMPTY_ENCODED);
+		match self.remove_prefix_at(root_handle, &path, NibbleSlice::new(prefix), limit, &mut count, &mut removed)? {
+			Some((handle, changed)) => {
+				trace!(target: "trie", "remove_prefix: altered trie={}", changed);
+				self.state.root_handle = NodeHandle::InMemory(handle);
+			}
+			None => {
+				trace!(target: "trie", "remove_prefix: obliterated trie");
+				self.state.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
+				*self.root = L::Codec::hashed_null_node();
+			}
+		}
+
+		Ok(count)
+	}
+
 	// cache a node by hash
-	fn cache(&mut self, hash: H::Out, key: &[u8]) -> Result<StorageHandle, H::Out, C::Error> {
+	fn cache(&mut self, hash: TrieHash<L>, key: &[u8]) -> Result<StorageHandle, TrieHash<L>, CError<L>> {
 		let node_encoded = self.db.get(&hash, key).ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash)))?;
-		let node = Node::from_encoded::<C, H>(
-			&node_encoded,
-			&*self.db,
-			&mut self.storage
-		);
-		Ok(self.storage.alloc(Stored::Cached(node, hash)))
+		let node = Node::from_encoded::<L>(&node_encoded, &mut self.state.storage)?;
+		Ok(self.state.storage.alloc(Stored::Cached(node, hash)))
 	}
 
 	// inspect a node, choosing either to replace, restore, or delete it.
 	// if restored or replaced, returns the new node along with a flag of whether it was changed.
-	fn inspect<F>(&mut self, stored: Stored<H::Out>, key: &mut Partial, inspector: F) -> Result<Option<(Stored<H::Out>, bool)>, H::Out, C::Error>
-	where F: FnOnce(&mut Self, Node<H::Out>, &mut Partial) -> Result<Action<H::Out>, H::Out, C::Error> {
+	fn inspect<F>(&mut self, stored: Stored<TrieHash<L>>, key: &mut Partial, inspector: F) -> Result<Option<(Stored<TrieHash<L>>, bool)>, TrieHash<L>, CError<L>>
+	where F: FnOnce(&mut Self, Node<TrieHash<L>>, &mut Partial) -> Result<Action<TrieHash<L>>, TrieHash<L>, CError<L>> {
 		Ok(match stored {
 			Stored::New(node) => match inspector(self, node, key)? {
 				Action::Restore(node) => Some((Stored::New(node), false)),
 				Action::Replace(node) => Some((Stored::New(node), true)),
 				Action::Delete => None,
 			},
-			Stored::Cached(node, hash) => match inspector(self, node, key)? {
-				Action::Restore(node) => Some((Stored::Cached(node, hash), false)),
-				Action::Replace(node) => {
-					self.death_row.insert((hash, key.encoded_prefix()));
-					Some((Stored::New(node), true))
-				}
-				Action::Delete => {
-					self.death_row.insert((hash, key.encoded_prefix()));
-					None
-				}
-			},
-		})
-	}
-
-	// walk the trie, attempting to find the key's node.
-	fn lookup<'x, 'key>(&'x self, mut partial: NibbleSlice<'key>, handle: &NodeHandle<H::Out>) -> Result<Option<DBValue>, H::Out, C::Error>
-		where 'x: 'key
-	{
-		let mut handle = handle;
-		loop {
-			let (mid, child) = match *handle {
-				NodeHandle::Hash(ref hash) => return Lookup {
-					db: &self.db,
-					query: DBValue::from_slice,
-					hash: hash.clone(),
-					marker: PhantomData::<C>,
-				}.look_up(partial),
-				NodeHandle::InMemory(ref handle) => match self.storage[handle] {
-					Node::Empty => return Ok(None),
-					Node::Leaf(ref key, ref value) => {
-						if NibbleSlice::from_encoded(key).0 == partial {
-							return Ok(Some(DBValue::from_slice(value)));
-						} else {
-							return Ok(None);
-						}
-					}
-					Node::Extension(ref slice, ref child) => {
-						let slice = NibbleSlice::from_encoded(slice).0;
-						if partial.starts_with(&slice) {
-							(slice.len(), child)
-						} else {
-							return Ok(None);
-						}
+			Stored::Cached(node, hash) => {
+				// the inspector advances the key past the node.
+				let prefix = key.encoded_prefix();
+				match inspector(self, node, key)? {
+					Action::Restore(node) => Some((Stored::Cached(node, hash), false)),
+					Action::Replace(node) => {
+						self.kill(hash, prefix);
+						Some((Stored::New(node), true))
 					}
-					Node::Branch(ref children, ref value) => {
-						if partial.is_empty() {
-							return Ok(value.as_ref().map(|v| DBValue::from_slice(v)));
-						} else {
-							let idx = partial.at(0);
-							match children[idx as usize].as_ref() {
-								Some(child) => (1, child),
-								None => return Ok(None),
-							}
-						}
+					Action::Delete => {
+						self.kill(hash, prefix);
+						None
 					}
 				}
-			};
-
-			partial = partial.mid(mid);
-			handle = child;
-		}
+			},
+		})
 	}
 
 	/// insert a key-value pair into the trie, creating new nodes if necessary.
-	fn insert_at(&mut self, handle: NodeHandle<H::Out>, key: &mut Partial, value: DBValue, old_val: &mut Option<DBValue>) -> Result<(StorageHandle, bool), H::Out, C::Error> {
+	fn insert_at(&mut self, handle: NodeHandle<TrieHash<L>>, key: &mut Partial, value: NodeValue<TrieHash<L>>, old_val: &mut Option<NodeValue<TrieHash<L>>>) -> Result<(StorageHandle, bool), TrieHash<L>, CError<L>> {
 		let h = match handle {
 			NodeHandle::InMemory(h) => h,
 			NodeHandle::Hash(h) => self.cache(h, &key.encoded_prefix())?,
 		};
-		let stored = self.storage.destroy(h);
+		let stored = self.state.storage.destroy(h);
 		let (new_stored, changed) = self.inspect(stored, key, move |trie, stored, key| {
 			trie.insert_inspector(stored, key, value, old_val).map(|a| a.into_action())
 		})?.expect("Insertion never deletes.");
 
-		Ok((self.storage.alloc(new_stored), changed))
+		Ok((self.state.storage.alloc(new_stored), changed))
 	}
 
 	/// the insertion inspector.
-	fn insert_inspector(&mut self, node: Node<H::Out>, key: &mut Partial, value: DBValue, old_val: &mut Option<DBValue>) -> Result<InsertAction<H::Out>, H::Out, C::Error> {
+	fn insert_inspector(&mut self, node: Node<TrieHash<L>>, key: &mut Partial, value: NodeValue<TrieHash<L>>, old_val: &mut Option<NodeValue<TrieHash<L>>>) -> Result<InsertAction<TrieHash<L>>, TrieHash<L>, CError<L>> {
 		let partial = key.mid();
 		trace!(target: "trie", "augmented (partial: {:?}, value: {:#x?})", partial, value);
 
@@ -505,14 +1010,16 @@ where
 				trace!(target: "trie", "branch: ROUTE,AUGMENT");
 
 				if partial.is_empty() {
-					let unchanged = stored_value.as_ref() == Some(&value);
-					let branch = Node::Branch(children, Some(value));
-					*old_val = stored_value;
-
-					match unchanged {
-						true => InsertAction::Restore(branch),
-						false => InsertAction::Replace(branch),
+					if let Some(ref stored) = stored_value {
+						if stored.same_as::<L>(&value) {
+							// keep the stored value, which may not have been fetched.
+							*old_val = Some(value);
+							return Ok(InsertAction::Restore(Node::Branch(children, stored_value)));
+						}
+						self.kill_value(stored, key.encoded_prefix());
 					}
+					*old_val = stored_value;
+					InsertAction::Replace(Node::Branch(children, Some(value)))
 				} else {
 					let idx = partial.at(0) as usize;
 					key.advance(1);
@@ -526,7 +1033,7 @@ where
 						}
 					} else {
 						// original had nothing there. compose a leaf.
-						let leaf = self.storage.alloc(Stored::New(Node::Leaf(key.mid().encoded(true), value)));
+						let leaf = self.state.storage.alloc(Stored::New(Node::Leaf(key.mid().encoded(true), value)));
 						children[idx] = Some(leaf.into());
 					}
 
@@ -539,13 +1046,14 @@ where
 				if cp == existing_key.len() && cp == partial.len() {
 					trace!(target: "trie", "equivalent-leaf: REPLACE");
 					// equivalent leaf.
-					let unchanged = stored_value == value;
-					*old_val = Some(stored_value);
-
-					match unchanged {
-						// unchanged. restore
-						true => InsertAction::Restore(Node::Leaf(encoded.clone(), value)),
-						false => InsertAction::Replace(Node::Leaf(encoded.clone(), value)),
+					if stored_value.same_as::<L>(&value) {
+						// unchanged. restore, keeping the stored value which may not have been fetched.
+						*old_val = Some(value);
+						InsertAction::Restore(Node::Leaf(encoded, stored_value))
+					} else {
+						self.kill_value(&stored_value, key.encoded_key());
+						*old_val = Some(stored_value);
+						InsertAction::Replace(Node::Leaf(encoded, value))
 					}
 				} else if cp == 0 {
 					trace!(target: "trie", "no-common-prefix, not-both-empty (exist={:?}; new={:?}): TRANSMUTE,AUGMENT", existing_key.len(), partial.len());
@@ -558,7 +1066,7 @@ where
 					} else {
 						let idx = existing_key.at(0) as usize;
 						let new_leaf = Node::Leaf(existing_key.mid(1).encoded(true), stored_value);
-						children[idx] = Some(self.storage.alloc(Stored::New(new_leaf)).into());
+						children[idx] = Some(self.state.storage.alloc(Stored::New(new_leaf)).into());
 
 						Node::Branch(children, None)
 					};
@@ -577,7 +1085,7 @@ where
 					let branch = self.insert_inspector(branch, key, value, old_val)?.unwrap_node();
 
 					// always replace since we took a leaf and made an extension.
-					let branch_handle = self.storage.alloc(Stored::New(branch)).into();
+					let branch_handle = self.state.storage.alloc(Stored::New(branch)).into();
 					InsertAction::Replace(Node::Extension(existing_key.encoded(false), branch_handle))
 				} else {
 					trace!(target: "trie", "partially-shared-prefix (exist={:?}; new={:?}; cp={:?}): AUGMENT-AT-END", existing_key.len(), partial.len(), cp);
@@ -594,7 +1102,7 @@ where
 					// make an extension using it. this is a replacement.
 					InsertAction::Replace(Node::Extension(
 						existing_key.encoded_leftmost(cp, false),
-						self.storage.alloc(Stored::New(augmented_low)).into()
+						self.state.storage.alloc(Stored::New(augmented_low)).into()
 					))
 				}
 			}
@@ -616,7 +1124,7 @@ where
 					} else {
 						// more work required after branching.
 						let ext = Node::Extension(existing_key.mid(1).encoded(false), child_branch);
-						Some(self.storage.alloc(Stored::New(ext)).into())
+						Some(self.state.storage.alloc(Stored::New(ext)).into())
 					};
 
 					// continue inserting.
@@ -650,7 +1158,7 @@ where
 					// this is known because the partial key is only the common prefix.
 					InsertAction::Replace(Node::Extension(
 						existing_key.encoded_leftmost(cp, false),
-						self.storage.alloc(Stored::New(augmented_low)).into()
+						self.state.storage.alloc(Stored::New(augmented_low)).into()
 					))
 				}
 			}
@@ -658,27 +1166,28 @@ where
 	}
 
 	/// Remove a node from the trie based on key.
-	fn remove_at(&mut self, handle: NodeHandle<H::Out>, key: &mut Partial, old_val: &mut Option<DBValue>) -> Result<Option<(StorageHandle, bool)>, H::Out, C::Error> {
+	fn remove_at(&mut self, handle: NodeHandle<TrieHash<L>>, key: &mut Partial, old_val: &mut Option<NodeValue<TrieHash<L>>>) -> Result<Option<(StorageHandle, bool)>, TrieHash<L>, CError<L>> {
 		let stored = match handle {
-			NodeHandle::InMemory(h) => self.storage.destroy(h),
+			NodeHandle::InMemory(h) => self.state.storage.destroy(h),
 			NodeHandle::Hash(h) => {
 				let handle = self.cache(h, &key.encoded_prefix())?;
-				self.storage.destroy(handle)
+				self.state.storage.destroy(handle)
 			}
 		};
 
 		let opt = self.inspect(stored, key, move |trie, node, key| trie.remove_inspector(node, key, old_val))?;
 
-		Ok(opt.map(|(new, changed)| (self.storage.alloc(new), changed)))
+		Ok(opt.map(|(new, changed)| (self.state.storage.alloc(new), changed)))
 	}
 
 	/// the removal inspector
-	fn remove_inspector(&mut self, node: Node<H::Out>, key: &mut Partial, old_val: &mut Option<DBValue>) -> Result<Action<H::Out>, H::Out, C::Error> {
+	fn remove_inspector(&mut self, node: Node<TrieHash<L>>, key: &mut Partial, old_val: &mut Option<NodeValue<TrieHash<L>>>) -> Result<Action<TrieHash<L>>, TrieHash<L>, CError<L>> {
 		let partial = key.mid();
 		Ok(match (node, partial.is_empty()) {
 			(Node::Empty, _) => Action::Delete,
 			(Node::Branch(c, None), true) => Action::Restore(Node::Branch(c, None)),
 			(Node::Branch(children, Some(val)), true) => {
+				self.kill_value(&val, key.encoded_prefix());
 				*old_val = Some(val);
 				// always replace since we took the value out.
 				Action::Replace(self.fix(Node::Branch(children, None), key.encoded_prefix())?)
@@ -715,6 +1224,7 @@ where
 			(Node::Leaf(encoded, value), _) => {
 				if NibbleSlice::from_encoded(&encoded).0 == partial {
 					// this is the node we were looking for. Let's delete it.
+					self.kill_value(&value, key.encoded_key());
 					*old_val = Some(value);
 					Action::Delete
 				} else {
@@ -758,24 +1268,365 @@ where
 		})
 	}
 
-	/// Given a node which may be in an _invalid state_, fix it such that it is then in a valid
-	/// state.
-	///
-	/// _invalid state_ means:
-	/// - Branch node where there is only a single entry;
-	/// - Extension node followed by anything other than a Branch node.
-	fn fix(&mut self, node: Node<H::Out>, key: NodeKey) -> Result<Node<H::Out>, H::Out, C::Error> {
-		match node {
-			Node::Branch(mut children, value) => {
-				// if only a single value, transmute to leaf/extension and feed through fixed.
-				#[derive(Debug)]
-				enum UsedIndex {
-					None,
-					One(u8),
-					Many,
-				};
-				let mut used_index = UsedIndex::None;
-				for i in 0..16 {
+	/// Remove the entries below the node at `path` whose remaining key starts with `prefix`,
+	/// until `count` reaches `limit`.
+	fn remove_prefix_at<F>(
+		&mut self,
+		handle: NodeHandle<TrieHash<L>>,
+		path: &NodeKey,
+		prefix: NibbleSlice,
+		limit: usize,
+		count: &mut usize,
+		removed: &mut F,
+	) -> Result<Option<(StorageHandle, bool)>, TrieHash<L>, CError<L>>
+	where F: FnMut(&[u8])
+	{
+		let stored = match handle {
+			NodeHandle::InMemory(h) => self.state.storage.destroy(h),
+			NodeHandle::Hash(h) => {
+				let handle = self.cache(h, path)?;
+				self.state.storage.destroy(handle)
+			}
+		};
+		let (node, hash) = match stored {
+			Stored::New(node) => (node, None),
+			Stored::Cached(node, hash) => (node, Some(hash)),
+		};
+
+		Ok(match self.remove_prefix_inspector(node, path, prefix, limit, count, removed)? {
+			Action::Restore(node) => {
+				let stored = match hash {
+					Some(hash) => Stored::Cached(node, hash),
+					None => Stored::New(node),
+				};
+				Some((self.state.storage.alloc(stored), false))
+			}
+			Action::Replace(node) => {
+				if let Some(hash) = hash {
+					self.kill(hash, path.clone());
+				}
+				Some((self.state.storage.alloc(Stored::New(node)), true))
+			}
+			Action::Delete => {
+				if let Some(hash) = hash {
+					self.kill(hash, path.clone());
+				}
+				None
+			}
+		})
+	}
+
+	/// the prefix removal inspector. An empty `prefix` removes everything below the node.
+	fn remove_prefix_inspector<F>(
+		&mut self,
+		node: Node<TrieHash<L>>,
+		path: &NodeKey,
+		prefix: NibbleSlice,
+		limit: usize,
+		count: &mut usize,
+		removed: &mut F,
+	) -> Result<Action<TrieHash<L>>, TrieHash<L>, CError<L>>
+	where F: FnMut(&[u8])
+	{
+		Ok(match node {
+			Node::Empty => Action::Delete,
+			Node::Leaf(encoded, value) => {
+				if *count < limit && NibbleSlice::from_encoded(&encoded).0.starts_with(&prefix) {
+					*count += 1;
+					let key = combine_encoded(path, &encoded);
+					removed(NibbleVec::from(NibbleSlice::from_encoded(&key).0).inner());
+					self.kill_value(&value, key);
+					Action::Delete
+				} else {
+					Action::Restore(Node::Leaf(encoded, value))
+				}
+			}
+			Node::Extension(encoded, child) => {
+				let rest = {
+					let existing = NibbleSlice::from_encoded(&encoded).0;
+					if existing.starts_with(&prefix) {
+						// the whole subtree is below the prefix.
+						Some(NibbleSlice::new(&[]))
+					} else if prefix.starts_with(&existing) {
+						Some(prefix.mid(existing.len()))
+					} else {
+						None
+					}
+				};
+				let rest = match rest {
+					Some(rest) => rest,
+					// diverges from the prefix -- nothing to do here.
+					None => return Ok(Action::Restore(Node::Extension(encoded, child))),
+				};
+
+				let child_path = combine_encoded(path, &encoded);
+				match self.remove_prefix_at(child, &child_path, rest, limit, count, removed)? {
+					Some((new_child, true)) => Action::Replace(self.fix(Node::Extension(encoded, new_child.into()), path.clone())?),
+					Some((new_child, false)) => Action::Restore(Node::Extension(encoded, new_child.into())),
+					None => Action::Delete,
+				}
+			}
+			Node::Branch(mut children, mut value) => {
+				let mut changed = false;
+				if prefix.is_empty() {
+					if *count < limit && value.is_some() {
+						*count += 1;
+						removed(NibbleVec::from(NibbleSlice::from_encoded(path).0).inner());
+						if let Some(value) = value.take() {
+							self.kill_value(&value, path.clone());
+						}
+						changed = true;
+					}
+					for i in 0..16 {
+						if *count >= limit {
+							break;
+						}
+						if let Some(child) = children[i].take() {
+							let child_path = combine_encoded(path, &NibbleSlice::new_offset(&[i as u8], 1).encoded(false));
+							children[i] = match self.remove_prefix_at(child, &child_path, prefix, limit, count, removed)? {
+								Some((new_child, child_changed)) => {
+									changed |= child_changed;
+									Some(new_child.into())
+								}
+								None => {
+									changed = true;
+									None
+								}
+							};
+						}
+					}
+				} else {
+					let idx = prefix.at(0) as usize;
+					if let Some(child) = children[idx].take() {
+						let child_path = combine_encoded(path, &NibbleSlice::new_offset(&[idx as u8], 1).encoded(false));
+						children[idx] = match self.remove_prefix_at(child, &child_path, prefix.mid(1), limit, count, removed)? {
+							Some((new_child, child_changed)) => {
+								changed = child_changed;
+								Some(new_child.into())
+							}
+							None => {
+								changed = true;
+								None
+							}
+						};
+					}
+				}
+
+				if !changed {
+					Action::Restore(Node::Branch(children, value))
+				} else if value.is_none() && children.iter().all(Option::is_none) {
+					Action::Delete
+				} else {
+					Action::Replace(self.fix(Node::Branch(children, value), path.clone())?)
+				}
+			}
+		})
+	}
+
+	/// Apply a sorted batch of changes below a node. All the keys share their first `depth` nibbles.
+	fn batch_at(&mut self, handle: NodeHandle<TrieHash<L>>, changes: &[Change<TrieHash<L>>], depth: usize) -> Result<Option<(StorageHandle, bool)>, TrieHash<L>, CError<L>> {
+		let mut key = Partial::new(NibbleSlice::new(&changes[0].0));
+		key.advance(depth);
+
+		if changes.len() == 1 {
+			let mut old_val = None;
+			return match changes[0].1 {
+				Some(ref value) => self.insert_at(handle, &mut key, value.clone(), &mut old_val).map(Some),
+				None => self.remove_at(handle, &mut key, &mut old_val),
+			};
+		}
+
+		let h = match handle {
+			NodeHandle::InMemory(h) => h,
+			NodeHandle::Hash(h) => self.cache(h, &key.encoded_prefix())?,
+		};
+		let stored = self.state.storage.destroy(h);
+		let opt = self.inspect(stored, &mut key, move |trie, node, key| trie.batch_inspector(node, key, changes))?;
+
+		Ok(opt.map(|(new, changed)| (self.state.storage.alloc(new), changed)))
+	}
+
+	/// the batch inspector. Only the prefix shared by all the changes is read from `key`.
+	fn batch_inspector(&mut self, node: Node<TrieHash<L>>, key: &mut Partial, changes: &[Change<TrieHash<L>>]) -> Result<Action<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let depth = key.split;
+
+		Ok(match node {
+			Node::Empty => {
+				// removals are no-ops here: build a subtree out of the insertions.
+				let first = match changes.iter().position(|c| c.1.is_some()) {
+					Some(first) => first,
+					None => return Ok(Action::Delete),
+				};
+				let last = changes.iter().rposition(|c| c.1.is_some()).expect("an insertion exists; qed");
+				let first_rest = rest(&changes[first], depth);
+				if first == last {
+					let value = changes[first].1.clone().expect("position of an insertion; qed");
+					return Ok(Action::Replace(Node::Leaf(first_rest.encoded(true), value)));
+				}
+
+				// keys are sorted, so the first and last insertions share the prefix of them all.
+				let cp = first_rest.common_prefix(&rest(&changes[last], depth));
+				let node = match cp {
+					0 => Node::Branch(empty_children(), None),
+					cp => {
+						let branch = self.state.storage.alloc(Stored::New(Node::Branch(empty_children(), None)));
+						Node::Extension(first_rest.encoded_leftmost(cp, false), branch.into())
+					}
+				};
+				match self.batch_inspector(node, key, changes)? {
+					Action::Replace(node) | Action::Restore(node) => Action::Replace(node),
+					Action::Delete => Action::Delete,
+				}
+			}
+			Node::Leaf(encoded, value) => {
+				// turn the leaf into one more insertion, unless a change to its key supersedes it.
+				let existing_key = NibbleSlice::from_encoded(&encoded).0;
+				let prefix = NibbleSlice::new(&changes[0].0);
+				let nibbles: Vec<u8> = (0..depth).map(|i| prefix.at(i)).chain(existing_key.iter()).collect();
+				let leaf_key: Vec<u8> = nibbles.chunks(2)
+					.map(|n| n[0] << 4 | n.get(1).cloned().unwrap_or(0))
+					.collect();
+
+				let mut merged = changes.to_vec();
+				match merged.binary_search_by(|c| c.0.cmp(&leaf_key)) {
+					Err(pos) => merged.insert(pos, (leaf_key, Some(value))),
+					Ok(pos) => match merged[pos].1 {
+						// keep the stored value, which may not have been fetched.
+						Some(ref mut change) if change.same_as::<L>(&value) => *change = value,
+						_ => self.kill_value(&value, NibbleSlice::new(&leaf_key).encoded(false)),
+					},
+				}
+				match self.batch_inspector(Node::Empty, key, &merged)? {
+					Action::Replace(node) | Action::Restore(node) => Action::Replace(node),
+					Action::Delete => Action::Delete,
+				}
+			}
+			Node::Extension(encoded, child_branch) => {
+				let (cp, existing_len) = {
+					let existing_key = NibbleSlice::from_encoded(&encoded).0;
+					let cp = changes.iter()
+						.filter(|c| c.1.is_some())
+						.map(|c| rest(c, depth).common_prefix(&existing_key))
+						.min()
+						.unwrap_or_else(|| existing_key.len());
+					(cp, existing_key.len())
+				};
+
+				if cp == existing_len {
+					// the changes not going through the extension remove missing keys.
+					let (start, end) = {
+						let existing_key = NibbleSlice::from_encoded(&encoded).0;
+						let below = |c: &Change<TrieHash<L>>| rest(c, depth).starts_with(&existing_key);
+						match changes.iter().position(&below) {
+							Some(start) => {
+								let len = changes[start..].iter().take_while(|c| below(c)).count();
+								(start, start + len)
+							}
+							None => return Ok(Action::Restore(Node::Extension(encoded, child_branch))),
+						}
+					};
+
+					let prefix = key.encoded_prefix();
+					match self.batch_at(child_branch, &changes[start..end], depth + existing_len)? {
+						Some((new_child, changed)) => {
+							let new_child = new_child.into();
+							match changed {
+								true => Action::Replace(self.fix(Node::Extension(encoded, new_child), prefix)?),
+								false => Action::Restore(Node::Extension(encoded, new_child)),
+							}
+						}
+						None => Action::Delete,
+					}
+				} else {
+					// split the extension where the first insertion leaves it.
+					let existing_key = NibbleSlice::from_encoded(&encoded).0;
+					let mut children = empty_children();
+					children[existing_key.at(cp) as usize] = if cp + 1 == existing_len {
+						Some(child_branch)
+					} else {
+						let ext = Node::Extension(existing_key.mid(cp + 1).encoded(false), child_branch);
+						Some(self.state.storage.alloc(Stored::New(ext)).into())
+					};
+					let node = match cp {
+						0 => Node::Branch(children, None),
+						cp => {
+							let branch = self.state.storage.alloc(Stored::New(Node::Branch(children, None)));
+							Node::Extension(existing_key.encoded_leftmost(cp, false), branch.into())
+						}
+					};
+					match self.batch_inspector(node, key, changes)? {
+						Action::Replace(node) | Action::Restore(node) => Action::Replace(node),
+						Action::Delete => Action::Delete,
+					}
+				}
+			}
+			Node::Branch(mut children, mut value) => {
+				let mut changed = false;
+				let mut i = 0;
+
+				// a key ending here comes first and changes the branch value.
+				if rest(&changes[0], depth).is_empty() {
+					let unchanged = match (&changes[0].1, &value) {
+						(Some(change), Some(value)) => change.same_as::<L>(value),
+						(change, value) => change.is_none() && value.is_none(),
+					};
+					if !unchanged {
+						if let Some(ref value) = value {
+							self.kill_value(value, key.encoded_prefix());
+						}
+						value = changes[0].1.clone();
+						changed = true;
+					}
+					i = 1;
+				}
+
+				// the other changes are grouped by the child they go to.
+				while i < changes.len() {
+					let idx = rest(&changes[i], depth).at(0);
+					let len = changes[i..].iter().take_while(|c| rest(c, depth).at(0) == idx).count();
+					let (child, existed) = match children[idx as usize].take() {
+						Some(child) => (child, true),
+						None => (self.state.storage.alloc(Stored::New(Node::Empty)).into(), false),
+					};
+					match self.batch_at(child, &changes[i..i + len], depth + 1)? {
+						Some((new_child, child_changed)) => {
+							children[idx as usize] = Some(new_child.into());
+							changed |= child_changed;
+						}
+						None => changed |= existed,
+					}
+					i += len;
+				}
+
+				if !changed {
+					Action::Restore(Node::Branch(children, value))
+				} else if value.is_none() && children.iter().all(Option::is_none) {
+					Action::Delete
+				} else {
+					Action::Replace(self.fix(Node::Branch(children, value), key.encoded_prefix())?)
+				}
+			}
+		})
+	}
+
+	/// Given a node which may be in an _invalid state_, fix it such that it is then in a valid
+	/// state.
+	///
+	/// _invalid state_ means:
+	/// - Branch node where there is only a single entry;
+	/// - Extension node followed by anything other than a Branch node.
+	fn fix(&mut self, node: Node<TrieHash<L>>, key: NodeKey) -> Result<Node<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		match node {
+			Node::Branch(mut children, value) => {
+				// if only a single value, transmute to leaf/extension and feed through fixed.
+				#[derive(Debug)]
+				enum UsedIndex {
+					None,
+					One(u8),
+					Many,
+				};
+				let mut used_index = UsedIndex::None;
+				for i in 0..16 {
 					match (children[i].is_none(), &used_index) {
 						(false, &UsedIndex::None) => used_index = UsedIndex::One(i as u8),
 						(false, &UsedIndex::One(_)) => {
@@ -809,10 +1660,10 @@ where
 			}
 			Node::Extension(partial, child) => {
 				let stored = match child {
-					NodeHandle::InMemory(h) => self.storage.destroy(h),
+					NodeHandle::InMemory(h) => self.state.storage.destroy(h),
 					NodeHandle::Hash(h) => {
 						let handle = self.cache(h, &combine_encoded(&key, &partial))?;
-						self.storage.destroy(handle)
+						self.state.storage.destroy(handle)
 					}
 				};
 
@@ -826,7 +1677,7 @@ where
 						// combine with node below.
 						if let Some(hash) = maybe_hash {
 							// delete the cached child since we are going to replace it.
-							self.death_row.insert((hash, key.clone()));
+							self.kill(hash, combine_encoded(&key, &partial));
 						}
 						let partial = NibbleSlice::from_encoded(&partial).0;
 						let sub_partial = NibbleSlice::from_encoded(&sub_partial).0;
@@ -840,7 +1691,7 @@ where
 						// combine with node below.
 						if let Some(hash) = maybe_hash {
 							// delete the cached child since we are going to replace it.
-							self.death_row.insert((hash, key));
+							self.kill(hash, combine_encoded(&key, &partial));
 						}
 						let partial = NibbleSlice::from_encoded(&partial).0;
 						let sub_partial = NibbleSlice::from_encoded(&sub_partial).0;
@@ -853,13 +1704,18 @@ where
 						trace!(target: "trie", "fixing: restoring extension");
 
 						// reallocate the child node.
-						let stored = if let Some(hash) = maybe_hash {
-							Stored::Cached(child_node, hash)
-						} else {
-							Stored::New(child_node)
+						let stored = match maybe_hash {
+							// without extension nodes the partial key is stored in the branch,
+							// so the cached branch changes along with this extension.
+							Some(hash) if !L::USE_EXTENSION => {
+								self.kill(hash, combine_encoded(&key, &partial));
+								Stored::New(child_node)
+							}
+							Some(hash) => Stored::Cached(child_node, hash),
+							None => Stored::New(child_node),
 						};
 
-						Ok(Node::Extension(partial, self.storage.alloc(stored).into()))
+						Ok(Node::Extension(partial, self.state.storage.alloc(stored).into()))
 					}
 				}
 			}
@@ -869,12 +1725,18 @@ where
 
 	/// Commit the in-memory changes to disk, freeing their storage and
 	/// updating the state root.
+	///
+	/// In change set mode, the changes held for the next change set are written as well.
 	pub fn commit(&mut self) {
 		trace!(target: "trie", "Committing trie changes to db.");
 
+		self.state.savepoints.clear();
+		self.clear_journals();
+		self.write_changeset();
+
 		// always kill all the nodes on death row.
-		trace!(target: "trie", "{:?} nodes to remove from db", self.death_row.len());
-		for (hash, prefix) in self.death_row.drain() {
+		trace!(target: "trie", "{:?} nodes to remove from db", self.state.death_row.len());
+		for (hash, prefix) in self.state.death_row.drain() {
 			self.db.remove(&hash, &prefix);
 		}
 
@@ -883,99 +1745,456 @@ where
 			NodeHandle::InMemory(h) => h,
 		};
 
-		match self.storage.destroy(handle) {
+		match self.state.storage.destroy(handle) {
 			Stored::New(node) => {
-				let encoded_root = node.into_encoded::<_, C, H>(|child, k| {
+				let db = &mut *self.db;
+				let hash_count = &mut self.state.hash_count;
+				let mut insert = |prefix: &[u8], encoded: &[u8]| {
+					*hash_count += 1;
+					db.insert(prefix, encoded)
+				};
+
+				let encoded_root = node.into_encoded::<_, L>(&mut self.state.storage, |storage, child, k| {
 					let combined = combine_encoded(nibbleslice::EMPTY_ENCODED, k);
-					self.commit_child(child, &combined)
+					commit_child::<L, _>(storage, child, &combined, &mut insert)
 				});
 				trace!(target: "trie", "encoded root node: {:#x?}", &encoded_root[..]);
 
-				*self.root = self.db.insert(nibbleslice::EMPTY_ENCODED, &encoded_root[..]);
-				self.hash_count += 1;
+				*self.root = insert(nibbleslice::EMPTY_ENCODED, &encoded_root[..]);
 
-				self.root_handle = NodeHandle::Hash(*self.root);
+				self.state.root_handle = NodeHandle::Hash(*self.root);
 			}
 			Stored::Cached(node, hash) => {
 				// probably won't happen, but update the root and move on.
 				*self.root = hash;
-				self.root_handle = NodeHandle::InMemory(self.storage.alloc(Stored::Cached(node, hash)));
+				self.state.root_handle = NodeHandle::InMemory(self.state.storage.alloc(Stored::Cached(node, hash)));
 			}
 		}
 	}
 
-	/// Commit a node by hashing it and writing it to the db. Returns a
-	/// `ChildReference` which in most cases carries a normal hash but for the
-	/// case where we can fit the actual data in the `Hasher`s output type, we
-	/// store the data inline. This function is used as the callback to the
-	/// `into_encoded` method of `Node`.
-	fn commit_child(&mut self, handle: NodeHandle<H::Out>, prefix: &NodeKey) -> ChildReference<H::Out> {
-		match handle {
-			NodeHandle::Hash(hash) => ChildReference::Hash(hash),
-			NodeHandle::InMemory(storage_handle) => {
-				match self.storage.destroy(storage_handle) {
-					Stored::Cached(_, hash) => ChildReference::Hash(hash),
-					Stored::New(node) => {
-						let encoded = {
-							let commit_child = |node_handle, partial: &NodeKey| {
-								let combined = combine_encoded(&prefix, partial);
-								self.commit_child(node_handle, &combined)
-							};
-							node.into_encoded::<_, C, H>(commit_child)
-						};
-						if encoded.len() >= H::LENGTH {
-							let hash = self.db.insert(&prefix, &encoded[..]);
-							self.hash_count +=1;
-							ChildReference::Hash(hash)
-						} else {
-							// it's a small value, so we cram it into a `H::Out` and tag with length
-							let mut h = H::Out::default();
-							let len = encoded.len();
-							h.as_mut()[..len].copy_from_slice(&encoded[..len]);
-							ChildReference::Inline(h, len)
-						}
+	/// Switch the trie to change set mode, in which querying the root or dropping the
+	/// trie never writes to the backing database. Querying the root commits the in-memory
+	/// changes to the next change set instead, and changes left uncommitted on drop are lost.
+	///
+	/// Committed nodes are kept in memory until the change set is applied, so that the trie
+	/// can still be read and changed meanwhile.
+	pub fn set_changeset_mode(&mut self) {
+		if self.state.changeset.is_none() {
+			self.state.changeset = Some(ChangeSet {
+				inserted: Vec::new(),
+				removed: Vec::new(),
+				root: *self.root,
+			});
+		}
+	}
+
+	/// Whether the trie is in change set mode. See `set_changeset_mode`.
+	pub fn is_changeset_mode(&self) -> bool {
+		self.state.changeset.is_some()
+	}
+
+	/// Commit the in-memory changes to a `ChangeSet` instead of the backing database,
+	/// switching the trie to change set mode if it is not already.
+	///
+	/// The change set holds all changes committed since the previous one, including those
+	/// committed by querying the root. Change sets must be applied to the database in order.
+	pub fn commit_changeset(&mut self) -> ChangeSet<TrieHash<L>> {
+		self.set_changeset_mode();
+		self.commit_to_changeset();
+
+		let next = ChangeSet {
+			inserted: Vec::new(),
+			removed: Vec::new(),
+			root: *self.root,
+		};
+		mem::replace(self.state.changeset.as_mut().expect("change set mode set above; qed"), next)
+	}
+
+	// commit the in-memory changes to the pending change set, keeping the committed nodes
+	// in memory as the database does not hold them yet.
+	fn commit_to_changeset(&mut self) {
+		trace!(target: "trie", "Committing trie changes to a change set.");
+
+		self.state.savepoints.clear();
+		self.clear_journals();
+
+		let changeset = match self.state.changeset {
+			Some(ref mut changeset) => changeset,
+			None => return,
+		};
+
+		trace!(target: "trie", "{:?} nodes to remove from db", self.state.death_row.len());
+		// nodes inserted since the last change set and killed since never reach the database.
+		let mut killed: HashSet<_> = self.state.death_row.drain().collect();
+		changeset.inserted.retain(|(hash, prefix, _)| !killed.remove(&(*hash, prefix.clone())));
+		changeset.removed.extend(killed);
+
+		if let NodeHandle::InMemory(ref handle) = self.state.root_handle {
+			let inserted = &mut changeset.inserted;
+			let hash_count = &mut self.state.hash_count;
+			let mut insert = |prefix: &[u8], encoded: &[u8]| {
+				let hash = L::Hash::hash(encoded);
+				inserted.push((hash, NodeKey::from_slice(prefix), DBValue::from_slice(encoded)));
+				*hash_count += 1;
+				hash
+			};
+
+			let prefix = NodeKey::from_slice(nibbleslice::EMPTY_ENCODED);
+			*self.root = match commit_node_cached::<L, _>(&mut self.state.storage, &self.state.root_handle, &prefix, &mut insert) {
+				ChildReference::Hash(hash) => hash,
+				ChildReference::Inline(encoded_root) => {
+					// the root is stored even when small enough to be inlined.
+					let hash = insert(nibbleslice::EMPTY_ENCODED, &encoded_root[..]);
+					let stored = &mut self.state.storage.nodes[handle.0];
+					if let Stored::New(node) = mem::replace(stored, Stored::New(Node::Empty)) {
+						*stored = Stored::Cached(node, hash);
 					}
+					hash
+				}
+			};
+		}
+		changeset.root = *self.root;
+	}
+
+	// write the changes held for the next change set to the database.
+	fn write_changeset(&mut self) {
+		if let Some(ref mut changeset) = self.state.changeset {
+			let changes = ChangeSet {
+				inserted: mem::take(&mut changeset.inserted),
+				removed: mem::take(&mut changeset.removed),
+				root: changeset.root,
+			};
+			changes.apply_to(self.db);
+		}
+	}
+
+	/// Commit the in-memory changes to disk like `commit`, encoding and hashing the
+	/// subtrees below each child of the root branch on a thread pool. A root extension
+	/// is passed through to the branch below it.
+	/// Nodes are written to the database in the same order as by `commit`.
+	#[cfg(feature = "parallel")]
+	pub fn commit_parallel(&mut self) {
+		use rayon::prelude::*;
+
+		trace!(target: "trie", "Committing trie changes to db in parallel.");
+
+		self.state.savepoints.clear();
+		self.clear_journals();
+		self.write_changeset();
+
+		trace!(target: "trie", "{:?} nodes to remove from db", self.state.death_row.len());
+		for (hash, prefix) in self.state.death_row.drain() {
+			self.db.remove(&hash, &prefix);
+		}
+
+		let handle = match self.root_handle() {
+			NodeHandle::Hash(_) => return, // no changes necessary.
+			NodeHandle::InMemory(h) => h,
+		};
+
+		// share out the children of the root branch, or of the branch below a root
+		// extension, which also holds the partial key of a root branch in layouts
+		// without extension nodes.
+		let (partial, mut children, value) = match self.state.storage.destroy(handle) {
+			Stored::New(Node::Branch(children, value)) => (None, children, value),
+			Stored::New(Node::Extension(partial, NodeHandle::InMemory(child))) => match self.state.storage.destroy(child) {
+				Stored::New(Node::Branch(children, value)) => (Some(partial), children, value),
+				stored => {
+					let child = self.state.storage.alloc(stored).into();
+					self.state.root_handle = NodeHandle::InMemory(self.state.storage.alloc(Stored::New(Node::Extension(partial, child))));
+					return self.commit();
 				}
+			},
+			stored => {
+				// nothing to share out: commit on this thread.
+				self.state.root_handle = NodeHandle::InMemory(self.state.storage.alloc(stored));
+				return self.commit();
+			}
+		};
+		let branch_prefix = match partial {
+			Some(ref partial) => combine_encoded(nibbleslice::EMPTY_ENCODED, partial),
+			None => NodeKey::from_slice(nibbleslice::EMPTY_ENCODED),
+		};
+
+		// move every child subtree into a storage of its own.
+		let subtrees: Vec<_> = children.iter_mut()
+			.enumerate()
+			.filter_map(|(i, child)| child.take().map(|child| {
+				let mut storage = NodeStorage::empty();
+				let handle = self.state.storage.detach(child, &mut storage);
+				(i, storage, handle)
+			}))
+			.collect();
+
+		let committed: Vec<_> = subtrees.into_par_iter()
+			.map(|(i, mut storage, handle)| {
+				let prefix = combine_encoded(
+					&branch_prefix,
+					&NibbleSlice::new_offset(&[i as u8], 1).encoded(false),
+				);
+				let mut nodes = Vec::new();
+				let reference = commit_node::<L, _>(&mut storage, handle, &prefix, &mut |prefix, encoded| {
+					let hash = L::Hash::hash(encoded);
+					nodes.push((hash, NodeKey::from_slice(prefix), DBValue::from_slice(encoded)));
+					hash
+				});
+				(i, reference, nodes)
+			})
+			.collect();
+
+		// as by `commit`, a value node of the branch comes before the nodes below it.
+		let value = value.map(|value| match value {
+			NodeValue::Inline(ref data) if !L::is_inline_value(data.len()) => {
+				self.state.hash_count += 1;
+				NodeValue::Node(self.db.insert(&branch_prefix, data))
 			}
+			value => value,
+		});
+		let value = value.as_ref().map(NodeValue::as_encoded);
+
+		let mut references: Vec<Option<ChildReference<TrieHash<L>>>> = (0..16).map(|_| None).collect();
+		for (i, reference, nodes) in committed {
+			for (hash, prefix, encoded) in nodes {
+				self.db.emplace(hash, &prefix, encoded);
+				self.state.hash_count += 1;
+			}
+			references[i] = Some(reference);
 		}
+
+		let encoded_root = match (L::USE_EXTENSION, partial) {
+			(true, None) => L::Codec::branch_node(references.into_iter(), value),
+			(true, Some(partial)) => {
+				let encoded_branch = L::Codec::branch_node(references.into_iter(), value);
+				let child = if L::is_inline(encoded_branch.len()) {
+					ChildReference::Inline(DBValue::from_slice(&encoded_branch))
+				} else {
+					self.state.hash_count += 1;
+					ChildReference::Hash(self.db.insert(&branch_prefix, &encoded_branch))
+				};
+				L::Codec::ext_node(&partial, child)
+			}
+			(false, partial) => {
+				let partial = partial.unwrap_or_else(|| NibbleSlice::new(&[]).encoded(false));
+				L::Codec::branch_node_nibbled(&partial, references.into_iter(), value)
+			}
+		};
+		trace!(target: "trie", "encoded root node: {:#x?}", &encoded_root[..]);
+
+		*self.root = self.db.insert(nibbleslice::EMPTY_ENCODED, &encoded_root[..]);
+		self.state.hash_count += 1;
+
+		self.state.root_handle = NodeHandle::Hash(*self.root);
 	}
 
 	// a hack to get the root node's handle
-	fn root_handle(&self) -> NodeHandle<H::Out> {
-		match self.root_handle {
+	fn root_handle(&self) -> NodeHandle<TrieHash<L>> {
+		match self.state.root_handle {
 			NodeHandle::Hash(h) => NodeHandle::Hash(h),
-			NodeHandle::InMemory(StorageHandle(x)) => NodeHandle::InMemory(StorageHandle(x)),
+			NodeHandle::InMemory(ref storage) => NodeHandle::InMemory(StorageHandle(storage.0)),
 		}
 	}
 }
 
-impl<'a, H, C> TrieMut<H, C> for TrieDBMut<'a, H, C>
+/// Commit a node of `storage`, handing every encoded node which is too large to be inlined
+/// in its parent to `insert` along with its prefix, in post-order. `insert` returns the hash
+/// of the node.
+fn commit_node<L, F>(
+	storage: &mut NodeStorage<TrieHash<L>>,
+	handle: NodeHandle<TrieHash<L>>,
+	prefix: &NodeKey,
+	insert: &mut F,
+) -> ChildReference<TrieHash<L>>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout,
+	F: FnMut(&[u8], &[u8]) -> TrieHash<L>,
 {
-	fn root(&mut self) -> &H::Out {
-		self.commit();
-		self.root
+	match handle {
+		NodeHandle::Hash(hash) => ChildReference::Hash(hash),
+		NodeHandle::InMemory(storage_handle) => {
+			match storage.destroy(storage_handle) {
+				Stored::Cached(_, hash) => ChildReference::Hash(hash),
+				Stored::New(node) => {
+					let encoded = node.into_encoded::<_, L>(storage, |storage, child, partial| {
+						let combined = combine_encoded(prefix, partial);
+						commit_child::<L, F>(storage, child, &combined, insert)
+					});
+					if L::is_inline(encoded.len()) {
+						// it's a small value, so we cram it into its parent
+						ChildReference::Inline(DBValue::from_slice(&encoded))
+					} else {
+						ChildReference::Hash(insert(prefix, &encoded[..]))
+					}
+				}
+			}
+		}
 	}
+}
 
-	fn is_empty(&self) -> bool {
-		match self.root_handle {
-			NodeHandle::Hash(h) => h == C::hashed_null_node(),
-			NodeHandle::InMemory(ref h) => match self.storage[h] {
-				Node::Empty => true,
-				_ => false,
+/// Commit a child node of `storage` as `commit_node` does, or hand a value too large to
+/// be kept in its node to `insert`.
+fn commit_child<L, F>(
+	storage: &mut NodeStorage<TrieHash<L>>,
+	child: NodeToEncode<TrieHash<L>>,
+	prefix: &NodeKey,
+	insert: &mut F,
+) -> ChildReference<TrieHash<L>>
+where
+	L: TrieLayout,
+	F: FnMut(&[u8], &[u8]) -> TrieHash<L>,
+{
+	match child {
+		NodeToEncode::Node(handle) => commit_node::<L, F>(storage, handle, prefix, insert),
+		NodeToEncode::Value(value) => ChildReference::Hash(insert(prefix, value)),
+	}
+}
+
+/// Commit a node of `storage` as `commit_node` does, but keep the committed nodes in
+/// `storage`, turning those handed to `insert` into cached nodes.
+fn commit_node_cached<L, F>(
+	storage: &mut NodeStorage<TrieHash<L>>,
+	handle: &NodeHandle<TrieHash<L>>,
+	prefix: &NodeKey,
+	insert: &mut F,
+) -> ChildReference<TrieHash<L>>
+where
+	L: TrieLayout,
+	F: FnMut(&[u8], &[u8]) -> TrieHash<L>,
+{
+	let index = match *handle {
+		NodeHandle::Hash(hash) => return ChildReference::Hash(hash),
+		NodeHandle::InMemory(ref storage_handle) => storage_handle.0,
+	};
+	// the new values are stored first, as `into_encoded` does, and kept along with their
+	// hash. Without extension nodes, the value of an extension is in the branch below.
+	let (value_index, value_prefix) = match storage.nodes[index] {
+		Stored::Cached(_, hash) => return ChildReference::Hash(hash),
+		Stored::New(Node::Extension(ref partial, NodeHandle::InMemory(ref branch))) if !L::USE_EXTENSION => {
+			(branch.0, combine_encoded(prefix, partial))
+		}
+		Stored::New(_) => (index, prefix.clone()),
+	};
+	if let Stored::New(ref mut node) = storage.nodes[value_index] {
+		node.store_values::<L, F>(&value_prefix, insert);
+	}
+	// the copy shares the handles of the children, which are committed in place.
+	let node = storage[&StorageHandle(index)].shallow_copy();
+
+	let child_cb = |storage: &mut NodeStorage<TrieHash<L>>, child: NodeToEncode<TrieHash<L>>, partial: &NodeKey| {
+		let combined = combine_encoded(prefix, partial);
+		match child {
+			NodeToEncode::Node(handle) => commit_node_cached::<L, F>(storage, &handle, &combined, insert),
+			NodeToEncode::Value(value) => ChildReference::Hash(insert(&combined, value)),
+		}
+	};
+	let encoded = match node {
+		// without extension nodes, the branch below is encoded along with the extension,
+		// and must not be destroyed as `into_encoded` does.
+		Node::Extension(ref partial, NodeHandle::InMemory(ref branch)) if !L::USE_EXTENSION => {
+			match storage[branch].shallow_copy() {
+				Node::Branch(mut children, value) => {
+					Node::encode_branch::<_, L>(Some(partial), &mut children, value, storage, child_cb)
+				}
+				_ => unreachable!("an extension is always followed by a branch; qed"),
+			}
+		}
+		node => node.into_encoded::<_, L>(storage, child_cb),
+	};
+
+	if L::is_inline(encoded.len()) {
+		ChildReference::Inline(DBValue::from_slice(&encoded))
+	} else {
+		let hash = insert(prefix, &encoded[..]);
+		if let Stored::New(node) = mem::replace(&mut storage.nodes[index], Stored::New(Node::Empty)) {
+			storage.nodes[index] = Stored::Cached(node, hash);
+		}
+		ChildReference::Hash(hash)
+	}
+}
+
+// walk the trie held in `storage` and `db`, attempting to find the key's node.
+fn lookup<'x, 'key, L>(
+	db: &'x dyn HashDBRef<L::Hash, DBValue>,
+	storage: &'x NodeStorage<TrieHash<L>>,
+	key: NibbleSlice<'key>,
+	handle: &'x NodeHandle<TrieHash<L>>,
+) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
+where
+	'x: 'key,
+	L: TrieLayout,
+{
+	let mut partial = key;
+	let mut key_nibbles = 0;
+	let mut handle = handle;
+	loop {
+		let (mid, child) = match *handle {
+			NodeHandle::Hash(ref hash) => return Lookup {
+				db,
+				query: DBValue::from_slice,
+				hash: *hash,
+				#[cfg(feature = "std")]
+				cache: None,
+				marker: PhantomData::<L>,
+			}.look_up_from(key, key_nibbles),
+			NodeHandle::InMemory(ref handle) => match storage[handle] {
+				Node::Empty => return Ok(None),
+				Node::Leaf(ref encoded, ref value) => {
+					if NibbleSlice::from_encoded(encoded).0 == partial {
+						return Ok(Some(value.load::<L>(db, &key.encoded(false))?));
+					} else {
+						return Ok(None);
+					}
+				}
+				Node::Extension(ref slice, ref child) => {
+					let slice = NibbleSlice::from_encoded(slice).0;
+					if partial.starts_with(&slice) {
+						(slice.len(), child)
+					} else {
+						return Ok(None);
+					}
+				}
+				Node::Branch(ref children, ref value) => {
+					if partial.is_empty() {
+						return value.as_ref().map(|v| v.load::<L>(db, &key.encoded(false))).transpose();
+					} else {
+						let idx = partial.at(0);
+						match children[idx as usize].as_ref() {
+							Some(child) => (1, child),
+							None => return Ok(None),
+						}
+					}
+				}
 			}
+		};
+
+		partial = partial.mid(mid);
+		key_nibbles += mid;
+		handle = child;
+	}
+}
+
+impl<'a, L> TrieMut<L> for TrieDBMut<'a, L>
+where
+	L: TrieLayout
+{
+	fn root(&mut self) -> &TrieHash<L> {
+		match self.state.changeset {
+			Some(_) => self.commit_to_changeset(),
+			None => self.commit(),
 		}
+		self.root
+	}
+
+	fn is_empty(&self) -> bool {
+		self.state.is_empty()
 	}
 
-	fn get<'x, 'key>(&'x self, key: &'key [u8]) -> Result<Option<DBValue>, H::Out, C::Error>
+	fn get<'x, 'key>(&'x self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
 		where 'x: 'key
 	{
-		self.lookup(NibbleSlice::new(key), &self.root_handle)
+		self.state.get(&self.db, key)
 	}
 
-	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error> {
+	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
 		if value.is_empty() { return self.remove(key) }
 
 		let mut old_val = None;
@@ -986,17 +2205,22 @@ where
 		let (new_handle, changed) = self.insert_at(
 			root_handle,
 			&mut Partial::new(NibbleSlice::new(key)),
-			DBValue::from_slice(value),
+			NodeValue::Inline(DBValue::from_slice(value)),
 			&mut old_val,
 		)?;
 
 		trace!(target: "trie", "insert: altered trie={}", changed);
-		self.root_handle = NodeHandle::InMemory(new_handle);
+		self.state.root_handle = NodeHandle::InMemory(new_handle);
 
-		Ok(old_val)
+		// the trie is changed even if the old value cannot be fetched.
+		old_val.map(|v| v.load::<L>(&self.db, &NibbleSlice::new(key).encoded(false))).transpose()
 	}
 
-	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, H::Out, C::Error> {
+	fn remove_prefix(&mut self, prefix: &[u8], limit: Option<usize>) -> Result<usize, TrieHash<L>, CError<L>> {
+		self.remove_prefix_with(prefix, limit, |_| ())
+	}
+
+	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
 		trace!(target: "trie", "remove: key={:#x?}", key);
 
 		let root_handle = self.root_handle();
@@ -1006,26 +2230,223 @@ where
 		match self.remove_at(root_handle, &mut key, &mut old_val)? {
 			Some((handle, changed)) => {
 				trace!(target: "trie", "remove: altered trie={}", changed);
-				self.root_handle = NodeHandle::InMemory(handle);
+				self.state.root_handle = NodeHandle::InMemory(handle);
 			}
 			None => {
 				trace!(target: "trie", "remove: obliterated trie");
-				self.root_handle = NodeHandle::Hash(C::hashed_null_node());
-				*self.root = C::hashed_null_node();
+				self.state.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
+				*self.root = L::Codec::hashed_null_node();
 			}
 		}
 
-		Ok(old_val)
+		// the trie is changed even if the old value cannot be fetched.
+		old_val.map(|v| v.load::<L>(&self.db, &key.encoded_key())).transpose()
 	}
 }
 
-impl<'a, H, C> Drop for TrieDBMut<'a, H, C>
+impl<'a, L> Drop for TrieDBMut<'a, L>
 where
-	H: Hasher,
-	C: NodeCodec<H>
+	L: TrieLayout
 {
 	fn drop(&mut self) {
-		self.commit();
+		if self.commit_on_drop && self.state.changeset.is_none() {
+			self.commit();
+		}
+	}
+}
+
+/// A `TrieDBMut` owning its backing database and root.
+///
+/// Changes are kept in memory until `commit` is called, which also happens when
+/// querying the root. Unlike `TrieDBMut`, dropping the trie does not commit: use
+/// `commit` then `into_inner` to get the database and root back.
+///
+/// # Example
+/// ```
+/// extern crate trie_db;
+/// extern crate reference_trie;
+/// extern crate keccak_hasher;
+/// extern crate memory_db;
+///
+/// use reference_trie::{RefTrieDB, RefTrieDBMutOwned, Trie, TrieMut};
+/// use keccak_hasher::KeccakHasher;
+/// use memory_db::*;
+///
+/// fn main() {
+///   let mut t = RefTrieDBMutOwned::new(MemoryDB::<KeccakHasher, HashKey<_>, _>::default());
+///   t.insert(b"foo", b"bar").unwrap();
+///   t.commit();
+///   let (memdb, root) = t.into_inner();
+///   assert!(RefTrieDB::new(&memdb, &root).unwrap().contains(b"foo").unwrap());
+/// }
+/// ```
+pub struct TrieDBMutOwned<L, D>
+where
+	L: TrieLayout,
+	D: HashDB<L::Hash, DBValue>,
+{
+	db: D,
+	root: TrieHash<L>,
+	state: TrieState<L>,
+}
+
+impl<L, D> TrieDBMutOwned<L, D>
+where
+	L: TrieLayout,
+	D: HashDB<L::Hash, DBValue>,
+{
+	/// Create a new trie with backing database `db` and empty root.
+	pub fn new(db: D) -> Self {
+		check_value_nodes::<L>();
+		let root = L::Codec::hashed_null_node();
+		TrieDBMutOwned { db, root, state: TrieState::new(root) }
+	}
+
+	/// Create a new trie with the backing database `db` and `root`.
+	/// Returns an error if `root` does not exist.
+	pub fn from_existing(db: D, root: TrieHash<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		check_value_nodes::<L>();
+		if !db.contains(&root, nibbleslice::EMPTY_ENCODED) {
+			return Err(Box::new(TrieError::InvalidStateRoot(root)));
+		}
+
+		Ok(TrieDBMutOwned { db, root, state: TrieState::new(root) })
+	}
+
+	/// Get the backing database.
+	pub fn db(&self) -> &D {
+		&self.db
+	}
+
+	/// Get the backing database mutably.
+	pub fn db_mut(&mut self) -> &mut D {
+		&mut self.db
+	}
+
+	/// Give back the backing database and the root as of the last commit. Changes
+	/// made since then are dropped.
+	pub fn into_inner(self) -> (D, TrieHash<L>) {
+		(self.db, self.root)
+	}
+
+	/// See `TrieDBMut::savepoint`.
+	pub fn savepoint(&mut self) -> Savepoint {
+		self.with_trie(|trie| trie.savepoint())
+	}
+
+	/// See `TrieDBMut::rollback_to`.
+	pub fn rollback_to(&mut self, savepoint: Savepoint) {
+		self.with_trie(|trie| trie.rollback_to(savepoint))
+	}
+
+	/// See `TrieDBMut::release`.
+	pub fn release(&mut self, savepoint: Savepoint) {
+		self.with_trie(|trie| trie.release(savepoint))
+	}
+
+	/// See `TrieDBMut::extend_sorted`.
+	pub fn extend_sorted<I, K, V>(&mut self, items: I) -> Result<(), TrieHash<L>, CError<L>>
+	where
+		I: IntoIterator<Item = (K, V)>,
+		K: AsRef<[u8]>,
+		V: AsRef<[u8]>,
+	{
+		self.with_trie(|trie| trie.extend_sorted(items))
+	}
+
+	/// See `TrieDBMut::apply_batch`.
+	pub fn apply_batch<I, K, V>(&mut self, changes: I) -> Result<(), TrieHash<L>, CError<L>>
+	where
+		I: IntoIterator<Item = (K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: AsRef<[u8]>,
+	{
+		self.with_trie(|trie| trie.apply_batch(changes))
+	}
+
+	/// See `TrieDBMut::remove_prefix_with`.
+	pub fn remove_prefix_with<F>(&mut self, prefix: &[u8], limit: Option<usize>, removed: F) -> Result<usize, TrieHash<L>, CError<L>>
+	where F: FnMut(&[u8])
+	{
+		self.with_trie(|trie| trie.remove_prefix_with(prefix, limit, removed))
+	}
+
+	/// Commit the in-memory changes to the backing database and update the root.
+	/// See `TrieDBMut::commit`.
+	pub fn commit(&mut self) {
+		self.with_trie(|trie| trie.commit())
+	}
+
+	/// See `TrieDBMut::commit_parallel`.
+	#[cfg(feature = "parallel")]
+	pub fn commit_parallel(&mut self) {
+		self.with_trie(|trie| trie.commit_parallel())
+	}
+
+	/// See `TrieDBMut::set_changeset_mode`.
+	pub fn set_changeset_mode(&mut self) {
+		self.with_trie(|trie| trie.set_changeset_mode())
+	}
+
+	/// See `TrieDBMut::is_changeset_mode`.
+	pub fn is_changeset_mode(&self) -> bool {
+		self.state.changeset.is_some()
+	}
+
+	/// See `TrieDBMut::commit_changeset`.
+	pub fn commit_changeset(&mut self) -> ChangeSet<TrieHash<L>> {
+		self.with_trie(|trie| trie.commit_changeset())
+	}
+
+	// Run `f` on a `TrieDBMut` working on this trie, keeping the in-memory state it
+	// leaves. The trie never commits on drop, so a panic in `f` leaves the database
+	// as it was and drops the uncommitted changes.
+	fn with_trie<F, R>(&mut self, f: F) -> R
+	where
+		F: FnOnce(&mut TrieDBMut<L>) -> R,
+	{
+		let state = mem::replace(&mut self.state, TrieState::new(self.root));
+		let mut trie = TrieDBMut::with_state(&mut self.db, &mut self.root, state);
+		trie.commit_on_drop = false;
+		let result = f(&mut trie);
+		self.state = mem::replace(&mut trie.state, TrieState::new(Default::default()));
+		result
+	}
+}
+
+impl<L, D> TrieMut<L> for TrieDBMutOwned<L, D>
+where
+	L: TrieLayout,
+	D: HashDB<L::Hash, DBValue>,
+{
+	fn root(&mut self) -> &TrieHash<L> {
+		self.with_trie(|trie| {
+			trie.root();
+		});
+		&self.root
+	}
+
+	fn is_empty(&self) -> bool {
+		self.state.is_empty()
+	}
+
+	fn get<'x, 'key>(&'x self, key: &'key [u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
+		where 'x: 'key
+	{
+		let db: &dyn HashDB<L::Hash, DBValue> = &self.db;
+		self.state.get(&db, key)
+	}
+
+	fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.with_trie(|trie| trie.insert(key, value))
+	}
+
+	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.with_trie(|trie| trie.remove(key))
+	}
+
+	fn remove_prefix(&mut self, prefix: &[u8], limit: Option<usize>) -> Result<usize, TrieHash<L>, CError<L>> {
+		self.with_trie(|trie| trie.remove_prefix(prefix, limit))
 	}
 }
 
@@ -1034,11 +2455,17 @@ mod tests {
 	use env_logger;
 	use standardmap::*;
 	use DBValue;
-	use memory_db::{MemoryDB, PrefixedKey};
+	use memory_db::{MemoryDB, PrefixedKey, HashKey};
 	use hash_db::{Hasher, HashDB};
 	use keccak_hasher::KeccakHasher;
-	use reference_trie::{RefTrieDBMut, TrieMut, NodeCodec,
-		ReferenceNodeCodec, ref_trie_root};
+	use reference_trie::{RefTrieDBMut, RefTrieDB, Trie, TrieMut, NodeCodec,
+		ReferenceNodeCodec, ref_trie_root, RefTrieDBMutNoExt, RefTrieDBNoExt,
+		ReferenceNodeCodecNoExt, ReferenceTrieStreamNoExt, ref_trie_root_no_ext,
+		ExtensionLayout, TrieLayout, TrieDB, TrieDBMut, ReferenceTrieStream, proof,
+		HashedValueLayout, NibbleSlice, RefTrieDBMutOwned, NoExtensionLayout, TrieStream, TrieError};
+	use reference_trie::node::Value;
+	use std::collections::BTreeMap;
+	use std::panic;
 
 	fn populate_trie<'db>(
 		db: &'db mut HashDB<KeccakHasher, DBValue>,
@@ -1394,4 +2821,1005 @@ mod tests {
 			assert!(t.remove(&key).unwrap().is_none());
 		}
 	}
+
+	#[test]
+	fn extend_sorted_matches_inserts() {
+		let mut seed = Default::default();
+		for count in &[0u32, 1, 2, 3, 20, 1000] {
+			let x = StandardMap {
+				alphabet: Alphabet::Low,
+				min_key: 1,
+				journal_key: 5,
+				value_mode: ValueMode::Random,
+				count: *count,
+			}.make_with(&mut seed);
+
+			let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+			let mut root = Default::default();
+			let mut memtrie = populate_trie(&mut memdb, &mut root, &x);
+
+			let mut sorted = x.clone();
+			sorted.sort_by(|a, b| a.0.cmp(&b.0));
+			let mut memdb2 = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+			let mut root2 = Default::default();
+			let mut batched = RefTrieDBMut::new(&mut memdb2, &mut root2);
+			batched.extend_sorted(sorted.iter().map(|(k, v)| (k, v))).unwrap();
+
+			assert_eq!(*batched.root(), *memtrie.root());
+		}
+	}
+
+	#[cfg(feature = "parallel")]
+	fn commit_in_parallel<L: TrieLayout<Hash = KeccakHasher>>(
+		pairs: &[(Vec<u8>, Vec<u8>)],
+		parallel: bool,
+	) -> (MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, <KeccakHasher as Hasher>::Out) {
+		let (first, second) = pairs.split_at(pairs.len() * 3 / 5);
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut memdb, &mut root);
+			for (key, value) in first {
+				t.insert(key, value).unwrap();
+			}
+			t.commit();
+			for (key, value) in second {
+				t.insert(key, value).unwrap();
+			}
+			t.remove(&first[0].0).unwrap();
+			if parallel {
+				t.commit_parallel();
+			} else {
+				t.commit();
+			}
+		}
+		(memdb, root)
+	}
+
+	#[cfg(feature = "parallel")]
+	#[test]
+	fn commit_parallel_matches_commit() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 2,
+			journal_key: 5,
+			value_mode: ValueMode::Random,
+			count: 500,
+		}.make_with(&mut seed);
+		// a common prefix puts an extension, or a partial key, at the root.
+		let prefixed: Vec<_> = x.iter()
+			.map(|(key, value)| ([&b"prefix"[..], key].concat(), value.clone()))
+			.collect();
+
+		for pairs in &[x, prefixed] {
+			let (mut serial_db, serial_root) = commit_in_parallel::<ExtensionLayout>(pairs, false);
+			let (mut parallel_db, parallel_root) = commit_in_parallel::<ExtensionLayout>(pairs, true);
+			assert_eq!(serial_root, parallel_root);
+			assert_eq!(serial_db.drain(), parallel_db.drain());
+
+			let (mut serial_db, serial_root) = commit_in_parallel::<NoExtensionLayout>(pairs, false);
+			let (mut parallel_db, parallel_root) = commit_in_parallel::<NoExtensionLayout>(pairs, true);
+			assert_eq!(serial_root, parallel_root);
+			assert_eq!(serial_db.drain(), parallel_db.drain());
+		}
+	}
+
+	#[test]
+	fn commit_changeset_matches_commit() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 2,
+			journal_key: 5,
+			value_mode: ValueMode::Random,
+			count: 200,
+		}.make_with(&mut seed);
+		let (first, second) = x.split_at(150);
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		populate_trie(&mut memdb, &mut root, first).commit();
+
+		let mut memdb2 = memdb.clone();
+		let mut root2 = root;
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+			for (key, value) in second {
+				t.insert(key, value).unwrap();
+			}
+			t.remove(&first[0].0).unwrap();
+		}
+
+		let changeset = {
+			let mut t = RefTrieDBMut::from_existing(&mut memdb2, &mut root2).unwrap();
+			for (key, value) in second {
+				t.insert(key, value).unwrap();
+			}
+			t.remove(&first[0].0).unwrap();
+			let changeset = t.commit_changeset();
+			assert_eq!(*t.root(), changeset.root);
+			changeset
+		};
+		assert_eq!(changeset.root, root);
+		assert!(!memdb2.contains(&root, &[]));
+
+		changeset.apply_to(&mut memdb2);
+		assert_eq!(memdb.drain(), memdb2.drain());
+	}
+
+	#[test]
+	fn changeset_drops_nodes_replaced_before_commit() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let changeset = {
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.set_changeset_mode();
+			t.insert(b"dog", &[1; 40]).unwrap();
+			t.insert(b"doge", &[2; 40]).unwrap();
+			t.root();
+			t.insert(b"doge", &[3; 40]).unwrap();
+			t.commit_changeset()
+		};
+		for (hash, prefix, _) in &changeset.inserted {
+			assert!(!changeset.removed.contains(&(*hash, prefix.clone())));
+		}
+		// only the empty root of the new trie is removed.
+		assert_eq!(changeset.removed.len(), 1);
+		assert_eq!(changeset.removed[0].0, ReferenceNodeCodec::hashed_null_node());
+
+		let mut expected = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut expected_root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut expected, &mut expected_root);
+			t.insert(b"dog", &[1; 40]).unwrap();
+			t.insert(b"doge", &[3; 40]).unwrap();
+		}
+		assert_eq!(changeset.root, expected_root);
+		assert_eq!(changeset.inserted.len(), expected.keys().len());
+	}
+
+	// change the trie built from `first` in two rounds, committing each to the database
+	// or to a change set.
+	fn change_in_rounds<L: TrieLayout<Hash = KeccakHasher>>(
+		first: &[(Vec<u8>, Vec<u8>)],
+		second: &[(Vec<u8>, Vec<u8>)],
+		changesets: bool,
+	) -> (MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>, <KeccakHasher as Hasher>::Out) {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut memdb, &mut root);
+			for (key, value) in first {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let initial = memdb.clone();
+
+		let mut pending = Vec::new();
+		{
+			let mut t = TrieDBMut::<L>::from_existing(&mut memdb, &mut root).unwrap();
+			if changesets {
+				t.set_changeset_mode();
+			}
+			for (key, value) in second {
+				t.insert(key, value).unwrap();
+			}
+			t.remove(&first[0].0).unwrap();
+			if changesets {
+				pending.push(t.commit_changeset());
+			} else {
+				t.commit();
+			}
+
+			// committed nodes can still be read and changed.
+			let mut expected: BTreeMap<_, _> = first.iter().chain(second.iter()).cloned().collect();
+			expected.remove(&first[0].0);
+			for (key, value) in expected {
+				assert_eq!(t.get(&key).unwrap().unwrap(), DBValue::from_slice(&value));
+			}
+			t.remove(&second[0].0).unwrap();
+			t.insert(&first[1].0, b"changed").unwrap();
+			t.remove_prefix(&first[2].0[..1], None).unwrap();
+			t.insert(&first[0].0, &first[0].1).unwrap();
+			let root = *t.root();
+			if changesets {
+				let changeset = t.commit_changeset();
+				assert_eq!(changeset.root, root);
+				pending.push(changeset);
+			}
+		}
+
+		if changesets {
+			assert_eq!(memdb.clone().drain(), initial.clone().drain());
+			for changeset in pending {
+				changeset.apply_to(&mut memdb);
+			}
+		}
+		(memdb, root)
+	}
+
+	#[test]
+	fn changeset_mode_matches_commit() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 2,
+			journal_key: 5,
+			value_mode: ValueMode::Random,
+			count: 200,
+		}.make_with(&mut seed);
+		let (first, second) = x.split_at(150);
+
+		let (mut memdb, root) = change_in_rounds::<ExtensionLayout>(first, second, false);
+		let (mut changeset_memdb, changeset_root) = change_in_rounds::<ExtensionLayout>(first, second, true);
+		assert_eq!(root, changeset_root);
+		assert_eq!(memdb.drain(), changeset_memdb.drain());
+
+		let (mut memdb, root) = change_in_rounds::<NoExtensionLayout>(first, second, false);
+		let (mut changeset_memdb, changeset_root) = change_in_rounds::<NoExtensionLayout>(first, second, true);
+		assert_eq!(root, changeset_root);
+		assert_eq!(memdb.drain(), changeset_memdb.drain());
+
+		// random values of 32 bytes become large enough for value nodes.
+		let x: Vec<_> = x.into_iter().map(|(k, v)| (k, v.repeat(2))).collect();
+		let (first, second) = x.split_at(150);
+		let (mut memdb, root) = change_in_rounds::<HashedValueLayout>(first, second, false);
+		let (mut changeset_memdb, changeset_root) = change_in_rounds::<HashedValueLayout>(first, second, true);
+		assert_eq!(root, changeset_root);
+		assert_eq!(memdb.drain(), changeset_memdb.drain());
+	}
+
+	#[test]
+	fn owned_trie_commits_explicitly() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 2,
+			journal_key: 5,
+			value_mode: ValueMode::Random,
+			count: 200,
+		}.make_with(&mut seed);
+		let (first, second) = x.split_at(150);
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		populate_trie(&mut memdb, &mut root, first).commit();
+		let mut memdb2 = memdb.clone();
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+			for (key, value) in second {
+				t.insert(key, value).unwrap();
+			}
+			t.remove(&first[0].0).unwrap();
+		}
+
+		let mut t = RefTrieDBMutOwned::from_existing(memdb2.clone(), ref_trie_root(first.to_vec())).unwrap();
+		for (key, value) in second {
+			t.insert(key, value).unwrap();
+		}
+		assert_eq!(t.remove(&first[0].0).unwrap(), Some(DBValue::from_slice(&first[0].1)));
+		assert_eq!(t.get(&second[0].0).unwrap(), Some(DBValue::from_slice(&second[0].1)));
+
+		// without a commit, the changes are dropped.
+		let (untouched, untouched_root) = t.into_inner();
+		assert_eq!(untouched_root, ref_trie_root(first.to_vec()));
+		assert_eq!(untouched.keys(), memdb2.keys());
+
+		let mut t = RefTrieDBMutOwned::from_existing(memdb2, ref_trie_root(first.to_vec())).unwrap();
+		for (key, value) in second {
+			t.insert(key, value).unwrap();
+		}
+		t.remove(&first[0].0).unwrap();
+		t.commit();
+		let (mut memdb2, root2) = t.into_inner();
+		assert_eq!(root2, root);
+		assert_eq!(memdb.drain(), memdb2.drain());
+	}
+
+	#[test]
+	fn owned_trie_forwards_the_trie_api() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 2,
+			journal_key: 5,
+			value_mode: ValueMode::Random,
+			count: 100,
+		}.make_with(&mut seed);
+		let (first, second) = x.split_at(50);
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut removed = Vec::new();
+		let changeset = {
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.extend_sorted(first.iter().cloned()).unwrap();
+			t.commit();
+			let savepoint = t.savepoint();
+			t.apply_batch(second.iter().map(|(k, v)| (k, Some(v)))).unwrap();
+			t.rollback_to(savepoint);
+			t.remove_prefix_with(&first[0].0[..1], None, |key| removed.push(key.to_vec())).unwrap();
+			t.commit_changeset()
+		};
+		changeset.apply_to(&mut memdb);
+		assert!(!removed.is_empty());
+
+		let mut owned_removed = Vec::new();
+		let mut t = RefTrieDBMutOwned::new(MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default());
+		t.extend_sorted(first.iter().cloned()).unwrap();
+		t.commit();
+		let savepoint = t.savepoint();
+		t.apply_batch(second.iter().map(|(k, v)| (k, Some(v)))).unwrap();
+		t.rollback_to(savepoint);
+		t.remove_prefix_with(&first[0].0[..1], None, |key| owned_removed.push(key.to_vec())).unwrap();
+		assert!(!t.is_changeset_mode());
+		let owned_changeset = t.commit_changeset();
+		assert!(t.is_changeset_mode());
+		assert_eq!(owned_changeset.root, root);
+		assert_eq!(owned_removed, removed);
+
+		owned_changeset.apply_to(t.db_mut());
+		let (mut owned_memdb, owned_root) = t.into_inner();
+		assert_eq!(owned_root, root);
+		assert_eq!(owned_memdb.drain(), memdb.drain());
+	}
+
+	#[test]
+	fn owned_trie_drops_changes_on_panic() {
+		let mut t = RefTrieDBMutOwned::new(MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default());
+		let first = t.savepoint();
+		let released = t.savepoint();
+		t.insert(b"dog", b"cat").unwrap();
+		t.rollback_to(first);
+		t.insert(b"horse", b"stallion").unwrap();
+		let _first = t.savepoint();
+		let _second = t.savepoint();
+
+		// the released savepoint has the index of a held one, but not its id.
+		let rollback = panic::catch_unwind(panic::AssertUnwindSafe(|| t.rollback_to(released)));
+		assert!(rollback.is_err());
+
+		// the database is left untouched, and the uncommitted changes are dropped.
+		assert_eq!(t.db().keys(), MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default().keys());
+		assert!(t.is_empty());
+	}
+
+	#[test]
+	fn savepoint_rollback() {
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"dog", b"cat").unwrap();
+			t.insert(b"doge", b"coin").unwrap();
+			t.insert(b"horse", b"stallion").unwrap();
+		}
+		let committed = root;
+		let keys = memdb.keys();
+
+		{
+			let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+			t.insert(b"dogs", b"cats").unwrap();
+			let outer = t.savepoint();
+			t.remove(b"doge").unwrap();
+			t.insert(b"horse", b"mare").unwrap();
+			let inner = t.savepoint();
+			t.remove(b"dog").unwrap();
+			t.rollback_to(inner);
+
+			assert_eq!(t.get(b"dog").unwrap().unwrap(), DBValue::from_slice(b"cat"));
+			assert!(t.get(b"doge").unwrap().is_none());
+
+			t.rollback_to(outer);
+			assert_eq!(t.get(b"doge").unwrap().unwrap(), DBValue::from_slice(b"coin"));
+			assert_eq!(t.get(b"horse").unwrap().unwrap(), DBValue::from_slice(b"stallion"));
+
+			t.remove(b"dogs").unwrap();
+		}
+		assert_eq!(root, committed);
+		assert_eq!(memdb.keys(), keys);
+	}
+
+	#[test]
+	fn savepoint_rollback_restores_emptied_root() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"dog", b"cat").unwrap();
+		}
+		let committed = root;
+
+		let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+		let savepoint = t.savepoint();
+		t.remove(b"dog").unwrap();
+		t.rollback_to(savepoint);
+		assert_eq!(t.get(b"dog").unwrap().unwrap(), DBValue::from_slice(b"cat"));
+		assert_eq!(*t.root(), committed);
+	}
+
+	#[test]
+	fn savepoint_rollback_restores_root_emptied_by_remove_prefix() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"dog", b"cat").unwrap();
+		}
+		let committed = root;
+
+		let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+		let savepoint = t.savepoint();
+		t.remove_prefix(b"do", None).unwrap();
+		t.rollback_to(savepoint);
+		assert_eq!(t.get(b"dog").unwrap().unwrap(), DBValue::from_slice(b"cat"));
+		assert_eq!(*t.root(), committed);
+	}
+
+	#[test]
+	fn savepoint_release() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		t.insert(b"dog", b"cat").unwrap();
+
+		let outer = t.savepoint();
+		t.insert(b"doge", b"coin").unwrap();
+		let inner = t.savepoint();
+		t.insert(b"horse", b"stallion").unwrap();
+		t.release(inner);
+		t.insert(b"dogs", b"cats").unwrap();
+		t.rollback_to(outer);
+
+		assert!(t.get(b"horse").unwrap().is_none());
+		assert_eq!(*t.root(), ref_trie_root(vec![(b"dog".to_vec(), b"cat".to_vec())]));
+
+		let savepoint = t.savepoint();
+		t.insert(b"horse", b"stallion").unwrap();
+		t.release(savepoint);
+		assert_eq!(*t.root(), ref_trie_root(vec![
+			(b"dog".to_vec(), b"cat".to_vec()),
+			(b"horse".to_vec(), b"stallion".to_vec()),
+		]));
+	}
+
+	#[test]
+	#[should_panic(expected = "savepoint has already been released")]
+	fn savepoint_released_by_rollback_is_rejected() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		t.insert(b"dog", b"cat").unwrap();
+
+		let outer = t.savepoint();
+		let inner = t.savepoint();
+		t.rollback_to(outer);
+
+		// both indices are in use again, by newer savepoints.
+		let _first = t.savepoint();
+		let _second = t.savepoint();
+		t.insert(b"horse", b"stallion").unwrap();
+		t.rollback_to(inner);
+	}
+
+	#[test]
+	fn remove_prefix_matches_removes() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"abc".to_vec()),
+			min_key: 1,
+			journal_key: 4,
+			value_mode: ValueMode::Random,
+			count: 100,
+		}.make_with(&mut seed);
+
+		for prefix in &[&b""[..], b"a", b"ab", b"abc", b"b", b"cccc", b"d"] {
+			let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+			let mut root = Default::default();
+			populate_trie(&mut memdb, &mut root, &x).commit();
+
+			let mut remaining = ::std::collections::BTreeMap::new();
+			remaining.extend(x.iter().cloned());
+			let expected = remaining.len();
+			remaining.retain(|k, _| !k.starts_with(prefix));
+			let expected = expected - remaining.len();
+
+			{
+				let mut t = RefTrieDBMut::from_existing(&mut memdb, &mut root).unwrap();
+				assert_eq!(t.remove_prefix(prefix, None).unwrap(), expected);
+			}
+			assert_eq!(root, ref_trie_root(remaining.clone()));
+
+			let t = RefTrieDB::new(&memdb, &root).unwrap();
+			for (key, value) in &remaining {
+				assert_eq!(t.get(key).unwrap().unwrap(), DBValue::from_slice(value));
+			}
+		}
+	}
+
+	#[test]
+	fn remove_prefix_with_limit() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		for key in &[&b"do"[..], b"dog", b"doge", b"dogs", b"dot", b"horse"] {
+			t.insert(key, key).unwrap();
+		}
+		t.commit();
+
+		let mut removed = Vec::new();
+		assert_eq!(t.remove_prefix_with(b"dog", Some(2), |k| removed.push(k.to_vec())).unwrap(), 2);
+		assert_eq!(removed, vec![b"dog".to_vec(), b"doge".to_vec()]);
+		assert_eq!(t.remove_prefix(b"dog", Some(2)).unwrap(), 1);
+		assert_eq!(t.remove_prefix(b"dog", Some(2)).unwrap(), 0);
+		assert_eq!(t.remove_prefix(b"", Some(0)).unwrap(), 0);
+
+		assert_eq!(*t.root(), ref_trie_root(vec![
+			(b"do".to_vec(), b"do".to_vec()),
+			(b"dot".to_vec(), b"dot".to_vec()),
+			(b"horse".to_vec(), b"horse".to_vec()),
+		]));
+	}
+
+	#[test]
+	fn apply_batch_matches_single_changes() {
+		let mut seed = Default::default();
+		for _ in 0..20 {
+			let x = StandardMap {
+				alphabet: Alphabet::Custom(b"abc".to_vec()),
+				min_key: 1,
+				journal_key: 4,
+				value_mode: ValueMode::Random,
+				count: 60,
+			}.make_with(&mut seed);
+			let (initial, changes) = x.split_at(30);
+			let changes: Vec<_> = changes.iter().enumerate()
+				.map(|(i, (k, v))| match i % 3 {
+					0 => (k.clone(), None),
+					1 => (initial[i].0.clone(), None),
+					_ => (k.clone(), Some(v.clone())),
+				})
+				.collect();
+
+			let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+			let mut root = Default::default();
+			let mut memtrie = populate_trie(&mut memdb, &mut root, initial);
+			for (k, v) in &changes {
+				match v {
+					Some(v) => memtrie.insert(k, v).unwrap(),
+					None => memtrie.remove(k).unwrap(),
+				};
+			}
+
+			let mut memdb2 = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+			let mut root2 = Default::default();
+			{
+				populate_trie(&mut memdb2, &mut root2, initial);
+			}
+			let mut batched = RefTrieDBMut::from_existing(&mut memdb2, &mut root2).unwrap();
+			batched.apply_batch(changes).unwrap();
+
+			assert_eq!(*batched.root(), *memtrie.root());
+		}
+	}
+
+	#[test]
+	fn apply_batch_last_change_wins() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		t.insert(b"A", b"A").unwrap();
+		t.insert(b"AB", b"AB").unwrap();
+		t.apply_batch(vec![
+			(&b"AB"[..], Some(&b"x"[..])),
+			(b"A", None),
+			(b"AB", None),
+			(b"B", Some(b"B")),
+			(b"A", Some(b"")),
+		]).unwrap();
+
+		assert_eq!(*t.root(), ref_trie_root(vec![(b"B".to_vec(), b"B".to_vec())]));
+		t.apply_batch(vec![(&b"B"[..], None::<&[u8]>)]).unwrap();
+		assert!(t.is_empty());
+		assert_eq!(*t.root(), ReferenceNodeCodec::hashed_null_node());
+	}
+
+	#[test]
+	fn insert_remove_without_extension_nodes() {
+		let mut seed = Default::default();
+		for _ in 0..10 {
+			let x = StandardMap {
+				alphabet: Alphabet::Custom(b"abc".to_vec()),
+				min_key: 1,
+				journal_key: 4,
+				value_mode: ValueMode::Random,
+				count: 100,
+			}.make_with(&mut seed);
+			let mut state: BTreeMap<_, _> = x.iter().cloned().collect();
+			let root_sorted = ::trie_root::trie_root_sorted::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(state.clone());
+			assert_eq!(root_sorted, Ok(ref_trie_root_no_ext(state.clone())));
+
+			let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+			let mut root = Default::default();
+			{
+				let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+				for (key, value) in &x {
+					t.insert(key, value).unwrap();
+				}
+				assert_eq!(*t.root(), ref_trie_root_no_ext(state.clone()));
+
+				// remove from the committed trie.
+				for (key, _) in x.iter().step_by(3) {
+					t.remove(key).unwrap();
+					state.remove(key);
+				}
+				assert_eq!(*t.root(), ref_trie_root_no_ext(state.clone()));
+			}
+
+			let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+			for (key, value) in &state {
+				assert_eq!(t.get(key).unwrap(), Some(DBValue::from_slice(value)));
+			}
+			let items: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+			assert_eq!(items, state.iter().map(|(k, v)| (k.clone(), DBValue::from_slice(v))).collect::<Vec<_>>());
+
+			let mut t = RefTrieDBMutNoExt::from_existing(&mut memdb, &mut root).unwrap();
+			for key in state.keys() {
+				t.remove(key).unwrap();
+			}
+			assert_eq!(*t.root(), ReferenceNodeCodecNoExt::hashed_null_node());
+		}
+	}
+
+	#[test]
+	fn sorted_root_matches_random_maps() {
+		let mut seed = Default::default();
+		for i in 0..300 {
+			let map: BTreeMap<_, _> = StandardMap {
+				alphabet: Alphabet::Custom(b"abcd".to_vec()),
+				min_key: 1,
+				journal_key: 4,
+				value_mode: ValueMode::Random,
+				count: i % 50,
+			}.make_with(&mut seed).into_iter().collect();
+
+			let sorted = ::trie_root::trie_root_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(map.clone());
+			assert_eq!(sorted, Ok(ref_trie_root(map.clone())));
+			let sorted = ::trie_root::trie_root_sorted::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(map.clone());
+			assert_eq!(sorted, Ok(ref_trie_root_no_ext(map)));
+		}
+	}
+
+	#[derive(Default, Clone)]
+	struct HashedLayout;
+
+	impl TrieLayout for HashedLayout {
+		const INLINE_THRESHOLD: Option<usize> = Some(0);
+		type Hash = KeccakHasher;
+		type Codec = ReferenceNodeCodec;
+	}
+
+	#[derive(Default, Clone)]
+	struct LargeInlineLayout;
+
+	impl TrieLayout for LargeInlineLayout {
+		const INLINE_THRESHOLD: Option<usize> = Some(100);
+		type Hash = KeccakHasher;
+		type Codec = ReferenceNodeCodec;
+	}
+
+	// Builds a trie under layout `L`, checks it against `trie_root` and reads it back,
+	// directly and through proofs, returning the number of nodes stored in the database.
+	fn stored_nodes_with_layout<L: TrieLayout<Hash = KeccakHasher, Codec = ReferenceNodeCodec> + Clone>(
+		x: &[(Vec<u8>, Vec<u8>)],
+	) -> usize {
+		let state: BTreeMap<_, _> = x.iter().cloned().collect();
+		let mut emitted = 0;
+		let expected_root = ::trie_root::trie_root_with_nodes::<KeccakHasher, ReferenceTrieStream<L>, _, _, _, _>(
+			state.clone(),
+			|_, _| emitted += 1,
+		);
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut memdb, &mut root);
+			for (key, value) in x {
+				t.insert(key, value).unwrap();
+			}
+		}
+		assert_eq!(root, expected_root);
+		assert_eq!(memdb.keys().len(), emitted);
+
+		let t = TrieDB::<L>::new(&memdb, &root).unwrap();
+		for (key, value) in &state {
+			assert_eq!(t.get(key).unwrap(), Some(DBValue::from_slice(value)));
+		}
+		let items: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+		assert_eq!(items, state.iter().map(|(k, v)| (k.clone(), DBValue::from_slice(v))).collect::<Vec<_>>());
+
+		let proven: Vec<_> = state.iter().step_by(7).map(|(k, v)| (k, Some(v))).collect();
+		let keys: Vec<_> = proven.iter().map(|(k, _)| k).collect();
+		let nodes = proof::generate_proof::<L, _>(&memdb, &root, &keys).unwrap();
+		assert_eq!(proof::verify_proof::<L, _, _>(&root, &nodes, &proven), Ok(()));
+		let compact = proof::encode_compact::<L>(&root, &nodes).unwrap();
+		assert_eq!(proof::decode_compact::<L>(&compact).unwrap(), nodes);
+		let (range, nodes) = proof::generate_range_proof::<L>(&memdb, &root, b"b", b"c").unwrap();
+		assert_eq!(proof::verify_range_proof::<L, _, _>(&root, b"b", b"c", &range, &nodes), Ok(()));
+		emitted
+	}
+
+	#[test]
+	fn inline_threshold() {
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"abcd".to_vec()),
+			min_key: 2,
+			journal_key: 6,
+			value_mode: ValueMode::Index,
+			count: 200,
+		}.make_with(&mut Default::default());
+
+		let hashed = stored_nodes_with_layout::<HashedLayout>(&x);
+		let default = stored_nodes_with_layout::<ExtensionLayout>(&x);
+		let large = stored_nodes_with_layout::<LargeInlineLayout>(&x);
+		assert!(hashed > default);
+		assert!(default > large);
+	}
+
+	// Store the trie for `pairs` with `trie_root_into_db` and with a `TrieDBMut` of layout
+	// `L`, checking that both store the same nodes.
+	fn trie_root_into_db_matches<L, S>(pairs: &[(Vec<u8>, Vec<u8>)])
+	where
+		L: TrieLayout<Hash = KeccakHasher>,
+		S: TrieStream,
+	{
+		let mut expected = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut expected_root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut expected, &mut expected_root);
+			for (key, value) in pairs {
+				t.insert(key, value).unwrap();
+			}
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let root = ::trie_root::trie_root_into_db::<KeccakHasher, S, _, _, _, _>(&mut memdb, pairs.to_vec());
+		assert_eq!(root, expected_root);
+		assert_eq!(memdb.drain(), expected.drain());
+	}
+
+	#[test]
+	fn trie_root_into_db_matches_triedbmut() {
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"abcd".to_vec()),
+			min_key: 2,
+			journal_key: 6,
+			value_mode: ValueMode::Random,
+			count: 200,
+		}.make_with(&mut Default::default());
+
+		trie_root_into_db_matches::<ExtensionLayout, ReferenceTrieStream>(&x);
+		trie_root_into_db_matches::<NoExtensionLayout, ReferenceTrieStreamNoExt>(&x);
+		trie_root_into_db_matches::<HashedLayout, ReferenceTrieStream<HashedLayout>>(&x);
+		trie_root_into_db_matches::<LargeInlineLayout, ReferenceTrieStream<LargeInlineLayout>>(&x);
+	}
+
+	#[test]
+	fn reference_stream_follows_the_layout() {
+		#[derive(Default, Clone)]
+		struct HashedNoExtLayout;
+
+		impl TrieLayout for HashedNoExtLayout {
+			const USE_EXTENSION: bool = false;
+			const INLINE_THRESHOLD: Option<usize> = Some(0);
+			type Hash = KeccakHasher;
+			type Codec = ReferenceNodeCodecNoExt;
+		}
+
+		assert!(<ReferenceTrieStream as TrieStream>::USE_EXTENSION);
+		assert!(!<ReferenceTrieStreamNoExt as TrieStream>::USE_EXTENSION);
+		assert!(!<ReferenceTrieStream<HashedNoExtLayout> as TrieStream>::USE_EXTENSION);
+
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"abcd".to_vec()),
+			min_key: 2,
+			journal_key: 6,
+			value_mode: ValueMode::Random,
+			count: 100,
+		}.make_with(&mut Default::default());
+		trie_root_into_db_matches::<HashedNoExtLayout, ReferenceTrieStream<HashedNoExtLayout>>(&x);
+	}
+
+	#[test]
+	fn trie_root_into_db_stores_an_inline_root() {
+		let pairs = vec![(b"a".to_vec(), b"b".to_vec())];
+		trie_root_into_db_matches::<ExtensionLayout, ReferenceTrieStream>(&pairs);
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let root = ::trie_root::trie_root_into_db::<KeccakHasher, ReferenceTrieStream, _, _, _, _>(&mut memdb, pairs);
+		assert_eq!(memdb.keys().len(), 1);
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		assert_eq!(t.get(b"a").unwrap(), Some(DBValue::from_slice(b"b")));
+	}
+
+	#[test]
+	fn trie_root_into_db_stores_an_empty_root() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let root = ::trie_root::trie_root_into_db::<KeccakHasher, ReferenceTrieStream, _, _, _, _>(
+			&mut memdb,
+			Vec::<(Vec<u8>, Vec<u8>)>::new(),
+		);
+		assert_eq!(root, ReferenceNodeCodec::hashed_null_node());
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		assert!(t.is_empty());
+	}
+
+	#[test]
+	fn hashed_values() {
+		let mut seed = Default::default();
+		let x: Vec<_> = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 2,
+			journal_key: 5,
+			value_mode: ValueMode::Random,
+			count: 300,
+		}.make_with(&mut seed).into_iter()
+			// random values of 32 bytes become large enough for value nodes.
+			.map(|(k, v)| (k, v.repeat(3)))
+			.collect();
+		let (first, second) = x.split_at(200);
+
+		let mut state: BTreeMap<_, _> = first.iter().cloned().collect();
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<HashedValueLayout>::new(&mut memdb, &mut root);
+			for (key, value) in first {
+				t.insert(key, value).unwrap();
+			}
+			t.commit();
+			for (key, value) in second {
+				t.insert(key, value).unwrap();
+				state.insert(key.clone(), value.clone());
+			}
+			for (key, value) in first.iter().step_by(3) {
+				let value: Vec<u8> = value.iter().rev().cloned().collect();
+				t.insert(key, &value).unwrap();
+				state.insert(key.clone(), value);
+			}
+			for (key, _) in first.iter().skip(1).step_by(3) {
+				t.remove(key).unwrap();
+				state.remove(key);
+			}
+		}
+
+		// the database holds exactly the nodes of the trie built from scratch.
+		let mut fresh = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut fresh_root = Default::default();
+		{
+			let mut t = TrieDBMut::<HashedValueLayout>::new(&mut fresh, &mut fresh_root);
+			for (key, value) in &state {
+				t.insert(key, value).unwrap();
+			}
+		}
+		assert_eq!(root, fresh_root);
+		assert_eq!(memdb.keys(), fresh.keys());
+		assert!(root != ref_trie_root_no_ext(state.clone()));
+
+		let large: Vec<_> = state.iter().filter(|(_, v)| v.len() >= 33).collect();
+		assert!(!large.is_empty());
+		for (key, value) in &large {
+			assert!(memdb.contains(&KeccakHasher::hash(value), &NibbleSlice::new(key).encoded(false)));
+		}
+
+		let t = TrieDB::<HashedValueLayout>::new(&memdb, &root).unwrap();
+		for (key, value) in &state {
+			assert_eq!(t.get(key).unwrap(), Some(DBValue::from_slice(value)));
+		}
+		let items: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+		assert_eq!(items, state.iter().map(|(k, v)| (k.clone(), DBValue::from_slice(v))).collect::<Vec<_>>());
+
+		// proofs carry the proven value nodes, which may also be left out.
+		let proven: Vec<_> = state.iter().step_by(5).map(|(k, v)| (k, Some(v))).collect();
+		let keys: Vec<_> = proven.iter().map(|(k, _)| k).collect();
+		let nodes = proof::generate_proof::<HashedValueLayout, _>(&memdb, &root, &keys).unwrap();
+		assert_eq!(proof::verify_proof::<HashedValueLayout, _, _>(&root, &nodes, &proven), Ok(()));
+		let compact = proof::encode_compact::<HashedValueLayout>(&root, &nodes).unwrap();
+		assert_eq!(proof::decode_compact::<HashedValueLayout>(&compact).unwrap(), nodes);
+		let without_values: Vec<_> = nodes.iter()
+			.filter(|node| !large.iter().any(|(_, value)| value == node))
+			.cloned()
+			.collect();
+		assert!(without_values.len() < nodes.len());
+		assert_eq!(proof::verify_proof::<HashedValueLayout, _, _>(&root, &without_values, &proven), Ok(()));
+		let (range, nodes) = proof::generate_range_proof::<HashedValueLayout>(&memdb, &root, b"b", b"d").unwrap();
+		assert!(range.iter().any(|(_, value)| value.len() >= 33));
+		assert_eq!(proof::verify_range_proof::<HashedValueLayout, _, _>(&root, b"b", b"d", &range, &nodes), Ok(()));
+
+		{
+			let mut t = TrieDBMut::<HashedValueLayout>::from_existing(&mut memdb, &mut root).unwrap();
+			for key in state.keys() {
+				t.remove(key).unwrap();
+			}
+		}
+		assert_eq!(root, ReferenceNodeCodecNoExt::hashed_null_node());
+		assert!(memdb.keys().is_empty());
+	}
+
+	#[test]
+	fn value_nodes_are_fetched_lazily() {
+		let large: Vec<_> = (0..16u8).map(|i| (vec![i, i], vec![i; 40])).collect();
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<HashedValueLayout>::new(&mut memdb, &mut root);
+			for (key, value) in &large {
+				t.insert(key, value).unwrap();
+			}
+		}
+		for (key, value) in &large {
+			memdb.remove(&KeccakHasher::hash(value), &NibbleSlice::new(key).encoded(false));
+		}
+
+		// restructuring the trie, keeping, replacing and removing values never needs the
+		// value nodes.
+		let mut state: BTreeMap<_, _> = large.iter().cloned().collect();
+		{
+			let mut t = TrieDBMut::<HashedValueLayout>::from_existing(&mut memdb, &mut root).unwrap();
+			for i in 0..16u8 {
+				t.insert(&[i, i, 0], &[i]).unwrap();
+				state.insert(vec![i, i, 0], vec![i]);
+			}
+			assert_eq!(t.remove_prefix(&[0], None).unwrap(), 2);
+			state.remove(&vec![0, 0]);
+			state.remove(&vec![0, 0, 0]);
+			let changes = vec![(vec![2, 2], Some(vec![2; 40])), (vec![3, 3], Some(vec![9; 40])), (vec![4, 4], None)];
+			t.apply_batch(changes).unwrap();
+			state.insert(vec![3, 3], vec![9; 40]);
+			state.remove(&vec![4, 4]);
+		}
+		for (key, value) in &large {
+			memdb.insert(&NibbleSlice::new(key).encoded(false), value);
+		}
+
+		let mut fresh = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut fresh_root = Default::default();
+		{
+			let mut t = TrieDBMut::<HashedValueLayout>::new(&mut fresh, &mut fresh_root);
+			for (key, value) in &state {
+				t.insert(key, value).unwrap();
+			}
+		}
+		assert_eq!(root, fresh_root);
+		assert_eq!(memdb.keys(), fresh.keys());
+	}
+
+	#[test]
+	#[should_panic(expected = "VALUE_THRESHOLD requires a codec able to encode value nodes")]
+	fn value_threshold_requires_value_nodes() {
+		struct InvalidLayout;
+		impl TrieLayout for InvalidLayout {
+			const VALUE_THRESHOLD: Option<usize> = Some(33);
+			type Hash = KeccakHasher;
+			type Codec = ReferenceNodeCodec;
+		}
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		TrieDBMut::<InvalidLayout>::new(&mut memdb, &mut root);
+	}
+
+	#[test]
+	fn malformed_value_hashes_are_rejected() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let leaf = ReferenceNodeCodecNoExt::leaf_node(&NibbleSlice::new(b"key").encoded(true), Value::Node(&[1, 2, 3]));
+		let mut root = memdb.insert(&NibbleSlice::new(&[]).encoded(false), &leaf);
+		let expected = TrieError::InvalidValueHash(vec![1, 2, 3]);
+
+		let t = TrieDB::<HashedValueLayout>::new(&memdb, &root).unwrap();
+		assert_eq!(*t.get(b"key").unwrap_err(), expected);
+		let mut t = TrieDBMut::<HashedValueLayout>::from_existing(&mut memdb, &mut root).unwrap();
+		assert_eq!(*t.insert(b"other", b"value").unwrap_err(), expected);
+	}
 }
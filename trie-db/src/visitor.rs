@@ -0,0 +1,386 @@
+// Copyright 2017, 2019 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structured traversal of a trie with a visitor.
+
+use hash_db::HashDBRef;
+use nibbleslice::NibbleSlice;
+use nibblevec::NibbleVec;
+use node::{Node, Value};
+use node_codec::NodeCodec;
+use triedb::TrieDB;
+use super::{DBValue, Result, Trie, TrieError, TrieLayout, TrieHash, CError};
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+/// Callbacks for the nodes met by `visit_trie`.
+///
+/// Each callback is given the nibble path leading to the node, which does not include
+/// the partial key of the node itself. Every callback does nothing by default.
+pub trait TrieVisitor<L: TrieLayout> {
+	/// Called on a branch, before its children. `partial` is the partial key of the
+	/// branch, which is always empty in layouts using extension nodes.
+	fn enter_branch(&mut self, _path: &NibbleVec, _partial: NibbleSlice, _value: Option<Value>) {}
+
+	/// Called on a leaf holding `value` under the partial key `partial`.
+	fn leaf(&mut self, _path: &NibbleVec, _partial: NibbleSlice, _value: Value) {}
+
+	/// Called on an extension, before its child.
+	fn extension(&mut self, _path: &NibbleVec, _partial: NibbleSlice) {}
+
+	/// Called with the encoded data of a child stored inline in its parent, before
+	/// the child itself is visited.
+	fn inline_child(&mut self, _path: &NibbleVec, _data: &[u8]) {}
+
+	/// Called on a child whose node is missing from the database. The traversal goes
+	/// on with the next child.
+	fn missing_node(&mut self, _path: &NibbleVec, _hash: &TrieHash<L>) {}
+
+	/// Whether to visit the child at `path`. A skipped child is neither fetched nor
+	/// reported to any other callback.
+	fn descend(&mut self, _path: &NibbleVec) -> bool { true }
+}
+
+/// Walk the trie depth-first in key order, reporting every node to `visitor`.
+///
+/// Value nodes are not fetched: values kept in them are given as `Value::Node`.
+///
+/// ```
+/// extern crate memory_db;
+/// extern crate reference_trie;
+/// extern crate keccak_hasher;
+///
+/// use memory_db::{MemoryDB, PrefixedKey};
+/// use keccak_hasher::KeccakHasher;
+/// use reference_trie::{ExtensionLayout, NibbleSlice, NibbleVec, RefTrieDB, RefTrieDBMut};
+/// use reference_trie::{TrieMut, TrieVisitor, visit_trie};
+/// use reference_trie::node::Value;
+///
+/// struct CountLeaves(usize);
+///
+/// impl TrieVisitor<ExtensionLayout> for CountLeaves {
+///   fn leaf(&mut self, _path: &NibbleVec, _partial: NibbleSlice, _value: Value) {
+///     self.0 += 1;
+///   }
+/// }
+///
+/// let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, _>::default();
+/// let mut root = Default::default();
+/// {
+///   let mut t = RefTrieDBMut::new(&mut db, &mut root);
+///   t.insert(b"foo", b"bar").unwrap();
+///   t.insert(b"food", b"bread").unwrap();
+/// }
+///
+/// let t = RefTrieDB::new(&db, &root).unwrap();
+/// let mut visitor = CountLeaves(0);
+/// visit_trie(&t, &mut visitor).unwrap();
+/// assert_eq!(visitor.0, 1);
+/// ```
+pub fn visit_trie<L, V>(trie: &TrieDB<L>, visitor: &mut V) -> Result<(), TrieHash<L>, CError<L>>
+where
+	L: TrieLayout,
+	V: TrieVisitor<L>,
+{
+	let data = trie.root_data()?;
+	visit_node::<L, V>(trie.db(), &mut NibbleVec::new(), *trie.root(), &data, visitor)
+}
+
+// Visit the encoded node at `path`. `hash` is the hash of the node, or of the node
+// holding it if it is inline.
+fn visit_node<L, V>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	path: &mut NibbleVec,
+	hash: TrieHash<L>,
+	data: &[u8],
+	visitor: &mut V,
+) -> Result<(), TrieHash<L>, CError<L>>
+where
+	L: TrieLayout,
+	V: TrieVisitor<L>,
+{
+	let len = path.len();
+	match L::Codec::decode(data).map_err(|e| Box::new(TrieError::DecoderError(hash, e)))? {
+		Node::Empty => {}
+		Node::Leaf(partial, value) => visitor.leaf(path, partial, value),
+		Node::Extension(partial, child) => {
+			visitor.extension(path, partial);
+			partial.iter().for_each(|nibble| path.push(nibble));
+			visit_child::<L, V>(db, path, hash, child, visitor)?;
+		}
+		Node::Branch(children, value) => {
+			visitor.enter_branch(path, NibbleSlice::new(&[]), value);
+			visit_children::<L, V>(db, path, hash, children, visitor)?;
+		}
+		Node::NibbledBranch(partial, children, value) => {
+			visitor.enter_branch(path, partial, value);
+			partial.iter().for_each(|nibble| path.push(nibble));
+			visit_children::<L, V>(db, path, hash, children, visitor)?;
+		}
+	}
+	while path.len() > len {
+		path.pop();
+	}
+	Ok(())
+}
+
+// Visit the children of the branch at `path`, in nibble order.
+fn visit_children<L, V>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	path: &mut NibbleVec,
+	parent: TrieHash<L>,
+	children: [Option<&[u8]>; 16],
+	visitor: &mut V,
+) -> Result<(), TrieHash<L>, CError<L>>
+where
+	L: TrieLayout,
+	V: TrieVisitor<L>,
+{
+	for (index, child) in children.iter().enumerate() {
+		if let Some(child) = child {
+			path.push(index as u8);
+			visit_child::<L, V>(db, path, parent, child, visitor)?;
+			path.pop();
+		}
+	}
+	Ok(())
+}
+
+// Visit the child at `path` referred to by `data`, unless the visitor skips it.
+fn visit_child<L, V>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	path: &mut NibbleVec,
+	parent: TrieHash<L>,
+	data: &[u8],
+	visitor: &mut V,
+) -> Result<(), TrieHash<L>, CError<L>>
+where
+	L: TrieLayout,
+	V: TrieVisitor<L>,
+{
+	if !visitor.descend(path) {
+		return Ok(())
+	}
+	match L::Codec::try_decode_hash(data) {
+		Some(hash) => {
+			let prefix = NibbleSlice::new(path.inner()).encoded_leftmost(path.len(), false);
+			match db.get(&hash, &prefix) {
+				Some(data) => visit_node::<L, V>(db, path, hash, &data, visitor),
+				None => {
+					visitor.missing_node(path, &hash);
+					Ok(())
+				}
+			}
+		}
+		None => {
+			visitor.inline_child(path, data);
+			visit_node::<L, V>(db, path, parent, data, visitor)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use memory_db::{MemoryDB, PrefixedKey};
+	use keccak_hasher::KeccakHasher;
+	use hash_db::HashDB;
+	use DBValue;
+	use reference_trie::{RefTrieDB, RefTrieDBMut, RefTrieDBNoExt, RefTrieDBMutNoExt, Trie, TrieMut, TrieLayout};
+	use reference_trie::{RefTrieDBNodeIterator, NibbleSlice, NibbleVec, TrieVisitor, visit_trie};
+	use reference_trie::node::Value;
+
+	type MemDB = MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>;
+	type Out = <KeccakHasher as ::hash_db::Hasher>::Out;
+
+	#[derive(Default)]
+	struct Collect {
+		leaves: Vec<(Vec<u8>, Vec<u8>)>,
+		branches: Vec<Vec<u8>>,
+		extensions: Vec<Vec<u8>>,
+		inline: Vec<Vec<u8>>,
+		missing: Vec<(Vec<u8>, Out)>,
+		skip: Option<Vec<u8>>,
+	}
+
+	fn nibbles(path: &NibbleVec, partial: NibbleSlice) -> Vec<u8> {
+		(0..path.len()).map(|i| path.at(i)).chain(partial.iter()).collect()
+	}
+
+	fn key(nibbles: &[u8]) -> Vec<u8> {
+		nibbles.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect()
+	}
+
+	fn inline_value(value: Value) -> Vec<u8> {
+		match value {
+			Value::Inline(value) => value.to_vec(),
+			Value::Node(_) => panic!("values are kept inline"),
+		}
+	}
+
+	impl<L: TrieLayout<Hash = KeccakHasher>> TrieVisitor<L> for Collect {
+		fn enter_branch(&mut self, path: &NibbleVec, partial: NibbleSlice, value: Option<Value>) {
+			let path = nibbles(path, partial);
+			if let Some(value) = value {
+				self.leaves.push((key(&path), inline_value(value)));
+			}
+			self.branches.push(path);
+		}
+
+		fn leaf(&mut self, path: &NibbleVec, partial: NibbleSlice, value: Value) {
+			self.leaves.push((key(&nibbles(path, partial)), inline_value(value)));
+		}
+
+		fn extension(&mut self, path: &NibbleVec, partial: NibbleSlice) {
+			self.extensions.push(nibbles(path, partial));
+		}
+
+		fn inline_child(&mut self, path: &NibbleVec, _data: &[u8]) {
+			self.inline.push(nibbles(path, NibbleSlice::new(&[])));
+		}
+
+		fn missing_node(&mut self, path: &NibbleVec, hash: &Out) {
+			self.missing.push((nibbles(path, NibbleSlice::new(&[])), *hash));
+		}
+
+		fn descend(&mut self, path: &NibbleVec) -> bool {
+			self.skip.as_ref().map_or(true, |skip| !nibbles(path, NibbleSlice::new(&[])).starts_with(skip))
+		}
+	}
+
+	fn pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+		vec![
+			(b"do".to_vec(), b"verb".to_vec()),
+			(b"dog".to_vec(), b"puppy".to_vec()),
+			(b"doge".to_vec(), b"coin".to_vec()),
+			(b"horse".to_vec(), b"stallion".to_vec()),
+			(b"house".to_vec(), b"building".to_vec()),
+			(b"houseful".to_vec(), b"container".to_vec()),
+		]
+	}
+
+	fn populate(db: &mut MemDB) -> Out {
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(db, &mut root);
+			for (key, value) in pairs() {
+				t.insert(&key, &value).unwrap();
+			}
+		}
+		root
+	}
+
+	#[test]
+	fn visits_every_value_in_order() {
+		let mut db = MemDB::default();
+		let root = populate(&mut db);
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		let mut visitor = Collect::default();
+		visit_trie(&t, &mut visitor).unwrap();
+
+		let items: Vec<_> = t.iter().unwrap()
+			.map(|x| x.map(|(key, value)| (key, value.to_vec())).unwrap())
+			.collect();
+		assert_eq!(visitor.leaves, items);
+		assert!(!visitor.extensions.is_empty());
+		assert!(visitor.missing.is_empty());
+		let mut sorted = visitor.branches.clone();
+		sorted.sort();
+		assert_eq!(visitor.branches, sorted);
+	}
+
+	#[test]
+	fn visits_every_value_without_extensions() {
+		let mut db = MemDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut db, &mut root);
+			for (key, value) in pairs() {
+				t.insert(&key, &value).unwrap();
+			}
+		}
+		let t = RefTrieDBNoExt::new(&db, &root).unwrap();
+		let mut visitor = Collect::default();
+		visit_trie(&t, &mut visitor).unwrap();
+
+		let items: Vec<_> = t.iter().unwrap()
+			.map(|x| x.map(|(key, value)| (key, value.to_vec())).unwrap())
+			.collect();
+		assert_eq!(visitor.leaves, items);
+		assert!(visitor.extensions.is_empty());
+		assert!(visitor.branches.iter().any(|path| !path.is_empty()));
+	}
+
+	#[test]
+	fn reports_inline_children() {
+		let mut db = MemDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			t.insert(b"A", b"A").unwrap();
+			t.insert(b"AA", b"AA").unwrap();
+			t.insert(b"AB", b"AB").unwrap();
+		}
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		let mut visitor = Collect::default();
+		visit_trie(&t, &mut visitor).unwrap();
+
+		assert_eq!(visitor.inline, vec![vec![4, 1], vec![4, 1, 4], vec![4, 1, 4, 1], vec![4, 1, 4, 2]]);
+		assert_eq!(visitor.leaves.len(), 3);
+	}
+
+	#[test]
+	fn skips_children_not_descended_into() {
+		let mut db = MemDB::default();
+		let root = populate(&mut db);
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		let mut visitor = Collect::default();
+		visitor.skip = Some(vec![6, 8]);
+		visit_trie(&t, &mut visitor).unwrap();
+
+		let leaves: Vec<_> = visitor.leaves.iter().map(|(key, _)| key.clone()).collect();
+		assert_eq!(leaves, vec![b"do".to_vec(), b"dog".to_vec(), b"doge".to_vec()]);
+	}
+
+	#[test]
+	fn reports_missing_nodes() {
+		let mut db = MemDB::default();
+		let root = populate(&mut db);
+		let mut full = Collect::default();
+		visit_trie(&RefTrieDB::new(&db, &root).unwrap(), &mut full).unwrap();
+
+		let (path, hash) = {
+			let t = RefTrieDB::new(&db, &root).unwrap();
+			RefTrieDBNodeIterator::new(&t).unwrap()
+				.map(|x| x.unwrap())
+				.find(|(path, hash, _, _)| hash.is_some() && !path.is_empty())
+				.map(|(path, hash, _, _)| (path, hash.unwrap()))
+				.unwrap()
+		};
+		let prefix = NibbleSlice::new(path.inner()).encoded_leftmost(path.len(), false);
+		HashDB::remove(&mut db, &hash, &prefix);
+
+		let mut visitor = Collect::default();
+		visit_trie(&RefTrieDB::new(&db, &root).unwrap(), &mut visitor).unwrap();
+		let path = nibbles(&path, NibbleSlice::new(&[]));
+		assert_eq!(visitor.missing, vec![(path.clone(), hash)]);
+
+		let expected: Vec<_> = full.leaves.iter()
+			.filter(|(key, _)| !NibbleSlice::new(key).iter().collect::<Vec<_>>().starts_with(&path))
+			.cloned()
+			.collect();
+		assert!(expected.len() < full.leaves.len());
+		assert_eq!(visitor.leaves, expected);
+	}
+}
@@ -0,0 +1,439 @@
+// Copyright 2017, 2018 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ethereum trie format: RLP encoded nodes with hex-prefix encoded partial keys, as
+//! specified in appendix D of the yellow paper.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use hash_db::Hasher;
+use hash256_std_hasher::Hash256StdHasher;
+use rlp::{DecoderError, Prototype, Rlp, RlpStream};
+use trie_db::{node::{Node, Value}, ChildReference, NibbleSlice, NodeCodec, TrieLayout};
+use trie_root::TrieStream;
+
+pub use rlp;
+
+/// Concrete `Hasher` impl for the Keccak-256 hash.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct KeccakHasher;
+
+impl Hasher for KeccakHasher {
+	type Out = [u8; 32];
+	type StdHasher = Hash256StdHasher;
+	const LENGTH: usize = 32;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		let mut out = [0u8; 32];
+		tiny_keccak::Keccak::keccak256(x, &mut out);
+		out
+	}
+}
+
+/// The Ethereum trie layout: Keccak hashes and RLP encoded nodes, with extension nodes.
+#[derive(Default, Clone)]
+pub struct EthereumLayout;
+
+impl TrieLayout for EthereumLayout {
+	type Hash = KeccakHasher;
+	type Codec = RlpNodeCodec;
+}
+
+pub type TrieDB<'a> = trie_db::TrieDB<'a, EthereumLayout>;
+pub type TrieDBMut<'a> = trie_db::TrieDBMut<'a, EthereumLayout>;
+pub type SecTrieDB<'a> = trie_db::SecTrieDB<'a, EthereumLayout>;
+pub type SecTrieDBMut<'a> = trie_db::SecTrieDBMut<'a, EthereumLayout>;
+pub type FatDB<'a> = trie_db::FatDB<'a, EthereumLayout>;
+pub type FatDBMut<'a> = trie_db::FatDBMut<'a, EthereumLayout>;
+
+/// Root of the Ethereum trie holding the given items.
+pub fn trie_root<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]> + Ord,
+	B: AsRef<[u8]>,
+{
+	trie_root::trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(input)
+}
+
+/// Root of the Ethereum trie holding the given items under the keccak hashes of their keys,
+/// as used for the state and storage tries.
+pub fn sec_trie_root<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+{
+	trie_root::sec_trie_root::<KeccakHasher, RlpTrieStream, _, _, _>(input)
+}
+
+/// Encode nibbles in hex-prefix notation, noting whether they end a `leaf`.
+fn hex_prefix(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+	let mut output = Vec::with_capacity(nibbles.len() / 2 + 1);
+	let flag = if leaf { 0x20 } else { 0 };
+	if nibbles.len() % 2 == 1 {
+		output.push(flag | 0x10 | nibbles[0]);
+	} else {
+		output.push(flag);
+	}
+	output.extend(nibbles[nibbles.len() % 2..].chunks(2).map(|ch| ch[0] << 4 | ch[1]));
+	output
+}
+
+/// Ethereum implementation of a `TrieStream`.
+#[derive(Default)]
+pub struct RlpTrieStream {
+	stream: RlpStream,
+}
+
+impl TrieStream for RlpTrieStream {
+	fn new() -> Self {
+		RlpTrieStream {
+			stream: RlpStream::new(),
+		}
+	}
+
+	fn append_empty_data(&mut self) {
+		self.stream.append_empty_data();
+	}
+
+	fn begin_branch(&mut self, _maybe_value: Option<&[u8]>, _has_children: impl Iterator<Item = bool>) {
+		self.stream.begin_list(17);
+	}
+
+	fn begin_nibbled_branch(&mut self, _partial: &[u8], _maybe_value: Option<&[u8]>, _has_children: impl Iterator<Item = bool>) {
+		unreachable!("no nibbled branches")
+	}
+
+	fn append_empty_child(&mut self) {
+		self.stream.append_empty_data();
+	}
+
+	fn end_branch(&mut self, value: Option<&[u8]>) {
+		match value {
+			Some(value) => self.stream.append(&value),
+			None => self.stream.append_empty_data(),
+		};
+	}
+
+	fn append_leaf(&mut self, key: &[u8], value: &[u8]) {
+		self.stream.begin_list(2);
+		self.stream.append(&&hex_prefix(key, true)[..]);
+		self.stream.append(&value);
+	}
+
+	fn append_extension(&mut self, key: &[u8]) {
+		self.stream.begin_list(2);
+		self.stream.append(&&hex_prefix(key, false)[..]);
+	}
+
+	fn append_substream<H: Hasher>(&mut self, other: Self) {
+		let data = other.out();
+		match Self::is_inline::<H>(data.len()) {
+			true => self.stream.append_raw(&data, 1),
+			false => self.stream.append(&H::hash(&data).as_ref()),
+		};
+	}
+
+	fn as_raw(&self) -> &[u8] {
+		self.stream.as_raw()
+	}
+
+	fn out(self) -> Vec<u8> {
+		self.stream.out()
+	}
+}
+
+/// Ethereum implementation of a `NodeCodec`.
+#[derive(Default, Clone)]
+pub struct RlpNodeCodec;
+
+/// Error decoding an RLP encoded node.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RlpNodeError(pub DecoderError);
+
+#[cfg(feature = "std")]
+impl std::error::Error for RlpNodeError {
+	fn description(&self) -> &str {
+		"rlp node decoding error"
+	}
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for RlpNodeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Bad node: {}", self.0)
+	}
+}
+
+impl From<DecoderError> for RlpNodeError {
+	fn from(e: DecoderError) -> Self {
+		RlpNodeError(e)
+	}
+}
+
+fn append_child(stream: &mut RlpStream, child: ChildReference<<KeccakHasher as Hasher>::Out>) {
+	match child {
+		ChildReference::Hash(h) => stream.append(&h.as_ref()),
+		ChildReference::Inline(inline_data) => stream.append_raw(&inline_data, 1),
+	};
+}
+
+// Ethereum tries keep every value in its node.
+fn inline_value(value: Value) -> &[u8] {
+	match value {
+		Value::Inline(value) => value,
+		Value::Node(_) => unreachable!("layouts using this codec keep every value in its node"),
+	}
+}
+
+impl NodeCodec<KeccakHasher> for RlpNodeCodec {
+	type Error = RlpNodeError;
+
+	fn hashed_null_node() -> <KeccakHasher as Hasher>::Out {
+		KeccakHasher::hash(&rlp::NULL_RLP)
+	}
+
+	fn decode(data: &[u8]) -> Result<Node, Self::Error> {
+		let r = Rlp::new(data);
+		match r.prototype()? {
+			// either a leaf or an extension, told apart by the flag of the hex-prefix key.
+			Prototype::List(2) => {
+				let (slice, is_leaf) = NibbleSlice::from_encoded(r.at(0)?.data()?);
+				if is_leaf {
+					Ok(Node::Leaf(slice, Value::Inline(r.at(1)?.data()?)))
+				} else {
+					Ok(Node::Extension(slice, r.at(1)?.as_raw()))
+				}
+			}
+			Prototype::List(17) => {
+				let mut children = [None; 16];
+				for (i, child) in children.iter_mut().enumerate() {
+					let item = r.at(i)?;
+					if !item.is_empty() {
+						*child = Some(item.as_raw());
+					}
+				}
+				let value = r.at(16)?;
+				let value = if value.is_empty() { None } else { Some(Value::Inline(value.data()?)) };
+				Ok(Node::Branch(children, value))
+			}
+			Prototype::Data(0) => Ok(Node::Empty),
+			_ => Err(DecoderError::Custom("Rlp is not a valid trie node").into()),
+		}
+	}
+
+	fn try_decode_hash(data: &[u8]) -> Option<<KeccakHasher as Hasher>::Out> {
+		let r = Rlp::new(data);
+		if r.is_data() && r.size() == KeccakHasher::LENGTH {
+			let mut h = <KeccakHasher as Hasher>::Out::default();
+			h.as_mut().copy_from_slice(r.data().ok()?);
+			Some(h)
+		} else {
+			None
+		}
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		Rlp::new(data).is_empty()
+	}
+
+	fn empty_node() -> Vec<u8> {
+		let mut stream = RlpStream::new();
+		stream.append_empty_data();
+		stream.out()
+	}
+
+	fn leaf_node(partial: &[u8], value: Value) -> Vec<u8> {
+		let mut stream = RlpStream::new_list(2);
+		stream.append(&partial);
+		stream.append(&inline_value(value));
+		stream.out()
+	}
+
+	fn ext_node(partial: &[u8], child: ChildReference<<KeccakHasher as Hasher>::Out>) -> Vec<u8> {
+		let mut stream = RlpStream::new_list(2);
+		stream.append(&partial);
+		append_child(&mut stream, child);
+		stream.out()
+	}
+
+	fn branch_node<I>(children: I, maybe_value: Option<Value>) -> Vec<u8> where
+		I: IntoIterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>> + Iterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>>
+	{
+		let mut stream = RlpStream::new_list(17);
+		for child in children {
+			match child {
+				Some(child) => append_child(&mut stream, child),
+				None => { stream.append_empty_data(); }
+			}
+		}
+		match maybe_value {
+			Some(value) => stream.append(&inline_value(value)),
+			None => stream.append_empty_data(),
+		};
+		stream.out()
+	}
+
+	fn branch_node_nibbled<I>(_partial: &[u8], _children: I, _maybe_value: Option<Value>) -> Vec<u8> where
+		I: IntoIterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>> + Iterator<Item=Option<ChildReference<<KeccakHasher as Hasher>::Out>>>
+	{
+		unreachable!("no nibbled branches")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use hex_literal::hex;
+	use memory_db::{MemoryDB, HashKey, PrefixedKey};
+	use std::collections::BTreeMap;
+	use trie_db::{DBValue, NodeCodec, Trie, TrieMut, proof};
+	use trie_standardmap::{Alphabet, StandardMap, ValueMode};
+	use super::{EthereumLayout, KeccakHasher, RlpNodeCodec, RlpTrieStream, TrieDB, TrieDBMut, SecTrieDBMut, trie_root, sec_trie_root};
+
+	fn db_root(items: &[(&[u8], &[u8])]) -> <KeccakHasher as hash_db::Hasher>::Out {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::new(&rlp::NULL_RLP);
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::new(&mut memdb, &mut root);
+			for (k, v) in items {
+				t.insert(k, v).unwrap();
+			}
+		}
+		let t = TrieDB::new(&memdb, &root).unwrap();
+		for (k, v) in items {
+			assert_eq!(&t.get(k).unwrap().unwrap()[..], *v);
+		}
+		root
+	}
+
+	#[test]
+	fn empty_root() {
+		let empty = hex!["56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"];
+		assert_eq!(RlpNodeCodec::hashed_null_node(), empty);
+		assert_eq!(trie_root(Vec::<(Vec<u8>, Vec<u8>)>::new()), empty);
+		assert_eq!(db_root(&[]), empty);
+	}
+
+	#[test]
+	fn yellow_paper_vectors() {
+		let vectors: Vec<(Vec<(&[u8], &[u8])>, [u8; 32])> = vec![
+			(
+				vec![(b"foo", b"bar"), (b"food", b"bass")],
+				hex!["17beaa1648bafa633cda809c90c04af50fc8aed3cb40d16efbddee6fdf63c4c3"],
+			),
+			(
+				vec![(b"be", b"e"), (b"dog", b"puppy"), (b"bed", b"d")],
+				hex!["3f67c7a47520f79faa29255d2d3c084a7a6df0453116ed7232ff10277a8be68b"],
+			),
+			(
+				vec![(b"test", b"test"), (b"te", b"testy")],
+				hex!["8452568af70d8d140f58d941338542f645fcca50094b20f3c3d8c3df49337928"],
+			),
+			(
+				vec![(b"do", b"verb"), (b"horse", b"stallion"), (b"doge", b"coin"), (b"dog", b"puppy")],
+				hex!["5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"],
+			),
+			(
+				vec![
+					(b"doe", b"reindeer"),
+					(b"dog", b"puppy"),
+					(b"dogglesworth", b"cat"),
+				],
+				hex!["8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"],
+			),
+		];
+
+		for (items, root) in vectors {
+			assert_eq!(trie_root(items.iter().cloned()), root);
+			assert_eq!(db_root(&items), root);
+		}
+	}
+
+	#[test]
+	fn secure_root() {
+		let items: Vec<(&[u8], &[u8])> = vec![
+			(b"doe", b"reindeer"),
+			(b"dog", b"puppy"),
+			(b"dogglesworth", b"cat"),
+		];
+		let root = hex!["d4cd937e4a4368d7931a9cf51686b7e10abb3dce38a39000fd7902a092b64585"];
+		assert_eq!(sec_trie_root(items.iter().cloned()), root);
+
+		let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::new(&rlp::NULL_RLP);
+		let mut db_root = Default::default();
+		let mut t = SecTrieDBMut::new(&mut memdb, &mut db_root);
+		for (k, v) in &items {
+			t.insert(k, v).unwrap();
+		}
+		assert_eq!(*t.root(), root);
+	}
+
+	#[test]
+	fn sorted_root_matches_random_maps() {
+		let mut seed = Default::default();
+		for i in 0..300 {
+			let map: BTreeMap<_, _> = StandardMap {
+				alphabet: Alphabet::Custom(b"abcd".to_vec()),
+				min_key: 1,
+				journal_key: 4,
+				value_mode: ValueMode::Random,
+				count: i % 50,
+			}.make_with(&mut seed).into_iter().collect();
+
+			let sorted = trie_root::trie_root_sorted::<KeccakHasher, RlpTrieStream, _, _, _>(map.clone());
+			assert_eq!(sorted, Ok(trie_root(map)));
+		}
+	}
+
+	#[test]
+	fn compact_proof_round_trip() {
+		let items: Vec<(&[u8], &[u8])> = vec![
+			(b"do", b"verb"),
+			(b"dog", b"puppy"),
+			(b"doge", b"coin"),
+			(b"horse", b"stallion"),
+			(b"house", b"building with a rather long description"),
+			(b"houseful", b"container"),
+		];
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::new(&rlp::NULL_RLP);
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::new(&mut memdb, &mut root);
+			for (k, v) in &items {
+				t.insert(k, v).unwrap();
+			}
+		}
+
+		let keys: Vec<&[u8]> = vec![b"dog", b"house", b"houseful", b"hors"];
+		let nodes = proof::generate_proof::<EthereumLayout, _>(&memdb, &root, &keys).unwrap();
+		let compact = proof::encode_compact::<EthereumLayout>(&root, &nodes).unwrap();
+		assert_eq!(compact.len(), nodes.len());
+		let decoded = proof::decode_compact::<EthereumLayout>(&compact).unwrap();
+		assert_eq!(decoded, nodes);
+
+		let checked: Vec<(&[u8], Option<&[u8]>)> = vec![
+			(b"dog", Some(b"puppy")),
+			(b"house", Some(b"building with a rather long description")),
+			(b"houseful", Some(b"container")),
+			(b"hors", None),
+		];
+		assert_eq!(proof::verify_proof::<EthereumLayout, _, _>(&root, &decoded, &checked), Ok(()));
+	}
+}
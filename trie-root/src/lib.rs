@@ -26,6 +26,8 @@ extern crate hash_db;
 use std::collections::BTreeMap;
 #[cfg(feature = "std")]
 use std::cmp;
+#[cfg(feature = "std")]
+use std::fmt;
 
 #[cfg(not(feature = "std"))]
 extern crate alloc;
@@ -37,10 +39,22 @@ use core::cmp;
 #[cfg(test)]
 extern crate keccak_hasher;
 
-pub use hash_db::Hasher;
+pub use hash_db::{HashDB, Hasher};
 
 /// Byte-stream oriented trait for constructing closed-form tries.
 pub trait TrieStream {
+	/// Whether the trie has extension nodes. Streams without them store the partial
+	/// key leading to a branch in the branch itself, see `begin_nibbled_branch`.
+	const USE_EXTENSION: bool = true;
+	/// Encoded nodes shorter than this are inlined in their parent instead of being
+	/// hashed; `None` stands for the length of the hash. `Some(0)` hashes every node.
+	const INLINE_THRESHOLD: Option<usize> = None;
+	/// Whether a node encoded in `len` bytes is inlined in its parent, to be used by
+	/// `append_substream`. Nodes as long as the hash are never inlined, so that they
+	/// cannot be mistaken for one.
+	fn is_inline<H: Hasher>(len: usize) -> bool {
+		len != H::LENGTH && len < Self::INLINE_THRESHOLD.unwrap_or(H::LENGTH)
+	}
 	/// Construct a new `TrieStream`
 	fn new() -> Self;
 	/// Append an Empty node
@@ -48,6 +62,10 @@ pub trait TrieStream {
 	/// Start a new Branch node, possibly with a value; takes a list indicating
 	/// which slots in the Branch node has further child nodes.
 	fn begin_branch(&mut self, maybe_value: Option<&[u8]>, has_children: impl Iterator<Item = bool>);
+	/// Start a new Branch node with a partial key, given as nibbles and possibly empty,
+	/// for streams without extension nodes. Otherwise the same as `begin_branch`; never
+	/// called for streams with extension nodes.
+	fn begin_nibbled_branch(&mut self, partial: &[u8], maybe_value: Option<&[u8]>, has_children: impl Iterator<Item = bool>);
 	// Append an empty child node. Optional.
 	fn append_empty_child(&mut self) {}
 	/// Wrap up a Branch node portion of a `TrieStream` and append the value
@@ -59,6 +77,8 @@ pub trait TrieStream {
 	fn append_extension(&mut self, key: &[u8]);
 	/// Append a Branch of Extension substream
 	fn append_substream<H: Hasher>(&mut self, other: Self);
+	/// The bytes appended so far, which are the encoded node once it is complete.
+	fn as_raw(&self) -> &[u8];
 	/// Return the finished `TrieStream` as a vector of bytes.
 	fn out(self) -> Vec<u8>;
 }
@@ -99,31 +119,74 @@ pub fn trie_root<H, S, I, A, B>(input: I) -> H::Out where
 	H: Hasher,
 	S: TrieStream,
 {
-	// first put elements into btree to sort them and to remove duplicates
-	let input = input
-		.into_iter()
-		.collect::<BTreeMap<_, _>>();
+	H::hash(&build_stream::<H, S, _, _, _, _>(input, |_, _| ()).out())
+}
 
-	// convert to nibbles
-	let mut nibbles = Vec::with_capacity(input.keys().map(|k| k.as_ref().len()).sum::<usize>() * 2);
-	let mut lens = Vec::with_capacity(input.len() + 1);
-	lens.push(0);
-	for k in input.keys() {
-		for &b in k.as_ref() {
-			nibbles.push(b >> 4);
-			nibbles.push(b & 0x0F);
+/// Generates a trie root hash for a vector of key-value tuples like `trie_root`, also passing each
+/// encoded node of the trie to `emit` along with the prefix to store it under in a `HashDB`.
+/// Children are emitted before their parent, so the root node comes last.
+///
+/// Like `trie-db`, nodes inlined in their parent are left out, see `TrieStream::is_inline`.
+/// Both the prefix and the inline threshold require `S` to encode nodes the way the `NodeCodec` used to read
+/// the trie does.
+pub fn trie_root_with_nodes<H, S, I, A, B, F>(input: I, mut emit: F) -> H::Out where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]> + Ord,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+	F: FnMut(&[u8], &[u8]),
+{
+	let stream = build_stream::<H, S, _, _, _, _>(input, |path, data| {
+		if !S::is_inline::<H>(data.len()) {
+			emit(&encode_prefix(path), data);
 		}
-		lens.push(nibbles.len());
-	}
+	});
 
-	// then move them to a vector
-	let input = input.into_iter().zip(lens.windows(2))
-		.map(|((_, v), w)| (&nibbles[w[0]..w[1]], v))
-		.collect::<Vec<_>>();
+	// the root is stored by hash whatever its size.
+	let data = stream.out();
+	emit(&encode_prefix(&[]), &data);
+	H::hash(&data)
+}
 
-	let mut stream = S::new();
-	build_trie::<H, S, _, _>(&input, 0, &mut stream);
-	H::hash(&stream.out())
+/// Generates a trie root hash for a vector of key-value tuples like `trie_root`, inserting the
+/// nodes of the trie into `db` so that it can be opened there. See `trie_root_with_nodes`.
+///
+/// ```rust
+/// extern crate trie_root;
+/// extern crate reference_trie;
+/// extern crate keccak_hasher;
+/// extern crate memory_db;
+/// use trie_root::{trie_root, trie_root_into_db};
+/// use reference_trie::{ReferenceTrieStream, RefTrieDB, Trie};
+/// use keccak_hasher::KeccakHasher;
+/// use memory_db::{MemoryDB, PrefixedKey};
+///
+/// fn main() {
+/// 	let v = vec![
+/// 		("doe", "reindeer"),
+/// 		("dog", "puppy"),
+/// 		("dogglesworth", "cat"),
+/// 	];
+///
+/// 	let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, _>::default();
+/// 	let root = trie_root_into_db::<KeccakHasher, ReferenceTrieStream, _, _, _, _>(&mut db, v.clone());
+/// 	assert_eq!(root, trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(v));
+///
+/// 	let trie = RefTrieDB::new(&db, &root).unwrap();
+/// 	assert_eq!(&trie.get(b"dogglesworth").unwrap().unwrap()[..], b"cat");
+/// }
+/// ```
+pub fn trie_root_into_db<H, S, I, A, B, T>(db: &mut dyn HashDB<H, T>, input: I) -> H::Out where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]> + Ord,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	trie_root_with_nodes::<H, S, _, _, _, _>(input, |prefix, data| {
+		db.insert(prefix, data);
+	})
 }
 
 //#[cfg(test)]	// consider feature="std"
@@ -133,12 +196,26 @@ pub fn unhashed_trie<H, S, I, A, B>(input: I) -> Vec<u8> where
 	B: AsRef<[u8]>,
 	H: Hasher,
 	S: TrieStream,
+{
+	build_stream::<H, S, _, _, _, _>(input, |_, _| ()).out()
+}
+
+/// Encodes the root node of the trie for a vector of key-value tuples, handing the encoding
+/// of every other node to `on_child` along with its path, as `build_trie` does.
+fn build_stream<H, S, I, A, B, F>(input: I, mut on_child: F) -> S where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]> + Ord,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+	F: FnMut(&[u8], &[u8]),
 {
 	// first put elements into btree to sort them and to remove duplicates
 	let input = input
 		.into_iter()
 		.collect::<BTreeMap<_, _>>();
 
+	// convert to nibbles
 	let mut nibbles = Vec::with_capacity(input.keys().map(|k| k.as_ref().len()).sum::<usize>() * 2);
 	let mut lens = Vec::with_capacity(input.len() + 1);
 	lens.push(0);
@@ -156,8 +233,137 @@ pub fn unhashed_trie<H, S, I, A, B>(input: I) -> Vec<u8> where
 		.collect::<Vec<_>>();
 
 	let mut stream = S::new();
-	build_trie::<H, S, _, _>(&input, 0, &mut stream);
-	stream.out()
+	build_trie::<H, S, _, _, _>(&input, 0, &mut stream, &mut on_child);
+	stream
+}
+
+/// Error returned when building a trie from input which should be in strictly ascending
+/// key order, but is not.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UnsortedInput {
+	/// The position in the input of the first key not greater than the one before it.
+	pub index: usize,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for UnsortedInput {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Input key at position {} is not greater than the previous one", self.index)
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for UnsortedInput {
+	fn description(&self) -> &str {
+		"Unsorted input"
+	}
+}
+
+/// Generates a trie root hash for key-value tuples given in strictly ascending key order.
+/// The result is the same as `trie_root`'s, but the input is consumed as a stream, only
+/// keeping the nodes along the path of the last key in memory.
+///
+/// ```rust
+/// extern crate trie_root;
+/// extern crate reference_trie;
+/// extern crate keccak_hasher;
+/// use trie_root::{trie_root, trie_root_sorted, UnsortedInput};
+/// use reference_trie::ReferenceTrieStream;
+/// use keccak_hasher::KeccakHasher;
+///
+/// fn main() {
+/// 	let v = vec![
+/// 		("doe", "reindeer"),
+/// 		("dog", "puppy"),
+/// 		("dogglesworth", "cat"),
+/// 	];
+/// 	let root = trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(v.clone());
+/// 	assert_eq!(trie_root_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(v), Ok(root));
+///
+/// 	let unsorted = vec![("dog", "puppy"), ("doe", "reindeer")];
+/// 	assert_eq!(
+/// 		trie_root_sorted::<KeccakHasher, ReferenceTrieStream, _, _, _>(unsorted),
+/// 		Err(UnsortedInput { index: 1 }),
+/// 	);
+/// }
+/// ```
+pub fn trie_root_sorted<H, S, I, A, B>(input: I) -> Result<H::Out, UnsortedInput> where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	unhashed_trie_sorted::<H, S, _, _, _>(input).map(|data| H::hash(&data))
+}
+
+/// Encodes the root node of the trie for key-value tuples given in strictly ascending key order,
+/// like `unhashed_trie` does for any input. See `trie_root_sorted`.
+pub fn unhashed_trie_sorted<H, S, I, A, B>(input: I) -> Result<Vec<u8>, UnsortedInput> where
+	I: IntoIterator<Item = (A, B)>,
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+	H: Hasher,
+	S: TrieStream,
+{
+	// the branches along the path of the last key, by increasing depth.
+	let mut stack: Vec<SortedBranch<S, B>> = Vec::new();
+	// the last key, in nibbles, and its value which has yet to be placed.
+	let mut last: Option<(Vec<u8>, B)> = None;
+
+	for (index, (k, v)) in input.into_iter().enumerate() {
+		let mut key = Vec::with_capacity(k.as_ref().len() * 2);
+		for &b in k.as_ref() {
+			key.push(b >> 4);
+			key.push(b & 0x0F);
+		}
+
+		if let Some((last_key, last_value)) = last.take() {
+			if key <= last_key {
+				return Err(UnsortedInput { index });
+			}
+
+			// everything deeper than the shared prefix is complete.
+			let shared = shared_prefix_len(&last_key, &key);
+			let mut node = SortedNode::Leaf(last_value);
+			while stack.last().map(|branch| branch.depth).unwrap_or(0) > shared {
+				let mut branch = stack.pop().expect("checked above; qed");
+				branch.attach::<H>(node, &last_key);
+				node = SortedNode::Branch(branch);
+			}
+
+			match stack.last_mut() {
+				Some(ref mut branch) if branch.depth == shared => branch.attach::<H>(node, &last_key),
+				_ => {
+					let mut branch = SortedBranch::new(shared);
+					match node {
+						// the last key is a prefix of the new one.
+						SortedNode::Leaf(value) if last_key.len() == shared => branch.value = Some(value),
+						node => branch.attach::<H>(node, &last_key),
+					}
+					stack.push(branch);
+				}
+			}
+		}
+
+		last = Some((key, v));
+	}
+
+	let (last_key, last_value) = match last {
+		Some(last) => last,
+		None => {
+			let mut stream = S::new();
+			stream.append_empty_data();
+			return Ok(stream.out());
+		}
+	};
+
+	let mut node = SortedNode::Leaf(last_value);
+	while let Some(mut branch) = stack.pop() {
+		branch.attach::<H>(node, &last_key);
+		node = SortedNode::Branch(branch);
+	}
+	Ok(node.into_stream::<H>(0, &last_key).out())
 }
 
 /// Generates a key-hashed (secure) trie root hash for a vector of key-value tuples.
@@ -193,14 +399,28 @@ pub fn sec_trie_root<H, S, I, A, B>(input: I) -> H::Out where
 	trie_root::<H, S, _, _, _>(input.into_iter().map(|(k, v)| (H::hash(k.as_ref()), v)))
 }
 
+/// Encodes the path of a node, as nibbles, into the prefix `trie-db` uses for it in a `HashDB`.
+fn encode_prefix(nibbles: &[u8]) -> Vec<u8> {
+	let odd = nibbles.len() % 2;
+	let mut prefix = Vec::with_capacity(nibbles.len() / 2 + 1);
+	prefix.push(if odd == 1 { 0x10 + nibbles[0] } else { 0 });
+	for pair in nibbles[odd..].chunks(2) {
+		prefix.push(pair[0] * 16 + pair[1]);
+	}
+	prefix
+}
+
 /// Takes a slice of key/value tuples where the key is a slice of nibbles
 /// and encodes it into the provided `Stream`.
+/// The encoding of each child node is handed to `on_child` with the node's path,
+/// before being appended.
 // pub fn build_trie<H, S, A, B>(input: &[(A, B)], cursor: usize, stream: &mut S)
-fn build_trie<H, S, A, B>(input: &[(A, B)], cursor: usize, stream: &mut S) where
+fn build_trie<H, S, A, B, F>(input: &[(A, B)], cursor: usize, stream: &mut S, on_child: &mut F) where
 	A: AsRef<[u8]>,
 	B: AsRef<[u8]>,
 	H: Hasher,
 	S: TrieStream,
+	F: FnMut(&[u8], &[u8]),
 {
 	match input.len() {
 		// No input, just append empty data.
@@ -221,12 +441,15 @@ fn build_trie<H, S, A, B>(input: &[(A, B)], cursor: usize, stream: &mut S) where
 			// than what we saw on the last call (`cursor`): append the new part
 			// of the path then recursively append the remainder of all items
 			// who had this partial key.
-			if shared_nibble_count > cursor {
+			if shared_nibble_count > cursor && S::USE_EXTENSION {
 				stream.append_extension(&key[cursor..shared_nibble_count]);
-				build_trie_trampoline::<H, _, _, _>(input, shared_nibble_count, stream);
+				build_trie_trampoline::<H, _, _, _, _>(input, shared_nibble_count, stream, on_child);
 				return;
 			}
 
+			// Without extension nodes the new part of the path goes into the branch node.
+			let (partial, cursor) = (&key[cursor..shared_nibble_count], shared_nibble_count);
+
 			// We'll be adding a branch node because the path is as long as it gets.
 			// First we need to figure out what entries this branch node will have...
 
@@ -251,14 +474,19 @@ fn build_trie<H, S, A, B>(input: &[(A, B)], cursor: usize, stream: &mut S) where
 			}
 
 			// Put out the node header:
-			stream.begin_branch(value, shared_nibble_counts.iter().map(|&n| n > 0));
+			let has_children = shared_nibble_counts.iter().map(|&n| n > 0);
+			if S::USE_EXTENSION {
+				stream.begin_branch(value, has_children);
+			} else {
+				stream.begin_nibbled_branch(partial, value, has_children);
+			}
 
 			// Fill in each slot in the branch node. We don't need to bother with empty slots since they
 			// were registered in the header.
 			let mut begin = match value { None => 0, _ => 1 };
 			for &count in &shared_nibble_counts {
 				if count > 0 {
-					build_trie_trampoline::<H, S, _, _>(&input[begin..(begin + count)], cursor + 1, stream);
+					build_trie_trampoline::<H, S, _, _, _>(&input[begin..(begin + count)], cursor + 1, stream, on_child);
 					begin += count;
 				} else {
 					stream.append_empty_child();
@@ -270,13 +498,99 @@ fn build_trie<H, S, A, B>(input: &[(A, B)], cursor: usize, stream: &mut S) where
 	}
 }
 
-fn build_trie_trampoline<H, S, A, B>(input: &[(A, B)], cursor: usize, stream: &mut S) where
+fn build_trie_trampoline<H, S, A, B, F>(input: &[(A, B)], cursor: usize, stream: &mut S, on_child: &mut F) where
 	A: AsRef<[u8]>,
 	B: AsRef<[u8]>,
 	H: Hasher,
 	S: TrieStream,
+	F: FnMut(&[u8], &[u8]),
 {
 	let mut substream = S::new();
-	build_trie::<H, _, _, _>(input, cursor, &mut substream);
+	build_trie::<H, _, _, _, _>(input, cursor, &mut substream, on_child);
+	on_child(&input[0].0.as_ref()[..cursor], substream.as_raw());
 	stream.append_substream::<H>(substream);
 }
+
+/// A complete subtree of the trie built from sorted input, made of either a single
+/// value or a branch.
+enum SortedNode<S, B> {
+	Leaf(B),
+	/// A branch with all its children.
+	Branch(SortedBranch<S, B>),
+}
+
+impl<S: TrieStream, B: AsRef<[u8]>> SortedNode<S, B> {
+	/// Encode the node starting at the `cursor`th nibble of `key`, which leads to it.
+	fn into_stream<H: Hasher>(self, cursor: usize, key: &[u8]) -> S {
+		match self {
+			SortedNode::Leaf(value) => {
+				let mut stream = S::new();
+				stream.append_leaf(&key[cursor..], value.as_ref());
+				stream
+			}
+			SortedNode::Branch(branch) => {
+				let partial = &key[cursor..branch.depth];
+				if partial.is_empty() || !S::USE_EXTENSION {
+					return branch.into_stream::<H>(partial)
+				}
+				let mut stream = S::new();
+				stream.append_extension(partial);
+				stream.append_substream::<H>(branch.into_stream::<H>(&[]));
+				stream
+			}
+		}
+	}
+}
+
+/// A branch of the trie built from sorted input, which may get more children.
+struct SortedBranch<S, B> {
+	depth: usize,
+	value: Option<B>,
+	/// Encoded children, by ascending index.
+	children: Vec<(u8, S)>,
+}
+
+impl<S: TrieStream, B: AsRef<[u8]>> SortedBranch<S, B> {
+	fn new(depth: usize) -> Self {
+		SortedBranch {
+			depth,
+			value: None,
+			children: Vec::new(),
+		}
+	}
+
+	/// Add a complete child on the path of `key`.
+	fn attach<H: Hasher>(&mut self, node: SortedNode<S, B>, key: &[u8]) {
+		let stream = node.into_stream::<H>(self.depth + 1, key);
+		self.children.push((key[self.depth], stream));
+	}
+
+	/// Encode the branch, along with the partial key leading to it if the stream has no
+	/// extension nodes.
+	fn into_stream<H: Hasher>(self, partial: &[u8]) -> S {
+		let mut has_children = [false; 16];
+		for &(i, _) in &self.children {
+			has_children[i as usize] = true;
+		}
+		let value = self.value.as_ref().map(AsRef::as_ref);
+		let mut children = self.children.into_iter().peekable();
+
+		let mut stream = S::new();
+		if S::USE_EXTENSION {
+			stream.begin_branch(value, has_children.iter().cloned());
+		} else {
+			stream.begin_nibbled_branch(partial, value, has_children.iter().cloned());
+		}
+		for i in 0..16 {
+			match children.peek() {
+				Some(&(index, _)) if index == i => {
+					let (_, child) = children.next().expect("peeked; qed");
+					stream.append_substream::<H>(child);
+				}
+				_ => stream.append_empty_child(),
+			}
+		}
+		stream.end_branch(value);
+		stream
+	}
+}